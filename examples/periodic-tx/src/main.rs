@@ -9,7 +9,7 @@ use teensy4_bsp as bsp;
 use cortex_m_rt::entry;
 use log::info;
 
-use teensy4_canfd::{CAN3FD, CANFDBuilder, TxFDFrame, RxFDFrame};
+use teensy4_canfd::{CAN3FD, CANFDBuilder, FrameFormat, TxFDFrame, RxFDFrame};
 use teensy4_canfd::config::{
     Clock, Config, Id, MailboxConfig, RegionConfig, RxMailboxConfig, TimingConfig,
 };
@@ -116,6 +116,7 @@ fn main() -> ! {
             id: Id::Standard(123),
             buffer: &buffer,
             priority: None,
+            format: FrameFormat::default(),
         };
 
         interrupt::free(|cs| {
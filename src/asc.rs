@@ -0,0 +1,986 @@
+//! Vector ASC trace logs, the text format CANoe & CANalyzer read & write
+//!
+//! Frames are written as `CANFD` lines (classical frames too, without the EDL flag) & errors as
+//! `ErrorFrame` events, one line per call into a buffer the caller hands in. Times in the log are
+//! from the start of the measurement, the `timestamp_us` handed in is on whatever clock the caller
+//! uses for everything else. The symbolic name, duration, bit count, CRC & bit timing fields are
+//! left empty or 0, we don't know them.
+//!
+//! `AscReader` goes the other way, for feeding recorded traces back in. It understands `CANFD`
+//! lines, classical CAN lines & error frames, and skips everything else.
+
+use crate::config::Id;
+use crate::util::{dlc_to_len, len_to_dlc};
+use crate::{FrameFormat, RxFDFrame, TxFDFrame};
+
+/// Longest line `write_*_frame` makes (newline included): timestamp, `CANFD`, channel, direction,
+/// ID, symbolic name, BRS, ESI, DLC, length, 64 bytes of data & the 8 trailing fields
+pub const MAX_LINE_LEN: usize = 21 + 7 + 3 + 1 + 4 + 1 + 9 + 2 + 32 + 9 + 64 * 3 + 68 + 1;
+
+/// Longest header `write_header` makes
+pub const MAX_HEADER_LEN: usize = 193;
+
+const FLAG_EDL: u32 = 0x1000; // FD frame
+const FLAG_BRS: u32 = 0x2000;
+const FLAG_ESI: u32 = 0x4000;
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AscError {
+    BufferTooSmall,
+    Syntax,      // A frame line that doesn't parse
+    Unsupported, // Remote frames, `RxFDFrame` can't hold those
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+/// Wall clock time the measurement started, for the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8, // 1 - 12
+    pub day: u8,   // 1 - 31
+    pub hour: u8,  // 0 - 23
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Frame {
+        direction: Direction,
+        frame: RxFDFrame, // Transmitted frames too, the frame's own `timestamp` is left at 0
+    },
+    ErrorFrame,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub timestamp_us: u64, // From the start of the measurement
+    pub channel: u8,
+    pub event: Event,
+}
+
+/// Widens the driver's 16 bit frame timestamps (counted in bit times at the nominal bitrate) into
+/// microseconds since the first frame. A frame has to come in at least every 65536 bit times, or
+/// a wrap of the counter goes unnoticed.
+pub struct TimestampExtender {
+    bitrate: u32,
+    last: Option<u16>,
+    bit_times: u64,
+}
+
+impl TimestampExtender {
+    /// `bitrate` is the nominal one, `TimingConfig::bitrate` gives it
+    pub fn new(bitrate: u32) -> Self {
+        Self {
+            bitrate: bitrate.max(1),
+            last: None,
+            bit_times: 0,
+        }
+    }
+
+    pub fn extend(&mut self, timestamp: u16) -> u64 {
+        if let Some(last) = self.last {
+            self.bit_times += timestamp.wrapping_sub(last) as u64;
+        }
+
+        self.last = Some(timestamp);
+
+        self.bit_times * 1_000_000 / self.bitrate as u64
+    }
+}
+
+pub struct AscWriter {
+    start_us: u64,
+}
+
+impl AscWriter {
+    /// `start_us` is when the measurement started, on the same clock as the frames' timestamps
+    pub fn new(start_us: u64) -> Self {
+        Self { start_us }
+    }
+
+    /// Writes the header & opens the trigger block, returns the number of bytes written
+    pub fn write_header(&self, out: &mut [u8], date: &DateTime) -> Result<usize, AscError> {
+        let mut writer = Writer { out, len: 0 };
+
+        writer.push_str("date ")?;
+        writer.push_date(date)?;
+        writer.push_str("\nbase hex  timestamps absolute\ninternal events logged\n")?;
+        writer.push_str("// version 9.0.0\nBegin Triggerblock ")?;
+        writer.push_date(date)?;
+        writer.push(b'\n')?;
+        writer.push_time(0)?;
+        writer.push_str(" Start of measurement\n")?;
+
+        Ok(writer.len)
+    }
+
+    /// Closes the trigger block, the last thing in the log
+    pub fn write_footer(&self, out: &mut [u8]) -> Result<usize, AscError> {
+        let mut writer = Writer { out, len: 0 };
+        writer.push_str("End TriggerBlock\n")?;
+
+        Ok(writer.len)
+    }
+
+    pub fn write_rx_frame(
+        &self,
+        out: &mut [u8],
+        timestamp_us: u64,
+        channel: u8,
+        frame: &RxFDFrame,
+    ) -> Result<usize, AscError> {
+        let len = (frame.buffer_len as usize).min(64);
+        let mut flags = 0;
+
+        if frame.fd_frame || len > 8 {
+            flags |= FLAG_EDL;
+        }
+
+        if frame.bitrate_switch {
+            flags |= FLAG_BRS;
+        }
+
+        if frame.error_state {
+            flags |= FLAG_ESI;
+        }
+
+        self.write_frame(
+            out,
+            timestamp_us,
+            channel,
+            Direction::Rx,
+            frame.id,
+            &frame.buffer[..len],
+            flags,
+        )
+    }
+
+    /// Remote frames are logged as empty classical frames
+    pub fn write_tx_frame(
+        &self,
+        out: &mut [u8],
+        timestamp_us: u64,
+        channel: u8,
+        frame: &TxFDFrame,
+    ) -> Result<usize, AscError> {
+        let flags = match frame.format {
+            FrameFormat::Fd {
+                bitrate_switch: true,
+            } => FLAG_EDL | FLAG_BRS,
+            FrameFormat::Fd {
+                bitrate_switch: false,
+            } => FLAG_EDL,
+            FrameFormat::Classic | FrameFormat::Remote { .. } => 0,
+        };
+
+        self.write_frame(
+            out,
+            timestamp_us,
+            channel,
+            Direction::Tx,
+            frame.id,
+            frame.payload(),
+            flags,
+        )
+    }
+
+    /// For bus errors, e.g. when `Stats::error_interrupts` goes up
+    pub fn write_error_frame(
+        &self,
+        out: &mut [u8],
+        timestamp_us: u64,
+        channel: u8,
+    ) -> Result<usize, AscError> {
+        let mut writer = Writer { out, len: 0 };
+
+        writer.push_time(timestamp_us.saturating_sub(self.start_us))?;
+        writer.push(b' ')?;
+        writer.push_decimal(channel as u64, 1)?;
+        writer.push_str("  ErrorFrame\n")?;
+
+        Ok(writer.len)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_frame(
+        &self,
+        out: &mut [u8],
+        timestamp_us: u64,
+        channel: u8,
+        direction: Direction,
+        id: Id,
+        data: &[u8],
+        flags: u32,
+    ) -> Result<usize, AscError> {
+        let mut writer = Writer { out, len: 0 };
+        let dlc = if flags & FLAG_EDL != 0 {
+            len_to_dlc(data.len() as u32)
+        } else {
+            data.len() as u32
+        };
+
+        writer.push_time(timestamp_us.saturating_sub(self.start_us))?;
+        writer.push_str(" CANFD ")?;
+        writer.push_padding(3 - decimal_digits(channel as u64))?;
+        writer.push_decimal(channel as u64, 1)?;
+
+        match direction {
+            Direction::Rx => writer.push_str(" Rx   ")?,
+            Direction::Tx => writer.push_str(" Tx   ")?,
+        }
+
+        match id {
+            Id::Standard(id) => {
+                let id = (id & 0x7FF) as u64;
+                writer.push_padding(8 - hex_digits(id))?;
+                writer.push_hex(id, hex_digits(id))?;
+            }
+            Id::Extended(id) => {
+                let id = (id & 0x1FFF_FFFF) as u64;
+                writer.push_padding(7_usize.saturating_sub(hex_digits(id)))?;
+                writer.push_hex(id, hex_digits(id))?;
+                writer.push(b'x')?;
+            }
+        }
+
+        // An empty symbolic name
+        writer.push_padding(2 + 32)?;
+        writer.push_str(if flags & FLAG_BRS != 0 { " 1" } else { " 0" })?;
+        writer.push_str(if flags & FLAG_ESI != 0 { " 1 " } else { " 0 " })?;
+        writer.push_hex(dlc as u64, 1)?;
+        writer.push_padding(3 - decimal_digits(data.len() as u64))?;
+        writer.push_decimal(data.len() as u64, 1)?;
+
+        for byte in data.iter() {
+            writer.push(b' ')?;
+            writer.push_hex(*byte as u64, 2)?;
+        }
+
+        // Duration, bit count, flags, CRC & the 4 bit timings
+        writer.push_str("        0    0 ")?;
+        writer.push_padding(8 - hex_digits(flags as u64))?;
+        writer.push_hex(flags as u64, hex_digits(flags as u64))?;
+        writer.push_str("        0        0        0        0        0\n")?;
+
+        Ok(writer.len)
+    }
+}
+
+/// Reads a log line by line, keeping track of the `base` & `timestamps` settings in its header
+pub struct AscReader {
+    decimal: bool,
+    relative: bool,
+    last_us: u64,
+}
+
+impl Default for AscReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AscReader {
+    pub fn new() -> Self {
+        Self {
+            decimal: false,
+            relative: false,
+            last_us: 0,
+        }
+    }
+
+    /// Parses a line, with or without the newline. None for anything that isn't a frame or an
+    /// error frame, like the header & other events.
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<LogEntry>, AscError> {
+        let mut fields = line.split_ascii_whitespace();
+        let first = match fields.next() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+
+        if first == "base" {
+            // `base hex  timestamps absolute`
+            self.decimal = fields.next() == Some("dec");
+            self.relative = fields.nth(1) == Some("relative");
+
+            return Ok(None);
+        }
+
+        let timestamp_us = match parse_time(first) {
+            Some(timestamp_us) => timestamp_us,
+            None => return Ok(None),
+        };
+
+        let second = fields.next().unwrap_or("");
+        let (channel, event) = if second == "CANFD" {
+            let channel = fields.next().ok_or(AscError::Syntax)?;
+            let channel = parse_decimal(channel).ok_or(AscError::Syntax)?;
+
+            (channel, self.parse_fd_frame(&mut fields)?)
+        } else if let Some(channel) = parse_decimal(second) {
+            (channel, self.parse_classical_frame(&mut fields)?)
+        } else {
+            return Ok(None);
+        };
+
+        let event = match event {
+            Some(event) if channel <= 0xFF => event,
+            Some(_) => return Err(AscError::Syntax),
+            None => return Ok(None),
+        };
+
+        let timestamp_us = if self.relative {
+            self.last_us = self.last_us.saturating_add(timestamp_us);
+            self.last_us
+        } else {
+            timestamp_us
+        };
+
+        Ok(Some(LogEntry {
+            timestamp_us,
+            channel: channel as u8,
+            event,
+        }))
+    }
+
+    // `<dir> <id> [name] <brs> <esi> <dlc> <len> <data..> <duration> <bits> <flags> ..`
+    fn parse_fd_frame<'a, I: Iterator<Item = &'a str>>(
+        &self,
+        fields: &mut I,
+    ) -> Result<Option<Event>, AscError> {
+        let direction = match parse_direction(fields.next())? {
+            Some(direction) => direction,
+            None => return Ok(None),
+        };
+
+        let id_text = fields.next().ok_or(AscError::Syntax)?;
+
+        if id_text == "ErrorFrame" {
+            return Ok(Some(Event::ErrorFrame));
+        }
+
+        let id = self.parse_id(id_text)?;
+
+        // The symbolic name is optional, BRS is always a 0 or a 1
+        let mut brs = fields.next().ok_or(AscError::Syntax)?;
+
+        if brs != "0" && brs != "1" {
+            brs = fields.next().ok_or(AscError::Syntax)?;
+        }
+
+        let esi = fields.next().ok_or(AscError::Syntax)?;
+        let dlc = fields.next().and_then(|dlc| parse_number(dlc, 16));
+        let len = fields.next().and_then(|len| parse_number(len, 10));
+
+        let (dlc, len) = match (brs, esi, dlc, len) {
+            ("0" | "1", "0" | "1", Some(dlc), Some(len)) if dlc <= 15 && len <= 64 => {
+                (dlc as u32, len as usize)
+            }
+            _ => return Err(AscError::Syntax),
+        };
+
+        let mut frame = empty_frame(id);
+        frame.bitrate_switch = brs == "1";
+        frame.error_state = esi == "1";
+        self.parse_data(fields, &mut frame, len)?;
+
+        // Duration & bit count come before the flags, if they're there at all
+        frame.fd_frame = match fields.nth(2).and_then(|flags| parse_number(flags, 16)) {
+            Some(flags) => flags as u32 & FLAG_EDL != 0,
+            None => frame.bitrate_switch || frame.error_state || len > 8,
+        };
+
+        if frame.fd_frame && dlc_to_len(dlc) != len as u32 {
+            return Err(AscError::Syntax);
+        }
+
+        Ok(Some(Event::Frame { direction, frame }))
+    }
+
+    // `<id> <dir> d <dlc> <data..>` or `ErrorFrame`
+    fn parse_classical_frame<'a, I: Iterator<Item = &'a str>>(
+        &self,
+        fields: &mut I,
+    ) -> Result<Option<Event>, AscError> {
+        let id_text = match fields.next() {
+            Some(id_text) => id_text,
+            None => return Ok(None),
+        };
+
+        if id_text == "ErrorFrame" {
+            return Ok(Some(Event::ErrorFrame));
+        }
+
+        // Other events on a channel, like `Statistic:` or `Chip status`
+        let id = match self.parse_id(id_text) {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+
+        let direction = parse_direction(fields.next())?;
+
+        match fields.next() {
+            Some("d") => {}
+            Some("r") => return Err(AscError::Unsupported),
+            _ => return Err(AscError::Syntax),
+        }
+
+        let dlc = fields.next().and_then(|dlc| parse_number(dlc, 16));
+        let dlc = dlc.filter(|dlc| *dlc <= 15).ok_or(AscError::Syntax)?;
+
+        let mut frame = empty_frame(id);
+        self.parse_data(fields, &mut frame, (dlc as usize).min(8))?;
+
+        Ok(direction.map(|direction| Event::Frame { direction, frame }))
+    }
+
+    fn parse_data<'a, I: Iterator<Item = &'a str>>(
+        &self,
+        fields: &mut I,
+        frame: &mut RxFDFrame,
+        len: usize,
+    ) -> Result<(), AscError> {
+        let radix = if self.decimal { 10 } else { 16 };
+
+        for byte in frame.buffer[..len].iter_mut() {
+            let value = fields.next().and_then(|text| parse_number(text, radix));
+            *byte = value
+                .filter(|value| *value <= 0xFF)
+                .ok_or(AscError::Syntax)? as u8;
+        }
+
+        frame.buffer_len = len as u32;
+
+        Ok(())
+    }
+
+    fn parse_id(&self, text: &str) -> Result<Id, AscError> {
+        let radix = if self.decimal { 10 } else { 16 };
+
+        match text.strip_suffix(&['x', 'X'][..]) {
+            Some(text) => parse_number(text, radix)
+                .filter(|id| *id <= 0x1FFF_FFFF)
+                .map(|id| Id::Extended(id as u32)),
+            None => parse_number(text, radix)
+                .filter(|id| *id <= 0x7FF)
+                .map(|id| Id::Standard(id as u32)),
+        }
+        .ok_or(AscError::Syntax)
+    }
+}
+
+// None for `TxRq`, a transmit request rather than a frame on the bus
+fn parse_direction(text: Option<&str>) -> Result<Option<Direction>, AscError> {
+    match text {
+        Some("Rx") => Ok(Some(Direction::Rx)),
+        Some("Tx") => Ok(Some(Direction::Tx)),
+        Some("TxRq") => Ok(None),
+        _ => Err(AscError::Syntax),
+    }
+}
+
+// Seconds with up to 9 decimals, into microseconds
+fn parse_time(text: &str) -> Option<u64> {
+    let (seconds, fraction) = text.split_once('.')?;
+
+    if fraction.is_empty() || fraction.len() > 9 {
+        return None;
+    }
+
+    let mut micros = parse_decimal(fraction)?;
+
+    for _ in fraction.len()..6 {
+        micros *= 10;
+    }
+
+    for _ in 6..fraction.len() {
+        micros /= 10;
+    }
+
+    parse_decimal(seconds)?
+        .checked_mul(1_000_000)?
+        .checked_add(micros)
+}
+
+fn parse_decimal(text: &str) -> Option<u64> {
+    parse_number(text, 10)
+}
+
+fn parse_number(text: &str, radix: u32) -> Option<u64> {
+    if text.is_empty() || !text.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+
+    u64::from_str_radix(text, radix).ok()
+}
+
+fn empty_frame(id: Id) -> RxFDFrame {
+    RxFDFrame {
+        id,
+        buffer_len: 0,
+        buffer: [0; 64],
+        timestamp: 0,
+        error_state: false,
+        fd_frame: false,
+        bitrate_switch: false,
+        overrun: false,
+        mailbox_index: 0,
+        priority: 0,
+    }
+}
+
+fn decimal_digits(value: u64) -> usize {
+    let mut digits = 1;
+
+    while digits < 20 && value >= 10_u64.pow(digits as u32) {
+        digits += 1;
+    }
+
+    digits
+}
+
+fn hex_digits(value: u64) -> usize {
+    (((64 - value.leading_zeros()) as usize).div_ceil(4)).max(1)
+}
+
+// Sakamoto's method, 0 is Sunday
+fn weekday(year: u16, month: u8, day: u8) -> usize {
+    const OFFSETS: [u32; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+
+    let month = month.clamp(1, 12) as usize;
+    let year = if month < 3 {
+        (year as u32).saturating_sub(1)
+    } else {
+        year as u32
+    };
+
+    ((year + year / 4 - year / 100 + year / 400 + OFFSETS[month - 1] + day as u32) % 7) as usize
+}
+
+struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn push(&mut self, byte: u8) -> Result<(), AscError> {
+        let slot = self.out.get_mut(self.len).ok_or(AscError::BufferTooSmall)?;
+        *slot = byte;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn push_str(&mut self, string: &str) -> Result<(), AscError> {
+        for byte in string.bytes() {
+            self.push(byte)?;
+        }
+
+        Ok(())
+    }
+
+    fn push_padding(&mut self, spaces: usize) -> Result<(), AscError> {
+        for _ in 0..spaces {
+            self.push(b' ')?;
+        }
+
+        Ok(())
+    }
+
+    fn push_hex(&mut self, value: u64, digits: usize) -> Result<(), AscError> {
+        for digit in (0..digits).rev() {
+            self.push(b"0123456789ABCDEF"[((value >> (digit * 4)) & 0xF) as usize])?;
+        }
+
+        Ok(())
+    }
+
+    // At least `min_digits`, padded with zeros
+    fn push_decimal(&mut self, value: u64, min_digits: usize) -> Result<(), AscError> {
+        for digit in (0..decimal_digits(value).max(min_digits)).rev() {
+            self.push(b'0' + ((value / 10_u64.pow(digit as u32)) % 10) as u8)?;
+        }
+
+        Ok(())
+    }
+
+    // Seconds with 6 decimals, right aligned to 11 characters
+    fn push_time(&mut self, timestamp_us: u64) -> Result<(), AscError> {
+        let seconds = timestamp_us / 1_000_000;
+
+        self.push_padding(4_usize.saturating_sub(decimal_digits(seconds)))?;
+        self.push_decimal(seconds, 1)?;
+        self.push(b'.')?;
+        self.push_decimal(timestamp_us % 1_000_000, 6)
+    }
+
+    // `Mon Oct 19 09:05:07.123 am 2026`
+    fn push_date(&mut self, date: &DateTime) -> Result<(), AscError> {
+        let hour = match date.hour % 12 {
+            0 => 12,
+            hour => hour,
+        };
+
+        self.push_str(WEEKDAYS[weekday(date.year, date.month, date.day)])?;
+        self.push(b' ')?;
+        self.push_str(MONTHS[date.month.clamp(1, 12) as usize - 1])?;
+        self.push(b' ')?;
+        self.push_decimal(date.day as u64, 2)?;
+        self.push(b' ')?;
+        self.push_decimal(hour as u64, 2)?;
+        self.push(b':')?;
+        self.push_decimal(date.minute as u64, 2)?;
+        self.push(b':')?;
+        self.push_decimal(date.second as u64, 2)?;
+        self.push(b'.')?;
+        self.push_decimal(date.millisecond as u64, 3)?;
+        self.push_str(if date.hour < 12 { " am " } else { " pm " })?;
+        self.push_decimal(date.year as u64, 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::rx_frame;
+
+    const DATE: DateTime = DateTime {
+        year: 2026,
+        month: 10,
+        day: 19,
+        hour: 0,
+        minute: 5,
+        second: 7,
+        millisecond: 12,
+    };
+
+    fn text(out: &[u8], len: usize) -> String {
+        String::from_utf8(out[..len].to_vec()).unwrap()
+    }
+
+    fn frame(id: Id, data: &[u8], fd_frame: bool, bitrate_switch: bool, esi: bool) -> RxFDFrame {
+        let mut frame = rx_frame(id, data);
+        frame.fd_frame = fd_frame;
+        frame.bitrate_switch = bitrate_switch;
+        frame.error_state = esi;
+        frame
+    }
+
+    fn parse_frame(reader: &mut AscReader, line: &str) -> (u64, u8, Direction, RxFDFrame) {
+        match reader.parse_line(line).unwrap().unwrap() {
+            LogEntry {
+                timestamp_us,
+                channel,
+                event: Event::Frame { direction, frame },
+            } => (timestamp_us, channel, direction, frame),
+            entry => panic!("{:?}", entry),
+        }
+    }
+
+    fn assert_same_frame(read: &RxFDFrame, written: &RxFDFrame) {
+        assert_eq!(read.id, written.id);
+        assert_eq!(read.buffer_len, written.buffer_len);
+        assert_eq!(read.buffer, written.buffer);
+        assert_eq!(read.fd_frame, written.fd_frame);
+        assert_eq!(read.bitrate_switch, written.bitrate_switch);
+        assert_eq!(read.error_state, written.error_state);
+    }
+
+    #[test]
+    fn header() {
+        let writer = AscWriter::new(0);
+        let mut out = [0; MAX_HEADER_LEN];
+
+        let len = writer.write_header(&mut out, &DATE).unwrap();
+        let header = text(&out, len);
+        assert_eq!(
+            header,
+            "date Mon Oct 19 12:05:07.012 am 2026\n\
+             base hex  timestamps absolute\n\
+             internal events logged\n\
+             // version 9.0.0\n\
+             Begin Triggerblock Mon Oct 19 12:05:07.012 am 2026\n   \
+             0.000000 Start of measurement\n"
+        );
+
+        // Nothing in the header or footer is a frame
+        let mut reader = AscReader::new();
+
+        for line in header.lines().chain(["End TriggerBlock"].iter().copied()) {
+            assert!(reader.parse_line(line).unwrap().is_none(), "{}", line);
+        }
+
+        let longest = DateTime {
+            year: 65535,
+            month: 12,
+            day: 31,
+            hour: 23,
+            minute: 59,
+            second: 59,
+            millisecond: 999,
+        };
+        assert_eq!(writer.write_header(&mut out, &longest), Ok(MAX_HEADER_LEN));
+        assert_eq!(
+            writer.write_header(&mut out[..MAX_HEADER_LEN - 1], &longest),
+            Err(AscError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn rx_round_trips() {
+        let writer = AscWriter::new(1_000_000);
+        let mut reader = AscReader::new();
+        let mut out = [0; MAX_LINE_LEN];
+
+        let len = writer
+            .write_rx_frame(
+                &mut out,
+                2_500_000,
+                1,
+                &rx_frame(Id::Standard(0x123), &[1, 2, 3]),
+            )
+            .unwrap();
+        assert_eq!(
+            text(&out, len),
+            concat!(
+                "   1.500000 CANFD   1 Rx        123                                   0 0 3  3 ",
+                "01 02 03        0    0        0        0        0        0        0        0\n",
+            )
+        );
+
+        let frames = [
+            frame(Id::Standard(0x123), &[1, 2, 3], false, false, false),
+            frame(Id::Standard(0x7FF), &[], false, false, false),
+            frame(Id::Extended(0x0123_4567), &[0xAB; 8], false, false, false),
+            frame(Id::Standard(0x001), &[0x11; 12], true, false, false),
+            frame(Id::Extended(0x1ABC), &[0x22; 20], true, true, false),
+            frame(Id::Standard(0x456), &[0x33; 5], true, true, true),
+            frame(Id::Extended(0x1FFF_FFFF), &[0x44; 64], true, false, true),
+        ];
+
+        for (i, written) in frames.iter().enumerate() {
+            let timestamp_us = 1_000_000 + i as u64 * 1_234;
+            let len = writer
+                .write_rx_frame(&mut out, timestamp_us, 2, written)
+                .unwrap();
+
+            let (read_us, channel, direction, read) = parse_frame(&mut reader, &text(&out, len));
+            assert_eq!(read_us, i as u64 * 1_234);
+            assert_eq!(channel, 2);
+            assert_eq!(direction, Direction::Rx);
+            assert_same_frame(&read, written);
+        }
+    }
+
+    #[test]
+    fn tx_round_trips() {
+        let writer = AscWriter::new(0);
+        let mut reader = AscReader::new();
+        let mut out = [0; MAX_LINE_LEN];
+
+        let cases = [
+            (FrameFormat::Classic, &[1, 2][..], false, false),
+            (
+                FrameFormat::Fd {
+                    bitrate_switch: false,
+                },
+                &[3; 16][..],
+                true,
+                false,
+            ),
+            (
+                FrameFormat::Fd {
+                    bitrate_switch: true,
+                },
+                &[4; 8][..],
+                true,
+                true,
+            ),
+            (FrameFormat::Remote { dlc: 4 }, &[][..], false, false),
+        ];
+
+        for (format, data, fd_frame, bitrate_switch) in cases.iter() {
+            let tx_frame = TxFDFrame {
+                id: Id::Extended(0x0123_4567),
+                buffer: data,
+                priority: None,
+                format: *format,
+            };
+            let len = writer.write_tx_frame(&mut out, 7, 3, &tx_frame).unwrap();
+
+            let (timestamp_us, channel, direction, read) =
+                parse_frame(&mut reader, &text(&out, len));
+            assert_eq!(timestamp_us, 7);
+            assert_eq!(channel, 3);
+            assert_eq!(direction, Direction::Tx);
+            assert_same_frame(
+                &read,
+                &frame(tx_frame.id, data, *fd_frame, *bitrate_switch, false),
+            );
+        }
+    }
+
+    #[test]
+    fn error_frames() {
+        let writer = AscWriter::new(1_000_000);
+        let mut reader = AscReader::new();
+        let mut out = [0; MAX_LINE_LEN];
+
+        let len = writer.write_error_frame(&mut out, 3_000_000, 1).unwrap();
+        assert_eq!(text(&out, len), "   2.000000 1  ErrorFrame\n");
+
+        for line in [text(&out, len).as_str(), "  12.3 CANFD   1 Rx ErrorFrame"].iter() {
+            let entry = reader.parse_line(line).unwrap().unwrap();
+            assert!(matches!(entry.event, Event::ErrorFrame));
+        }
+    }
+
+    #[test]
+    fn longest_line() {
+        let writer = AscWriter::new(0);
+        let mut reader = AscReader::new();
+        let mut out = [0; MAX_LINE_LEN];
+        let frame = frame(Id::Extended(0x1FFF_FFFF), &[0x55; 64], true, true, true);
+
+        let len = writer
+            .write_rx_frame(&mut out, u64::MAX, 255, &frame)
+            .unwrap();
+        assert_eq!(len, MAX_LINE_LEN);
+        assert_eq!(
+            writer.write_rx_frame(&mut out[..len - 1], u64::MAX, 255, &frame),
+            Err(AscError::BufferTooSmall)
+        );
+
+        let (timestamp_us, channel, _, read) = parse_frame(&mut reader, &text(&out, len));
+        assert_eq!(timestamp_us, u64::MAX);
+        assert_eq!(channel, 255);
+        assert_same_frame(&read, &frame);
+    }
+
+    #[test]
+    fn other_tools() {
+        let mut reader = AscReader::new();
+
+        let (timestamp_us, _, direction, frame) = parse_frame(
+            &mut reader,
+            "   0.010000 1  1A2             Rx   d 8 00 11 22 33 44 55 66 77  Length = 240000 \
+             BitCount = 124 ID = 418",
+        );
+        assert_eq!(timestamp_us, 10_000);
+        assert_eq!(direction, Direction::Rx);
+        assert_eq!(frame.id, Id::Standard(0x1A2));
+        assert_eq!(
+            frame.buffer[..8],
+            [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]
+        );
+        assert!(!frame.fd_frame);
+
+        // With a symbolic name & no trailing fields
+        let (timestamp_us, _, _, frame) = parse_frame(
+            &mut reader,
+            "  12.3 CANFD   1 Rx 100x  EngineData  1 0 a 16 00 01 02 03 04 05 06 07 08 09 0a 0b \
+             0c 0d 0e 0f",
+        );
+        assert_eq!(timestamp_us, 12_300_000);
+        assert_eq!(frame.id, Id::Extended(0x100));
+        assert_eq!(frame.buffer_len, 16);
+        assert!(frame.fd_frame && frame.bitrate_switch);
+
+        let skipped = [
+            "   0.010000 1  Statistic: D 0 R 0 XD 0 XR 0 E 0 O 0 B 0.00%",
+            "   0.010000 1  1A2   TxRq d 1 00",
+            "   0.010000 CANFD   1 TxRq 1A2 0 0 1  1 00",
+            "// a comment",
+            "",
+        ];
+
+        for line in skipped.iter() {
+            assert!(reader.parse_line(line).unwrap().is_none(), "{}", line);
+        }
+
+        assert_eq!(
+            reader
+                .parse_line("   0.010000 1  1A2             Rx   r")
+                .unwrap_err(),
+            AscError::Unsupported
+        );
+
+        let invalid = [
+            // A length that doesn't go with the DLC
+            "  12.3 CANFD   1 Rx 100 1 0 a 12 00 01 02 03 04 05 06 07 08 09 0a 0b",
+            "  12.3 CANFD   1 Rx 800 1 0 1  1 00",
+            "  12.3 CANFD   1 Rx 100 2 0 1  1 00",
+            "  12.3 CANFD   1 Rx 100 1 0 1  1 100",
+            "  12.3 CANFD 256 Rx 100 1 0 1  1 00",
+            "   0.010000 1  1A2             Rx   d 2 00",
+            "   0.010000 1  1A2             Up   d 1 00",
+        ];
+
+        for line in invalid.iter() {
+            assert_eq!(
+                reader.parse_line(line).unwrap_err(),
+                AscError::Syntax,
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn decimal_relative() {
+        let mut reader = AscReader::new();
+        assert!(reader
+            .parse_line("base dec  timestamps relative")
+            .unwrap()
+            .is_none());
+
+        let (first_us, _, _, frame) = parse_frame(
+            &mut reader,
+            "   1.000000 1  291             Rx   d 2 255 16",
+        );
+        assert_eq!(frame.id, Id::Standard(0x123));
+        assert_eq!(frame.buffer[..2], [0xFF, 0x10]);
+
+        let (second_us, _, _, frame) = parse_frame(
+            &mut reader,
+            "   0.500000 CANFD   1 Rx 536870911x 1 0 9 12 0 1 2 3 4 5 6 7 8 9 10 11",
+        );
+        assert_eq!(frame.id, Id::Extended(0x1FFF_FFFF));
+        assert_eq!(frame.buffer[..12], [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+
+        // Each line's time is from the one before
+        assert_eq!((first_us, second_us), (1_000_000, 1_500_000));
+
+        // Hex bytes don't parse any more, until a new header switches back
+        assert_eq!(
+            reader.parse_line("   0.1 1  291 Rx d 1 FF").unwrap_err(),
+            AscError::Syntax
+        );
+        reader.parse_line("base hex  timestamps absolute").unwrap();
+
+        let (timestamp_us, _, _, frame) = parse_frame(&mut reader, "   0.1 1  1A2 Rx d 1 FF");
+        assert_eq!(timestamp_us, 100_000);
+        assert_eq!(frame.id, Id::Standard(0x1A2));
+        assert_eq!(frame.buffer[0], 0xFF);
+    }
+
+    #[test]
+    fn timestamp_extender() {
+        let mut extender = TimestampExtender::new(500_000);
+        assert_eq!(extender.extend(65_000), 0);
+        // Across the wrap of the counter
+        assert_eq!(extender.extend(464), 2_000);
+        assert_eq!(extender.extend(1_464), 4_000);
+    }
+}
@@ -0,0 +1,619 @@
+//! Bus load estimation from the frames we send and receive
+//!
+//! Every frame is turned into its on-wire length, split into the bits sent at the nominal
+//! (arbitration) bitrate and the bits sent at the FD (data) bitrate, and the time it kept the bus
+//! busy is accumulated into a sliding window. The window is made of `BUS_LOAD_SLOTS` slots, the
+//! oldest one is dropped every time the window advances by a slot.
+
+use crate::config::{Config, Id};
+use crate::util::{dlc_to_len, len_to_dlc};
+use crate::{RxFDFrame, TxFDFrame};
+
+pub const BUS_LOAD_SLOTS: usize = 16;
+
+// CRC delimiter, ACK, ACK delimiter, EOF & intermission, none of these are stuffed
+const FRAME_TAIL_BITS: u32 = 1 + 1 + 1 + 7 + 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stuffing {
+    WorstCase, // Assume a stuff bit after every 4 bits, cheap but pessimistic
+    Exact,     // Generate the actual bit stream (including the classical CRC) and count
+}
+
+/// The length of a frame on the wire, in bits sent at each bitrate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameBits {
+    pub nominal: u32,
+    pub data: u32,
+}
+
+impl FrameBits {
+    pub fn total(&self) -> u32 {
+        self.nominal + self.data
+    }
+}
+
+/// Computes the on-wire length of a frame. `data` has to be the full payload as sent, so a
+/// length that is valid for a DLC. The data phase bits are only split out when the frame is an
+/// FD frame with the bitrate switch set, otherwise everything is sent at the nominal bitrate.
+pub fn frame_bits(
+    id: Id,
+    data: &[u8],
+    fd_frame: bool,
+    bitrate_switch: bool,
+    error_state: bool,
+    stuffing: Stuffing,
+) -> FrameBits {
+    let data_len = data.len().min(64) as u32;
+    let data = &data[..data_len as usize];
+    let extended = match id {
+        Id::Standard(_) => false,
+        Id::Extended(_) => true,
+    };
+
+    let mut bits = if fd_frame {
+        // SOF, ID (+ SRR, IDE, ID ext), RRS, IDE, FDF, res & BRS
+        let arbitration = if extended { 36 } else { 17 };
+        // ESI, DLC & data
+        let dynamic_data = 1 + 4 + data_len * 8;
+        // Stuff count, CRC & the fixed stuff bits before every 4 of those bits
+        let fixed = if data_len <= 16 {
+            4 + 17 + 6
+        } else {
+            4 + 21 + 7
+        };
+
+        let (arbitration_stuff, data_stuff) = match stuffing {
+            Stuffing::WorstCase => {
+                let total_stuff = (arbitration + dynamic_data - 1) / 4;
+                let arbitration_stuff = (arbitration - 1) / 4;
+
+                (arbitration_stuff, total_stuff - arbitration_stuff)
+            }
+            Stuffing::Exact => {
+                let mut stream = BitStream::new();
+                write_header(&mut stream, id, true, bitrate_switch, error_state, data_len);
+                stream.write_bytes(data);
+
+                count_stuff_bits(&stream, arbitration as usize)
+            }
+        };
+
+        FrameBits {
+            nominal: arbitration + arbitration_stuff + FRAME_TAIL_BITS,
+            data: dynamic_data + data_stuff + fixed,
+        }
+    } else {
+        let data_len = data_len.min(8);
+        let data = &data[..data_len as usize];
+        // SOF, ID (+ SRR, IDE, ID ext), RTR, IDE/r1, r0, DLC, data & CRC
+        let stuffed_len = (if extended { 54 } else { 34 }) + data_len * 8;
+
+        let stuff = match stuffing {
+            Stuffing::WorstCase => (stuffed_len - 1) / 4,
+            Stuffing::Exact => {
+                let mut stream = BitStream::new();
+                write_header(&mut stream, id, false, false, false, data_len);
+                stream.write_bytes(data);
+
+                let crc = crc15(&stream);
+                stream.write_bits(crc as u32, 15);
+
+                count_stuff_bits(&stream, stream.len).0
+            }
+        };
+
+        FrameBits {
+            nominal: stuffed_len + stuff + FRAME_TAIL_BITS,
+            data: 0,
+        }
+    };
+
+    if !(fd_frame && bitrate_switch) {
+        bits.nominal += bits.data;
+        bits.data = 0;
+    }
+
+    bits
+}
+
+/// On-wire length of a received frame
+pub fn rx_frame_bits(frame: &RxFDFrame, stuffing: Stuffing) -> FrameBits {
+    frame_bits(
+        frame.id,
+        &frame.buffer[..frame.buffer_len.min(64) as usize],
+        frame.fd_frame,
+        frame.bitrate_switch,
+        frame.error_state,
+        stuffing,
+    )
+}
+
+/// On-wire length of a transmitted frame, FD payloads are padded up to the next DLC length
+pub fn tx_frame_bits(frame: &TxFDFrame, stuffing: Stuffing) -> FrameBits {
+    let mut buffer = [0_u8; 64];
+    let payload = frame.payload();
+    buffer[..payload.len()].copy_from_slice(payload);
+
+    let padded_len = dlc_to_len(len_to_dlc(payload.len() as u32)) as usize;
+
+    frame_bits(
+        frame.id,
+        &buffer[..padded_len],
+        frame.format.is_fd(),
+        frame.format.bitrate_switch(),
+        false,
+        stuffing,
+    )
+}
+
+pub struct BusLoadEstimator {
+    nominal_bitrate: u32,
+    data_bitrate: u32,
+    stuffing: Stuffing,
+    slot_us: u32,
+    slots: [u64; BUS_LOAD_SLOTS], // Nanoseconds the bus was busy during each slot
+    current_slot: usize,
+    current_slot_start_us: u32,
+    filled_slots: usize,
+}
+
+impl BusLoadEstimator {
+    /// Creates an estimator using the bitrates from the driver config, averaging over
+    /// `window_us` microseconds
+    pub fn new(config: &Config, window_us: u32, stuffing: Stuffing) -> Self {
+        Self::with_bitrates(
+            config.timing_classical.bitrate(config.clock_speed),
+            config.timing_fd.bitrate(config.clock_speed),
+            window_us,
+            stuffing,
+        )
+    }
+
+    pub fn with_bitrates(
+        nominal_bitrate: u32,
+        data_bitrate: u32,
+        window_us: u32,
+        stuffing: Stuffing,
+    ) -> Self {
+        Self {
+            nominal_bitrate: nominal_bitrate.max(1),
+            data_bitrate: data_bitrate.max(1),
+            stuffing,
+            slot_us: (window_us / BUS_LOAD_SLOTS as u32).max(1),
+            slots: [0; BUS_LOAD_SLOTS],
+            current_slot: 0,
+            current_slot_start_us: 0,
+            filled_slots: 0,
+        }
+    }
+
+    /// How long a frame of the given length keeps the bus busy, in nanoseconds
+    pub fn frame_time_ns(&self, bits: FrameBits) -> u64 {
+        (bits.nominal as u64 * 1_000_000_000) / self.nominal_bitrate as u64
+            + (bits.data as u64 * 1_000_000_000) / self.data_bitrate as u64
+    }
+
+    /// Records a received frame, `now_us` is a free running microsecond time base that's
+    /// allowed to wrap
+    pub fn record_rx(&mut self, frame: &RxFDFrame, now_us: u32) {
+        let bits = rx_frame_bits(frame, self.stuffing);
+        self.record_bits(bits, now_us);
+    }
+
+    pub fn record_tx(&mut self, frame: &TxFDFrame, now_us: u32) {
+        let bits = tx_frame_bits(frame, self.stuffing);
+        self.record_bits(bits, now_us);
+    }
+
+    pub fn record_bits(&mut self, bits: FrameBits, now_us: u32) {
+        self.advance(now_us);
+
+        let busy_ns = self.frame_time_ns(bits);
+        self.slots[self.current_slot] += busy_ns;
+    }
+
+    /// The bus load over the window in percent, 0.0 - 100.0
+    pub fn load_percent(&mut self, now_us: u32) -> f32 {
+        self.advance(now_us);
+
+        let busy_ns: u64 = self.slots.iter().sum();
+        let current_slot_us = now_us.wrapping_sub(self.current_slot_start_us) as u64;
+        let window_ns =
+            ((self.filled_slots as u64) * self.slot_us as u64 + current_slot_us) * 1_000;
+
+        if window_ns == 0 {
+            return 0.0;
+        }
+
+        ((busy_ns as f32 / window_ns as f32) * 100.0).min(100.0)
+    }
+
+    pub fn reset(&mut self, now_us: u32) {
+        self.slots = [0; BUS_LOAD_SLOTS];
+        self.current_slot = 0;
+        self.current_slot_start_us = now_us;
+        self.filled_slots = 0;
+    }
+
+    fn advance(&mut self, now_us: u32) {
+        let elapsed_slots = now_us.wrapping_sub(self.current_slot_start_us) / self.slot_us;
+
+        if elapsed_slots as usize >= BUS_LOAD_SLOTS {
+            // Nothing in the window is recent enough to matter anymore
+            self.reset(now_us);
+            return;
+        }
+
+        for _ in 0..elapsed_slots {
+            self.current_slot = (self.current_slot + 1) % BUS_LOAD_SLOTS;
+            self.slots[self.current_slot] = 0;
+            self.filled_slots = (self.filled_slots + 1).min(BUS_LOAD_SLOTS - 1);
+        }
+
+        self.current_slot_start_us = self
+            .current_slot_start_us
+            .wrapping_add(elapsed_slots * self.slot_us);
+    }
+}
+
+// The dynamically stuffed part of any frame is at most 41 + 64 * 8 bits long (extended FD)
+struct BitStream {
+    bits: [u8; 72],
+    len: usize,
+}
+
+impl BitStream {
+    fn new() -> Self {
+        Self {
+            bits: [0; 72],
+            len: 0,
+        }
+    }
+
+    fn write_bits(&mut self, value: u32, count: u32) {
+        for shift in (0..count).rev() {
+            if (value >> shift) & 0b1 == 0b1 {
+                self.bits[self.len / 8] |= 0x80 >> (self.len % 8);
+            }
+
+            self.len += 1;
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_bits(*byte as u32, 8);
+        }
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
+
+fn write_header(
+    stream: &mut BitStream,
+    id: Id,
+    fd_frame: bool,
+    bitrate_switch: bool,
+    error_state: bool,
+    data_len: u32,
+) {
+    stream.write_bits(0, 1); // SOF
+
+    match id {
+        Id::Standard(id) => {
+            stream.write_bits(id & 0x7FF, 11);
+            stream.write_bits(0, 1); // RTR/RRS
+            stream.write_bits(0, 1); // IDE
+        }
+        Id::Extended(id) => {
+            stream.write_bits((id >> 18) & 0x7FF, 11);
+            stream.write_bits(1, 1); // SRR
+            stream.write_bits(1, 1); // IDE
+            stream.write_bits(id & 0x3_FFFF, 18);
+            stream.write_bits(0, 1); // RTR/RRS
+        }
+    }
+
+    if fd_frame {
+        stream.write_bits(1, 1); // FDF
+        stream.write_bits(0, 1); // res
+        stream.write_bits(bitrate_switch as u32, 1);
+        stream.write_bits(error_state as u32, 1);
+    } else {
+        if let Id::Extended(_) = id {
+            stream.write_bits(0, 1); // r1
+        }
+
+        stream.write_bits(0, 1); // r0
+    }
+
+    stream.write_bits(len_to_dlc(data_len), 4);
+}
+
+fn crc15(stream: &BitStream) -> u16 {
+    let mut crc = 0_u16;
+
+    for index in 0..stream.len {
+        let crc_next = stream.bit(index) ^ ((crc >> 14) & 0b1 == 0b1);
+        crc = (crc << 1) & 0x7FFF;
+
+        if crc_next {
+            crc ^= 0x4599;
+        }
+    }
+
+    crc
+}
+
+/// Counts the stuff bits that get inserted into the stream, returned as the number inserted in
+/// the first `split` bits and the number inserted after them
+fn count_stuff_bits(stream: &BitStream, split: usize) -> (u32, u32) {
+    let mut before = 0;
+    let mut after = 0;
+    let mut last = stream.bit(0);
+    let mut run = 0;
+
+    for index in 0..stream.len {
+        let bit = stream.bit(index);
+
+        if bit == last {
+            run += 1;
+        } else {
+            last = bit;
+            run = 1;
+        }
+
+        if run == 5 {
+            if index < split {
+                before += 1;
+            } else {
+                after += 1;
+            }
+
+            // The stuff bit is the complement & starts a new run
+            last = !bit;
+            run = 1;
+        }
+    }
+
+    (before, after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FrameFormat;
+
+    #[test]
+    fn classical_worst_case() {
+        let bits = frame_bits(
+            Id::Standard(0x123),
+            &[0; 8],
+            false,
+            false,
+            false,
+            Stuffing::WorstCase,
+        );
+        assert_eq!(
+            bits,
+            FrameBits {
+                nominal: 98 + 24 + 13,
+                data: 0
+            }
+        );
+
+        let bits = frame_bits(
+            Id::Extended(0x123),
+            &[],
+            false,
+            false,
+            false,
+            Stuffing::WorstCase,
+        );
+        assert_eq!(
+            bits,
+            FrameBits {
+                nominal: 54 + 13 + 13,
+                data: 0
+            }
+        );
+    }
+
+    #[test]
+    fn classical_exact() {
+        // 34 dominant bits including the CRC of all zeros, a stuff bit after every 5
+        let bits = frame_bits(Id::Standard(0), &[], false, false, false, Stuffing::Exact);
+        assert_eq!(
+            bits,
+            FrameBits {
+                nominal: 34 + 6 + 13,
+                data: 0
+            }
+        );
+
+        let data = [0x55; 8];
+        let exact = frame_bits(
+            Id::Standard(0x555),
+            &data,
+            false,
+            false,
+            false,
+            Stuffing::Exact,
+        );
+        let worst = frame_bits(
+            Id::Standard(0x555),
+            &data,
+            false,
+            false,
+            false,
+            Stuffing::WorstCase,
+        );
+        assert!(exact.nominal < worst.nominal);
+        assert!(exact.nominal >= 98 + 13);
+    }
+
+    #[test]
+    fn fd_worst_case_crc_lengths() {
+        let bits = frame_bits(
+            Id::Standard(0x123),
+            &[0; 8],
+            true,
+            true,
+            false,
+            Stuffing::WorstCase,
+        );
+        assert_eq!(
+            bits,
+            FrameBits {
+                nominal: 17 + 4 + 13,
+                data: 69 + 17 + 27
+            }
+        );
+
+        // CRC17 up to 16 bytes, CRC21 above
+        let bits = frame_bits(
+            Id::Standard(0x123),
+            &[0; 16],
+            true,
+            true,
+            false,
+            Stuffing::WorstCase,
+        );
+        assert_eq!(bits.data, 133 + (149 / 4 - 4) + 27);
+
+        let bits = frame_bits(
+            Id::Standard(0x123),
+            &[0; 20],
+            true,
+            true,
+            false,
+            Stuffing::WorstCase,
+        );
+        assert_eq!(bits.data, 165 + (181 / 4 - 4) + 32);
+
+        let bits = frame_bits(
+            Id::Standard(0x123),
+            &[0; 64],
+            true,
+            true,
+            false,
+            Stuffing::WorstCase,
+        );
+        assert_eq!(
+            bits,
+            FrameBits {
+                nominal: 34,
+                data: 517 + 129 + 32
+            }
+        );
+    }
+
+    #[test]
+    fn fd_exact() {
+        let bits = frame_bits(Id::Standard(0), &[], true, true, false, Stuffing::Exact);
+        assert_eq!(
+            bits,
+            FrameBits {
+                nominal: 17 + 2 + 13,
+                data: 5 + 1 + 27
+            }
+        );
+
+        // Without the bitrate switch everything is at the nominal bitrate
+        let bits = frame_bits(Id::Standard(0), &[], true, false, false, Stuffing::Exact);
+        assert_eq!(
+            bits,
+            FrameBits {
+                nominal: 32 + 33,
+                data: 0
+            }
+        );
+    }
+
+    #[test]
+    fn tx_frames_are_padded() {
+        let frame = TxFDFrame {
+            id: Id::Standard(0x123),
+            buffer: &[0; 9],
+            priority: None,
+            format: FrameFormat::default(),
+        };
+
+        let padded = frame_bits(
+            Id::Standard(0x123),
+            &[0; 12],
+            true,
+            true,
+            false,
+            Stuffing::WorstCase,
+        );
+        assert_eq!(tx_frame_bits(&frame, Stuffing::WorstCase), padded);
+    }
+
+    #[test]
+    fn tx_frame_formats() {
+        let frame = |format| TxFDFrame {
+            id: Id::Extended(0x1234),
+            buffer: &[0xAA; 8],
+            priority: None,
+            format,
+        };
+        let bits = |data: &[u8], fd_frame, bitrate_switch| {
+            frame_bits(
+                Id::Extended(0x1234),
+                data,
+                fd_frame,
+                bitrate_switch,
+                false,
+                Stuffing::WorstCase,
+            )
+        };
+
+        assert_eq!(
+            tx_frame_bits(&frame(FrameFormat::Classic), Stuffing::WorstCase),
+            bits(&[0xAA; 8], false, false)
+        );
+        assert_eq!(
+            tx_frame_bits(
+                &frame(FrameFormat::Fd {
+                    bitrate_switch: false
+                }),
+                Stuffing::WorstCase
+            ),
+            bits(&[0xAA; 8], true, false)
+        );
+        assert_eq!(
+            tx_frame_bits(&frame(FrameFormat::Remote { dlc: 8 }), Stuffing::WorstCase),
+            bits(&[], false, false)
+        );
+    }
+
+    #[test]
+    fn estimator() {
+        let mut estimator =
+            BusLoadEstimator::with_bitrates(500_000, 2_000_000, 16_000, Stuffing::WorstCase);
+        let bits = FrameBits {
+            nominal: 40,
+            data: 120,
+        };
+        assert_eq!(estimator.frame_time_ns(bits), 80_000 + 60_000);
+
+        // 140us busy every 1400us across a wrap of the time base
+        let mut now_us = u32::MAX - 20_000;
+        estimator.reset(now_us);
+
+        for _ in 0..40 {
+            estimator.record_bits(bits, now_us);
+            now_us = now_us.wrapping_add(1400);
+        }
+
+        let load = estimator.load_percent(now_us);
+        assert!((load - 10.0).abs() < 1.0, "{}", load);
+
+        // Nothing recent
+        assert_eq!(estimator.load_percent(now_us.wrapping_add(100_000)), 0.0);
+    }
+}
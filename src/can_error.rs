@@ -3,6 +3,7 @@
 //! Author: David Allen (hbddallen@gmail.com)
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum CANFDError {
     BaudrateTooHigh,                     // Make sure baudrate is within limits
     PrescalarTooHigh,                    // Check timing config
@@ -12,8 +13,10 @@ pub enum CANFDError {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum RxTxError {
     MailboxUnavailable, // Could not use this mailbox, it was unavailable for the operation
     FrameTooBigForRegions, // Both regions are smaller than this frame size
+    FrameTooBigForFormat, // Classical & remote frames carry at most 8 bytes
     Unknown,            // Placeholder, *shouldn't* ever get this
 }
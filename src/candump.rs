@@ -0,0 +1,382 @@
+//! The `candump -l` log format, one frame per line
+//!
+//! `(1436509052.249713) can0 123#DEADBEEF` for classical frames & `(1436509052.249713) can0
+//! 1234ABCD##5112233` for FD frames, where the digit after `##` holds the CANFD_* flags. Standard
+//! IDs are 3 hex digits, extended ones 8. The driver's own timestamp is only 16 bits of bit times,
+//! so the caller hands in a full one in microseconds.
+
+use crate::config::Id;
+use crate::socketcan::{CANFD_BRS, CANFD_ESI, CANFD_FDF, CAN_ERR_FLAG};
+use crate::util::{dlc_to_len, len_to_dlc};
+use crate::RxFDFrame;
+
+/// Longest line `write_frame` makes (newline included), with an interface name of up to 15
+/// characters like Linux allows
+pub const MAX_LINE_LEN: usize = (1 + 14 + 1 + 6 + 1) + 1 + 15 + 1 + 8 + 3 + 128 + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandumpError {
+    BufferTooSmall,
+    Syntax,      // Not a line candump would write
+    Unsupported, // Remote & error frames, `RxFDFrame` can't hold those
+}
+
+/// A parsed line, the frame's own `timestamp` is left at 0
+#[derive(Debug, Clone)]
+pub struct LogEntry<'a> {
+    pub timestamp_us: u64,
+    pub interface: &'a str,
+    pub frame: RxFDFrame,
+}
+
+/// Writes a frame as a log line ending with a newline, returns the number of bytes written
+pub fn write_frame(
+    out: &mut [u8],
+    timestamp_us: u64,
+    interface: &str,
+    frame: &RxFDFrame,
+) -> Result<usize, CandumpError> {
+    let mut writer = Writer { out, len: 0 };
+    let len = (frame.buffer_len as usize).min(64);
+
+    writer.push(b'(')?;
+    writer.push_decimal(timestamp_us / 1_000_000, 1)?;
+    writer.push(b'.')?;
+    writer.push_decimal(timestamp_us % 1_000_000, 6)?;
+    writer.push_str(") ")?;
+    writer.push_str(interface)?;
+    writer.push(b' ')?;
+
+    match frame.id {
+        Id::Standard(id) => writer.push_hex(id & 0x7FF, 3)?,
+        Id::Extended(id) => writer.push_hex(id & 0x1FFF_FFFF, 8)?,
+    }
+
+    writer.push(b'#')?;
+
+    if frame.fd_frame || len > 8 {
+        let mut flags = CANFD_FDF;
+
+        if frame.bitrate_switch {
+            flags |= CANFD_BRS;
+        }
+
+        if frame.error_state {
+            flags |= CANFD_ESI;
+        }
+
+        writer.push(b'#')?;
+        writer.push_hex(flags as u32, 1)?;
+    }
+
+    for byte in frame.buffer[..len].iter() {
+        writer.push_hex(*byte as u32, 2)?;
+    }
+
+    writer.push(b'\n')?;
+
+    Ok(writer.len)
+}
+
+/// Parses a log line, with or without the newline
+pub fn parse_line(line: &str) -> Result<LogEntry<'_>, CandumpError> {
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    let line = line.strip_prefix('(').ok_or(CandumpError::Syntax)?;
+    let (timestamp, rest) = line.split_once(") ").ok_or(CandumpError::Syntax)?;
+    let (seconds, micros) = timestamp.split_once('.').ok_or(CandumpError::Syntax)?;
+    let (interface, rest) = rest.split_once(' ').ok_or(CandumpError::Syntax)?;
+
+    // Anything after the frame is extra info from other options
+    let frame_text = rest.split(' ').next().unwrap_or("");
+    let (id_text, data_text) = frame_text.split_once('#').ok_or(CandumpError::Syntax)?;
+
+    if micros.len() != 6 {
+        return Err(CandumpError::Syntax);
+    }
+
+    let seconds = parse_decimal(seconds)?;
+    let micros = parse_decimal(micros)?;
+    let timestamp_us = seconds
+        .checked_mul(1_000_000)
+        .and_then(|us| us.checked_add(micros))
+        .ok_or(CandumpError::Syntax)?;
+
+    let id = parse_hex(id_text)?;
+    let id = match id_text.len() {
+        3 if id <= 0x7FF => Id::Standard(id),
+        8 if id & CAN_ERR_FLAG != 0 => return Err(CandumpError::Unsupported),
+        8 if id <= 0x1FFF_FFFF => Id::Extended(id),
+        _ => return Err(CandumpError::Syntax),
+    };
+
+    let mut frame = RxFDFrame {
+        id,
+        buffer_len: 0,
+        buffer: [0; 64],
+        timestamp: 0,
+        error_state: false,
+        fd_frame: false,
+        bitrate_switch: false,
+        overrun: false,
+        mailbox_index: 0,
+        priority: 0,
+    };
+
+    let data_text = if let Some(fd_text) = data_text.strip_prefix('#') {
+        let flags = fd_text.get(..1).ok_or(CandumpError::Syntax)?;
+        let flags = parse_hex(flags)? as u8;
+
+        frame.fd_frame = true;
+        frame.bitrate_switch = flags & CANFD_BRS != 0;
+        frame.error_state = flags & CANFD_ESI != 0;
+
+        &fd_text[1..]
+    } else if data_text.starts_with('R') {
+        return Err(CandumpError::Unsupported);
+    } else {
+        // A classical frame can have its DLC at the end when it's over 8, `_F`
+        data_text.split('_').next().unwrap_or("")
+    };
+
+    let max_len = if frame.fd_frame { 64 } else { 8 };
+    let mut digits = data_text.bytes().filter(|c| *c != b'.');
+
+    while let Some(high) = digits.next() {
+        let low = digits.next().ok_or(CandumpError::Syntax)?;
+        let len = frame.buffer_len as usize;
+
+        if len >= max_len {
+            return Err(CandumpError::Syntax);
+        }
+
+        frame.buffer[len] = (hex_value(high)? << 4) | hex_value(low)?;
+        frame.buffer_len += 1;
+    }
+
+    // FD payloads only come in the lengths a DLC can give
+    if frame.fd_frame && dlc_to_len(len_to_dlc(frame.buffer_len)) != frame.buffer_len {
+        return Err(CandumpError::Syntax);
+    }
+
+    Ok(LogEntry {
+        timestamp_us,
+        interface,
+        frame,
+    })
+}
+
+struct Writer<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn push(&mut self, byte: u8) -> Result<(), CandumpError> {
+        let slot = self
+            .out
+            .get_mut(self.len)
+            .ok_or(CandumpError::BufferTooSmall)?;
+        *slot = byte;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn push_str(&mut self, string: &str) -> Result<(), CandumpError> {
+        for byte in string.bytes() {
+            self.push(byte)?;
+        }
+
+        Ok(())
+    }
+
+    fn push_hex(&mut self, value: u32, digits: u32) -> Result<(), CandumpError> {
+        for digit in (0..digits).rev() {
+            self.push(b"0123456789ABCDEF"[((value >> (digit * 4)) & 0xF) as usize])?;
+        }
+
+        Ok(())
+    }
+
+    // At least `min_digits`, padded with zeros
+    fn push_decimal(&mut self, value: u64, min_digits: u32) -> Result<(), CandumpError> {
+        let mut digits = 1;
+
+        while digits < 20 && value >= 10_u64.pow(digits) {
+            digits += 1;
+        }
+
+        for digit in (0..digits.max(min_digits)).rev() {
+            self.push(b'0' + ((value / 10_u64.pow(digit)) % 10) as u8)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_decimal(text: &str) -> Result<u64, CandumpError> {
+    if text.is_empty() || !text.bytes().all(|c| c.is_ascii_digit()) {
+        return Err(CandumpError::Syntax);
+    }
+
+    text.parse().map_err(|_| CandumpError::Syntax)
+}
+
+fn parse_hex(text: &str) -> Result<u32, CandumpError> {
+    if text.is_empty() || !text.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(CandumpError::Syntax);
+    }
+
+    u32::from_str_radix(text, 16).map_err(|_| CandumpError::Syntax)
+}
+
+fn hex_value(c: u8) -> Result<u8, CandumpError> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(CandumpError::Syntax),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::rx_frame;
+
+    const TIMESTAMP_US: u64 = 1_436_509_052_249_713;
+
+    fn frame(id: Id, data: &[u8], fd_frame: bool, bitrate_switch: bool, esi: bool) -> RxFDFrame {
+        let mut frame = rx_frame(id, data);
+        frame.fd_frame = fd_frame;
+        frame.bitrate_switch = bitrate_switch;
+        frame.error_state = esi;
+        frame
+    }
+
+    fn line(timestamp_us: u64, frame: &RxFDFrame) -> String {
+        let mut out = [0; MAX_LINE_LEN];
+        let len = write_frame(&mut out, timestamp_us, "can0", frame).unwrap();
+        String::from_utf8(out[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let cases = [
+            (
+                frame(
+                    Id::Standard(0x123),
+                    &[0xDE, 0xAD, 0xBE, 0xEF],
+                    false,
+                    false,
+                    false,
+                ),
+                "(1436509052.249713) can0 123#DEADBEEF\n",
+            ),
+            (
+                frame(Id::Standard(0x7FF), &[], false, false, false),
+                "(1436509052.249713) can0 7FF#\n",
+            ),
+            (
+                frame(
+                    Id::Extended(0x1234_ABCD),
+                    &[0x11, 0x22, 0x33],
+                    true,
+                    true,
+                    false,
+                ),
+                "(1436509052.249713) can0 1234ABCD##5112233\n",
+            ),
+            (
+                frame(Id::Standard(0x001), &[0xAA; 12], true, false, true),
+                "(1436509052.249713) can0 001##6AAAAAAAAAAAAAAAAAAAAAAAA\n",
+            ),
+            (
+                frame(Id::Extended(0x0000_0001), &[0x42; 8], true, true, true),
+                "(1436509052.249713) can0 00000001##74242424242424242\n",
+            ),
+        ];
+
+        for (frame, expected) in cases.iter() {
+            let text = line(TIMESTAMP_US, frame);
+            assert_eq!(text, *expected);
+
+            let entry = parse_line(&text).unwrap();
+            assert_eq!(entry.timestamp_us, TIMESTAMP_US);
+            assert_eq!(entry.interface, "can0");
+            assert_eq!(entry.frame.id, frame.id);
+            assert_eq!(entry.frame.buffer_len, frame.buffer_len);
+            assert_eq!(entry.frame.buffer, frame.buffer);
+            assert_eq!(entry.frame.fd_frame, frame.fd_frame);
+            assert_eq!(entry.frame.bitrate_switch, frame.bitrate_switch);
+            assert_eq!(entry.frame.error_state, frame.error_state);
+        }
+
+        assert_eq!(
+            line(5, &frame(Id::Standard(1), &[1], false, false, false)),
+            "(0.000005) can0 001#01\n"
+        );
+    }
+
+    #[test]
+    fn longest_line() {
+        let frame = frame(Id::Extended(0x1FFF_FFFF), &[0x55; 64], true, true, true);
+        let mut out = [0; MAX_LINE_LEN];
+        let len = write_frame(&mut out, u64::MAX, "abcdefghijklmno", &frame).unwrap();
+        assert_eq!(len, MAX_LINE_LEN);
+
+        let entry = parse_line(core::str::from_utf8(&out).unwrap()).unwrap();
+        assert_eq!(entry.timestamp_us, u64::MAX);
+        assert_eq!(entry.frame.buffer_len, 64);
+
+        assert_eq!(
+            write_frame(&mut out[..len - 1], u64::MAX, "abcdefghijklmno", &frame),
+            Err(CandumpError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn parsing() {
+        // Separators, a DLC over 8 & a trailing direction from `-x`
+        let entry = parse_line("(0.000005) vcan0 123#11.22.33_F\r\n").unwrap();
+        assert_eq!(entry.interface, "vcan0");
+        assert_eq!(entry.frame.buffer[..3], [0x11, 0x22, 0x33]);
+        assert_eq!(entry.frame.buffer_len, 3);
+
+        let entry = parse_line("(1.000000) can1 0AB##1 T").unwrap();
+        assert!(entry.frame.fd_frame && entry.frame.bitrate_switch);
+        assert_eq!(entry.frame.buffer_len, 0);
+
+        let unsupported = [
+            "(0.000005) can0 123#R",
+            "(0.000005) can0 20000080#0000000000000000",
+        ];
+
+        for line in unsupported.iter() {
+            assert_eq!(parse_line(line).unwrap_err(), CandumpError::Unsupported);
+        }
+
+        let invalid = [
+            "0.000005 can0 123#00",
+            "(0.5) can0 123#00",
+            "(0.000005) can0 1234#00",
+            "(0.000005) can0 800#00",
+            "(0.000005) can0 123#001122334455667788",
+            "(0.000005) can0 123#0",
+            "(0.000005) can0 123#0G",
+            "(0.000005) can0 123##",
+            // FD payloads between the DLC lengths
+            "(0.000005) can0 123##1001122334455667788",
+            "(0.000005) can0 123##1001122334455667788990011223344",
+        ];
+
+        for line in invalid.iter() {
+            assert_eq!(
+                parse_line(line).unwrap_err(),
+                CandumpError::Syntax,
+                "{}",
+                line
+            );
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! CANopen (CiA 301) protocols on top of the driver
+//!
+//! Like the other protocol layers these don't own the bus, received frames are handed to
+//! `handle_frame` and `poll` sends whatever is due. Times are in microseconds from a free running
+//! time base that's allowed to wrap.
+
+pub mod nmt;
+pub mod od;
+pub mod pdo;
+pub mod sdo;
+
+use crate::can_error::RxTxError;
+use crate::config::{Id, RxMailboxConfig};
+use crate::{FrameFormat, Transmitter, TxFDFrame};
+use od::SdoAbortCode;
+
+pub const COB_ID_NMT: u32 = 0x000;
+pub const COB_ID_SYNC: u32 = 0x080;
+pub const COB_ID_TPDO1: u32 = 0x180;
+pub const COB_ID_RPDO1: u32 = 0x200;
+pub const COB_ID_TPDO2: u32 = 0x280;
+pub const COB_ID_RPDO2: u32 = 0x300;
+pub const COB_ID_TPDO3: u32 = 0x380;
+pub const COB_ID_RPDO3: u32 = 0x400;
+pub const COB_ID_TPDO4: u32 = 0x480;
+pub const COB_ID_RPDO4: u32 = 0x500;
+pub const COB_ID_SDO_TX: u32 = 0x580; // Server to client
+pub const COB_ID_SDO_RX: u32 = 0x600; // Client to server
+pub const COB_ID_HEARTBEAT: u32 = 0x700;
+
+pub const MAX_NODE_ID: u8 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanOpenError {
+    InvalidNodeId,    // Node IDs are 1 - 127
+    NoSpace,          // A fixed size table or buffer is full
+    Busy,             // A transfer is already in progress
+    Od(SdoAbortCode), // The object dictionary refused the access
+    Bus(RxTxError),
+}
+
+/// Rx mailbox filter accepting every COB-ID in a function code's range (0x700 - 0x77F for the
+/// heartbeats), the low 7 bits are the node ID & don't care
+pub fn function_code_rx_config(function_code_cob_id: u32) -> RxMailboxConfig {
+    RxMailboxConfig {
+        id: Id::Standard(function_code_cob_id & 0x780),
+        id_mask: 0x780,
+    }
+}
+
+/// Rx mailbox filter for a single COB-ID
+pub fn cob_id_rx_config(cob_id: u32) -> RxMailboxConfig {
+    RxMailboxConfig {
+        id: Id::Standard(cob_id & 0x7FF),
+        id_mask: 0x7FF,
+    }
+}
+
+pub(crate) fn valid_node_id(node_id: u8) -> bool {
+    (1..=MAX_NODE_ID).contains(&node_id)
+}
+
+pub(crate) fn transmit<T: Transmitter>(
+    can: &mut T,
+    cob_id: u32,
+    data: &[u8],
+) -> Result<(), CanOpenError> {
+    let frame = TxFDFrame {
+        id: Id::Standard(cob_id),
+        buffer: data,
+        priority: None,
+        format: FrameFormat::Classic,
+    };
+
+    can.transmit(&frame).map_err(CanOpenError::Bus)
+}
+
+/// Splits a received standard ID into the function code & node ID parts
+pub(crate) fn split_cob_id(id: Id) -> Option<(u32, u8)> {
+    match id {
+        Id::Standard(cob_id) => Some((cob_id & 0x780, (cob_id & 0x7F) as u8)),
+        Id::Extended(_) => None,
+    }
+}
@@ -0,0 +1,583 @@
+//! Network management: NMT master commands, our own NMT state & heartbeat production, and
+//! consuming the heartbeats of other nodes
+
+use super::{split_cob_id, transmit, valid_node_id, CanOpenError, COB_ID_HEARTBEAT, COB_ID_NMT};
+use crate::{RxFDFrame, Transmitter};
+
+pub const MAX_HEARTBEAT_CONSUMERS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmtState {
+    BootUp,
+    Stopped,
+    Operational,
+    PreOperational,
+}
+
+impl NmtState {
+    pub fn to_heartbeat(self) -> u8 {
+        match self {
+            NmtState::BootUp => 0x00,
+            NmtState::Stopped => 0x04,
+            NmtState::Operational => 0x05,
+            NmtState::PreOperational => 0x7F,
+        }
+    }
+
+    pub fn from_heartbeat(value: u8) -> Option<Self> {
+        match value & 0x7F {
+            0x00 => Some(NmtState::BootUp),
+            0x04 => Some(NmtState::Stopped),
+            0x05 => Some(NmtState::Operational),
+            0x7F => Some(NmtState::PreOperational),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NmtCommand {
+    Start,
+    Stop,
+    EnterPreOperational,
+    ResetNode,
+    ResetCommunication,
+}
+
+impl NmtCommand {
+    pub fn to_cs(self) -> u8 {
+        match self {
+            NmtCommand::Start => 0x01,
+            NmtCommand::Stop => 0x02,
+            NmtCommand::EnterPreOperational => 0x80,
+            NmtCommand::ResetNode => 0x81,
+            NmtCommand::ResetCommunication => 0x82,
+        }
+    }
+
+    pub fn from_cs(cs: u8) -> Option<Self> {
+        match cs {
+            0x01 => Some(NmtCommand::Start),
+            0x02 => Some(NmtCommand::Stop),
+            0x80 => Some(NmtCommand::EnterPreOperational),
+            0x81 => Some(NmtCommand::ResetNode),
+            0x82 => Some(NmtCommand::ResetCommunication),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatEvent {
+    StateChanged {
+        node_id: u8,
+        old_state: Option<NmtState>, // None for the first heartbeat or after a timeout
+        new_state: NmtState,
+    },
+    Timeout {
+        node_id: u8,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HeartbeatConsumer {
+    node_id: u8,
+    timeout_us: u32,
+    state: Option<NmtState>,
+    last_us: u32,
+}
+
+pub struct Nmt {
+    node_id: u8,
+    state: NmtState,
+    heartbeat_period_us: u32,
+    last_heartbeat_us: Option<u32>,
+    consumers: [Option<HeartbeatConsumer>; MAX_HEARTBEAT_CONSUMERS],
+    heartbeat_callback: Option<fn(HeartbeatEvent)>,
+    command_callback: Option<fn(NmtCommand)>,
+}
+
+impl Nmt {
+    /// Creates the NMT state of our own node, a `heartbeat_period_us` of 0 disables producing
+    /// heartbeats
+    pub fn new(node_id: u8, heartbeat_period_us: u32) -> Result<Self, CanOpenError> {
+        if !valid_node_id(node_id) {
+            return Err(CanOpenError::InvalidNodeId);
+        }
+
+        Ok(Self {
+            node_id,
+            state: NmtState::BootUp,
+            heartbeat_period_us,
+            last_heartbeat_us: None,
+            consumers: [None; MAX_HEARTBEAT_CONSUMERS],
+            heartbeat_callback: None,
+            command_callback: None,
+        })
+    }
+
+    pub fn node_id(&self) -> u8 {
+        self.node_id
+    }
+
+    pub fn state(&self) -> NmtState {
+        self.state
+    }
+
+    /// Changes our own state, the master doesn't receive its own NMT commands
+    pub fn set_state(&mut self, state: NmtState) {
+        self.state = state;
+    }
+
+    pub fn set_heartbeat_period(&mut self, heartbeat_period_us: u32) {
+        self.heartbeat_period_us = heartbeat_period_us;
+    }
+
+    /// Called for every state change & timeout of a consumed heartbeat
+    pub fn set_heartbeat_callback(&mut self, callback: Option<fn(HeartbeatEvent)>) {
+        self.heartbeat_callback = callback;
+    }
+
+    /// Called for every NMT command addressed to us, after our own state has been updated. The
+    /// resets have to be carried out by the application.
+    pub fn set_command_callback(&mut self, callback: Option<fn(NmtCommand)>) {
+        self.command_callback = callback;
+    }
+
+    /// Sends the boot-up message & enters pre-operational, like a node does after a reset
+    pub fn boot_up<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<(), CanOpenError> {
+        self.state = NmtState::BootUp;
+        self.send_heartbeat(can, now_us)?;
+        self.state = NmtState::PreOperational;
+
+        Ok(())
+    }
+
+    /// Sends an NMT command as the master, a `node_id` of 0 addresses every node
+    pub fn send_command<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        command: NmtCommand,
+        node_id: u8,
+    ) -> Result<(), CanOpenError> {
+        if node_id != 0 && !valid_node_id(node_id) {
+            return Err(CanOpenError::InvalidNodeId);
+        }
+
+        transmit(can, COB_ID_NMT, &[command.to_cs(), node_id])
+    }
+
+    /// Starts watching the heartbeat of a node, replacing the timeout if it's already watched
+    pub fn add_heartbeat_consumer(
+        &mut self,
+        node_id: u8,
+        timeout_us: u32,
+        now_us: u32,
+    ) -> Result<(), CanOpenError> {
+        if !valid_node_id(node_id) {
+            return Err(CanOpenError::InvalidNodeId);
+        }
+
+        let consumer = HeartbeatConsumer {
+            node_id,
+            timeout_us,
+            state: None,
+            last_us: now_us,
+        };
+
+        if let Some(existing) = self
+            .consumers
+            .iter_mut()
+            .flatten()
+            .find(|consumer| consumer.node_id == node_id)
+        {
+            existing.timeout_us = timeout_us;
+            return Ok(());
+        }
+
+        match self.consumers.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(consumer);
+                Ok(())
+            }
+            None => Err(CanOpenError::NoSpace),
+        }
+    }
+
+    pub fn remove_heartbeat_consumer(&mut self, node_id: u8) {
+        for slot in self.consumers.iter_mut() {
+            if let Some(consumer) = slot {
+                if consumer.node_id == node_id {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    /// The last state a consumed node reported, None if it never did or timed out
+    pub fn node_state(&self, node_id: u8) -> Option<NmtState> {
+        self.consumers
+            .iter()
+            .flatten()
+            .find(|consumer| consumer.node_id == node_id)
+            .and_then(|consumer| consumer.state)
+    }
+
+    /// Feeds a received frame in, handles NMT commands for us & heartbeats of consumed nodes
+    pub fn handle_frame(&mut self, frame: &RxFDFrame, now_us: u32) {
+        let (function_code, node_id) = match split_cob_id(frame.id) {
+            Some(split) => split,
+            None => return,
+        };
+
+        if function_code == COB_ID_NMT && node_id == 0 {
+            if frame.buffer_len < 2 || (frame.buffer[1] != 0 && frame.buffer[1] != self.node_id) {
+                return;
+            }
+
+            if let Some(command) = NmtCommand::from_cs(frame.buffer[0]) {
+                self.apply_command(command);
+
+                if let Some(callback) = self.command_callback {
+                    callback(command);
+                }
+            }
+        } else if function_code == COB_ID_HEARTBEAT && frame.buffer_len >= 1 {
+            let new_state = match NmtState::from_heartbeat(frame.buffer[0]) {
+                Some(state) => state,
+                None => return,
+            };
+
+            let callback = self.heartbeat_callback;
+
+            if let Some(consumer) = self
+                .consumers
+                .iter_mut()
+                .flatten()
+                .find(|consumer| consumer.node_id == node_id)
+            {
+                let old_state = consumer.state;
+                consumer.state = Some(new_state);
+                consumer.last_us = now_us;
+
+                if old_state != Some(new_state) {
+                    if let Some(callback) = callback {
+                        callback(HeartbeatEvent::StateChanged {
+                            node_id,
+                            old_state,
+                            new_state,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends our heartbeat when it's due & checks the consumed heartbeats for timeouts
+    pub fn poll<T: Transmitter>(&mut self, can: &mut T, now_us: u32) -> Result<(), CanOpenError> {
+        let callback = self.heartbeat_callback;
+
+        for consumer in self.consumers.iter_mut().flatten() {
+            // Only nodes we've heard from can time out, like the CiA 301 consumer
+            if consumer.state.is_some()
+                && consumer.timeout_us != 0
+                && now_us.wrapping_sub(consumer.last_us) > consumer.timeout_us
+            {
+                consumer.state = None;
+
+                if let Some(callback) = callback {
+                    callback(HeartbeatEvent::Timeout {
+                        node_id: consumer.node_id,
+                    });
+                }
+            }
+        }
+
+        if self.heartbeat_period_us == 0 || self.state == NmtState::BootUp {
+            return Ok(());
+        }
+
+        let due = match self.last_heartbeat_us {
+            Some(last_us) => now_us.wrapping_sub(last_us) >= self.heartbeat_period_us,
+            None => true,
+        };
+
+        if due {
+            self.send_heartbeat(can, now_us)?;
+        }
+
+        Ok(())
+    }
+
+    fn send_heartbeat<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<(), CanOpenError> {
+        transmit(
+            can,
+            COB_ID_HEARTBEAT + self.node_id as u32,
+            &[self.state.to_heartbeat()],
+        )?;
+
+        self.last_heartbeat_us = Some(now_us);
+
+        Ok(())
+    }
+
+    fn apply_command(&mut self, command: NmtCommand) {
+        self.state = match command {
+            NmtCommand::Start => NmtState::Operational,
+            NmtCommand::Stop => NmtState::Stopped,
+            NmtCommand::EnterPreOperational => NmtState::PreOperational,
+            // The application resets & calls `boot_up` again
+            NmtCommand::ResetNode | NmtCommand::ResetCommunication => NmtState::BootUp,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Id;
+    use crate::mock::{rx_frame, Bus, SentFrame};
+    use crate::FrameFormat;
+    use std::cell::RefCell;
+
+    std::thread_local! {
+        static EVENTS: RefCell<Vec<HeartbeatEvent>> = const { RefCell::new(Vec::new()) };
+        static COMMANDS: RefCell<Vec<NmtCommand>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_event(event: HeartbeatEvent) {
+        EVENTS.with(|events| events.borrow_mut().push(event));
+    }
+
+    fn record_command(command: NmtCommand) {
+        COMMANDS.with(|commands| commands.borrow_mut().push(command));
+    }
+
+    fn take_events() -> Vec<HeartbeatEvent> {
+        EVENTS.with(|events| events.take())
+    }
+
+    fn heartbeat(node_id: u8, state: NmtState) -> RxFDFrame {
+        rx_frame(
+            Id::Standard(COB_ID_HEARTBEAT + node_id as u32),
+            &[state.to_heartbeat()],
+        )
+    }
+
+    fn command(command: NmtCommand, node_id: u8) -> RxFDFrame {
+        rx_frame(Id::Standard(COB_ID_NMT), &[command.to_cs(), node_id])
+    }
+
+    fn sent_heartbeat(node_id: u8, state: NmtState) -> SentFrame {
+        SentFrame {
+            id: Id::Standard(COB_ID_HEARTBEAT + node_id as u32),
+            data: vec![state.to_heartbeat()],
+            format: FrameFormat::Classic,
+        }
+    }
+
+    #[test]
+    fn invalid_node_ids() {
+        assert_eq!(Nmt::new(0, 0).err(), Some(CanOpenError::InvalidNodeId));
+        assert_eq!(Nmt::new(128, 0).err(), Some(CanOpenError::InvalidNodeId));
+
+        let mut nmt = Nmt::new(1, 0).unwrap();
+        let mut bus = Bus::default();
+        assert_eq!(
+            nmt.send_command(&mut bus, NmtCommand::Start, 200),
+            Err(CanOpenError::InvalidNodeId)
+        );
+        assert_eq!(
+            nmt.add_heartbeat_consumer(0, 1000, 0),
+            Err(CanOpenError::InvalidNodeId)
+        );
+    }
+
+    #[test]
+    fn send_command_framing() {
+        let mut nmt = Nmt::new(1, 0).unwrap();
+        let mut bus = Bus::default();
+
+        nmt.send_command(&mut bus, NmtCommand::Start, 0).unwrap();
+        nmt.send_command(&mut bus, NmtCommand::ResetNode, 0x22)
+            .unwrap();
+
+        let sent = bus.take();
+        assert_eq!(sent[0].id, Id::Standard(COB_ID_NMT));
+        assert_eq!(sent[0].data, [0x01, 0x00]);
+        assert_eq!(sent[1].data, [0x81, 0x22]);
+    }
+
+    #[test]
+    fn command_state_transitions() {
+        let mut nmt = Nmt::new(5, 0).unwrap();
+        nmt.set_command_callback(Some(record_command));
+        nmt.boot_up(&mut Bus::default(), 0).unwrap();
+        assert_eq!(nmt.state(), NmtState::PreOperational);
+
+        nmt.handle_frame(&command(NmtCommand::Start, 5), 0);
+        assert_eq!(nmt.state(), NmtState::Operational);
+
+        nmt.handle_frame(&command(NmtCommand::Stop, 0), 0);
+        assert_eq!(nmt.state(), NmtState::Stopped);
+
+        // Addressed to another node, or too short
+        nmt.handle_frame(&command(NmtCommand::Start, 6), 0);
+        nmt.handle_frame(&rx_frame(Id::Standard(COB_ID_NMT), &[0x01]), 0);
+        assert_eq!(nmt.state(), NmtState::Stopped);
+
+        // Unknown command specifier
+        nmt.handle_frame(&rx_frame(Id::Standard(COB_ID_NMT), &[0x03, 5]), 0);
+        assert_eq!(nmt.state(), NmtState::Stopped);
+
+        nmt.handle_frame(&command(NmtCommand::EnterPreOperational, 5), 0);
+        assert_eq!(nmt.state(), NmtState::PreOperational);
+
+        nmt.handle_frame(&command(NmtCommand::ResetCommunication, 0), 0);
+        assert_eq!(nmt.state(), NmtState::BootUp);
+
+        assert_eq!(
+            COMMANDS.with(|commands| commands.take()),
+            [
+                NmtCommand::Start,
+                NmtCommand::Stop,
+                NmtCommand::EnterPreOperational,
+                NmtCommand::ResetCommunication,
+            ]
+        );
+    }
+
+    #[test]
+    fn heartbeat_production() {
+        let mut nmt = Nmt::new(5, 1000).unwrap();
+        let mut bus = Bus::default();
+
+        // Nothing before the boot-up message
+        nmt.poll(&mut bus, 0).unwrap();
+        assert!(bus.take().is_empty());
+
+        nmt.boot_up(&mut bus, 100).unwrap();
+        assert_eq!(bus.take(), [sent_heartbeat(5, NmtState::BootUp)]);
+
+        nmt.poll(&mut bus, 1099).unwrap();
+        assert!(bus.take().is_empty());
+
+        nmt.poll(&mut bus, 1100).unwrap();
+        assert_eq!(bus.take(), [sent_heartbeat(5, NmtState::PreOperational)]);
+
+        nmt.set_state(NmtState::Operational);
+        nmt.poll(&mut bus, 2100).unwrap();
+        assert_eq!(bus.take(), [sent_heartbeat(5, NmtState::Operational)]);
+
+        // A period of 0 stops them
+        nmt.set_heartbeat_period(0);
+        nmt.poll(&mut bus, 10_000).unwrap();
+        assert!(bus.take().is_empty());
+    }
+
+    #[test]
+    fn heartbeat_production_across_wrap() {
+        let mut nmt = Nmt::new(5, 1000).unwrap();
+        let mut bus = Bus::default();
+
+        nmt.boot_up(&mut bus, u32::MAX - 499).unwrap();
+        nmt.poll(&mut bus, 499).unwrap();
+        assert_eq!(bus.take().len(), 1);
+
+        nmt.poll(&mut bus, 500).unwrap();
+        assert_eq!(bus.take(), [sent_heartbeat(5, NmtState::PreOperational)]);
+    }
+
+    #[test]
+    fn heartbeat_consumption() {
+        let mut nmt = Nmt::new(1, 0).unwrap();
+        let mut bus = Bus::default();
+        nmt.set_heartbeat_callback(Some(record_event));
+        nmt.add_heartbeat_consumer(7, 500, 0).unwrap();
+
+        // Not heard from yet, so can't time out
+        nmt.poll(&mut bus, 10_000).unwrap();
+        assert_eq!(nmt.node_state(7), None);
+        assert!(take_events().is_empty());
+
+        nmt.handle_frame(&heartbeat(7, NmtState::BootUp), 10_000);
+        nmt.handle_frame(&heartbeat(7, NmtState::PreOperational), 10_100);
+        nmt.handle_frame(&heartbeat(7, NmtState::PreOperational), 10_200);
+        // Nodes we don't consume are ignored
+        nmt.handle_frame(&heartbeat(8, NmtState::Operational), 10_200);
+
+        assert_eq!(nmt.node_state(7), Some(NmtState::PreOperational));
+        assert_eq!(nmt.node_state(8), None);
+        assert_eq!(
+            take_events(),
+            [
+                HeartbeatEvent::StateChanged {
+                    node_id: 7,
+                    old_state: None,
+                    new_state: NmtState::BootUp,
+                },
+                HeartbeatEvent::StateChanged {
+                    node_id: 7,
+                    old_state: Some(NmtState::BootUp),
+                    new_state: NmtState::PreOperational,
+                },
+            ]
+        );
+
+        nmt.poll(&mut bus, 10_700).unwrap();
+        assert!(take_events().is_empty());
+
+        nmt.poll(&mut bus, 10_701).unwrap();
+        assert_eq!(take_events(), [HeartbeatEvent::Timeout { node_id: 7 }]);
+        assert_eq!(nmt.node_state(7), None);
+
+        // Only reported once
+        nmt.poll(&mut bus, 20_000).unwrap();
+        assert!(take_events().is_empty());
+
+        nmt.handle_frame(&heartbeat(7, NmtState::Operational), 20_000);
+        assert_eq!(
+            take_events(),
+            [HeartbeatEvent::StateChanged {
+                node_id: 7,
+                old_state: None,
+                new_state: NmtState::Operational,
+            }]
+        );
+    }
+
+    #[test]
+    fn heartbeat_consumer_slots() {
+        let mut nmt = Nmt::new(1, 0).unwrap();
+
+        for node_id in 1..=MAX_HEARTBEAT_CONSUMERS as u8 {
+            nmt.add_heartbeat_consumer(node_id, 100, 0).unwrap();
+        }
+
+        // Re-adding only updates the timeout
+        nmt.add_heartbeat_consumer(1, 200, 0).unwrap();
+        assert_eq!(
+            nmt.add_heartbeat_consumer(100, 100, 0),
+            Err(CanOpenError::NoSpace)
+        );
+
+        nmt.remove_heartbeat_consumer(1);
+        nmt.add_heartbeat_consumer(100, 100, 0).unwrap();
+        nmt.handle_frame(&heartbeat(100, NmtState::Stopped), 0);
+        assert_eq!(nmt.node_state(100), Some(NmtState::Stopped));
+    }
+
+    #[test]
+    fn heartbeat_toggle_bit_ignored() {
+        assert_eq!(NmtState::from_heartbeat(0x85), Some(NmtState::Operational));
+        assert_eq!(NmtState::from_heartbeat(0x01), None);
+    }
+}
@@ -0,0 +1,400 @@
+//! The object dictionary, a table of index/subindex entries backed by caller supplied storage
+//!
+//! Values are stored little endian like they go out on the bus. The typed `read_*`/`write_*`
+//! helpers are for the application and skip the access rights, `sdo_read`/`sdo_write` are what
+//! the SDO server uses and enforce them.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    Boolean,
+    Integer8,
+    Integer16,
+    Integer32,
+    Integer64,
+    Unsigned8,
+    Unsigned16,
+    Unsigned32,
+    Unsigned64,
+    Real32,
+    Real64,
+    VisibleString,
+    OctetString,
+    Domain,
+}
+
+impl DataType {
+    /// Size of the value in bytes, None for the variable length types
+    pub fn size(self) -> Option<usize> {
+        match self {
+            DataType::Boolean | DataType::Integer8 | DataType::Unsigned8 => Some(1),
+            DataType::Integer16 | DataType::Unsigned16 => Some(2),
+            DataType::Integer32 | DataType::Unsigned32 | DataType::Real32 => Some(4),
+            DataType::Integer64 | DataType::Unsigned64 | DataType::Real64 => Some(8),
+            DataType::VisibleString | DataType::OctetString | DataType::Domain => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+    Const,
+}
+
+impl AccessType {
+    pub fn readable(self) -> bool {
+        self != AccessType::WriteOnly
+    }
+
+    pub fn writable(self) -> bool {
+        self == AccessType::WriteOnly || self == AccessType::ReadWrite
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdoAbortCode {
+    ToggleBitNotAlternated,
+    Timeout,
+    InvalidCommand,
+    InvalidBlockSize,
+    InvalidSequenceNumber,
+    CrcError,
+    OutOfMemory,
+    UnsupportedAccess,
+    WriteOnly,
+    ReadOnly,
+    ObjectDoesNotExist,
+    NotMappable,
+    PdoLengthExceeded,
+    LengthMismatch,
+    LengthTooHigh,
+    LengthTooLow,
+    SubIndexDoesNotExist,
+    ValueRangeExceeded,
+    GeneralError,
+    DataCannotBeStored,
+    DeviceState,
+    Other(u32),
+}
+
+impl SdoAbortCode {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            SdoAbortCode::ToggleBitNotAlternated => 0x0503_0000,
+            SdoAbortCode::Timeout => 0x0504_0000,
+            SdoAbortCode::InvalidCommand => 0x0504_0001,
+            SdoAbortCode::InvalidBlockSize => 0x0504_0002,
+            SdoAbortCode::InvalidSequenceNumber => 0x0504_0003,
+            SdoAbortCode::CrcError => 0x0504_0004,
+            SdoAbortCode::OutOfMemory => 0x0504_0005,
+            SdoAbortCode::UnsupportedAccess => 0x0601_0000,
+            SdoAbortCode::WriteOnly => 0x0601_0001,
+            SdoAbortCode::ReadOnly => 0x0601_0002,
+            SdoAbortCode::ObjectDoesNotExist => 0x0602_0000,
+            SdoAbortCode::NotMappable => 0x0604_0041,
+            SdoAbortCode::PdoLengthExceeded => 0x0604_0042,
+            SdoAbortCode::LengthMismatch => 0x0607_0010,
+            SdoAbortCode::LengthTooHigh => 0x0607_0012,
+            SdoAbortCode::LengthTooLow => 0x0607_0013,
+            SdoAbortCode::SubIndexDoesNotExist => 0x0609_0011,
+            SdoAbortCode::ValueRangeExceeded => 0x0609_0030,
+            SdoAbortCode::GeneralError => 0x0800_0000,
+            SdoAbortCode::DataCannotBeStored => 0x0800_0020,
+            SdoAbortCode::DeviceState => 0x0800_0022,
+            SdoAbortCode::Other(code) => code,
+        }
+    }
+
+    pub fn from_u32(code: u32) -> Self {
+        match code {
+            0x0503_0000 => SdoAbortCode::ToggleBitNotAlternated,
+            0x0504_0000 => SdoAbortCode::Timeout,
+            0x0504_0001 => SdoAbortCode::InvalidCommand,
+            0x0504_0002 => SdoAbortCode::InvalidBlockSize,
+            0x0504_0003 => SdoAbortCode::InvalidSequenceNumber,
+            0x0504_0004 => SdoAbortCode::CrcError,
+            0x0504_0005 => SdoAbortCode::OutOfMemory,
+            0x0601_0000 => SdoAbortCode::UnsupportedAccess,
+            0x0601_0001 => SdoAbortCode::WriteOnly,
+            0x0601_0002 => SdoAbortCode::ReadOnly,
+            0x0602_0000 => SdoAbortCode::ObjectDoesNotExist,
+            0x0604_0041 => SdoAbortCode::NotMappable,
+            0x0604_0042 => SdoAbortCode::PdoLengthExceeded,
+            0x0607_0010 => SdoAbortCode::LengthMismatch,
+            0x0607_0012 => SdoAbortCode::LengthTooHigh,
+            0x0607_0013 => SdoAbortCode::LengthTooLow,
+            0x0609_0011 => SdoAbortCode::SubIndexDoesNotExist,
+            0x0609_0030 => SdoAbortCode::ValueRangeExceeded,
+            0x0800_0000 => SdoAbortCode::GeneralError,
+            0x0800_0020 => SdoAbortCode::DataCannotBeStored,
+            0x0800_0022 => SdoAbortCode::DeviceState,
+            code => SdoAbortCode::Other(code),
+        }
+    }
+}
+
+pub struct OdEntry<'a> {
+    pub index: u16,
+    pub subindex: u8,
+    pub data_type: DataType,
+    pub access: AccessType,
+    pub pdo_mappable: bool,
+    data: &'a mut [u8], // Storage, its length is the capacity of variable length entries
+    len: usize,         // Current length of the value
+}
+
+impl<'a> OdEntry<'a> {
+    /// Creates an entry, fixed size types use the first `size()` bytes of `data` & variable
+    /// length ones can grow up to its length. Panics if `data` is too short for a fixed size type.
+    pub fn new(
+        index: u16,
+        subindex: u8,
+        data_type: DataType,
+        access: AccessType,
+        data: &'a mut [u8],
+    ) -> Self {
+        let len = match data_type.size() {
+            Some(size) => {
+                assert!(
+                    data.len() >= size,
+                    "OD entry storage too short for its type"
+                );
+                size
+            }
+            None => data.len(),
+        };
+
+        Self {
+            index,
+            subindex,
+            data_type,
+            access,
+            pdo_mappable: false,
+            data,
+            len,
+        }
+    }
+
+    /// Allows mapping the entry into PDOs
+    pub fn mappable(mut self) -> Self {
+        self.pdo_mappable = true;
+        self
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.data_type.size().unwrap_or(self.data.len())
+    }
+
+    /// Checks a value written to the entry fits its type
+    pub fn check_len(&self, len: usize) -> Result<(), SdoAbortCode> {
+        match self.data_type.size() {
+            Some(size) if len > size => Err(SdoAbortCode::LengthTooHigh),
+            Some(size) if len < size => Err(SdoAbortCode::LengthTooLow),
+            None if len > self.data.len() => Err(SdoAbortCode::LengthTooHigh),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_value(&mut self, value: &[u8]) -> Result<(), SdoAbortCode> {
+        self.check_len(value.len())?;
+
+        self.data[..value.len()].copy_from_slice(value);
+        self.len = value.len();
+
+        Ok(())
+    }
+}
+
+pub struct ObjectDictionary<'a, 'd> {
+    entries: &'a mut [OdEntry<'d>],
+}
+
+macro_rules! od_typed_accessors {
+    ($($read:ident, $write:ident, $t:ty;)*) => {
+        $(
+            pub fn $read(&self, index: u16, subindex: u8) -> Result<$t, SdoAbortCode> {
+                let mut bytes = [0_u8; core::mem::size_of::<$t>()];
+                let value = self.entry(index, subindex)?.value();
+
+                if value.len() != bytes.len() {
+                    return Err(SdoAbortCode::LengthMismatch);
+                }
+
+                bytes.copy_from_slice(value);
+                Ok(<$t>::from_le_bytes(bytes))
+            }
+
+            pub fn $write(&mut self, index: u16, subindex: u8, value: $t) -> Result<(), SdoAbortCode> {
+                self.entry_mut(index, subindex)?.set_value(&value.to_le_bytes())
+            }
+        )*
+    };
+}
+
+impl<'a, 'd> ObjectDictionary<'a, 'd> {
+    pub fn new(entries: &'a mut [OdEntry<'d>]) -> Self {
+        Self { entries }
+    }
+
+    pub fn entry(&self, index: u16, subindex: u8) -> Result<&OdEntry<'d>, SdoAbortCode> {
+        match self.position(index, subindex) {
+            Ok(position) => Ok(&self.entries[position]),
+            Err(code) => Err(code),
+        }
+    }
+
+    pub fn entry_mut(
+        &mut self,
+        index: u16,
+        subindex: u8,
+    ) -> Result<&mut OdEntry<'d>, SdoAbortCode> {
+        match self.position(index, subindex) {
+            Ok(position) => Ok(&mut self.entries[position]),
+            Err(code) => Err(code),
+        }
+    }
+
+    /// Reads a value for an SDO upload, checking the access rights
+    pub fn sdo_read(&self, index: u16, subindex: u8) -> Result<&[u8], SdoAbortCode> {
+        let entry = self.entry(index, subindex)?;
+
+        if !entry.access.readable() {
+            return Err(SdoAbortCode::WriteOnly);
+        }
+
+        Ok(entry.value())
+    }
+
+    /// Checks an SDO download of `len` bytes would be accepted, before any data is transferred
+    pub fn sdo_check_write(
+        &self,
+        index: u16,
+        subindex: u8,
+        len: Option<usize>,
+    ) -> Result<(), SdoAbortCode> {
+        let entry = self.entry(index, subindex)?;
+
+        if !entry.access.writable() {
+            return Err(SdoAbortCode::ReadOnly);
+        }
+
+        match len {
+            Some(len) => entry.check_len(len),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a value from an SDO download, checking the access rights
+    pub fn sdo_write(
+        &mut self,
+        index: u16,
+        subindex: u8,
+        value: &[u8],
+    ) -> Result<(), SdoAbortCode> {
+        self.sdo_check_write(index, subindex, None)?;
+        self.entry_mut(index, subindex)?.set_value(value)
+    }
+
+    pub fn read(&self, index: u16, subindex: u8) -> Result<&[u8], SdoAbortCode> {
+        Ok(self.entry(index, subindex)?.value())
+    }
+
+    pub fn write(&mut self, index: u16, subindex: u8, value: &[u8]) -> Result<(), SdoAbortCode> {
+        self.entry_mut(index, subindex)?.set_value(value)
+    }
+
+    od_typed_accessors! {
+        read_u8, write_u8, u8;
+        read_u16, write_u16, u16;
+        read_u32, write_u32, u32;
+        read_u64, write_u64, u64;
+        read_i8, write_i8, i8;
+        read_i16, write_i16, i16;
+        read_i32, write_i32, i32;
+        read_i64, write_i64, i64;
+        read_f32, write_f32, f32;
+        read_f64, write_f64, f64;
+    }
+
+    fn position(&self, index: u16, subindex: u8) -> Result<usize, SdoAbortCode> {
+        let mut index_exists = false;
+
+        for (position, entry) in self.entries.iter().enumerate() {
+            if entry.index == index {
+                if entry.subindex == subindex {
+                    return Ok(position);
+                }
+
+                index_exists = true;
+            }
+        }
+
+        if index_exists {
+            Err(SdoAbortCode::SubIndexDoesNotExist)
+        } else {
+            Err(SdoAbortCode::ObjectDoesNotExist)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic]
+    fn fixed_size_storage_too_short() {
+        let mut storage = [0; 2];
+        OdEntry::new(
+            0x2000,
+            0,
+            DataType::Unsigned32,
+            AccessType::ReadWrite,
+            &mut storage,
+        );
+    }
+
+    #[test]
+    fn value_lengths() {
+        let (mut value, mut string) = ([0; 8], [0; 4]);
+        let mut entries = [
+            OdEntry::new(
+                0x2000,
+                0,
+                DataType::Unsigned16,
+                AccessType::ReadWrite,
+                &mut value,
+            ),
+            OdEntry::new(
+                0x2001,
+                0,
+                DataType::VisibleString,
+                AccessType::ReadWrite,
+                &mut string,
+            ),
+        ];
+        let mut od = ObjectDictionary::new(&mut entries);
+
+        assert_eq!(od.read(0x2000, 0).unwrap().len(), 2);
+        assert_eq!(
+            od.write(0x2000, 0, &[1, 2, 3]),
+            Err(SdoAbortCode::LengthTooHigh)
+        );
+        assert_eq!(od.write(0x2000, 0, &[1]), Err(SdoAbortCode::LengthTooLow));
+        assert_eq!(od.write_u16(0x2000, 0, 0x1234), Ok(()));
+        assert_eq!(od.read_u16(0x2000, 0), Ok(0x1234));
+
+        assert_eq!(od.write(0x2001, 0, b"abc"), Ok(()));
+        assert_eq!(od.read(0x2001, 0), Ok(&b"abc"[..]));
+        assert_eq!(
+            od.write(0x2001, 0, b"abcde"),
+            Err(SdoAbortCode::LengthTooHigh)
+        );
+    }
+}
@@ -0,0 +1,683 @@
+//! Process data objects: TPDOs & RPDOs mapping object dictionary entries into frame payloads,
+//! and the SYNC producer/consumer driving the synchronous ones
+//!
+//! PDOs should only be sent & applied while the node is operational, which is left to the
+//! application. Mapped entries have to be `pdo_mappable` & fixed size, and a PDO can carry up to
+//! a full 64 byte FD payload. PDOs longer than 8 bytes go out as FD frames, zero padded to the
+//! next valid FD length.
+
+use super::od::{ObjectDictionary, SdoAbortCode};
+use super::{CanOpenError, COB_ID_SYNC};
+use crate::config::Id;
+use crate::util::{dlc_to_len, len_to_dlc};
+use crate::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+
+pub const MAX_PDO_MAPPINGS: usize = 16;
+pub const MAX_PDO_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransmissionType {
+    SyncAcyclic,    // Sent (or applied) on the first SYNC after an event
+    SyncCyclic(u8), // Sent every n SYNCs (1 - 240), received ones are applied on the next SYNC
+    EventDriven,    // Sent on events & the event timer, received ones are applied right away
+}
+
+impl TransmissionType {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            TransmissionType::SyncAcyclic => 0,
+            TransmissionType::SyncCyclic(syncs) => syncs.clamp(1, 240),
+            TransmissionType::EventDriven => 0xFF,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(TransmissionType::SyncAcyclic),
+            1..=240 => Some(TransmissionType::SyncCyclic(value)),
+            0xFE | 0xFF => Some(TransmissionType::EventDriven),
+            _ => None,
+        }
+    }
+}
+
+/// One entry of a PDO mapping, in the order it appears in the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PdoMapping {
+    pub index: u16,
+    pub subindex: u8,
+    pub len: u8, // Bytes
+}
+
+impl PdoMapping {
+    /// The mapping parameter value as stored at 0x1600/0x1A00, with the length in bits
+    pub fn to_u32(self) -> u32 {
+        ((self.index as u32) << 16) | ((self.subindex as u32) << 8) | (self.len as u32 * 8)
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        Self {
+            index: (value >> 16) as u16,
+            subindex: (value >> 8) as u8,
+            len: (value as u8) / 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncEvent {
+    /// Only sent when the producer has a counter overflow set
+    pub counter: Option<u8>,
+    /// Driver timestamp of a received SYNC, None for our own
+    pub timestamp: Option<u16>,
+}
+
+pub struct Sync {
+    cob_id: u32,
+    period_us: u32,
+    counter_overflow: u8,
+    counter: u8,
+    last_us: Option<u32>,
+}
+
+impl Sync {
+    /// Creates a SYNC consumer, which also produces SYNCs every `period_us` when it isn't 0
+    pub fn new(period_us: u32) -> Self {
+        Self {
+            cob_id: COB_ID_SYNC,
+            period_us,
+            counter_overflow: 0,
+            counter: 1,
+            last_us: None,
+        }
+    }
+
+    pub fn set_cob_id(&mut self, cob_id: u32) {
+        self.cob_id = cob_id & 0x7FF;
+    }
+
+    pub fn set_period(&mut self, period_us: u32) {
+        self.period_us = period_us;
+    }
+
+    /// Makes the producer send a counter that wraps after `overflow` (2 - 240), 0 disables it
+    pub fn set_counter_overflow(&mut self, overflow: u8) {
+        self.counter_overflow = match overflow {
+            0 | 1 => 0,
+            overflow => overflow.min(240),
+        };
+        self.counter = 1;
+    }
+
+    /// Feeds a received frame in, returns the event when it's a SYNC
+    pub fn handle_frame(&mut self, frame: &RxFDFrame) -> Option<SyncEvent> {
+        if frame.id != Id::Standard(self.cob_id) {
+            return None;
+        }
+
+        Some(SyncEvent {
+            counter: if frame.buffer_len >= 1 {
+                Some(frame.buffer[0])
+            } else {
+                None
+            },
+            timestamp: Some(frame.timestamp),
+        })
+    }
+
+    /// Sends a SYNC when one is due. We don't receive our own frames, so the event is returned
+    /// for our own PDOs.
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<Option<SyncEvent>, CanOpenError> {
+        if self.period_us == 0 {
+            return Ok(None);
+        }
+
+        let due = match self.last_us {
+            Some(last_us) => now_us.wrapping_sub(last_us) >= self.period_us,
+            None => true,
+        };
+
+        if !due {
+            return Ok(None);
+        }
+
+        let counter = if self.counter_overflow != 0 {
+            Some(self.counter)
+        } else {
+            None
+        };
+
+        match counter {
+            Some(counter) => super::transmit(can, self.cob_id, &[counter])?,
+            None => super::transmit(can, self.cob_id, &[])?,
+        }
+
+        // Keep the period steady instead of drifting by however late we were polled
+        self.last_us = match self.last_us {
+            Some(last_us) if now_us.wrapping_sub(last_us) < 2 * self.period_us => {
+                Some(last_us.wrapping_add(self.period_us))
+            }
+            _ => Some(now_us),
+        };
+
+        if self.counter_overflow != 0 {
+            self.counter = if self.counter >= self.counter_overflow {
+                1
+            } else {
+                self.counter + 1
+            };
+        }
+
+        Ok(Some(SyncEvent {
+            counter,
+            timestamp: None,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    entries: [PdoMapping; MAX_PDO_MAPPINGS],
+    count: usize,
+    len: usize,
+}
+
+impl Mapping {
+    fn new() -> Self {
+        Self {
+            entries: [PdoMapping::default(); MAX_PDO_MAPPINGS],
+            count: 0,
+            len: 0,
+        }
+    }
+
+    fn add(
+        &mut self,
+        od: &ObjectDictionary,
+        index: u16,
+        subindex: u8,
+        transmit: bool,
+    ) -> Result<(), CanOpenError> {
+        let entry = od.entry(index, subindex).map_err(CanOpenError::Od)?;
+
+        if !entry.pdo_mappable {
+            return Err(CanOpenError::Od(SdoAbortCode::NotMappable));
+        }
+
+        if transmit && !entry.access.readable() {
+            return Err(CanOpenError::Od(SdoAbortCode::WriteOnly));
+        }
+
+        if !transmit && !entry.access.writable() {
+            return Err(CanOpenError::Od(SdoAbortCode::ReadOnly));
+        }
+
+        let len = match entry.data_type.size() {
+            Some(size) => size,
+            None => return Err(CanOpenError::Od(SdoAbortCode::NotMappable)),
+        };
+
+        if self.len + len > MAX_PDO_LEN {
+            return Err(CanOpenError::Od(SdoAbortCode::PdoLengthExceeded));
+        }
+
+        if self.count >= MAX_PDO_MAPPINGS {
+            return Err(CanOpenError::NoSpace);
+        }
+
+        self.entries[self.count] = PdoMapping {
+            index,
+            subindex,
+            len: len as u8,
+        };
+        self.count += 1;
+        self.len += len;
+
+        Ok(())
+    }
+
+    fn entries(&self) -> &[PdoMapping] {
+        &self.entries[..self.count]
+    }
+
+    fn pack(
+        &self,
+        od: &ObjectDictionary,
+        payload: &mut [u8; MAX_PDO_LEN],
+    ) -> Result<usize, CanOpenError> {
+        let mut offset = 0;
+
+        for mapping in self.entries() {
+            let value = od
+                .read(mapping.index, mapping.subindex)
+                .map_err(CanOpenError::Od)?;
+            let len = mapping.len as usize;
+
+            if value.len() != len {
+                return Err(CanOpenError::Od(SdoAbortCode::LengthMismatch));
+            }
+
+            payload[offset..offset + len].copy_from_slice(value);
+            offset += len;
+        }
+
+        Ok(offset)
+    }
+
+    fn unpack(&self, od: &mut ObjectDictionary, payload: &[u8]) -> Result<(), CanOpenError> {
+        if payload.len() < self.len {
+            return Err(CanOpenError::Od(SdoAbortCode::LengthTooLow));
+        }
+
+        let mut offset = 0;
+
+        for mapping in self.entries() {
+            let len = mapping.len as usize;
+
+            od.write(
+                mapping.index,
+                mapping.subindex,
+                &payload[offset..offset + len],
+            )
+            .map_err(CanOpenError::Od)?;
+            offset += len;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Tpdo {
+    cob_id: u32,
+    transmission: TransmissionType,
+    priority: Option<u8>,
+    inhibit_time_us: u32,
+    event_timer_us: u32,
+    mapping: Mapping,
+    enabled: bool,
+    event_pending: bool,
+    sync_count: u8,
+    last_tx_us: Option<u32>,
+}
+
+impl Tpdo {
+    pub fn new(cob_id: u32, transmission: TransmissionType) -> Self {
+        Self {
+            cob_id: cob_id & 0x7FF,
+            transmission,
+            priority: None,
+            inhibit_time_us: 0,
+            event_timer_us: 0,
+            mapping: Mapping::new(),
+            enabled: true,
+            event_pending: false,
+            sync_count: 0,
+            last_tx_us: None,
+        }
+    }
+
+    pub fn cob_id(&self) -> u32 {
+        self.cob_id
+    }
+
+    pub fn set_transmission_type(&mut self, transmission: TransmissionType) {
+        self.transmission = transmission;
+        self.sync_count = 0;
+    }
+
+    /// Local priority of the Tx mailbox, see `TxFDFrame::priority`
+    pub fn set_priority(&mut self, priority: Option<u8>) {
+        self.priority = priority;
+    }
+
+    /// Minimum time between two event driven transmissions, 0 disables it
+    pub fn set_inhibit_time(&mut self, inhibit_time_us: u32) {
+        self.inhibit_time_us = inhibit_time_us;
+    }
+
+    /// Sends an event driven PDO when nothing was sent for this long, 0 disables it
+    pub fn set_event_timer(&mut self, event_timer_us: u32) {
+        self.event_timer_us = event_timer_us;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.event_pending = false;
+        self.sync_count = 0;
+    }
+
+    /// Appends an entry to the mapping, it has to be mappable & readable
+    pub fn map(
+        &mut self,
+        od: &ObjectDictionary,
+        index: u16,
+        subindex: u8,
+    ) -> Result<(), CanOpenError> {
+        self.mapping.add(od, index, subindex, true)
+    }
+
+    pub fn clear_mapping(&mut self) {
+        self.mapping = Mapping::new();
+    }
+
+    pub fn mapping(&self) -> &[PdoMapping] {
+        self.mapping.entries()
+    }
+
+    /// Flags that a mapped value changed, event driven & acyclic PDOs are sent because of it
+    pub fn trigger(&mut self) {
+        self.event_pending = true;
+    }
+
+    /// Sends the synchronous PDO if this SYNC is its turn
+    pub fn handle_sync<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        od: &ObjectDictionary,
+        _sync: &SyncEvent,
+        now_us: u32,
+    ) -> Result<(), CanOpenError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        match self.transmission {
+            TransmissionType::SyncAcyclic if self.event_pending => self.send(can, od, now_us),
+            TransmissionType::SyncCyclic(syncs) => {
+                self.sync_count += 1;
+
+                if self.sync_count >= syncs {
+                    self.sync_count = 0;
+                    self.send(can, od, now_us)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends the event driven PDO when an event is pending past the inhibit time, or when the
+    /// event timer expired
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        od: &ObjectDictionary,
+        now_us: u32,
+    ) -> Result<(), CanOpenError> {
+        if !self.enabled || self.transmission != TransmissionType::EventDriven {
+            return Ok(());
+        }
+
+        let since_last_us = self.last_tx_us.map(|last_us| now_us.wrapping_sub(last_us));
+        let inhibited = match since_last_us {
+            Some(since_last_us) => since_last_us < self.inhibit_time_us,
+            None => false,
+        };
+        let timer_expired = match since_last_us {
+            Some(since_last_us) => self.event_timer_us != 0 && since_last_us >= self.event_timer_us,
+            None => self.event_timer_us != 0,
+        };
+
+        if (self.event_pending && !inhibited) || timer_expired {
+            self.send(can, od, now_us)?;
+        }
+
+        Ok(())
+    }
+
+    fn send<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        od: &ObjectDictionary,
+        now_us: u32,
+    ) -> Result<(), CanOpenError> {
+        let mut payload = [0_u8; MAX_PDO_LEN];
+        let len = self.mapping.pack(od, &mut payload)?;
+
+        // Anything past 8 bytes needs an FD frame, which only comes in DLC sized lengths
+        let (len, format) = if len > 8 {
+            let padded_len = dlc_to_len(len_to_dlc(len as u32)) as usize;
+            (padded_len, FrameFormat::default())
+        } else {
+            (len, FrameFormat::Classic)
+        };
+
+        let frame = TxFDFrame {
+            id: Id::Standard(self.cob_id),
+            buffer: &payload[..len],
+            priority: self.priority,
+            format,
+        };
+
+        // A failed send keeps the event pending so it's retried
+        can.transmit(&frame).map_err(CanOpenError::Bus)?;
+
+        self.event_pending = false;
+        self.last_tx_us = Some(now_us);
+
+        Ok(())
+    }
+}
+
+pub struct Rpdo {
+    cob_id: u32,
+    transmission: TransmissionType,
+    mapping: Mapping,
+    enabled: bool,
+    pending: [u8; MAX_PDO_LEN], // Received payload waiting for the next SYNC
+    pending_len: Option<usize>,
+    last_timestamp: Option<u16>,
+}
+
+impl Rpdo {
+    pub fn new(cob_id: u32, transmission: TransmissionType) -> Self {
+        Self {
+            cob_id: cob_id & 0x7FF,
+            transmission,
+            mapping: Mapping::new(),
+            enabled: true,
+            pending: [0; MAX_PDO_LEN],
+            pending_len: None,
+            last_timestamp: None,
+        }
+    }
+
+    pub fn cob_id(&self) -> u32 {
+        self.cob_id
+    }
+
+    pub fn set_transmission_type(&mut self, transmission: TransmissionType) {
+        self.transmission = transmission;
+        self.pending_len = None;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.pending_len = None;
+    }
+
+    /// Appends an entry to the mapping, it has to be mappable & writable
+    pub fn map(
+        &mut self,
+        od: &ObjectDictionary,
+        index: u16,
+        subindex: u8,
+    ) -> Result<(), CanOpenError> {
+        self.mapping.add(od, index, subindex, false)
+    }
+
+    pub fn clear_mapping(&mut self) {
+        self.mapping = Mapping::new();
+    }
+
+    pub fn mapping(&self) -> &[PdoMapping] {
+        self.mapping.entries()
+    }
+
+    /// Driver timestamp of the last PDO received
+    pub fn last_timestamp(&self) -> Option<u16> {
+        self.last_timestamp
+    }
+
+    /// Feeds a received frame in. Event driven PDOs are written to the object dictionary right
+    /// away & synchronous ones on the next SYNC. Returns true when the frame was our PDO.
+    pub fn handle_frame(
+        &mut self,
+        od: &mut ObjectDictionary,
+        frame: &RxFDFrame,
+    ) -> Result<bool, CanOpenError> {
+        if !self.enabled || frame.id != Id::Standard(self.cob_id) {
+            return Ok(false);
+        }
+
+        let len = (frame.buffer_len as usize).min(MAX_PDO_LEN);
+
+        if len < self.mapping.len {
+            return Err(CanOpenError::Od(SdoAbortCode::LengthTooLow));
+        }
+
+        self.last_timestamp = Some(frame.timestamp);
+
+        if self.transmission == TransmissionType::EventDriven {
+            self.mapping.unpack(od, &frame.buffer[..len])?;
+        } else {
+            self.pending[..len].copy_from_slice(&frame.buffer[..len]);
+            self.pending_len = Some(len);
+        }
+
+        Ok(true)
+    }
+
+    /// Applies the PDO received since the last SYNC
+    pub fn handle_sync(
+        &mut self,
+        od: &mut ObjectDictionary,
+        _sync: &SyncEvent,
+    ) -> Result<(), CanOpenError> {
+        if let Some(len) = self.pending_len.take() {
+            self.mapping.unpack(od, &self.pending[..len])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canopen::od::{AccessType, DataType, OdEntry};
+    use crate::mock::{Bus, SentFrame};
+
+    const COB_ID: u32 = 0x181;
+
+    // Entries 0x2000:1 (u64), 0x2000:2 (u32), 0x2000:3 (u16) & 0x2000:4 (u8)
+    fn od_entries(storage: &mut [[u8; 8]; 4]) -> [OdEntry<'_>; 4] {
+        fn entry(subindex: u8, data_type: DataType, data: &mut [u8; 8]) -> OdEntry<'_> {
+            OdEntry::new(0x2000, subindex, data_type, AccessType::ReadWrite, data).mappable()
+        }
+
+        let [a, b, c, d] = storage;
+        [
+            entry(1, DataType::Unsigned64, a),
+            entry(2, DataType::Unsigned32, b),
+            entry(3, DataType::Unsigned16, c),
+            entry(4, DataType::Unsigned8, d),
+        ]
+    }
+
+    fn send(tpdo: &mut Tpdo, od: &ObjectDictionary) -> SentFrame {
+        let mut bus = Bus::default();
+        tpdo.trigger();
+        tpdo.poll(&mut bus, od, 0).unwrap();
+
+        let mut sent = bus.take();
+        assert_eq!(sent.len(), 1);
+        sent.pop().unwrap()
+    }
+
+    #[test]
+    fn classic_up_to_8_bytes() {
+        let mut storage = [[0; 8]; 4];
+        let mut entries = od_entries(&mut storage);
+        let mut od = ObjectDictionary::new(&mut entries);
+        od.write_u32(0x2000, 2, 0x1122_3344).unwrap();
+        od.write_u16(0x2000, 3, 0x5566).unwrap();
+
+        let mut tpdo = Tpdo::new(COB_ID, TransmissionType::EventDriven);
+        tpdo.map(&od, 0x2000, 2).unwrap();
+        tpdo.map(&od, 0x2000, 3).unwrap();
+
+        let frame = send(&mut tpdo, &od);
+        assert_eq!(frame.id, Id::Standard(COB_ID));
+        assert_eq!(frame.format, FrameFormat::Classic);
+        assert_eq!(frame.data, [0x44, 0x33, 0x22, 0x11, 0x66, 0x55]);
+    }
+
+    #[test]
+    fn fd_past_8_bytes() {
+        let mut storage = [[0; 8]; 4];
+        let mut entries = od_entries(&mut storage);
+        let mut od = ObjectDictionary::new(&mut entries);
+        od.write_u64(0x2000, 1, 0x0807_0605_0403_0201).unwrap();
+        od.write_u32(0x2000, 2, 0x0C0B_0A09).unwrap();
+        od.write_u8(0x2000, 4, 0x0D).unwrap();
+
+        let mut tpdo = Tpdo::new(COB_ID, TransmissionType::EventDriven);
+        for subindex in [1, 2, 4] {
+            tpdo.map(&od, 0x2000, subindex).unwrap();
+        }
+
+        // 13 bytes mapped, padded to the 16 byte FD length
+        let frame = send(&mut tpdo, &od);
+        assert_eq!(
+            frame.format,
+            FrameFormat::Fd {
+                bitrate_switch: true
+            }
+        );
+        assert_eq!(
+            frame.data,
+            [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 0, 0, 0]
+        );
+
+        // And the other end unpacks it again
+        let mut rx_storage = [[0; 8]; 4];
+        let mut rx_entries = od_entries(&mut rx_storage);
+        let mut rx_od = ObjectDictionary::new(&mut rx_entries);
+        let mut rpdo = Rpdo::new(COB_ID, TransmissionType::EventDriven);
+        for subindex in [1, 2, 4] {
+            rpdo.map(&rx_od, 0x2000, subindex).unwrap();
+        }
+
+        assert_eq!(rpdo.handle_frame(&mut rx_od, &frame.to_rx()), Ok(true));
+        assert_eq!(rx_od.read_u64(0x2000, 1), Ok(0x0807_0605_0403_0201));
+        assert_eq!(rx_od.read_u32(0x2000, 2), Ok(0x0C0B_0A09));
+        assert_eq!(rx_od.read_u8(0x2000, 4), Ok(0x0D));
+    }
+
+    #[test]
+    fn full_64_byte_mapping() {
+        let mut storage = [[0; 8]; 4];
+        let mut entries = od_entries(&mut storage);
+        let mut od = ObjectDictionary::new(&mut entries);
+
+        let mut tpdo = Tpdo::new(COB_ID, TransmissionType::EventDriven);
+        for _ in 0..8 {
+            tpdo.map(&od, 0x2000, 1).unwrap();
+        }
+        assert_eq!(
+            tpdo.map(&od, 0x2000, 4),
+            Err(CanOpenError::Od(SdoAbortCode::PdoLengthExceeded))
+        );
+
+        od.write_u64(0x2000, 1, u64::MAX).unwrap();
+        let frame = send(&mut tpdo, &od);
+        assert!(frame.format.is_fd());
+        assert_eq!(frame.data, [0xFF; 64]);
+    }
+}
@@ -0,0 +1,1975 @@
+//! SDO server & client, with expedited, segmented and block transfers
+//!
+//! Both sides handle one transfer at a time and stage the data in a caller supplied buffer, so
+//! its size limits the largest segmented or block transfer. Block transfers always offer the CRC
+//! and use it when the other side supports it too.
+
+use super::od::{ObjectDictionary, SdoAbortCode};
+use super::{transmit, valid_node_id, CanOpenError, COB_ID_SDO_RX, COB_ID_SDO_TX};
+use crate::can_error::RxTxError;
+use crate::config::Id;
+use crate::{RxFDFrame, Transmitter};
+
+pub const SDO_BLOCK_SIZE: u8 = 127;
+
+// Client command specifiers
+const CCS_DOWNLOAD_SEGMENT: u8 = 0;
+const CCS_INITIATE_DOWNLOAD: u8 = 1;
+const CCS_INITIATE_UPLOAD: u8 = 2;
+const CCS_UPLOAD_SEGMENT: u8 = 3;
+const CCS_BLOCK_UPLOAD: u8 = 5;
+const CCS_BLOCK_DOWNLOAD: u8 = 6;
+
+// Server command specifiers
+const SCS_UPLOAD_SEGMENT: u8 = 0;
+const SCS_DOWNLOAD_SEGMENT: u8 = 1;
+const SCS_INITIATE_UPLOAD: u8 = 2;
+const SCS_INITIATE_DOWNLOAD: u8 = 3;
+const SCS_BLOCK_DOWNLOAD: u8 = 5;
+const SCS_BLOCK_UPLOAD: u8 = 6;
+
+const CS_ABORT: u8 = 4;
+
+// Block transfer sub commands
+const BLOCK_INITIATE: u8 = 0;
+const BLOCK_END: u8 = 1;
+const BLOCK_ACK: u8 = 2;
+const BLOCK_START: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdoEvent {
+    DownloadComplete {
+        index: u16,
+        subindex: u8,
+    },
+    UploadComplete {
+        index: u16,
+        subindex: u8,
+        len: usize,
+    },
+    Aborted {
+        index: u16,
+        subindex: u8,
+        code: SdoAbortCode,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdoTransferMode {
+    Normal, // Expedited when the data fits in the initiate frame, segmented otherwise
+    Block,
+}
+
+/// The CRC-16 (CCITT, XModem flavour) used by block transfers
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0_u16;
+
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+fn initiate_frame(command: u8, index: u16, subindex: u8, data: [u8; 4]) -> [u8; 8] {
+    let index = index.to_le_bytes();
+
+    [
+        command, index[0], index[1], subindex, data[0], data[1], data[2], data[3],
+    ]
+}
+
+fn abort_frame(index: u16, subindex: u8, code: SdoAbortCode) -> [u8; 8] {
+    initiate_frame(CS_ABORT << 5, index, subindex, code.to_u32().to_le_bytes())
+}
+
+fn segment_frame(command: u8, data: &[u8]) -> [u8; 8] {
+    let mut frame = [0_u8; 8];
+    frame[0] = command;
+    frame[1..1 + data.len()].copy_from_slice(data);
+
+    frame
+}
+
+fn frame_index(data: &[u8]) -> (u16, u8) {
+    (u16::from_le_bytes([data[1], data[2]]), data[3])
+}
+
+fn frame_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[4], data[5], data[6], data[7]])
+}
+
+/// Bytes of the last 7 byte segment that don't hold data
+fn unused_in_last_segment(len: usize) -> u8 {
+    if len == 0 {
+        7
+    } else {
+        ((7 - len % 7) % 7) as u8
+    }
+}
+
+fn is_abort(data: &[u8], receiving_segments: bool) -> bool {
+    if receiving_segments {
+        // Block segments use the whole command byte, the last one has the top bit set too
+        data[0] == CS_ABORT << 5
+    } else {
+        data[0] >> 5 == CS_ABORT
+    }
+}
+
+fn sdo_frame_data(frame: &RxFDFrame, cob_id: u32) -> Option<&[u8]> {
+    if frame.id != Id::Standard(cob_id) || frame.buffer_len < 8 {
+        return None;
+    }
+
+    Some(&frame.buffer[..8])
+}
+
+/// Sends the next block segments, used by the server for uploads & the client for downloads.
+/// Returns true once the sub-block (or the whole transfer) has been sent.
+fn send_block_segments<T: Transmitter>(
+    can: &mut T,
+    cob_id: u32,
+    data: &[u8],
+    block_start: usize,
+    next_seq: &mut u8,
+    block_size: u8,
+) -> Result<bool, CanOpenError> {
+    loop {
+        let offset = block_start + (*next_seq as usize - 1) * 7;
+        let end = (offset + 7).min(data.len());
+        let last = offset + 7 >= data.len();
+
+        let command = ((last as u8) << 7) | *next_seq;
+        let frame = segment_frame(command, &data[offset.min(end)..end]);
+
+        match transmit(can, cob_id, &frame) {
+            Ok(()) => (),
+            Err(CanOpenError::Bus(RxTxError::MailboxUnavailable)) => return Ok(false),
+            Err(err) => return Err(err),
+        }
+
+        if last || *next_seq >= block_size {
+            return Ok(true);
+        }
+
+        *next_seq += 1;
+    }
+}
+
+/// Receives a block segment, used by the server for downloads & the client for uploads.
+/// Returns the ack sequence number once the sub-block is over.
+fn receive_block_segment(
+    data: &[u8],
+    buffer: &mut [u8],
+    received: &mut usize,
+    next_seq: &mut u8,
+    last: &mut bool,
+    block_size: u8,
+) -> Option<u8> {
+    let seq = data[0] & 0x7F;
+    let is_last = data[0] & 0x80 != 0;
+
+    if seq == *next_seq {
+        let end = (*received + 7).min(buffer.len());
+
+        if *received < end {
+            buffer[*received..end].copy_from_slice(&data[1..1 + end - *received]);
+        }
+
+        *received += 7;
+        *last = is_last;
+
+        if is_last || seq >= block_size {
+            let ack_seq = seq;
+            *next_seq = 1;
+            return Some(ack_seq);
+        }
+
+        *next_seq += 1;
+        None
+    } else if is_last || seq >= block_size {
+        // The sub-block ended with segments missing, ack what we got so they're sent again
+        let ack_seq = *next_seq - 1;
+        *next_seq = 1;
+        Some(ack_seq)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ServerState {
+    Idle,
+    SegmentedDownload {
+        index: u16,
+        subindex: u8,
+        received: usize,
+        toggle: bool,
+    },
+    SegmentedUpload {
+        index: u16,
+        subindex: u8,
+        len: usize,
+        sent: usize,
+        toggle: bool,
+    },
+    BlockDownload {
+        index: u16,
+        subindex: u8,
+        crc: bool,
+        received: usize,
+        next_seq: u8,
+        last: bool,
+    },
+    BlockDownloadEnd {
+        index: u16,
+        subindex: u8,
+        crc: bool,
+        received: usize,
+    },
+    BlockUploadStart {
+        index: u16,
+        subindex: u8,
+        len: usize,
+        crc: bool,
+        block_size: u8,
+    },
+    BlockUploadSending {
+        index: u16,
+        subindex: u8,
+        len: usize,
+        crc: bool,
+        block_size: u8,
+        block_start: usize,
+        next_seq: u8,
+    },
+    BlockUploadAck {
+        index: u16,
+        subindex: u8,
+        len: usize,
+        crc: bool,
+        block_start: usize,
+    },
+    BlockUploadEnd {
+        index: u16,
+        subindex: u8,
+        len: usize,
+    },
+}
+
+impl ServerState {
+    fn index(&self) -> (u16, u8) {
+        match *self {
+            ServerState::Idle => (0, 0),
+            ServerState::SegmentedDownload {
+                index, subindex, ..
+            }
+            | ServerState::SegmentedUpload {
+                index, subindex, ..
+            }
+            | ServerState::BlockDownload {
+                index, subindex, ..
+            }
+            | ServerState::BlockDownloadEnd {
+                index, subindex, ..
+            }
+            | ServerState::BlockUploadStart {
+                index, subindex, ..
+            }
+            | ServerState::BlockUploadSending {
+                index, subindex, ..
+            }
+            | ServerState::BlockUploadAck {
+                index, subindex, ..
+            }
+            | ServerState::BlockUploadEnd {
+                index, subindex, ..
+            } => (index, subindex),
+        }
+    }
+}
+
+pub struct SdoServer<'a> {
+    node_id: u8,
+    timeout_us: u32,
+    buffer: &'a mut [u8],
+    state: ServerState,
+    last_us: u32,
+}
+
+impl<'a> SdoServer<'a> {
+    /// Creates the default SDO server of a node, `buffer` holds segmented & block transfers
+    pub fn new(node_id: u8, timeout_us: u32, buffer: &'a mut [u8]) -> Result<Self, CanOpenError> {
+        if !valid_node_id(node_id) {
+            return Err(CanOpenError::InvalidNodeId);
+        }
+
+        Ok(Self {
+            node_id,
+            timeout_us,
+            buffer,
+            state: ServerState::Idle,
+            last_us: 0,
+        })
+    }
+
+    pub fn is_busy(&self) -> bool {
+        !matches!(self.state, ServerState::Idle)
+    }
+
+    /// Feeds a received frame in, only requests for our node are handled
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        od: &mut ObjectDictionary,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<SdoEvent>, CanOpenError> {
+        let mut data = [0_u8; 8];
+        match sdo_frame_data(frame, COB_ID_SDO_RX + self.node_id as u32) {
+            Some(frame_data) => data.copy_from_slice(frame_data),
+            None => return Ok(None),
+        }
+
+        self.last_us = now_us;
+
+        let receiving_segments = matches!(self.state, ServerState::BlockDownload { .. });
+
+        if is_abort(&data, receiving_segments) {
+            let (index, subindex) = frame_index(&data);
+            self.state = ServerState::Idle;
+
+            return Ok(Some(SdoEvent::Aborted {
+                index,
+                subindex,
+                code: SdoAbortCode::from_u32(frame_u32(&data)),
+            }));
+        }
+
+        if receiving_segments {
+            return self.receive_download_segment(can, &data);
+        }
+
+        let result = match data[0] >> 5 {
+            CCS_INITIATE_DOWNLOAD => self.initiate_download(can, od, &data),
+            CCS_DOWNLOAD_SEGMENT => self.download_segment(can, od, &data),
+            CCS_INITIATE_UPLOAD => self.initiate_upload(can, od, &data),
+            CCS_UPLOAD_SEGMENT => self.upload_segment(can, &data),
+            CCS_BLOCK_DOWNLOAD => self.block_download(can, od, &data),
+            CCS_BLOCK_UPLOAD => self.block_upload(can, od, &data),
+            _ => Err(SdoAbortCode::InvalidCommand),
+        };
+
+        match result {
+            Ok(event) => Ok(event),
+            Err(code) => {
+                let (index, subindex) = match self.state {
+                    ServerState::Idle => frame_index(&data),
+                    state => state.index(),
+                };
+
+                self.abort(can, index, subindex, code)
+            }
+        }
+    }
+
+    /// Checks the transfer timeout & sends the block upload segments that are due
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<Option<SdoEvent>, CanOpenError> {
+        if let ServerState::Idle = self.state {
+            return Ok(None);
+        }
+
+        if now_us.wrapping_sub(self.last_us) > self.timeout_us {
+            let (index, subindex) = self.state.index();
+            return self.abort(can, index, subindex, SdoAbortCode::Timeout);
+        }
+
+        self.send_upload_segments(can)?;
+
+        Ok(None)
+    }
+
+    fn abort<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        index: u16,
+        subindex: u8,
+        code: SdoAbortCode,
+    ) -> Result<Option<SdoEvent>, CanOpenError> {
+        self.state = ServerState::Idle;
+        self.send(can, abort_frame(index, subindex, code))?;
+
+        Ok(Some(SdoEvent::Aborted {
+            index,
+            subindex,
+            code,
+        }))
+    }
+
+    fn send<T: Transmitter>(&self, can: &mut T, frame: [u8; 8]) -> Result<(), CanOpenError> {
+        transmit(can, COB_ID_SDO_TX + self.node_id as u32, &frame)
+    }
+
+    fn initiate_download<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        od: &mut ObjectDictionary,
+        data: &[u8; 8],
+    ) -> Result<Option<SdoEvent>, SdoAbortCode> {
+        let (index, subindex) = frame_index(data);
+        let expedited = data[0] & 0b10 != 0;
+        let size_indicated = data[0] & 0b01 != 0;
+        self.state = ServerState::Idle;
+
+        if expedited {
+            let len = if size_indicated {
+                4 - ((data[0] >> 2) & 0b11) as usize
+            } else {
+                4
+            };
+
+            od.sdo_write(index, subindex, &data[4..4 + len])?;
+            self.send_response(
+                can,
+                initiate_frame(SCS_INITIATE_DOWNLOAD << 5, index, subindex, [0; 4]),
+            )?;
+
+            return Ok(Some(SdoEvent::DownloadComplete { index, subindex }));
+        }
+
+        let size = if size_indicated {
+            Some(frame_u32(data) as usize)
+        } else {
+            None
+        };
+
+        self.check_download(od, index, subindex, size)?;
+        self.send_response(
+            can,
+            initiate_frame(SCS_INITIATE_DOWNLOAD << 5, index, subindex, [0; 4]),
+        )?;
+
+        self.state = ServerState::SegmentedDownload {
+            index,
+            subindex,
+            received: 0,
+            toggle: false,
+        };
+
+        Ok(None)
+    }
+
+    fn download_segment<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        od: &mut ObjectDictionary,
+        data: &[u8; 8],
+    ) -> Result<Option<SdoEvent>, SdoAbortCode> {
+        let (index, subindex, received, toggle) = match self.state {
+            ServerState::SegmentedDownload {
+                index,
+                subindex,
+                received,
+                toggle,
+            } => (index, subindex, received, toggle),
+            _ => return Err(SdoAbortCode::InvalidCommand),
+        };
+
+        if (data[0] & 0x10 != 0) != toggle {
+            return Err(SdoAbortCode::ToggleBitNotAlternated);
+        }
+
+        let segment_len = 7 - ((data[0] >> 1) & 0b111) as usize;
+        let last = data[0] & 0b1 != 0;
+
+        if received + segment_len > self.buffer.len() {
+            return Err(SdoAbortCode::OutOfMemory);
+        }
+
+        self.buffer[received..received + segment_len].copy_from_slice(&data[1..1 + segment_len]);
+        let received = received + segment_len;
+
+        if last {
+            od.sdo_write(index, subindex, &self.buffer[..received])?;
+        }
+
+        let command = (SCS_DOWNLOAD_SEGMENT << 5) | ((toggle as u8) << 4);
+        self.send_response(can, segment_frame(command, &[]))?;
+
+        if last {
+            self.state = ServerState::Idle;
+            return Ok(Some(SdoEvent::DownloadComplete { index, subindex }));
+        }
+
+        self.state = ServerState::SegmentedDownload {
+            index,
+            subindex,
+            received,
+            toggle: !toggle,
+        };
+
+        Ok(None)
+    }
+
+    fn initiate_upload<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        od: &mut ObjectDictionary,
+        data: &[u8; 8],
+    ) -> Result<Option<SdoEvent>, SdoAbortCode> {
+        let (index, subindex) = frame_index(data);
+        let value = od.sdo_read(index, subindex)?;
+        let len = value.len();
+        self.state = ServerState::Idle;
+
+        if len > 0 && len <= 4 {
+            let mut expedited = [0_u8; 4];
+            expedited[..len].copy_from_slice(value);
+
+            let command = (SCS_INITIATE_UPLOAD << 5) | (((4 - len) as u8) << 2) | 0b11;
+            self.send_response(can, initiate_frame(command, index, subindex, expedited))?;
+
+            return Ok(Some(SdoEvent::UploadComplete {
+                index,
+                subindex,
+                len,
+            }));
+        }
+
+        if len > self.buffer.len() {
+            return Err(SdoAbortCode::OutOfMemory);
+        }
+
+        self.buffer[..len].copy_from_slice(value);
+
+        let command = (SCS_INITIATE_UPLOAD << 5) | 0b01;
+        self.send_response(
+            can,
+            initiate_frame(command, index, subindex, (len as u32).to_le_bytes()),
+        )?;
+
+        self.state = ServerState::SegmentedUpload {
+            index,
+            subindex,
+            len,
+            sent: 0,
+            toggle: false,
+        };
+
+        Ok(None)
+    }
+
+    fn upload_segment<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        data: &[u8; 8],
+    ) -> Result<Option<SdoEvent>, SdoAbortCode> {
+        let (index, subindex, len, sent, toggle) = match self.state {
+            ServerState::SegmentedUpload {
+                index,
+                subindex,
+                len,
+                sent,
+                toggle,
+            } => (index, subindex, len, sent, toggle),
+            _ => return Err(SdoAbortCode::InvalidCommand),
+        };
+
+        if (data[0] & 0x10 != 0) != toggle {
+            return Err(SdoAbortCode::ToggleBitNotAlternated);
+        }
+
+        let segment_len = (len - sent).min(7);
+        let last = sent + segment_len == len;
+
+        let command = (SCS_UPLOAD_SEGMENT << 5)
+            | ((toggle as u8) << 4)
+            | (((7 - segment_len) as u8) << 1)
+            | last as u8;
+        let frame = segment_frame(command, &self.buffer[sent..sent + segment_len]);
+        self.send_response(can, frame)?;
+
+        if last {
+            self.state = ServerState::Idle;
+            return Ok(Some(SdoEvent::UploadComplete {
+                index,
+                subindex,
+                len,
+            }));
+        }
+
+        self.state = ServerState::SegmentedUpload {
+            index,
+            subindex,
+            len,
+            sent: sent + segment_len,
+            toggle: !toggle,
+        };
+
+        Ok(None)
+    }
+
+    fn block_download<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        od: &mut ObjectDictionary,
+        data: &[u8; 8],
+    ) -> Result<Option<SdoEvent>, SdoAbortCode> {
+        match (data[0] & 0b1, self.state) {
+            (BLOCK_INITIATE, ServerState::Idle) => {
+                let (index, subindex) = frame_index(data);
+                let crc = data[0] & 0b100 != 0;
+                let size = if data[0] & 0b10 != 0 {
+                    Some(frame_u32(data) as usize)
+                } else {
+                    None
+                };
+
+                self.check_download(od, index, subindex, size)?;
+
+                let command = (SCS_BLOCK_DOWNLOAD << 5) | 0b100 | BLOCK_INITIATE;
+                self.send_response(
+                    can,
+                    initiate_frame(command, index, subindex, [SDO_BLOCK_SIZE, 0, 0, 0]),
+                )?;
+
+                self.state = ServerState::BlockDownload {
+                    index,
+                    subindex,
+                    crc,
+                    received: 0,
+                    next_seq: 1,
+                    last: false,
+                };
+
+                Ok(None)
+            }
+            (
+                BLOCK_END,
+                ServerState::BlockDownloadEnd {
+                    index,
+                    subindex,
+                    crc,
+                    received,
+                },
+            ) => {
+                let unused = ((data[0] >> 2) & 0b111) as usize;
+                let len = received.saturating_sub(unused);
+
+                if len > self.buffer.len() {
+                    return Err(SdoAbortCode::OutOfMemory);
+                }
+
+                if crc && crc16(&self.buffer[..len]) != u16::from_le_bytes([data[1], data[2]]) {
+                    return Err(SdoAbortCode::CrcError);
+                }
+
+                od.sdo_write(index, subindex, &self.buffer[..len])?;
+
+                let command = (SCS_BLOCK_DOWNLOAD << 5) | BLOCK_END;
+                self.send_response(can, segment_frame(command, &[]))?;
+                self.state = ServerState::Idle;
+
+                Ok(Some(SdoEvent::DownloadComplete { index, subindex }))
+            }
+            _ => Err(SdoAbortCode::InvalidCommand),
+        }
+    }
+
+    fn receive_download_segment<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        data: &[u8; 8],
+    ) -> Result<Option<SdoEvent>, CanOpenError> {
+        if let ServerState::BlockDownload {
+            index,
+            subindex,
+            crc,
+            mut received,
+            mut next_seq,
+            mut last,
+        } = self.state
+        {
+            let ack_seq = receive_block_segment(
+                data,
+                self.buffer,
+                &mut received,
+                &mut next_seq,
+                &mut last,
+                SDO_BLOCK_SIZE,
+            );
+
+            self.state = ServerState::BlockDownload {
+                index,
+                subindex,
+                crc,
+                received,
+                next_seq,
+                last,
+            };
+
+            if let Some(ack_seq) = ack_seq {
+                let command = (SCS_BLOCK_DOWNLOAD << 5) | BLOCK_ACK;
+                self.send(can, segment_frame(command, &[ack_seq, SDO_BLOCK_SIZE]))?;
+
+                if last {
+                    self.state = ServerState::BlockDownloadEnd {
+                        index,
+                        subindex,
+                        crc,
+                        received,
+                    };
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn block_upload<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        od: &mut ObjectDictionary,
+        data: &[u8; 8],
+    ) -> Result<Option<SdoEvent>, SdoAbortCode> {
+        match (data[0] & 0b11, self.state) {
+            (BLOCK_INITIATE, ServerState::Idle) => {
+                let (index, subindex) = frame_index(data);
+                let crc = data[0] & 0b100 != 0;
+                let block_size = data[4];
+
+                if block_size == 0 || block_size > SDO_BLOCK_SIZE {
+                    return Err(SdoAbortCode::InvalidBlockSize);
+                }
+
+                let value = od.sdo_read(index, subindex)?;
+                let len = value.len();
+
+                if len > self.buffer.len() {
+                    return Err(SdoAbortCode::OutOfMemory);
+                }
+
+                self.buffer[..len].copy_from_slice(value);
+
+                let command = (SCS_BLOCK_UPLOAD << 5) | 0b100 | 0b10 | BLOCK_INITIATE;
+                self.send_response(
+                    can,
+                    initiate_frame(command, index, subindex, (len as u32).to_le_bytes()),
+                )?;
+
+                self.state = ServerState::BlockUploadStart {
+                    index,
+                    subindex,
+                    len,
+                    crc,
+                    block_size,
+                };
+
+                Ok(None)
+            }
+            (
+                BLOCK_START,
+                ServerState::BlockUploadStart {
+                    index,
+                    subindex,
+                    len,
+                    crc,
+                    block_size,
+                },
+            ) => {
+                self.state = ServerState::BlockUploadSending {
+                    index,
+                    subindex,
+                    len,
+                    crc,
+                    block_size,
+                    block_start: 0,
+                    next_seq: 1,
+                };
+
+                self.send_upload_segments(can)
+                    .map_err(|_| SdoAbortCode::GeneralError)?;
+
+                Ok(None)
+            }
+            (
+                BLOCK_ACK,
+                ServerState::BlockUploadAck {
+                    index,
+                    subindex,
+                    len,
+                    crc,
+                    block_start,
+                },
+            ) => {
+                let ack_seq = data[1];
+                let block_size = data[2];
+
+                if block_size == 0 || block_size > SDO_BLOCK_SIZE {
+                    return Err(SdoAbortCode::InvalidBlockSize);
+                }
+
+                let block_start = block_start + ack_seq as usize * 7;
+
+                if block_start >= len && ack_seq > 0 {
+                    let crc = if crc { crc16(&self.buffer[..len]) } else { 0 };
+                    let command =
+                        (SCS_BLOCK_UPLOAD << 5) | (unused_in_last_segment(len) << 2) | BLOCK_END;
+                    self.send_response(can, segment_frame(command, &crc.to_le_bytes()))?;
+
+                    self.state = ServerState::BlockUploadEnd {
+                        index,
+                        subindex,
+                        len,
+                    };
+                } else {
+                    self.state = ServerState::BlockUploadSending {
+                        index,
+                        subindex,
+                        len,
+                        crc,
+                        block_size,
+                        block_start,
+                        next_seq: 1,
+                    };
+
+                    self.send_upload_segments(can)
+                        .map_err(|_| SdoAbortCode::GeneralError)?;
+                }
+
+                Ok(None)
+            }
+            (
+                BLOCK_END,
+                ServerState::BlockUploadEnd {
+                    index,
+                    subindex,
+                    len,
+                },
+            ) => {
+                self.state = ServerState::Idle;
+
+                Ok(Some(SdoEvent::UploadComplete {
+                    index,
+                    subindex,
+                    len,
+                }))
+            }
+            _ => Err(SdoAbortCode::InvalidCommand),
+        }
+    }
+
+    fn send_upload_segments<T: Transmitter>(&mut self, can: &mut T) -> Result<(), CanOpenError> {
+        if let ServerState::BlockUploadSending {
+            index,
+            subindex,
+            len,
+            crc,
+            block_size,
+            block_start,
+            mut next_seq,
+        } = self.state
+        {
+            let done = send_block_segments(
+                can,
+                COB_ID_SDO_TX + self.node_id as u32,
+                &self.buffer[..len],
+                block_start,
+                &mut next_seq,
+                block_size,
+            )?;
+
+            self.state = if done {
+                ServerState::BlockUploadAck {
+                    index,
+                    subindex,
+                    len,
+                    crc,
+                    block_start,
+                }
+            } else {
+                ServerState::BlockUploadSending {
+                    index,
+                    subindex,
+                    len,
+                    crc,
+                    block_size,
+                    block_start,
+                    next_seq,
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    fn check_download(
+        &self,
+        od: &ObjectDictionary,
+        index: u16,
+        subindex: u8,
+        size: Option<usize>,
+    ) -> Result<(), SdoAbortCode> {
+        od.sdo_check_write(index, subindex, size)?;
+
+        match size {
+            Some(size) if size > self.buffer.len() => Err(SdoAbortCode::OutOfMemory),
+            _ => Ok(()),
+        }
+    }
+
+    fn send_response<T: Transmitter>(
+        &self,
+        can: &mut T,
+        frame: [u8; 8],
+    ) -> Result<(), SdoAbortCode> {
+        self.send(can, frame)
+            .map_err(|_| SdoAbortCode::GeneralError)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ClientState {
+    Idle,
+    DownloadInitiate {
+        index: u16,
+        subindex: u8,
+        len: usize,
+        expedited: bool,
+    },
+    DownloadSegment {
+        index: u16,
+        subindex: u8,
+        len: usize,
+        sent: usize,
+        toggle: bool,
+    },
+    UploadInitiate {
+        index: u16,
+        subindex: u8,
+    },
+    UploadSegment {
+        index: u16,
+        subindex: u8,
+        received: usize,
+        toggle: bool,
+    },
+    BlockDownloadInitiate {
+        index: u16,
+        subindex: u8,
+        len: usize,
+    },
+    BlockDownloadSending {
+        index: u16,
+        subindex: u8,
+        len: usize,
+        crc: bool,
+        block_size: u8,
+        block_start: usize,
+        next_seq: u8,
+    },
+    BlockDownloadAck {
+        index: u16,
+        subindex: u8,
+        len: usize,
+        crc: bool,
+        block_start: usize,
+    },
+    BlockDownloadEnd {
+        index: u16,
+        subindex: u8,
+    },
+    BlockUploadInitiate {
+        index: u16,
+        subindex: u8,
+    },
+    BlockUploadReceiving {
+        index: u16,
+        subindex: u8,
+        crc: bool,
+        received: usize,
+        next_seq: u8,
+        last: bool,
+    },
+    BlockUploadEnd {
+        index: u16,
+        subindex: u8,
+        crc: bool,
+        received: usize,
+    },
+}
+
+impl ClientState {
+    fn index(&self) -> (u16, u8) {
+        match *self {
+            ClientState::Idle => (0, 0),
+            ClientState::DownloadInitiate {
+                index, subindex, ..
+            }
+            | ClientState::DownloadSegment {
+                index, subindex, ..
+            }
+            | ClientState::UploadInitiate { index, subindex }
+            | ClientState::UploadSegment {
+                index, subindex, ..
+            }
+            | ClientState::BlockDownloadInitiate {
+                index, subindex, ..
+            }
+            | ClientState::BlockDownloadSending {
+                index, subindex, ..
+            }
+            | ClientState::BlockDownloadAck {
+                index, subindex, ..
+            }
+            | ClientState::BlockDownloadEnd { index, subindex }
+            | ClientState::BlockUploadInitiate { index, subindex }
+            | ClientState::BlockUploadReceiving {
+                index, subindex, ..
+            }
+            | ClientState::BlockUploadEnd {
+                index, subindex, ..
+            } => (index, subindex),
+        }
+    }
+}
+
+pub struct SdoClient<'a> {
+    server_node_id: u8,
+    timeout_us: u32,
+    buffer: &'a mut [u8],
+    state: ClientState,
+    last_us: u32,
+    data_len: usize,
+}
+
+impl<'a> SdoClient<'a> {
+    /// Creates a client for the default SDO server of a node, `buffer` holds the data of
+    /// downloads & receives the data of uploads
+    pub fn new(
+        server_node_id: u8,
+        timeout_us: u32,
+        buffer: &'a mut [u8],
+    ) -> Result<Self, CanOpenError> {
+        if !valid_node_id(server_node_id) {
+            return Err(CanOpenError::InvalidNodeId);
+        }
+
+        Ok(Self {
+            server_node_id,
+            timeout_us,
+            buffer,
+            state: ClientState::Idle,
+            last_us: 0,
+            data_len: 0,
+        })
+    }
+
+    pub fn is_busy(&self) -> bool {
+        !matches!(self.state, ClientState::Idle)
+    }
+
+    /// The data of the last completed upload
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[..self.data_len]
+    }
+
+    /// Starts writing `data` to an entry of the server
+    pub fn download<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        index: u16,
+        subindex: u8,
+        data: &[u8],
+        mode: SdoTransferMode,
+        now_us: u32,
+    ) -> Result<(), CanOpenError> {
+        if self.is_busy() {
+            return Err(CanOpenError::Busy);
+        }
+
+        if data.len() > self.buffer.len() {
+            return Err(CanOpenError::NoSpace);
+        }
+
+        self.buffer[..data.len()].copy_from_slice(data);
+        self.data_len = 0;
+        self.last_us = now_us;
+
+        let len = data.len();
+        let size = (len as u32).to_le_bytes();
+
+        match mode {
+            SdoTransferMode::Normal if len > 0 && len <= 4 => {
+                let mut expedited = [0_u8; 4];
+                expedited[..len].copy_from_slice(data);
+
+                let command = (CCS_INITIATE_DOWNLOAD << 5) | (((4 - len) as u8) << 2) | 0b11;
+                self.send(can, initiate_frame(command, index, subindex, expedited))?;
+
+                self.state = ClientState::DownloadInitiate {
+                    index,
+                    subindex,
+                    len,
+                    expedited: true,
+                };
+            }
+            SdoTransferMode::Normal => {
+                let command = (CCS_INITIATE_DOWNLOAD << 5) | 0b01;
+                self.send(can, initiate_frame(command, index, subindex, size))?;
+
+                self.state = ClientState::DownloadInitiate {
+                    index,
+                    subindex,
+                    len,
+                    expedited: false,
+                };
+            }
+            SdoTransferMode::Block => {
+                let command = (CCS_BLOCK_DOWNLOAD << 5) | 0b100 | 0b10 | BLOCK_INITIATE;
+                self.send(can, initiate_frame(command, index, subindex, size))?;
+
+                self.state = ClientState::BlockDownloadInitiate {
+                    index,
+                    subindex,
+                    len,
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts reading an entry of the server, the value ends up in `data()`
+    pub fn upload<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        index: u16,
+        subindex: u8,
+        mode: SdoTransferMode,
+        now_us: u32,
+    ) -> Result<(), CanOpenError> {
+        if self.is_busy() {
+            return Err(CanOpenError::Busy);
+        }
+
+        self.data_len = 0;
+        self.last_us = now_us;
+
+        match mode {
+            SdoTransferMode::Normal => {
+                self.send(
+                    can,
+                    initiate_frame(CCS_INITIATE_UPLOAD << 5, index, subindex, [0; 4]),
+                )?;
+                self.state = ClientState::UploadInitiate { index, subindex };
+            }
+            SdoTransferMode::Block => {
+                let command = (CCS_BLOCK_UPLOAD << 5) | 0b100 | BLOCK_INITIATE;
+                let block_size = SDO_BLOCK_SIZE.min((self.buffer.len() / 7).clamp(1, 127) as u8);
+                self.send(
+                    can,
+                    initiate_frame(command, index, subindex, [block_size, 0, 0, 0]),
+                )?;
+                self.state = ClientState::BlockUploadInitiate { index, subindex };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Aborts the transfer in progress
+    pub fn abort<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        code: SdoAbortCode,
+    ) -> Result<Option<SdoEvent>, CanOpenError> {
+        if let ClientState::Idle = self.state {
+            return Ok(None);
+        }
+
+        let (index, subindex) = self.state.index();
+        self.state = ClientState::Idle;
+        self.send(can, abort_frame(index, subindex, code))?;
+
+        Ok(Some(SdoEvent::Aborted {
+            index,
+            subindex,
+            code,
+        }))
+    }
+
+    /// Checks the transfer timeout & sends the block download segments that are due
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<Option<SdoEvent>, CanOpenError> {
+        if let ClientState::Idle = self.state {
+            return Ok(None);
+        }
+
+        if now_us.wrapping_sub(self.last_us) > self.timeout_us {
+            return self.abort(can, SdoAbortCode::Timeout);
+        }
+
+        self.send_download_segments(can)?;
+
+        Ok(None)
+    }
+
+    /// Feeds a received frame in, only responses of our server are handled
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<SdoEvent>, CanOpenError> {
+        let mut data = [0_u8; 8];
+        match sdo_frame_data(frame, COB_ID_SDO_TX + self.server_node_id as u32) {
+            Some(frame_data) => data.copy_from_slice(frame_data),
+            None => return Ok(None),
+        }
+
+        if let ClientState::Idle = self.state {
+            return Ok(None);
+        }
+
+        self.last_us = now_us;
+
+        let receiving_segments = matches!(self.state, ClientState::BlockUploadReceiving { .. });
+
+        if is_abort(&data, receiving_segments) {
+            let (index, subindex) = self.state.index();
+            self.state = ClientState::Idle;
+
+            return Ok(Some(SdoEvent::Aborted {
+                index,
+                subindex,
+                code: SdoAbortCode::from_u32(frame_u32(&data)),
+            }));
+        }
+
+        match self.handle_response(can, &data) {
+            Ok(event) => Ok(event),
+            Err(code) => self.abort(can, code),
+        }
+    }
+
+    fn handle_response<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        data: &[u8; 8],
+    ) -> Result<Option<SdoEvent>, SdoAbortCode> {
+        let scs = data[0] >> 5;
+
+        match self.state {
+            // The server only answers once the whole sub-block is sent
+            ClientState::Idle | ClientState::BlockDownloadSending { .. } => Ok(None),
+            ClientState::DownloadInitiate {
+                index,
+                subindex,
+                len,
+                expedited,
+            } => {
+                if scs != SCS_INITIATE_DOWNLOAD {
+                    return Err(SdoAbortCode::InvalidCommand);
+                }
+
+                if expedited {
+                    self.state = ClientState::Idle;
+                    return Ok(Some(SdoEvent::DownloadComplete { index, subindex }));
+                }
+
+                self.send_download_segment(can, index, subindex, len, 0, false)
+            }
+            ClientState::DownloadSegment {
+                index,
+                subindex,
+                len,
+                sent,
+                toggle,
+            } => {
+                if scs != SCS_DOWNLOAD_SEGMENT {
+                    return Err(SdoAbortCode::InvalidCommand);
+                }
+
+                if (data[0] & 0x10 != 0) != toggle {
+                    return Err(SdoAbortCode::ToggleBitNotAlternated);
+                }
+
+                if sent == len {
+                    self.state = ClientState::Idle;
+                    return Ok(Some(SdoEvent::DownloadComplete { index, subindex }));
+                }
+
+                self.send_download_segment(can, index, subindex, len, sent, !toggle)
+            }
+            ClientState::UploadInitiate { index, subindex } => {
+                if scs != SCS_INITIATE_UPLOAD {
+                    return Err(SdoAbortCode::InvalidCommand);
+                }
+
+                let expedited = data[0] & 0b10 != 0;
+                let size_indicated = data[0] & 0b01 != 0;
+
+                if expedited {
+                    let len = if size_indicated {
+                        4 - ((data[0] >> 2) & 0b11) as usize
+                    } else {
+                        4
+                    };
+
+                    if len > self.buffer.len() {
+                        return Err(SdoAbortCode::OutOfMemory);
+                    }
+
+                    self.buffer[..len].copy_from_slice(&data[4..4 + len]);
+                    self.data_len = len;
+                    self.state = ClientState::Idle;
+
+                    return Ok(Some(SdoEvent::UploadComplete {
+                        index,
+                        subindex,
+                        len,
+                    }));
+                }
+
+                if size_indicated && frame_u32(data) as usize > self.buffer.len() {
+                    return Err(SdoAbortCode::OutOfMemory);
+                }
+
+                self.send_response(can, segment_frame(CCS_UPLOAD_SEGMENT << 5, &[]))?;
+                self.state = ClientState::UploadSegment {
+                    index,
+                    subindex,
+                    received: 0,
+                    toggle: false,
+                };
+
+                Ok(None)
+            }
+            ClientState::UploadSegment {
+                index,
+                subindex,
+                received,
+                toggle,
+            } => {
+                if scs != SCS_UPLOAD_SEGMENT {
+                    return Err(SdoAbortCode::InvalidCommand);
+                }
+
+                if (data[0] & 0x10 != 0) != toggle {
+                    return Err(SdoAbortCode::ToggleBitNotAlternated);
+                }
+
+                let segment_len = 7 - ((data[0] >> 1) & 0b111) as usize;
+                let last = data[0] & 0b1 != 0;
+
+                if received + segment_len > self.buffer.len() {
+                    return Err(SdoAbortCode::OutOfMemory);
+                }
+
+                self.buffer[received..received + segment_len]
+                    .copy_from_slice(&data[1..1 + segment_len]);
+                let received = received + segment_len;
+
+                if last {
+                    self.data_len = received;
+                    self.state = ClientState::Idle;
+
+                    return Ok(Some(SdoEvent::UploadComplete {
+                        index,
+                        subindex,
+                        len: received,
+                    }));
+                }
+
+                let command = (CCS_UPLOAD_SEGMENT << 5) | ((!toggle as u8) << 4);
+                self.send_response(can, segment_frame(command, &[]))?;
+                self.state = ClientState::UploadSegment {
+                    index,
+                    subindex,
+                    received,
+                    toggle: !toggle,
+                };
+
+                Ok(None)
+            }
+            ClientState::BlockDownloadInitiate {
+                index,
+                subindex,
+                len,
+            } => {
+                if scs != SCS_BLOCK_DOWNLOAD || data[0] & 0b11 != BLOCK_INITIATE {
+                    return Err(SdoAbortCode::InvalidCommand);
+                }
+
+                let block_size = data[4];
+                if block_size == 0 || block_size > SDO_BLOCK_SIZE {
+                    return Err(SdoAbortCode::InvalidBlockSize);
+                }
+
+                self.state = ClientState::BlockDownloadSending {
+                    index,
+                    subindex,
+                    len,
+                    crc: data[0] & 0b100 != 0,
+                    block_size,
+                    block_start: 0,
+                    next_seq: 1,
+                };
+
+                self.send_download_segments(can)
+                    .map_err(|_| SdoAbortCode::GeneralError)?;
+
+                Ok(None)
+            }
+            ClientState::BlockDownloadAck {
+                index,
+                subindex,
+                len,
+                crc,
+                block_start,
+            } => {
+                if scs != SCS_BLOCK_DOWNLOAD || data[0] & 0b11 != BLOCK_ACK {
+                    return Err(SdoAbortCode::InvalidCommand);
+                }
+
+                let ack_seq = data[1];
+                let block_size = data[2];
+
+                if block_size == 0 || block_size > SDO_BLOCK_SIZE {
+                    return Err(SdoAbortCode::InvalidBlockSize);
+                }
+
+                let block_start = block_start + ack_seq as usize * 7;
+
+                if block_start >= len && ack_seq > 0 {
+                    let crc = if crc { crc16(&self.buffer[..len]) } else { 0 };
+                    let command =
+                        (CCS_BLOCK_DOWNLOAD << 5) | (unused_in_last_segment(len) << 2) | BLOCK_END;
+                    self.send_response(can, segment_frame(command, &crc.to_le_bytes()))?;
+
+                    self.state = ClientState::BlockDownloadEnd { index, subindex };
+                } else {
+                    self.state = ClientState::BlockDownloadSending {
+                        index,
+                        subindex,
+                        len,
+                        crc,
+                        block_size,
+                        block_start,
+                        next_seq: 1,
+                    };
+
+                    self.send_download_segments(can)
+                        .map_err(|_| SdoAbortCode::GeneralError)?;
+                }
+
+                Ok(None)
+            }
+            ClientState::BlockDownloadEnd { index, subindex } => {
+                if scs != SCS_BLOCK_DOWNLOAD || data[0] & 0b11 != BLOCK_END {
+                    return Err(SdoAbortCode::InvalidCommand);
+                }
+
+                self.state = ClientState::Idle;
+                Ok(Some(SdoEvent::DownloadComplete { index, subindex }))
+            }
+            ClientState::BlockUploadInitiate { index, subindex } => {
+                if scs != SCS_BLOCK_UPLOAD || data[0] & 0b1 != BLOCK_INITIATE {
+                    return Err(SdoAbortCode::InvalidCommand);
+                }
+
+                if data[0] & 0b10 != 0 && frame_u32(data) as usize > self.buffer.len() {
+                    return Err(SdoAbortCode::OutOfMemory);
+                }
+
+                let command = (CCS_BLOCK_UPLOAD << 5) | BLOCK_START;
+                self.send_response(can, segment_frame(command, &[]))?;
+
+                self.state = ClientState::BlockUploadReceiving {
+                    index,
+                    subindex,
+                    crc: data[0] & 0b100 != 0,
+                    received: 0,
+                    next_seq: 1,
+                    last: false,
+                };
+
+                Ok(None)
+            }
+            ClientState::BlockUploadReceiving {
+                index,
+                subindex,
+                crc,
+                mut received,
+                mut next_seq,
+                mut last,
+            } => {
+                let block_size = SDO_BLOCK_SIZE.min((self.buffer.len() / 7).clamp(1, 127) as u8);
+                let ack_seq = receive_block_segment(
+                    data,
+                    self.buffer,
+                    &mut received,
+                    &mut next_seq,
+                    &mut last,
+                    block_size,
+                );
+
+                self.state = ClientState::BlockUploadReceiving {
+                    index,
+                    subindex,
+                    crc,
+                    received,
+                    next_seq,
+                    last,
+                };
+
+                if let Some(ack_seq) = ack_seq {
+                    let command = (CCS_BLOCK_UPLOAD << 5) | BLOCK_ACK;
+                    self.send_response(can, segment_frame(command, &[ack_seq, block_size]))?;
+
+                    if last {
+                        self.state = ClientState::BlockUploadEnd {
+                            index,
+                            subindex,
+                            crc,
+                            received,
+                        };
+                    }
+                }
+
+                Ok(None)
+            }
+            ClientState::BlockUploadEnd {
+                index,
+                subindex,
+                crc,
+                received,
+            } => {
+                if scs != SCS_BLOCK_UPLOAD || data[0] & 0b1 != BLOCK_END {
+                    return Err(SdoAbortCode::InvalidCommand);
+                }
+
+                let unused = ((data[0] >> 2) & 0b111) as usize;
+                let len = received.saturating_sub(unused);
+
+                if len > self.buffer.len() {
+                    return Err(SdoAbortCode::OutOfMemory);
+                }
+
+                if crc && crc16(&self.buffer[..len]) != u16::from_le_bytes([data[1], data[2]]) {
+                    return Err(SdoAbortCode::CrcError);
+                }
+
+                let command = (CCS_BLOCK_UPLOAD << 5) | BLOCK_END;
+                self.send_response(can, segment_frame(command, &[]))?;
+
+                self.data_len = len;
+                self.state = ClientState::Idle;
+
+                Ok(Some(SdoEvent::UploadComplete {
+                    index,
+                    subindex,
+                    len,
+                }))
+            }
+        }
+    }
+
+    fn send_download_segment<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        index: u16,
+        subindex: u8,
+        len: usize,
+        sent: usize,
+        toggle: bool,
+    ) -> Result<Option<SdoEvent>, SdoAbortCode> {
+        let segment_len = (len - sent).min(7);
+        let last = sent + segment_len == len;
+
+        let command = (CCS_DOWNLOAD_SEGMENT << 5)
+            | ((toggle as u8) << 4)
+            | (((7 - segment_len) as u8) << 1)
+            | last as u8;
+        let frame = segment_frame(command, &self.buffer[sent..sent + segment_len]);
+        self.send_response(can, frame)?;
+
+        self.state = ClientState::DownloadSegment {
+            index,
+            subindex,
+            len,
+            sent: sent + segment_len,
+            toggle,
+        };
+
+        Ok(None)
+    }
+
+    fn send_download_segments<T: Transmitter>(&mut self, can: &mut T) -> Result<(), CanOpenError> {
+        if let ClientState::BlockDownloadSending {
+            index,
+            subindex,
+            len,
+            crc,
+            block_size,
+            block_start,
+            mut next_seq,
+        } = self.state
+        {
+            let done = send_block_segments(
+                can,
+                COB_ID_SDO_RX + self.server_node_id as u32,
+                &self.buffer[..len],
+                block_start,
+                &mut next_seq,
+                block_size,
+            )?;
+
+            self.state = if done {
+                ClientState::BlockDownloadAck {
+                    index,
+                    subindex,
+                    len,
+                    crc,
+                    block_start,
+                }
+            } else {
+                ClientState::BlockDownloadSending {
+                    index,
+                    subindex,
+                    len,
+                    crc,
+                    block_size,
+                    block_start,
+                    next_seq,
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    fn send<T: Transmitter>(&self, can: &mut T, frame: [u8; 8]) -> Result<(), CanOpenError> {
+        transmit(can, COB_ID_SDO_RX + self.server_node_id as u32, &frame)
+    }
+
+    fn send_response<T: Transmitter>(
+        &self,
+        can: &mut T,
+        frame: [u8; 8],
+    ) -> Result<(), SdoAbortCode> {
+        self.send(can, frame)
+            .map_err(|_| SdoAbortCode::GeneralError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canopen::od::{AccessType, DataType, OdEntry};
+    use crate::mock::Bus;
+
+    const NODE_ID: u8 = 5;
+    const TIMEOUT_US: u32 = 1_000_000;
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    // Passes frames between the client & the server until the client is done, skipping the
+    // `drop`th frame sent. Returns the events & how many frames went over the bus.
+    fn exchange(
+        bus: &mut Bus,
+        client: &mut SdoClient,
+        server: &mut SdoServer,
+        od: &mut ObjectDictionary,
+        drop: Option<usize>,
+    ) -> (Vec<SdoEvent>, usize) {
+        let mut events = Vec::new();
+        let mut count = 0;
+
+        for _ in 0..10_000 {
+            for frame in bus.take() {
+                count += 1;
+
+                if Some(count) == drop {
+                    continue;
+                }
+
+                let frame = frame.to_rx();
+                events.extend(server.handle_frame(bus, od, &frame, 0).unwrap());
+                events.extend(client.handle_frame(bus, &frame, 0).unwrap());
+            }
+
+            events.extend(server.poll(bus, 0).unwrap());
+            events.extend(client.poll(bus, 0).unwrap());
+
+            if !client.is_busy() && bus.sent.is_empty() {
+                return (events, count);
+            }
+        }
+
+        panic!("transfer stalled");
+    }
+
+    // Downloads then uploads `data` through a domain entry, or the u32 one if it's 4 bytes. `drop`
+    // loses a frame of the download.
+    fn round_trip(data: &[u8], mode: SdoTransferMode, drop: Option<usize>) -> usize {
+        let mut value = [0; 4];
+        let mut domain = [0; 3000];
+        let mut entries = [
+            OdEntry::new(
+                0x2000,
+                0,
+                DataType::Unsigned32,
+                AccessType::ReadWrite,
+                &mut value,
+            ),
+            OdEntry::new(
+                0x2001,
+                0,
+                DataType::Domain,
+                AccessType::ReadWrite,
+                &mut domain,
+            ),
+        ];
+        let mut od = ObjectDictionary::new(&mut entries);
+        let (mut server_buffer, mut client_buffer) = ([0; 3000], [0; 3000]);
+        let mut server = SdoServer::new(NODE_ID, TIMEOUT_US, &mut server_buffer).unwrap();
+        let mut client = SdoClient::new(NODE_ID, TIMEOUT_US, &mut client_buffer).unwrap();
+        let mut bus = Bus::default();
+        let index = if data.len() == 4 { 0x2000 } else { 0x2001 };
+
+        client.download(&mut bus, index, 0, data, mode, 0).unwrap();
+        let (events, download_frames) = exchange(&mut bus, &mut client, &mut server, &mut od, drop);
+
+        let complete = SdoEvent::DownloadComplete { index, subindex: 0 };
+        assert_eq!(events, [complete, complete]);
+        assert_eq!(od.read(index, 0).unwrap(), data);
+
+        client.upload(&mut bus, index, 0, mode, 0).unwrap();
+        let (events, upload_frames) = exchange(&mut bus, &mut client, &mut server, &mut od, None);
+
+        let complete = SdoEvent::UploadComplete {
+            index,
+            subindex: 0,
+            len: data.len(),
+        };
+        assert_eq!(events, [complete, complete]);
+        assert_eq!(client.data(), data);
+        assert!(!server.is_busy());
+
+        download_frames + upload_frames
+    }
+
+    #[test]
+    fn expedited_transfers() {
+        for len in 1..=4 {
+            // An initiate frame & its response each way
+            assert_eq!(round_trip(&message(len), SdoTransferMode::Normal, None), 4);
+        }
+    }
+
+    #[test]
+    fn segmented_transfers() {
+        for len in [0_usize, 5, 7, 8, 13, 14, 15, 100, 889, 2000] {
+            let segments = len.div_ceil(7).max(1);
+            let frames = round_trip(&message(len), SdoTransferMode::Normal, None);
+
+            // Initiate & segment frames and their responses, in both directions
+            assert_eq!(frames, 2 * 2 * (1 + segments), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn block_transfers() {
+        for len in [0, 1, 4, 7, 8, 100, 889, 890, 2000] {
+            round_trip(&message(len), SdoTransferMode::Block, None);
+        }
+    }
+
+    #[test]
+    fn block_download_recovers_a_lost_segment() {
+        // Frame 3 onwards are segments of the first block, its ack asks for them again
+        for drop in 3..10 {
+            round_trip(&message(500), SdoTransferMode::Block, Some(drop));
+        }
+    }
+
+    #[test]
+    fn aborts() {
+        let mut value = [0; 4];
+        let mut read_only = [7, 0];
+        let mut entries = [
+            OdEntry::new(
+                0x2000,
+                0,
+                DataType::Unsigned32,
+                AccessType::ReadWrite,
+                &mut value,
+            ),
+            OdEntry::new(
+                0x2002,
+                0,
+                DataType::Unsigned16,
+                AccessType::ReadOnly,
+                &mut read_only,
+            ),
+        ];
+        let mut od = ObjectDictionary::new(&mut entries);
+        let (mut server_buffer, mut client_buffer) = ([0; 64], [0; 64]);
+        let mut server = SdoServer::new(NODE_ID, TIMEOUT_US, &mut server_buffer).unwrap();
+        let mut client = SdoClient::new(NODE_ID, TIMEOUT_US, &mut client_buffer).unwrap();
+        let mut bus = Bus::default();
+
+        let requests: [(u16, u8, &[u8], SdoTransferMode, SdoAbortCode); 5] = [
+            (
+                0x2002,
+                0,
+                &[1, 2],
+                SdoTransferMode::Normal,
+                SdoAbortCode::ReadOnly,
+            ),
+            (
+                0x2000,
+                0,
+                &[1, 2],
+                SdoTransferMode::Normal,
+                SdoAbortCode::LengthTooLow,
+            ),
+            (
+                0x2000,
+                0,
+                &[0; 9],
+                SdoTransferMode::Block,
+                SdoAbortCode::LengthTooHigh,
+            ),
+            (
+                0x2000,
+                1,
+                &[1],
+                SdoTransferMode::Normal,
+                SdoAbortCode::SubIndexDoesNotExist,
+            ),
+            (
+                0x3000,
+                0,
+                &[1],
+                SdoTransferMode::Block,
+                SdoAbortCode::ObjectDoesNotExist,
+            ),
+        ];
+
+        for (index, subindex, data, mode, code) in requests.iter().copied() {
+            client
+                .download(&mut bus, index, subindex, data, mode, 0)
+                .unwrap();
+            let (events, _) = exchange(&mut bus, &mut client, &mut server, &mut od, None);
+
+            // The server reports what it sent & the client what it got
+            let aborted = SdoEvent::Aborted {
+                index,
+                subindex,
+                code,
+            };
+            assert_eq!(events, [aborted, aborted]);
+        }
+
+        assert_eq!(od.read_u32(0x2000, 0), Ok(0));
+
+        // The client giving up part way through a segmented upload reaches the server
+        od.write_u32(0x2000, 0, 0x1234_5678).unwrap();
+        client
+            .upload(&mut bus, 0x2002, 0, SdoTransferMode::Normal, 0)
+            .unwrap();
+        assert_eq!(
+            client.abort(&mut bus, SdoAbortCode::GeneralError).unwrap(),
+            Some(SdoEvent::Aborted {
+                index: 0x2002,
+                subindex: 0,
+                code: SdoAbortCode::GeneralError,
+            })
+        );
+
+        let frame = bus.take().pop().unwrap();
+        assert_eq!(frame.id, Id::Standard(COB_ID_SDO_RX + NODE_ID as u32));
+        assert_eq!(frame.data[0], CS_ABORT << 5);
+        assert_eq!(frame.data[4..], 0x0800_0000_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut value = [0; 4];
+        let mut entries = [OdEntry::new(
+            0x2000,
+            0,
+            DataType::Unsigned32,
+            AccessType::ReadWrite,
+            &mut value,
+        )];
+        let mut od = ObjectDictionary::new(&mut entries);
+        let (mut server_buffer, mut client_buffer) = ([0; 64], [0; 64]);
+        let mut server = SdoServer::new(NODE_ID, TIMEOUT_US, &mut server_buffer).unwrap();
+        let mut client = SdoClient::new(NODE_ID, TIMEOUT_US, &mut client_buffer).unwrap();
+        let mut bus = Bus::default();
+        let timed_out = Some(SdoEvent::Aborted {
+            index: 0x2000,
+            subindex: 0,
+            code: SdoAbortCode::Timeout,
+        });
+
+        // No response from the server
+        client
+            .upload(&mut bus, 0x2000, 0, SdoTransferMode::Normal, 0)
+            .unwrap();
+        bus.take();
+        assert_eq!(client.poll(&mut bus, TIMEOUT_US).unwrap(), None);
+        assert_eq!(client.poll(&mut bus, TIMEOUT_US + 1).unwrap(), timed_out);
+        assert!(!client.is_busy());
+
+        // No segments from the client after the server accepted a block download
+        client
+            .download(
+                &mut bus,
+                0x2000,
+                0,
+                &[1, 2, 3, 4],
+                SdoTransferMode::Block,
+                0,
+            )
+            .unwrap();
+        let request = bus.take().pop().unwrap();
+        server
+            .handle_frame(&mut bus, &mut od, &request.to_rx(), 0)
+            .unwrap();
+        assert!(server.is_busy());
+        bus.take();
+
+        assert_eq!(server.poll(&mut bus, TIMEOUT_US + 1).unwrap(), timed_out);
+        assert!(!server.is_busy());
+        assert_eq!(bus.take().pop().unwrap().data[0], CS_ABORT << 5);
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(&[]), 0);
+    }
+}
@@ -3,6 +3,7 @@
 //! Author: David Allen (hbddallen@gmail.com)
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Clock {
     Clock8Mhz,
     Clock16Mhz,
@@ -56,6 +57,7 @@ impl Clock {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct TimingConfig {
     pub prescalar_division: u32,
     pub prop_seg: u8,
@@ -64,7 +66,79 @@ pub struct TimingConfig {
     pub jump_width: u8,
 }
 
+impl TimingConfig {
+    /// The bitrate these timings give with the given clock, clamped the same way as when they
+    /// get written to the CBT/FDCBT registers
+    pub fn bitrate(&self, clock_speed: Clock) -> u32 {
+        let div = self.prescalar_division.clamp(1, 1023);
+        let time_quanta = 1
+            + self.prop_seg.clamp(1, 63) as u32
+            + self.phase_seg_1.clamp(1, 31) as u32
+            + self.phase_seg_2.clamp(1, 31) as u32;
+
+        clock_speed.to_hz() / (div * time_quanta)
+    }
+
+    /// Timings giving exactly `bitrate` with the given clock, with as many time quanta as fit and
+    /// the sample point near 80% (75% in the data phase, which has shorter phase segments). None
+    /// if the clock doesn't divide into that bitrate.
+    pub fn from_bitrate(clock_speed: Clock, bitrate: u32, data_phase: bool) -> Option<Self> {
+        let (max_prop_seg, max_phase_seg, sample_percent) = if data_phase {
+            (31, 8, 75)
+        } else {
+            (63, 31, 80)
+        };
+
+        if bitrate == 0 {
+            return None;
+        }
+
+        for div in 1..=1023 {
+            let clocks_per_bit = div * bitrate as u64;
+            let clock = clock_speed.to_hz() as u64;
+
+            if clocks_per_bit > clock {
+                break;
+            }
+
+            let time_quanta = clock / clocks_per_bit;
+
+            if !clock.is_multiple_of(clocks_per_bit) || time_quanta > 1 + max_prop_seg + 2 * max_phase_seg {
+                continue;
+            }
+
+            // Sync segment, at least 1 for each of the others & 2 for phase segment 2
+            if time_quanta < 5 {
+                break;
+            }
+
+            let phase_seg_2 =
+                ((time_quanta * (100 - sample_percent) + 50) / 100).clamp(2, max_phase_seg);
+            let before_sample = time_quanta - 1 - phase_seg_2;
+            let phase_seg_1 = (before_sample / 2)
+                .max(before_sample.saturating_sub(max_prop_seg))
+                .clamp(1, max_phase_seg);
+            let prop_seg = before_sample - phase_seg_1;
+
+            if prop_seg < 1 || prop_seg > max_prop_seg {
+                continue;
+            }
+
+            return Some(Self {
+                prescalar_division: div as u32,
+                prop_seg: prop_seg as u8,
+                phase_seg_1: phase_seg_1 as u8,
+                phase_seg_2: phase_seg_2 as u8,
+                jump_width: phase_seg_2 as u8,
+            });
+        }
+
+        None
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Config {
     pub clock_speed: Clock,
     pub timing_classical: TimingConfig,
@@ -75,6 +149,7 @@ pub struct Config {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum RegionConfig {
     MB8 {
         mailbox_configs: [MailboxConfig; 32],
@@ -91,6 +166,7 @@ pub enum RegionConfig {
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MailboxConfig {
     Unconfigured,
     Rx { rx_config: RxMailboxConfig },
@@ -104,12 +180,14 @@ impl Default for MailboxConfig {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Id {
     Standard(u32),
     Extended(u32),
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct RxMailboxConfig {
     pub id: Id,       // The ID to match incoming messages with
     pub id_mask: u32, // A bitmask used to compared the incoming ID, 0 is don't care, 1 is match
@@ -161,3 +239,48 @@ impl RegionConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The CBT/FDCBT field values the timings get written as: prescaler, propagation segment,
+    /// phase segments 1 & 2 and the jump width, each one less than the timing
+    fn register_fields(timing: &TimingConfig) -> [u32; 5] {
+        [
+            timing.prescalar_division - 1,
+            timing.prop_seg as u32 - 1,
+            timing.phase_seg_1 as u32 - 1,
+            timing.phase_seg_2 as u32 - 1,
+            timing.jump_width as u32 - 1,
+        ]
+    }
+
+    #[test]
+    fn from_bitrate() {
+        let cases = [
+            // 80 tq, sampling at 80%
+            (Clock::Clock80Mhz, 500_000, false, [1, 31, 30, 15, 15]),
+            (Clock::Clock80Mhz, 1_000_000, false, [0, 31, 30, 15, 15]),
+            // 40 tq, the phase segments are capped at 8 in the data phase
+            (Clock::Clock80Mhz, 2_000_000, true, [0, 22, 7, 7, 7]),
+            (Clock::Clock80Mhz, 5_000_000, true, [0, 5, 4, 3, 3]),
+            (Clock::Clock80Mhz, 8_000_000, true, [0, 2, 2, 2, 2]),
+            (Clock::Clock24Mhz, 500_000, false, [0, 18, 17, 9, 9]),
+            (Clock::Clock24Mhz, 1_000_000, false, [0, 8, 8, 4, 4]),
+            (Clock::Clock24Mhz, 2_000_000, true, [0, 3, 3, 2, 2]),
+        ];
+
+        for (clock, bitrate, data_phase, fields) in cases.iter() {
+            let timing = TimingConfig::from_bitrate(*clock, *bitrate, *data_phase).unwrap();
+
+            assert_eq!(register_fields(&timing), *fields, "{:?} {}", clock, bitrate);
+            assert_eq!(timing.bitrate(*clock), *bitrate);
+        }
+
+        // 24 MHz doesn't divide into 5 Mbit/s, or into enough time quanta for 8 Mbit/s
+        assert!(TimingConfig::from_bitrate(Clock::Clock24Mhz, 5_000_000, true).is_none());
+        assert!(TimingConfig::from_bitrate(Clock::Clock24Mhz, 8_000_000, true).is_none());
+        assert!(TimingConfig::from_bitrate(Clock::Clock80Mhz, 0, false).is_none());
+    }
+}
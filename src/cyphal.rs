@@ -0,0 +1,1067 @@
+//! Cyphal/CAN (UAVCAN v1) transport over CAN FD
+//!
+//! Everything Cyphal needs from the CAN ID (priority, message or service, subject or service ID
+//! and the node IDs) is packed into the 29-bit ID, each frame ends with a tail byte carrying the
+//! start/end of transfer flags, a toggle bit & the 5-bit transfer-ID. Transfers that don't fit in
+//! one frame are split over 64 byte FD frames, with a CRC over the whole payload at the end.
+//!
+//! Subscriptions are made per subject or service with a buffer for reassembling multi-frame
+//! transfers, its length is the extent: longer transfers are truncated to it. A subscription
+//! reassembles one multi-frame transfer at a time, a multi-frame transfer from another source
+//! starting before it's done is dropped. Duplicates, such as the same transfer received over
+//! redundant interfaces, are dropped per source with the transfer-ID timeout.
+
+use crate::can_error::RxTxError;
+use crate::config::{Id, RxMailboxConfig};
+use crate::util::{dlc_to_len, len_to_dlc};
+use crate::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+
+pub const MTU_CAN_FD: usize = 64;
+pub const MAX_SUBSCRIPTIONS: usize = 8;
+pub const MAX_SESSIONS: usize = 8; // Sources tracked per subscription for dropping duplicates
+pub const DEFAULT_TRANSFER_ID_TIMEOUT_US: u32 = 2_000_000;
+
+pub const NODE_ID_MAX: u8 = 127;
+pub const SUBJECT_ID_MAX: u16 = 8191;
+pub const SERVICE_ID_MAX: u16 = 511;
+
+pub const PRIORITY_EXCEPTIONAL: u8 = 0;
+pub const PRIORITY_NOMINAL: u8 = 4;
+pub const PRIORITY_OPTIONAL: u8 = 7;
+
+pub const TRANSFER_CRC_INIT: u16 = 0xFFFF;
+const FRAME_PAYLOAD_LEN: usize = MTU_CAN_FD - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CyphalError {
+    InvalidNodeId,  // Over 127
+    InvalidPortId,  // Over the subject or service ID range
+    Anonymous,      // Anonymous nodes can only publish single-frame messages
+    Busy,           // A transfer is already being sent
+    NoSpace,        // All subscriptions are taken
+    BufferTooSmall, // The transfer doesn't fit in the Tx buffer
+    CrcMismatch,    // A multi-frame transfer arrived with a bad transfer CRC & was dropped
+    Bus(RxTxError), // The driver refused a frame
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferKind {
+    Message,
+    Request,
+    Response,
+}
+
+/// The fields packed into a Cyphal 29-bit ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CyphalId {
+    /// 0 - 7, 0 is the highest
+    pub priority: u8,
+    pub kind: TransferKind,
+    /// Subject ID for messages, service ID otherwise
+    pub port_id: u16,
+    /// None for anonymous messages
+    pub source_node_id: Option<u8>,
+    /// Only carried by services
+    pub destination_node_id: Option<u8>,
+}
+
+impl CyphalId {
+    /// Anonymous messages get the low bits of the ID from `pseudo_node_id`, it should differ
+    /// between nodes sending at the same time
+    pub fn to_id(&self, pseudo_node_id: u8) -> Id {
+        let priority = (self.priority.min(7) as u32) << 26;
+
+        Id::Extended(match self.kind {
+            TransferKind::Message => {
+                let (anonymous, source) = match self.source_node_id {
+                    Some(node_id) => (0, node_id),
+                    None => (1, pseudo_node_id),
+                };
+
+                priority
+                    | (anonymous << 24)
+                    | (0b11 << 21)
+                    | ((self.port_id as u32 & SUBJECT_ID_MAX as u32) << 8)
+                    | (source & NODE_ID_MAX) as u32
+            }
+            TransferKind::Request | TransferKind::Response => {
+                let request = (self.kind == TransferKind::Request) as u32;
+
+                priority
+                    | (1 << 25)
+                    | (request << 24)
+                    | ((self.port_id as u32 & SERVICE_ID_MAX as u32) << 14)
+                    | ((self.destination_node_id.unwrap_or(0) & NODE_ID_MAX) as u32) << 7
+                    | (self.source_node_id.unwrap_or(0) & NODE_ID_MAX) as u32
+            }
+        })
+    }
+
+    /// Decodes an extended ID, None for standard IDs & IDs with the reserved bits set
+    pub fn from_id(id: Id) -> Option<Self> {
+        let id = match id {
+            Id::Extended(id) => id,
+            Id::Standard(_) => return None,
+        };
+
+        if id & (1 << 23) != 0 {
+            return None;
+        }
+
+        let priority = ((id >> 26) & 0b111) as u8;
+        let source = (id & NODE_ID_MAX as u32) as u8;
+
+        if id & (1 << 25) == 0 {
+            if id & (1 << 7) != 0 {
+                return None;
+            }
+
+            Some(Self {
+                priority,
+                kind: TransferKind::Message,
+                port_id: ((id >> 8) & SUBJECT_ID_MAX as u32) as u16,
+                source_node_id: if id & (1 << 24) == 0 {
+                    Some(source)
+                } else {
+                    None
+                },
+                destination_node_id: None,
+            })
+        } else {
+            let destination = ((id >> 7) & NODE_ID_MAX as u32) as u8;
+
+            if destination == source {
+                return None;
+            }
+
+            Some(Self {
+                priority,
+                kind: if id & (1 << 24) != 0 {
+                    TransferKind::Request
+                } else {
+                    TransferKind::Response
+                },
+                port_id: ((id >> 14) & SERVICE_ID_MAX as u32) as u16,
+                source_node_id: Some(source),
+                destination_node_id: Some(destination),
+            })
+        }
+    }
+}
+
+/// Rx mailbox filter accepting a subject from any source
+pub fn subject_rx_config(subject_id: u16) -> RxMailboxConfig {
+    RxMailboxConfig {
+        id: Id::Extended((subject_id as u32 & SUBJECT_ID_MAX as u32) << 8),
+        id_mask: (1 << 25) | ((SUBJECT_ID_MAX as u32) << 8),
+    }
+}
+
+/// Rx mailbox filter accepting all requests & responses addressed to a node
+pub fn service_rx_config(node_id: u8) -> RxMailboxConfig {
+    RxMailboxConfig {
+        id: Id::Extended((1 << 25) | ((node_id & NODE_ID_MAX) as u32) << 7),
+        id_mask: (1 << 25) | ((NODE_ID_MAX as u32) << 7),
+    }
+}
+
+/// The last byte of every frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TailByte {
+    pub start_of_transfer: bool,
+    pub end_of_transfer: bool,
+    pub toggle: bool,    // Alternates every frame, starting with 1
+    pub transfer_id: u8, // 0 - 31
+}
+
+impl TailByte {
+    pub fn to_u8(self) -> u8 {
+        ((self.start_of_transfer as u8) << 7)
+            | ((self.end_of_transfer as u8) << 6)
+            | ((self.toggle as u8) << 5)
+            | (self.transfer_id & 0x1F)
+    }
+
+    pub fn from_u8(value: u8) -> Self {
+        Self {
+            start_of_transfer: value & 0x80 != 0,
+            end_of_transfer: value & 0x40 != 0,
+            toggle: value & 0x20 != 0,
+            transfer_id: value & 0x1F,
+        }
+    }
+}
+
+/// CRC-16/CCITT-FALSE used as the transfer CRC, start with `TRANSFER_CRC_INIT`. Running it over
+/// data followed by its own CRC (big endian) gives 0.
+pub fn transfer_crc(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// What's needed to send a transfer, for responses the transfer-ID is the one of the request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferMetadata {
+    pub priority: u8,
+    pub kind: TransferKind,
+    pub port_id: u16,
+    pub remote_node_id: Option<u8>, // The server or client of a service transfer
+    pub transfer_id: u8,            // Incremented per transfer on the port by the sender
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CyphalEvent {
+    Received {
+        priority: u8,
+        kind: TransferKind,
+        port_id: u16,
+        source_node_id: Option<u8>,
+        transfer_id: u8,
+        len: usize, // The payload is waiting in `received()`, with any padding
+    },
+    TransmitComplete {
+        kind: TransferKind,
+        port_id: u16,
+        transfer_id: u8,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Session {
+    active: bool,
+    source_node_id: u8,
+    transfer_id: u8,
+    last_us: u32, // When the last transfer from the source started
+}
+
+struct Subscription<'a> {
+    kind: TransferKind,
+    port_id: u16,
+    transfer_id_timeout_us: u32,
+    buffer: &'a mut [u8],
+    sessions: [Session; MAX_SESSIONS],
+
+    // The multi-frame transfer being reassembled
+    rx_active: bool,
+    rx_priority: u8,
+    rx_source_node_id: u8,
+    rx_transfer_id: u8,
+    rx_toggle: bool,
+    rx_len: usize, // Including the CRC & anything truncated
+    rx_crc: u16,
+    rx_last_us: u32,
+}
+
+impl<'a> Subscription<'a> {
+    /// Whether a transfer starting now is new, rather than a duplicate of one already received
+    fn accept(&mut self, source_node_id: u8, transfer_id: u8, now_us: u32) -> bool {
+        let timeout_us = self.transfer_id_timeout_us;
+        let position = self
+            .sessions
+            .iter()
+            .position(|session| session.active && session.source_node_id == source_node_id);
+
+        let position = match position {
+            Some(position) => {
+                let session = &self.sessions[position];
+
+                if session.transfer_id == transfer_id
+                    && now_us.wrapping_sub(session.last_us) < timeout_us
+                {
+                    return false;
+                }
+
+                position
+            }
+            None => self
+                .sessions
+                .iter()
+                .position(|session| !session.active)
+                .unwrap_or_else(|| {
+                    let mut oldest = 0;
+
+                    for (position, session) in self.sessions.iter().enumerate() {
+                        if now_us.wrapping_sub(session.last_us)
+                            > now_us.wrapping_sub(self.sessions[oldest].last_us)
+                        {
+                            oldest = position;
+                        }
+                    }
+
+                    oldest
+                }),
+        };
+
+        self.sessions[position] = Session {
+            active: true,
+            source_node_id,
+            transfer_id,
+            last_us: now_us,
+        };
+
+        true
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        if self.rx_len < self.buffer.len() {
+            let copied = data.len().min(self.buffer.len() - self.rx_len);
+            self.buffer[self.rx_len..self.rx_len + copied].copy_from_slice(&data[..copied]);
+        }
+
+        self.rx_len += data.len();
+        self.rx_crc = transfer_crc(self.rx_crc, data);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Received {
+    Nothing,
+    Frame { len: usize },
+    Subscription { index: usize, len: usize },
+}
+
+pub struct CyphalTransport<'a> {
+    node_id: Option<u8>,
+    subscriptions: [Option<Subscription<'a>>; MAX_SUBSCRIPTIONS],
+    frame_buffer: [u8; FRAME_PAYLOAD_LEN], // Single-frame transfers are received here
+    received: Received,
+
+    tx_buffer: &'a mut [u8],
+    tx_metadata: Option<TransferMetadata>,
+    tx_id: Id,
+    tx_len: usize,
+    tx_padding: usize,
+    tx_crc: u16,
+    tx_offset: usize, // Into the payload followed by the padding & CRC
+    tx_toggle: bool,
+}
+
+impl<'a> CyphalTransport<'a> {
+    /// Creates the transport, `node_id` is None for an anonymous node
+    pub fn new(node_id: Option<u8>, tx_buffer: &'a mut [u8]) -> Result<Self, CyphalError> {
+        if node_id.is_some_and(|node_id| node_id > NODE_ID_MAX) {
+            return Err(CyphalError::InvalidNodeId);
+        }
+
+        Ok(Self {
+            node_id,
+            subscriptions: Default::default(),
+            frame_buffer: [0; FRAME_PAYLOAD_LEN],
+            received: Received::Nothing,
+            tx_buffer,
+            tx_metadata: None,
+            tx_id: Id::Extended(0),
+            tx_len: 0,
+            tx_padding: 0,
+            tx_crc: 0,
+            tx_offset: 0,
+            tx_toggle: true,
+        })
+    }
+
+    pub fn node_id(&self) -> Option<u8> {
+        self.node_id
+    }
+
+    /// Changes the node ID, e.g. once one was allocated to an anonymous node
+    pub fn set_node_id(&mut self, node_id: Option<u8>) -> Result<(), CyphalError> {
+        if node_id.is_some_and(|node_id| node_id > NODE_ID_MAX) {
+            return Err(CyphalError::InvalidNodeId);
+        }
+
+        self.node_id = node_id;
+
+        Ok(())
+    }
+
+    pub fn is_transmitting(&self) -> bool {
+        self.tx_metadata.is_some()
+    }
+
+    /// Starts receiving a subject or service, transfers longer than `buffer` are truncated
+    pub fn subscribe(
+        &mut self,
+        kind: TransferKind,
+        port_id: u16,
+        buffer: &'a mut [u8],
+        transfer_id_timeout_us: u32,
+    ) -> Result<(), CyphalError> {
+        let max_port_id = match kind {
+            TransferKind::Message => SUBJECT_ID_MAX,
+            _ => SERVICE_ID_MAX,
+        };
+
+        if port_id > max_port_id {
+            return Err(CyphalError::InvalidPortId);
+        }
+
+        self.unsubscribe(kind, port_id);
+
+        let slot = self
+            .subscriptions
+            .iter_mut()
+            .find(|subscription| subscription.is_none())
+            .ok_or(CyphalError::NoSpace)?;
+
+        *slot = Some(Subscription {
+            kind,
+            port_id,
+            transfer_id_timeout_us,
+            buffer,
+            sessions: [Session::default(); MAX_SESSIONS],
+            rx_active: false,
+            rx_priority: 0,
+            rx_source_node_id: 0,
+            rx_transfer_id: 0,
+            rx_toggle: false,
+            rx_len: 0,
+            rx_crc: TRANSFER_CRC_INIT,
+            rx_last_us: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Stops receiving a subject or service, handing its buffer back
+    pub fn unsubscribe(&mut self, kind: TransferKind, port_id: u16) -> Option<&'a mut [u8]> {
+        let index = self.subscription_index(kind, port_id)?;
+
+        if let Received::Subscription {
+            index: received, ..
+        } = self.received
+        {
+            if received == index {
+                self.received = Received::Nothing;
+            }
+        }
+
+        self.subscriptions[index]
+            .take()
+            .map(|subscription| subscription.buffer)
+    }
+
+    /// The payload of the last transfer received, valid until the next frame is handled
+    pub fn received(&self) -> &[u8] {
+        match self.received {
+            Received::Nothing => &[],
+            Received::Frame { len } => &self.frame_buffer[..len],
+            Received::Subscription { index, len } => match &self.subscriptions[index] {
+                Some(subscription) => &subscription.buffer[..len],
+                None => &[],
+            },
+        }
+    }
+
+    /// Starts sending a transfer, as many frames as there are free Tx mailboxes for go out right
+    /// away & `poll` sends the rest
+    pub fn send<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        metadata: TransferMetadata,
+        data: &[u8],
+    ) -> Result<Option<CyphalEvent>, CyphalError> {
+        if self.is_transmitting() {
+            return Err(CyphalError::Busy);
+        }
+
+        let max_port_id = match metadata.kind {
+            TransferKind::Message => SUBJECT_ID_MAX,
+            _ => SERVICE_ID_MAX,
+        };
+
+        if metadata.port_id > max_port_id {
+            return Err(CyphalError::InvalidPortId);
+        }
+
+        if metadata.kind != TransferKind::Message
+            && metadata
+                .remote_node_id
+                .is_none_or(|node_id| node_id > NODE_ID_MAX)
+        {
+            return Err(CyphalError::InvalidNodeId);
+        }
+
+        if self.node_id.is_none()
+            && (metadata.kind != TransferKind::Message || data.len() > FRAME_PAYLOAD_LEN)
+        {
+            return Err(CyphalError::Anonymous);
+        }
+
+        if data.len() > self.tx_buffer.len() {
+            return Err(CyphalError::BufferTooSmall);
+        }
+
+        let id = CyphalId {
+            priority: metadata.priority,
+            kind: metadata.kind,
+            port_id: metadata.port_id,
+            source_node_id: self.node_id,
+            destination_node_id: metadata.remote_node_id,
+        };
+
+        self.tx_buffer[..data.len()].copy_from_slice(data);
+        self.tx_id = id.to_id(transfer_crc(TRANSFER_CRC_INIT, data) as u8);
+        self.tx_len = data.len();
+        self.tx_offset = 0;
+        self.tx_toggle = true;
+
+        if data.len() <= FRAME_PAYLOAD_LEN {
+            self.tx_padding = 0;
+        } else {
+            // The padding the last frame needs to have a valid FD length goes before the CRC,
+            // & is covered by it
+            let stream_len = data.len() + 2;
+            let last_len = stream_len - (stream_len - 1) / FRAME_PAYLOAD_LEN * FRAME_PAYLOAD_LEN;
+            self.tx_padding = padded_len(last_len + 1) - (last_len + 1);
+            self.tx_crc = transfer_crc(TRANSFER_CRC_INIT, data);
+
+            for _ in 0..self.tx_padding {
+                self.tx_crc = transfer_crc(self.tx_crc, &[0]);
+            }
+        }
+
+        self.tx_metadata = Some(metadata);
+
+        self.send_frames(can)
+    }
+
+    /// Sends the frames still waiting for a mailbox & drops reassemblies that timed out
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<Option<CyphalEvent>, CyphalError> {
+        for subscription in self.subscriptions.iter_mut().flatten() {
+            if subscription.rx_active
+                && now_us.wrapping_sub(subscription.rx_last_us)
+                    > subscription.transfer_id_timeout_us
+            {
+                subscription.rx_active = false;
+            }
+        }
+
+        self.send_frames(can)
+    }
+
+    /// Feeds a received frame in, returns the event when it completes a transfer
+    pub fn handle_frame(
+        &mut self,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<CyphalEvent>, CyphalError> {
+        self.received = Received::Nothing;
+
+        let id = match CyphalId::from_id(frame.id) {
+            Some(id) if frame.buffer_len >= 1 => id,
+            _ => return Ok(None),
+        };
+
+        if id.kind != TransferKind::Message
+            && (self.node_id.is_none() || id.destination_node_id != self.node_id)
+        {
+            return Ok(None);
+        }
+
+        let index = match self.subscription_index(id.kind, id.port_id) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        let len = (frame.buffer_len as usize).min(MTU_CAN_FD);
+        let payload = &frame.buffer[..len - 1];
+        let tail = TailByte::from_u8(frame.buffer[len - 1]);
+        let subscription = self.subscriptions[index].as_mut().unwrap();
+
+        let event = |len| CyphalEvent::Received {
+            priority: id.priority,
+            kind: id.kind,
+            port_id: id.port_id,
+            source_node_id: id.source_node_id,
+            transfer_id: tail.transfer_id,
+            len,
+        };
+
+        if tail.start_of_transfer {
+            if !tail.toggle {
+                return Ok(None);
+            }
+
+            if let Some(source_node_id) = id.source_node_id {
+                if tail.end_of_transfer
+                    || !subscription.rx_active
+                    || subscription.rx_source_node_id == source_node_id
+                    || now_us.wrapping_sub(subscription.rx_last_us)
+                        > subscription.transfer_id_timeout_us
+                {
+                    if !subscription.accept(source_node_id, tail.transfer_id, now_us) {
+                        return Ok(None);
+                    }
+                } else {
+                    // Still busy with a transfer from another source
+                    return Ok(None);
+                }
+            } else if !tail.end_of_transfer {
+                // Anonymous transfers are single-frame only
+                return Ok(None);
+            }
+
+            if tail.end_of_transfer {
+                let len = payload.len().min(subscription.buffer.len());
+                self.frame_buffer[..len].copy_from_slice(&payload[..len]);
+                self.received = Received::Frame { len };
+
+                return Ok(Some(event(len)));
+            }
+
+            subscription.rx_active = true;
+            subscription.rx_priority = id.priority;
+            subscription.rx_source_node_id = id.source_node_id.unwrap_or(0);
+            subscription.rx_transfer_id = tail.transfer_id;
+            subscription.rx_toggle = true;
+            subscription.rx_len = 0;
+            subscription.rx_crc = TRANSFER_CRC_INIT;
+            subscription.rx_last_us = now_us;
+            subscription.append(payload);
+
+            return Ok(None);
+        }
+
+        if !subscription.rx_active
+            || id.source_node_id != Some(subscription.rx_source_node_id)
+            || tail.transfer_id != subscription.rx_transfer_id
+            || tail.toggle == subscription.rx_toggle
+        {
+            return Ok(None);
+        }
+
+        subscription.rx_toggle = tail.toggle;
+        subscription.rx_last_us = now_us;
+        subscription.append(payload);
+
+        if !tail.end_of_transfer {
+            return Ok(None);
+        }
+
+        subscription.rx_active = false;
+
+        if subscription.rx_len < 2 || subscription.rx_crc != 0 {
+            return Err(CyphalError::CrcMismatch);
+        }
+
+        let len = (subscription.rx_len - 2).min(subscription.buffer.len());
+        self.received = Received::Subscription { index, len };
+
+        Ok(Some(event(len)))
+    }
+
+    fn subscription_index(&self, kind: TransferKind, port_id: u16) -> Option<usize> {
+        self.subscriptions.iter().position(|subscription| {
+            subscription.as_ref().is_some_and(|subscription| {
+                subscription.kind == kind && subscription.port_id == port_id
+            })
+        })
+    }
+
+    fn send_frames<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+    ) -> Result<Option<CyphalEvent>, CyphalError> {
+        let metadata = match self.tx_metadata {
+            Some(metadata) => metadata,
+            None => return Ok(None),
+        };
+
+        let single_frame = self.tx_len <= FRAME_PAYLOAD_LEN;
+        let stream_len = if single_frame {
+            self.tx_len
+        } else {
+            self.tx_len + self.tx_padding + 2
+        };
+
+        loop {
+            let mut buffer = [0_u8; MTU_CAN_FD];
+            let chunk = (stream_len - self.tx_offset).min(FRAME_PAYLOAD_LEN);
+            let end_of_transfer = self.tx_offset + chunk == stream_len;
+
+            for (i, byte) in buffer[..chunk].iter_mut().enumerate() {
+                let position = self.tx_offset + i;
+
+                *byte = if position < self.tx_len {
+                    self.tx_buffer[position]
+                } else if position < self.tx_len + self.tx_padding {
+                    0
+                } else if position == self.tx_len + self.tx_padding {
+                    (self.tx_crc >> 8) as u8
+                } else {
+                    self.tx_crc as u8
+                };
+            }
+
+            // Only single-frame transfers still need padding here, for multi-frame ones it's
+            // already part of the stream
+            let len = padded_len(chunk + 1);
+            buffer[len - 1] = TailByte {
+                start_of_transfer: self.tx_offset == 0,
+                end_of_transfer,
+                toggle: self.tx_toggle,
+                transfer_id: metadata.transfer_id,
+            }
+            .to_u8();
+
+            let frame = TxFDFrame {
+                id: self.tx_id,
+                buffer: &buffer[..len],
+                priority: None,
+                format: FrameFormat::default(),
+            };
+
+            match can.transmit(&frame) {
+                Ok(()) => (),
+                Err(RxTxError::MailboxUnavailable) => return Ok(None),
+                Err(err) => {
+                    self.tx_metadata = None;
+                    return Err(CyphalError::Bus(err));
+                }
+            }
+
+            if end_of_transfer {
+                self.tx_metadata = None;
+
+                return Ok(Some(CyphalEvent::TransmitComplete {
+                    kind: metadata.kind,
+                    port_id: metadata.port_id,
+                    transfer_id: metadata.transfer_id & 0x1F,
+                }));
+            }
+
+            self.tx_offset += chunk;
+            self.tx_toggle = !self.tx_toggle;
+        }
+    }
+}
+
+// Rounds a frame length up to the next valid FD length
+fn padded_len(len: usize) -> usize {
+    dlc_to_len(len_to_dlc(len as u32)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Bus, SentFrame};
+
+    const SUBJECT: u16 = 1234;
+
+    fn message(transfer_id: u8) -> TransferMetadata {
+        TransferMetadata {
+            priority: PRIORITY_NOMINAL,
+            kind: TransferKind::Message,
+            port_id: SUBJECT,
+            remote_node_id: None,
+            transfer_id,
+        }
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn tail(frame: &SentFrame) -> TailByte {
+        TailByte::from_u8(*frame.data.last().unwrap())
+    }
+
+    // Sends a message from node 10 & returns the frames that went out
+    fn send(data: &[u8], transfer_id: u8) -> Vec<SentFrame> {
+        let mut tx_buffer = [0; 256];
+        let mut transport = CyphalTransport::new(Some(10), &mut tx_buffer).unwrap();
+        let mut bus = Bus::default();
+
+        assert_eq!(
+            transport.send(&mut bus, message(transfer_id), data),
+            Ok(Some(CyphalEvent::TransmitComplete {
+                kind: TransferKind::Message,
+                port_id: SUBJECT,
+                transfer_id,
+            }))
+        );
+
+        bus.take()
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(transfer_crc(TRANSFER_CRC_INIT, b"123456789"), 0x29B1);
+        assert_eq!(transfer_crc(TRANSFER_CRC_INIT, b"123456789\x29\xB1"), 0);
+    }
+
+    #[test]
+    fn tail_byte() {
+        let tail = TailByte {
+            start_of_transfer: true,
+            end_of_transfer: false,
+            toggle: true,
+            transfer_id: 17,
+        };
+
+        assert_eq!(tail.to_u8(), 0b101_10001);
+        assert_eq!(TailByte::from_u8(0b101_10001), tail);
+    }
+
+    #[test]
+    fn ids() {
+        let heartbeat = CyphalId {
+            priority: PRIORITY_NOMINAL,
+            kind: TransferKind::Message,
+            port_id: 7509,
+            source_node_id: Some(42),
+            destination_node_id: None,
+        };
+        assert_eq!(heartbeat.to_id(0), Id::Extended(0x107D_552A));
+        assert_eq!(CyphalId::from_id(heartbeat.to_id(0)), Some(heartbeat));
+
+        let request = CyphalId {
+            priority: PRIORITY_NOMINAL,
+            kind: TransferKind::Request,
+            port_id: 430,
+            source_node_id: Some(123),
+            destination_node_id: Some(42),
+        };
+        assert_eq!(request.to_id(0), Id::Extended(0x136B_957B));
+        assert_eq!(CyphalId::from_id(request.to_id(0)), Some(request));
+
+        // Reserved bit 23, & a service to itself
+        assert_eq!(CyphalId::from_id(Id::Extended(0x108D_552A)), None);
+        assert_eq!(CyphalId::from_id(Id::Extended(0x136B_952A)), None);
+        assert_eq!(CyphalId::from_id(Id::Standard(0x123)), None);
+    }
+
+    #[test]
+    fn single_frame() {
+        let frames = send(&[1, 2, 3, 4, 5], 3);
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data, [1, 2, 3, 4, 5, 0b111_00011]);
+        assert!(frames[0].format.is_fd());
+
+        // 12 bytes of payload need padding to the 16 byte FD length
+        let frames = send(&payload(12), 3);
+        assert_eq!(frames[0].data.len(), 16);
+        assert_eq!(frames[0].data[12..15], [0, 0, 0]);
+
+        let mut buffer = [0; 64];
+        let mut tx_buffer = [0; 0];
+        let mut transport = CyphalTransport::new(Some(20), &mut tx_buffer).unwrap();
+        transport
+            .subscribe(TransferKind::Message, SUBJECT, &mut buffer, 1000)
+            .unwrap();
+
+        assert_eq!(
+            transport.handle_frame(&frames[0].to_rx(), 0),
+            Ok(Some(CyphalEvent::Received {
+                priority: PRIORITY_NOMINAL,
+                kind: TransferKind::Message,
+                port_id: SUBJECT,
+                source_node_id: Some(10),
+                transfer_id: 3,
+                len: 15,
+            }))
+        );
+        assert_eq!(transport.received()[..12], payload(12)[..]);
+    }
+
+    #[test]
+    fn multi_frame() {
+        let data = payload(100);
+        let frames = send(&data, 9);
+
+        // 63 bytes, then the other 37 padded by 8 before the CRC to fill a 48 byte frame
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].data.len(), 64);
+        assert_eq!(frames[1].data.len(), 48);
+        assert_eq!(frames[0].data[..63], data[..63]);
+        assert_eq!(frames[1].data[..37], data[63..]);
+        assert_eq!(frames[1].data[37..45], [0; 8]);
+
+        let mut crc = transfer_crc(TRANSFER_CRC_INIT, &data);
+        crc = transfer_crc(crc, &[0; 8]);
+        assert_eq!(frames[1].data[45..47], crc.to_be_bytes());
+
+        let tails: Vec<_> = frames.iter().map(tail).collect();
+        assert_eq!(
+            tails,
+            [
+                TailByte {
+                    start_of_transfer: true,
+                    end_of_transfer: false,
+                    toggle: true,
+                    transfer_id: 9,
+                },
+                TailByte {
+                    start_of_transfer: false,
+                    end_of_transfer: true,
+                    toggle: false,
+                    transfer_id: 9,
+                },
+            ]
+        );
+
+        let mut buffer = [0; 128];
+        let mut tx_buffer = [0; 0];
+        let mut transport = CyphalTransport::new(Some(20), &mut tx_buffer).unwrap();
+        transport
+            .subscribe(TransferKind::Message, SUBJECT, &mut buffer, 1000)
+            .unwrap();
+
+        assert_eq!(transport.handle_frame(&frames[0].to_rx(), 0), Ok(None));
+        assert!(matches!(
+            transport.handle_frame(&frames[1].to_rx(), 10),
+            Ok(Some(CyphalEvent::Received {
+                transfer_id: 9,
+                len: 108,
+                ..
+            }))
+        ));
+        assert_eq!(transport.received()[..100], data[..]);
+    }
+
+    #[test]
+    fn multi_frame_bad_crc() {
+        let mut frames = send(&payload(100), 0);
+        frames[1].data[0] ^= 1;
+
+        let mut buffer = [0; 128];
+        let mut tx_buffer = [0; 0];
+        let mut transport = CyphalTransport::new(Some(20), &mut tx_buffer).unwrap();
+        transport
+            .subscribe(TransferKind::Message, SUBJECT, &mut buffer, 1000)
+            .unwrap();
+
+        transport.handle_frame(&frames[0].to_rx(), 0).unwrap();
+        assert_eq!(
+            transport.handle_frame(&frames[1].to_rx(), 0),
+            Err(CyphalError::CrcMismatch)
+        );
+    }
+
+    #[test]
+    fn toggle_and_transfer_id_rejection() {
+        let frames = send(&payload(100), 4);
+        let with_tail = |frame: &SentFrame, tail: TailByte| {
+            let mut frame = frame.to_rx();
+            frame.buffer[frame.buffer_len as usize - 1] = tail.to_u8();
+            frame
+        };
+
+        let mut buffer = [0; 128];
+        let mut tx_buffer = [0; 0];
+        let mut transport = CyphalTransport::new(Some(20), &mut tx_buffer).unwrap();
+        transport
+            .subscribe(TransferKind::Message, SUBJECT, &mut buffer, 1000)
+            .unwrap();
+
+        // A start of transfer has to have the toggle bit set
+        let cleared_toggle = TailByte {
+            toggle: false,
+            ..tail(&frames[0])
+        };
+        transport
+            .handle_frame(&with_tail(&frames[0], cleared_toggle), 0)
+            .unwrap();
+        assert_eq!(transport.handle_frame(&frames[1].to_rx(), 0), Ok(None));
+
+        transport.handle_frame(&frames[0].to_rx(), 0).unwrap();
+
+        // The toggle bit didn't alternate, or the transfer-ID doesn't match
+        let repeated_toggle = TailByte {
+            toggle: true,
+            ..tail(&frames[1])
+        };
+        let other_transfer = TailByte {
+            transfer_id: 5,
+            ..tail(&frames[1])
+        };
+        for tail in [repeated_toggle, other_transfer] {
+            assert_eq!(
+                transport.handle_frame(&with_tail(&frames[1], tail), 0),
+                Ok(None)
+            );
+        }
+
+        assert!(transport
+            .handle_frame(&frames[1].to_rx(), 0)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn duplicate_transfers_dropped() {
+        let first = send(&[1], 7).pop().unwrap().to_rx();
+        let next = send(&[2], 8).pop().unwrap().to_rx();
+
+        let mut buffer = [0; 8];
+        let mut tx_buffer = [0; 0];
+        let mut transport = CyphalTransport::new(Some(20), &mut tx_buffer).unwrap();
+        transport
+            .subscribe(TransferKind::Message, SUBJECT, &mut buffer, 1000)
+            .unwrap();
+
+        assert!(transport.handle_frame(&first, 0).unwrap().is_some());
+        assert_eq!(transport.handle_frame(&first, 999), Ok(None));
+        assert!(transport.handle_frame(&next, 999).unwrap().is_some());
+
+        // The same transfer-ID is a new transfer again once the timeout passed
+        assert!(transport.handle_frame(&next, 2000).unwrap().is_some());
+    }
+
+    #[test]
+    fn waits_for_free_mailboxes() {
+        let mut tx_buffer = [0; 256];
+        let mut transport = CyphalTransport::new(Some(10), &mut tx_buffer).unwrap();
+        let mut bus = Bus {
+            full: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            transport.send(&mut bus, message(0), &payload(100)),
+            Ok(None)
+        );
+        assert!(transport.is_transmitting());
+        assert_eq!(
+            transport.send(&mut bus, message(1), &[0]),
+            Err(CyphalError::Busy)
+        );
+
+        bus.full = false;
+        assert!(transport.poll(&mut bus, 0).unwrap().is_some());
+        assert_eq!(bus.take().len(), 2);
+        assert!(!transport.is_transmitting());
+    }
+
+    #[test]
+    fn anonymous_nodes() {
+        let mut tx_buffer = [0; 256];
+        let mut transport = CyphalTransport::new(None, &mut tx_buffer).unwrap();
+        let mut bus = Bus::default();
+
+        assert_eq!(
+            transport.send(&mut bus, message(0), &payload(64)),
+            Err(CyphalError::Anonymous)
+        );
+        transport.send(&mut bus, message(0), &[1, 2]).unwrap();
+
+        let id = CyphalId::from_id(bus.take()[0].id).unwrap();
+        assert_eq!(id.source_node_id, None);
+    }
+}
@@ -4,6 +4,7 @@
 //! Author: David Allen (hbddallen@gmail.com)
 
 use super::can_error::CANFDError;
+use super::config::TimingConfig;
 use super::CANFD;
 use imxrt_ral as ral;
 
@@ -51,7 +52,8 @@ impl CANFD {
         self.reset();
 
         // Disable loop back (LPB) & listen only (LOM) & timer sync (TSYN)
-        ral::modify_reg!(ral::can3, self.instance, CTRL1, LPB: 0b0, LOM: 0b0, TSYN: 0b0);
+        // Enable error (ERRMSK) & bus off (BOFFMSK) interrupts, only used for the statistics
+        ral::modify_reg!(ral::can3, self.instance, CTRL1, LPB: 0b0, LOM: 0b0, TSYN: 0b0, ERRMSK: 0b1, BOFFMSK: 0b1);
 
         // Set:         Maximum # of message buffers (from region sizes)
         // Disable:     Self wakeup (SLFWAK)
@@ -66,28 +68,7 @@ impl CANFD {
 
         // --- Set timing config for classical CAN --- //
 
-        let timing = &self.config.timing_classical;
-
-        let div = (timing.prescalar_division.max(1).min(1023) - 1) as u32;
-        let prop_seg = (timing.prop_seg.max(1).min(63) - 1) as u32;
-        let seg1 = (timing.phase_seg_1.max(1).min(31) - 1) as u32;
-        let seg2 = (timing.phase_seg_2.max(1).min(31) - 1) as u32;
-        let rjw = (timing.jump_width.max(1).min(31) - 1) as u32;
-
-        // Write timing config to register
-        self.exec_freeze(|| {
-            ral::modify_reg!(
-                ral::can3,
-                self.instance,
-                CBT,
-                EPRESDIV: div,
-                EPROPSEG: prop_seg,
-                EPSEG1: seg1,
-                EPSEG2: seg2,
-                ERJW: rjw,
-                BTF: 0b1
-            );
-        });
+        self.exec_freeze(|| self.write_classical_timing());
 
         Ok(())
     }
@@ -95,14 +76,6 @@ impl CANFD {
     fn init_fd(&mut self) -> Result<(), CANFDError> {
         // --- Set timing config for CAN FD--- //
 
-        let timing = &self.config.timing_fd;
-
-        let fdiv = timing.prescalar_division.max(1).min(1023) - 1;
-        let fprop_seg = (timing.prop_seg.max(1).min(63) - 1) as u32;
-        let fseg1 = (timing.phase_seg_1.max(1).min(31) - 1) as u32;
-        let fseg2 = (timing.phase_seg_2.max(1).min(31) - 1) as u32;
-        let frjw = (timing.jump_width.max(1).min(31) - 1) as u32;
-
         let tdcen: u32 = if self.config.transceiver_compensation.is_none() {
             0b0
         } else {
@@ -117,16 +90,7 @@ impl CANFD {
 
         // Write timing config to register
         self.exec_freeze(|| {
-            ral::modify_reg!(
-                ral::can3,
-                self.instance,
-                FDCBT,
-                FPRESDIV: fdiv,
-                FPROPSEG: fprop_seg,
-                FPSEG1: fseg1,
-                FPSEG2: fseg2,
-                FRJW: frjw
-            );
+            self.write_fd_timing();
 
             // For the C++ FlexCAN library
             //ral::write_reg!(ral::can3, &self.instance, FDCBT, 0x31423); 
@@ -134,6 +98,9 @@ impl CANFD {
             // Enable: CAN FD
             ral::modify_reg!(ral::can3, self.instance, MCR, FDEN: 0b1);
 
+            // Enable: Error interrupts in the fast (data) phase (ERRMSK_FAST)
+            ral::modify_reg!(ral::can3, self.instance, CTRL2, ERRMSK_FAST: 0b1);
+
             // Enable:      Bit rate switch enable (FDRATE), enables faster bitrates in FD
             // Set:         Transceiver delay compensation (TDCOFF), shouldn't matter if disabled
             // Set:         Transceiver delay compensation enable (TDCEN)
@@ -151,4 +118,60 @@ impl CANFD {
 
         Ok(())
     }
+
+    /// Changes the bitrates on the fly, the controller is frozen (off the bus) while it happens
+    pub(crate) fn set_timing(&mut self, classical: TimingConfig, fd: TimingConfig) {
+        self.config.timing_classical = classical;
+        self.config.timing_fd = fd;
+
+        self.exec_freeze(|| {
+            self.write_classical_timing();
+            self.write_fd_timing();
+        });
+    }
+
+    // Only while frozen
+    fn write_classical_timing(&self) {
+        let timing = &self.config.timing_classical;
+
+        let div = (timing.prescalar_division.max(1).min(1023) - 1) as u32;
+        let prop_seg = (timing.prop_seg.max(1).min(63) - 1) as u32;
+        let seg1 = (timing.phase_seg_1.max(1).min(31) - 1) as u32;
+        let seg2 = (timing.phase_seg_2.max(1).min(31) - 1) as u32;
+        let rjw = (timing.jump_width.max(1).min(31) - 1) as u32;
+
+        ral::modify_reg!(
+            ral::can3,
+            self.instance,
+            CBT,
+            EPRESDIV: div,
+            EPROPSEG: prop_seg,
+            EPSEG1: seg1,
+            EPSEG2: seg2,
+            ERJW: rjw,
+            BTF: 0b1
+        );
+    }
+
+    // Only while frozen
+    fn write_fd_timing(&self) {
+        let timing = &self.config.timing_fd;
+
+        let fdiv = timing.prescalar_division.max(1).min(1023) - 1;
+        let fprop_seg = (timing.prop_seg.max(1).min(63) - 1) as u32;
+        let fseg1 = (timing.phase_seg_1.max(1).min(31) - 1) as u32;
+        let fseg2 = (timing.phase_seg_2.max(1).min(31) - 1) as u32;
+        let frjw = (timing.jump_width.max(1).min(31) - 1) as u32;
+
+        ral::modify_reg!(
+            ral::can3,
+            self.instance,
+            FDCBT,
+            FPRESDIV: fdiv,
+            FPROPSEG: fprop_seg,
+            FPSEG1: fseg1,
+            FPSEG2: fseg2,
+            FRJW: frjw
+        );
+    }
 }
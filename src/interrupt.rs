@@ -33,6 +33,12 @@ impl CANFD {
             }
 
             if let Some(rx_frame) = self.receive(mb_index) {
+                self.stats.count_rx_frame(mb_index);
+
+                if rx_frame.overrun {
+                    self.stats.count_rx_overrun();
+                }
+
                 if let Some(rx_callback) = self.rx_callback {
                     rx_callback(cs, rx_frame);
                 }
@@ -53,6 +59,37 @@ impl CANFD {
             IFLAG2,
             ((reset_mask >> 32) & 0xFFFF_FFFF) as u32
         );
+
+        self.handle_error_interrupt();
+    }
+
+    fn handle_error_interrupt(&self) {
+        let (errint, boffint, errint_fast) = ral::read_reg!(
+            ral::can3,
+            &self.instance,
+            ESR1,
+            ERRINT,
+            BOFFINT,
+            ERRINT_FAST
+        );
+
+        if errint == 0b1 || errint_fast == 0b1 {
+            self.stats.count_error_interrupt();
+        }
+
+        if boffint == 0b1 {
+            self.stats.count_bus_off();
+        }
+
+        // The interrupt flags are write 1 to clear, the rest of ESR1 is read only
+        ral::write_reg!(
+            ral::can3,
+            &self.instance,
+            ESR1,
+            ERRINT: errint,
+            BOFFINT: boffint,
+            ERRINT_FAST: errint_fast
+        );
     }
 
     fn read_iflag(&self) -> u64 {
@@ -0,0 +1,957 @@
+//! ISO-TP (ISO 15765-2) transport layer, for sending payloads that don't fit in a single frame
+//!
+//! A channel is one pair of Tx/Rx IDs. It doesn't own the bus, received frames get fed in with
+//! `handle_frame` (usually from the rx callback), and `poll` has to be called regularly to send
+//! consecutive frames and check the N_As/N_Bs/N_Cr timeouts. All times are in microseconds from a
+//! free running time base that's allowed to wrap.
+
+use crate::can_error::RxTxError;
+use crate::config::Id;
+use crate::util::{dlc_to_len, len_to_dlc};
+use crate::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+
+const PCI_SINGLE_FRAME: u8 = 0x0;
+const PCI_FIRST_FRAME: u8 = 0x1;
+const PCI_CONSECUTIVE_FRAME: u8 = 0x2;
+const PCI_FLOW_CONTROL: u8 = 0x3;
+
+const FLOW_STATUS_CONTINUE: u8 = 0x0;
+const FLOW_STATUS_WAIT: u8 = 0x1;
+const FLOW_STATUS_OVERFLOW: u8 = 0x2;
+
+const DEFAULT_PADDING: u8 = 0xCC;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Addressing {
+    Normal,
+    Extended {
+        target_address: u8, // Written as the first byte of every frame we send
+        source_address: u8, // Our own address, expected as the first byte of received frames
+    },
+}
+
+impl Addressing {
+    fn len(&self) -> usize {
+        match self {
+            Addressing::Normal => 0,
+            Addressing::Extended { .. } => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IsoTpConfig {
+    pub tx_id: Id,
+    pub rx_id: Id,
+    pub addressing: Addressing,
+    /// Largest frame we send, 8 for classical sized frames up to 64
+    pub tx_dl: usize,
+    /// Consecutive frames the sender may send per flow control, 0 is all
+    pub block_size: u8,
+    /// Separation time we ask the sender for, in the ISO-TP encoding
+    pub st_min: u8,
+    /// N_As, how long a frame may wait for a free Tx mailbox
+    pub timeout_as_us: u32,
+    /// N_Bs, how long we wait for a flow control frame
+    pub timeout_bs_us: u32,
+    /// N_Cr, how long we wait for the next consecutive frame
+    pub timeout_cr_us: u32,
+    /// Pad frames up to 8 bytes with this, FD frames are always padded
+    pub padding: Option<u8>,
+}
+
+impl IsoTpConfig {
+    pub fn new(tx_id: Id, rx_id: Id) -> Self {
+        Self {
+            tx_id,
+            rx_id,
+            addressing: Addressing::Normal,
+            tx_dl: 64,
+            block_size: 0,
+            st_min: 0,
+            timeout_as_us: 1_000_000,
+            timeout_bs_us: 1_000_000,
+            timeout_cr_us: 1_000_000,
+            padding: Some(DEFAULT_PADDING),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsoTpError {
+    Busy,                // A transmission is already in progress
+    MessageTooLong,      // The message doesn't fit in the Tx buffer
+    BufferTooSmall,      // A received message doesn't fit in the Rx buffer
+    TimeoutAs,           // A frame couldn't be handed to the bus in time
+    TimeoutBs,           // The receiver didn't send a flow control in time
+    TimeoutCr,           // The sender didn't send the next consecutive frame in time
+    WrongSequenceNumber, // A consecutive frame was lost or reordered
+    Overflow,            // The receiver doesn't have room for our message
+    InvalidFrame,        // A frame that doesn't follow the protocol
+    Bus(RxTxError),      // The driver refused a frame
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsoTpEvent {
+    Received { len: usize }, // A full message is waiting in `received()`
+    TransmitComplete,        // The last frame of the message we were sending went out
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TxState {
+    Idle,
+    Single {
+        len: usize,
+    },
+    First {
+        len: usize,
+    },
+    WaitFlowControl {
+        len: usize,
+        sent: usize,
+        next_sn: u8,
+        since_us: u32,
+    },
+    Consecutive {
+        len: usize,
+        sent: usize,
+        next_sn: u8,
+        block_remaining: Option<u8>, // None when the receiver doesn't want flow control again
+        st_min_us: u32,
+        last_us: Option<u32>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RxState {
+    Idle,
+    Receiving {
+        len: usize,
+        received: usize,
+        next_sn: u8,
+        block_count: u8,
+        last_us: u32,
+    },
+}
+
+pub struct IsoTp<'a> {
+    config: IsoTpConfig,
+    tx_buffer: &'a mut [u8],
+    rx_buffer: &'a mut [u8],
+    tx_state: TxState,
+    tx_pending_since: Option<u32>,
+    rx_state: RxState,
+    rx_complete_len: usize,
+}
+
+impl<'a> IsoTp<'a> {
+    /// Creates a channel, messages we send are copied into `tx_buffer` and received messages are
+    /// reassembled in `rx_buffer`, so they limit the message sizes in each direction
+    pub fn new(config: IsoTpConfig, tx_buffer: &'a mut [u8], rx_buffer: &'a mut [u8]) -> Self {
+        let mut config = config;
+        config.tx_dl = dlc_to_len(len_to_dlc(config.tx_dl.clamp(8, 64) as u32)) as usize;
+
+        Self {
+            config,
+            tx_buffer,
+            rx_buffer,
+            tx_state: TxState::Idle,
+            tx_pending_since: None,
+            rx_state: RxState::Idle,
+            rx_complete_len: 0,
+        }
+    }
+
+    pub fn config(&self) -> &IsoTpConfig {
+        &self.config
+    }
+
+    pub fn is_transmitting(&self) -> bool {
+        !matches!(self.tx_state, TxState::Idle)
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        !matches!(self.rx_state, RxState::Idle)
+    }
+
+    /// The last fully received message, valid until the next one starts arriving
+    pub fn received(&self) -> &[u8] {
+        &self.rx_buffer[..self.rx_complete_len]
+    }
+
+    /// Starts sending a message, the first frame goes out right away and the rest from `poll`
+    pub fn send<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        data: &[u8],
+        now_us: u32,
+    ) -> Result<Option<IsoTpEvent>, IsoTpError> {
+        if self.is_transmitting() {
+            return Err(IsoTpError::Busy);
+        }
+
+        if data.len() > self.tx_buffer.len() {
+            return Err(IsoTpError::MessageTooLong);
+        }
+
+        self.tx_buffer[..data.len()].copy_from_slice(data);
+        self.tx_pending_since = None;
+        self.tx_state = if data.len() <= self.single_frame_capacity() {
+            TxState::Single { len: data.len() }
+        } else {
+            TxState::First { len: data.len() }
+        };
+
+        self.poll_tx(can, now_us)
+    }
+
+    /// Stops any transmission & reception in progress
+    pub fn abort(&mut self) {
+        self.tx_state = TxState::Idle;
+        self.tx_pending_since = None;
+        self.rx_state = RxState::Idle;
+    }
+
+    /// Sends what's due & checks the timeouts, should be called at least once per STmin
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<Option<IsoTpEvent>, IsoTpError> {
+        if let RxState::Receiving { last_us, .. } = self.rx_state {
+            if now_us.wrapping_sub(last_us) > self.config.timeout_cr_us {
+                self.rx_state = RxState::Idle;
+                return Err(IsoTpError::TimeoutCr);
+            }
+        }
+
+        self.poll_tx(can, now_us)
+    }
+
+    /// Feeds a received frame to the channel, frames with other IDs are ignored
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<IsoTpEvent>, IsoTpError> {
+        if frame.id != self.config.rx_id {
+            return Ok(None);
+        }
+
+        let frame_len = (frame.buffer_len as usize).min(64);
+        let mut data = &frame.buffer[..frame_len];
+
+        if let Addressing::Extended { source_address, .. } = self.config.addressing {
+            match data.split_first() {
+                Some((address, rest)) if *address == source_address => data = rest,
+                _ => return Ok(None),
+            }
+        }
+
+        if data.is_empty() {
+            return Err(IsoTpError::InvalidFrame);
+        }
+
+        match data[0] >> 4 {
+            PCI_SINGLE_FRAME => self.receive_single_frame(data, frame_len),
+            PCI_FIRST_FRAME => self.receive_first_frame(can, data, now_us),
+            PCI_CONSECUTIVE_FRAME => self.receive_consecutive_frame(can, data, now_us),
+            PCI_FLOW_CONTROL => self.receive_flow_control(can, data, now_us),
+            _ => Err(IsoTpError::InvalidFrame),
+        }
+    }
+
+    fn receive_single_frame(
+        &mut self,
+        data: &[u8],
+        frame_len: usize,
+    ) -> Result<Option<IsoTpEvent>, IsoTpError> {
+        // Low nibble is the length for frames up to 8 bytes, otherwise it's escaped to 0 and the
+        // length is in the next byte. The escape isn't allowed in frames up to 8 bytes.
+        let (len, payload) = if data[0] & 0x0F != 0 && frame_len <= 8 {
+            ((data[0] & 0x0F) as usize, &data[1..])
+        } else if data[0] & 0x0F == 0 && frame_len > 8 && data.len() >= 2 {
+            (data[1] as usize, &data[2..])
+        } else {
+            return Err(IsoTpError::InvalidFrame);
+        };
+
+        if len == 0 || len > payload.len() {
+            return Err(IsoTpError::InvalidFrame);
+        }
+
+        // A new single frame takes over from a segmented message that was being received
+        self.rx_state = RxState::Idle;
+
+        if len > self.rx_buffer.len() {
+            return Err(IsoTpError::BufferTooSmall);
+        }
+
+        self.rx_buffer[..len].copy_from_slice(&payload[..len]);
+        self.rx_complete_len = len;
+
+        Ok(Some(IsoTpEvent::Received { len }))
+    }
+
+    fn receive_first_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        data: &[u8],
+        now_us: u32,
+    ) -> Result<Option<IsoTpEvent>, IsoTpError> {
+        if data.len() < 2 {
+            return Err(IsoTpError::InvalidFrame);
+        }
+
+        // 12 bit length, or escaped to 0 followed by a 32 bit length for messages over 4095 bytes
+        let short_len = (((data[0] & 0x0F) as usize) << 8) | data[1] as usize;
+        let (len, payload) = if short_len != 0 {
+            (short_len, &data[2..])
+        } else if data.len() >= 6 {
+            let len = u32::from_be_bytes([data[2], data[3], data[4], data[5]]) as usize;
+            (len, &data[6..])
+        } else {
+            return Err(IsoTpError::InvalidFrame);
+        };
+
+        if len <= payload.len() {
+            return Err(IsoTpError::InvalidFrame);
+        }
+
+        self.rx_state = RxState::Idle;
+
+        if len > self.rx_buffer.len() {
+            self.transmit_flow_control(can, FLOW_STATUS_OVERFLOW)?;
+            return Err(IsoTpError::BufferTooSmall);
+        }
+
+        self.rx_buffer[..payload.len()].copy_from_slice(payload);
+        self.transmit_flow_control(can, FLOW_STATUS_CONTINUE)?;
+
+        self.rx_state = RxState::Receiving {
+            len,
+            received: payload.len(),
+            next_sn: 1,
+            block_count: 0,
+            last_us: now_us,
+        };
+
+        Ok(None)
+    }
+
+    fn receive_consecutive_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        data: &[u8],
+        now_us: u32,
+    ) -> Result<Option<IsoTpEvent>, IsoTpError> {
+        let (len, received, next_sn, block_count) = match self.rx_state {
+            RxState::Receiving {
+                len,
+                received,
+                next_sn,
+                block_count,
+                ..
+            } => (len, received, next_sn, block_count),
+            RxState::Idle => return Ok(None),
+        };
+
+        if data[0] & 0x0F != next_sn {
+            self.rx_state = RxState::Idle;
+            return Err(IsoTpError::WrongSequenceNumber);
+        }
+
+        let chunk_len = (len - received).min(data.len() - 1);
+        self.rx_buffer[received..received + chunk_len].copy_from_slice(&data[1..1 + chunk_len]);
+        let received = received + chunk_len;
+
+        if received == len {
+            self.rx_state = RxState::Idle;
+            self.rx_complete_len = len;
+
+            return Ok(Some(IsoTpEvent::Received { len }));
+        }
+
+        let mut block_count = block_count;
+
+        if self.config.block_size != 0 {
+            block_count += 1;
+
+            if block_count >= self.config.block_size {
+                block_count = 0;
+
+                if let Err(err) = self.transmit_flow_control(can, FLOW_STATUS_CONTINUE) {
+                    self.rx_state = RxState::Idle;
+                    return Err(err);
+                }
+            }
+        }
+
+        self.rx_state = RxState::Receiving {
+            len,
+            received,
+            next_sn: (next_sn + 1) & 0x0F,
+            block_count,
+            last_us: now_us,
+        };
+
+        Ok(None)
+    }
+
+    fn receive_flow_control<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        data: &[u8],
+        now_us: u32,
+    ) -> Result<Option<IsoTpEvent>, IsoTpError> {
+        let (len, sent, next_sn) = match self.tx_state {
+            TxState::WaitFlowControl {
+                len, sent, next_sn, ..
+            } => (len, sent, next_sn),
+            _ => return Ok(None),
+        };
+
+        if data.len() < 3 {
+            return Err(IsoTpError::InvalidFrame);
+        }
+
+        match data[0] & 0x0F {
+            FLOW_STATUS_CONTINUE => {
+                self.tx_state = TxState::Consecutive {
+                    len,
+                    sent,
+                    next_sn,
+                    block_remaining: if data[1] == 0 { None } else { Some(data[1]) },
+                    st_min_us: st_min_to_us(data[2]),
+                    last_us: None,
+                };
+
+                self.poll_tx(can, now_us)
+            }
+            FLOW_STATUS_WAIT => {
+                self.tx_state = TxState::WaitFlowControl {
+                    len,
+                    sent,
+                    next_sn,
+                    since_us: now_us,
+                };
+
+                Ok(None)
+            }
+            FLOW_STATUS_OVERFLOW => {
+                self.tx_state = TxState::Idle;
+                Err(IsoTpError::Overflow)
+            }
+            _ => {
+                self.tx_state = TxState::Idle;
+                Err(IsoTpError::InvalidFrame)
+            }
+        }
+    }
+
+    fn poll_tx<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<Option<IsoTpEvent>, IsoTpError> {
+        let addressing_len = self.config.addressing.len();
+        let mut frame = [0_u8; 64];
+
+        match self.tx_state {
+            TxState::Idle => Ok(None),
+            TxState::Single { len } => {
+                let mut index = addressing_len;
+
+                if len + addressing_len < 8 {
+                    frame[index] = (PCI_SINGLE_FRAME << 4) | len as u8;
+                    index += 1;
+                } else {
+                    frame[index] = PCI_SINGLE_FRAME << 4;
+                    frame[index + 1] = len as u8;
+                    index += 2;
+                }
+
+                frame[index..index + len].copy_from_slice(&self.tx_buffer[..len]);
+
+                if self.transmit(can, &mut frame, index + len, now_us)? {
+                    self.tx_state = TxState::Idle;
+                    return Ok(Some(IsoTpEvent::TransmitComplete));
+                }
+
+                Ok(None)
+            }
+            TxState::First { len } => {
+                let mut index = addressing_len;
+
+                if len <= 0xFFF {
+                    frame[index] = (PCI_FIRST_FRAME << 4) | (len >> 8) as u8;
+                    frame[index + 1] = len as u8;
+                    index += 2;
+                } else {
+                    frame[index] = PCI_FIRST_FRAME << 4;
+                    frame[index + 1] = 0;
+                    frame[index + 2..index + 6].copy_from_slice(&(len as u32).to_be_bytes());
+                    index += 6;
+                }
+
+                let chunk_len = self.config.tx_dl - index;
+                frame[index..index + chunk_len].copy_from_slice(&self.tx_buffer[..chunk_len]);
+
+                if self.transmit(can, &mut frame, self.config.tx_dl, now_us)? {
+                    self.tx_state = TxState::WaitFlowControl {
+                        len,
+                        sent: chunk_len,
+                        next_sn: 1,
+                        since_us: now_us,
+                    };
+                }
+
+                Ok(None)
+            }
+            TxState::WaitFlowControl { since_us, .. } => {
+                if now_us.wrapping_sub(since_us) > self.config.timeout_bs_us {
+                    self.tx_state = TxState::Idle;
+                    return Err(IsoTpError::TimeoutBs);
+                }
+
+                Ok(None)
+            }
+            TxState::Consecutive {
+                len,
+                mut sent,
+                mut next_sn,
+                mut block_remaining,
+                st_min_us,
+                mut last_us,
+            } => {
+                // Send as many consecutive frames as the separation time allows right now
+                loop {
+                    if let Some(last_us) = last_us {
+                        if now_us.wrapping_sub(last_us) < st_min_us {
+                            break;
+                        }
+                    }
+
+                    let chunk_len = (len - sent).min(self.config.tx_dl - addressing_len - 1);
+                    frame[addressing_len] = (PCI_CONSECUTIVE_FRAME << 4) | next_sn;
+                    frame[addressing_len + 1..addressing_len + 1 + chunk_len]
+                        .copy_from_slice(&self.tx_buffer[sent..sent + chunk_len]);
+
+                    if !self.transmit(can, &mut frame, addressing_len + 1 + chunk_len, now_us)? {
+                        break;
+                    }
+
+                    sent += chunk_len;
+                    next_sn = (next_sn + 1) & 0x0F;
+                    last_us = Some(now_us);
+
+                    if sent == len {
+                        self.tx_state = TxState::Idle;
+                        return Ok(Some(IsoTpEvent::TransmitComplete));
+                    }
+
+                    if let Some(remaining) = block_remaining {
+                        if remaining <= 1 {
+                            self.tx_state = TxState::WaitFlowControl {
+                                len,
+                                sent,
+                                next_sn,
+                                since_us: now_us,
+                            };
+
+                            return Ok(None);
+                        }
+
+                        block_remaining = Some(remaining - 1);
+                    }
+                }
+
+                self.tx_state = TxState::Consecutive {
+                    len,
+                    sent,
+                    next_sn,
+                    block_remaining,
+                    st_min_us,
+                    last_us,
+                };
+
+                Ok(None)
+            }
+        }
+    }
+
+    fn transmit_flow_control<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        flow_status: u8,
+    ) -> Result<(), IsoTpError> {
+        let index = self.config.addressing.len();
+        let mut frame = [0_u8; 64];
+
+        frame[index] = (PCI_FLOW_CONTROL << 4) | flow_status;
+        frame[index + 1] = self.config.block_size;
+        frame[index + 2] = self.config.st_min;
+
+        let len = self.pad_frame(&mut frame, index + 3);
+
+        can.transmit(&self.tx_frame(&frame[..len]))
+            .map_err(IsoTpError::Bus)
+    }
+
+    /// Hands a frame to the bus, `Ok(false)` means no mailbox was free & it should be retried
+    fn transmit<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        frame: &mut [u8; 64],
+        len: usize,
+        now_us: u32,
+    ) -> Result<bool, IsoTpError> {
+        let len = self.pad_frame(frame, len);
+
+        match can.transmit(&self.tx_frame(&frame[..len])) {
+            Ok(()) => {
+                self.tx_pending_since = None;
+                Ok(true)
+            }
+            Err(RxTxError::MailboxUnavailable) => {
+                let since_us = *self.tx_pending_since.get_or_insert(now_us);
+
+                if now_us.wrapping_sub(since_us) > self.config.timeout_as_us {
+                    self.tx_state = TxState::Idle;
+                    self.tx_pending_since = None;
+                    return Err(IsoTpError::TimeoutAs);
+                }
+
+                Ok(false)
+            }
+            Err(err) => {
+                self.tx_state = TxState::Idle;
+                self.tx_pending_since = None;
+                Err(IsoTpError::Bus(err))
+            }
+        }
+    }
+
+    fn tx_frame<'b>(&self, buffer: &'b [u8]) -> TxFDFrame<'b> {
+        TxFDFrame {
+            id: self.config.tx_id,
+            buffer,
+            priority: None,
+            format: if self.config.tx_dl <= 8 {
+                FrameFormat::Classic
+            } else {
+                FrameFormat::default()
+            },
+        }
+    }
+
+    /// Writes the address byte & pads the frame, returning the length to send
+    fn pad_frame(&self, frame: &mut [u8; 64], len: usize) -> usize {
+        if let Addressing::Extended { target_address, .. } = self.config.addressing {
+            frame[0] = target_address;
+        }
+
+        let padded_len = if len > 8 {
+            dlc_to_len(len_to_dlc(len as u32)) as usize
+        } else if self.config.padding.is_some() {
+            8
+        } else {
+            len
+        };
+
+        let padding = self.config.padding.unwrap_or(DEFAULT_PADDING);
+        for byte in frame[len..padded_len].iter_mut() {
+            *byte = padding;
+        }
+
+        padded_len
+    }
+
+    fn single_frame_capacity(&self) -> usize {
+        let addressing_len = self.config.addressing.len();
+
+        if self.config.tx_dl > 8 {
+            // Escaped single frames can use the whole frame
+            self.config.tx_dl - addressing_len - 2
+        } else {
+            7 - addressing_len
+        }
+    }
+}
+
+/// Decodes the STmin byte of a flow control, reserved values are treated as the maximum
+fn st_min_to_us(st_min: u8) -> u32 {
+    match st_min {
+        0x00..=0x7F => st_min as u32 * 1_000,
+        0xF1..=0xF9 => (st_min - 0xF0) as u32 * 100,
+        _ => 127_000,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{rx_frame, Bus};
+
+    const A: Id = Id::Standard(0x700);
+    const B: Id = Id::Standard(0x708);
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    // Sends a message from one channel to the other over a loopback bus, returning the frames
+    fn loopback(len: usize, sender: IsoTpConfig, receiver: IsoTpConfig) -> Vec<Vec<u8>> {
+        let (mut tx_a, mut rx_a) = (vec![0; 9000], vec![0; 9000]);
+        let (mut tx_b, mut rx_b) = (vec![0; 9000], vec![0; 9000]);
+        let mut a = IsoTp::new(sender, &mut tx_a, &mut rx_a);
+        let mut b = IsoTp::new(receiver, &mut tx_b, &mut rx_b);
+        let msg = message(len);
+        let mut bus = Bus::default();
+        let mut frames = Vec::new();
+        let mut now_us = 0;
+        let mut sent =
+            a.send(&mut bus, &msg, now_us).unwrap() == Some(IsoTpEvent::TransmitComplete);
+        let mut received = None;
+
+        while !(sent && received.is_some()) {
+            assert!(now_us < 10_000_000, "stalled sending {} bytes", len);
+            now_us += 100;
+
+            while !bus.sent.is_empty() {
+                for frame in bus.take() {
+                    assert!(frame.data.len() <= 64);
+                    frames.push(frame.data.clone());
+
+                    if frame.id == A {
+                        if let Some(IsoTpEvent::Received { len }) =
+                            b.handle_frame(&mut bus, &frame.to_rx(), now_us).unwrap()
+                        {
+                            received = Some(len);
+                        }
+                    } else {
+                        let event = a.handle_frame(&mut bus, &frame.to_rx(), now_us).unwrap();
+                        sent |= event == Some(IsoTpEvent::TransmitComplete);
+                    }
+                }
+            }
+
+            sent |= a.poll(&mut bus, now_us).unwrap() == Some(IsoTpEvent::TransmitComplete);
+            b.poll(&mut bus, now_us).unwrap();
+        }
+
+        assert_eq!(received, Some(len));
+        assert_eq!(b.received(), &msg[..]);
+
+        frames
+    }
+
+    fn configs(tx_dl: usize, block_size: u8, st_min: u8) -> (IsoTpConfig, IsoTpConfig) {
+        let mut sender = IsoTpConfig::new(A, B);
+        let mut receiver = IsoTpConfig::new(B, A);
+        sender.tx_dl = tx_dl;
+        receiver.tx_dl = tx_dl;
+        receiver.block_size = block_size;
+        receiver.st_min = st_min;
+
+        (sender, receiver)
+    }
+
+    #[test]
+    fn round_trips() {
+        for &len in &[1, 6, 7, 8, 20, 61, 62, 63, 100, 4095, 4096, 8999] {
+            for &tx_dl in &[8, 12, 64] {
+                for &(block_size, st_min) in &[(0, 0), (3, 1), (1, 0xF3)] {
+                    let (sender, receiver) = configs(tx_dl, block_size, st_min);
+                    loopback(len, sender, receiver);
+
+                    let (mut sender, mut receiver) = configs(tx_dl, block_size, st_min);
+                    sender.addressing = Addressing::Extended {
+                        target_address: 2,
+                        source_address: 1,
+                    };
+                    receiver.addressing = Addressing::Extended {
+                        target_address: 1,
+                        source_address: 2,
+                    };
+                    loopback(len, sender, receiver);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn classical_frames() {
+        let (sender, receiver) = configs(8, 0, 0);
+        let frames = loopback(7, sender, receiver);
+        assert_eq!(frames, vec![[&[0x07][..], &message(7)].concat()]);
+
+        // FF, FC, CF with the padding on the last one
+        let frames = loopback(10, sender, receiver);
+        let msg = message(10);
+        assert_eq!(frames[0], [&[0x10, 10][..], &msg[..6]].concat());
+        assert_eq!(frames[1], vec![0x30, 0, 0, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]);
+        assert_eq!(frames[2], [&[0x21][..], &msg[6..], &[0xCC; 3]].concat());
+    }
+
+    #[test]
+    fn fd_escape_sequences() {
+        let (sender, receiver) = configs(64, 0, 0);
+
+        // Single frames over 8 bytes escape the length to the second byte
+        let frames = loopback(20, sender, receiver);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0][..2], [0x00, 20]);
+        assert_eq!(frames[0].len(), 24);
+
+        // First frames over 4095 bytes escape to a 32 bit length
+        let frames = loopback(5000, sender, receiver);
+        assert_eq!(frames[0][..6], [0x10, 0x00, 0x00, 0x00, 0x13, 0x88]);
+        assert_eq!(frames[0][6..], message(58)[..]);
+        assert_eq!(frames[1][..3], [0x30, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_escaped_single_frames_in_classical_frames() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut channel = IsoTp::new(IsoTpConfig::new(B, A), &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        let frame = rx_frame(A, &[0x00, 5, 1, 2, 3, 4, 5, 0xCC]);
+        assert_eq!(
+            channel.handle_frame(&mut bus, &frame, 0),
+            Err(IsoTpError::InvalidFrame)
+        );
+
+        let frame = rx_frame(A, &[0x00, 5, 1, 2, 3, 4, 5, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]);
+        assert_eq!(
+            channel.handle_frame(&mut bus, &frame, 0),
+            Ok(Some(IsoTpEvent::Received { len: 5 }))
+        );
+        assert_eq!(channel.received(), &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn block_size_and_st_min() {
+        let (mut tx, mut rx) = ([0; 100], [0; 100]);
+        let (sender, _) = configs(8, 0, 0);
+        let mut channel = IsoTp::new(sender, &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        channel.send(&mut bus, &message(40), 0).unwrap();
+        assert_eq!(bus.take().len(), 1);
+
+        // Two frames per block, 5ms apart
+        let fc = rx_frame(B, &[0x30, 2, 5]);
+        channel.handle_frame(&mut bus, &fc, 1_000).unwrap();
+        assert_eq!(bus.take().len(), 1);
+        channel.poll(&mut bus, 5_999).unwrap();
+        assert!(bus.sent.is_empty());
+        channel.poll(&mut bus, 6_000).unwrap();
+        assert_eq!(bus.take()[0].data[0], 0x22);
+
+        // Waiting for the next flow control
+        channel.poll(&mut bus, 20_000).unwrap();
+        assert!(bus.sent.is_empty());
+
+        // STmin in 100us steps
+        let fc = rx_frame(B, &[0x30, 0, 0xF5]);
+        channel.handle_frame(&mut bus, &fc, 30_000).unwrap();
+        channel.poll(&mut bus, 30_499).unwrap();
+        assert_eq!(bus.take().len(), 1);
+        channel.poll(&mut bus, 30_500).unwrap();
+        assert_eq!(bus.take()[0].data[0], 0x24);
+    }
+
+    #[test]
+    fn receiver_sends_flow_control_per_block() {
+        let (mut tx, mut rx) = ([0; 100], [0; 100]);
+        let (_, mut receiver) = configs(8, 0, 0);
+        receiver.block_size = 2;
+        let mut channel = IsoTp::new(receiver, &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        channel
+            .handle_frame(&mut bus, &rx_frame(A, &[0x10, 30, 0, 0, 0, 0, 0, 0]), 0)
+            .unwrap();
+        assert_eq!(bus.take()[0].data[..3], [0x30, 2, 0]);
+
+        channel
+            .handle_frame(&mut bus, &rx_frame(A, &[0x21, 0, 0, 0, 0, 0, 0, 0]), 0)
+            .unwrap();
+        assert!(bus.sent.is_empty());
+        channel
+            .handle_frame(&mut bus, &rx_frame(A, &[0x22, 0, 0, 0, 0, 0, 0, 0]), 0)
+            .unwrap();
+        assert_eq!(bus.take()[0].data[..3], [0x30, 2, 0]);
+
+        assert_eq!(
+            channel.handle_frame(&mut bus, &rx_frame(A, &[0x24, 0, 0, 0, 0, 0, 0, 0]), 0),
+            Err(IsoTpError::WrongSequenceNumber)
+        );
+        assert!(!channel.is_receiving());
+    }
+
+    #[test]
+    fn timeouts() {
+        let (mut tx, mut rx) = ([0; 100], [0; 100]);
+        let mut channel = IsoTp::new(IsoTpConfig::new(A, B), &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        // N_Bs
+        channel.send(&mut bus, &[0; 80], 0).unwrap();
+        assert_eq!(channel.poll(&mut bus, 1_000_000), Ok(None));
+        assert_eq!(
+            channel.poll(&mut bus, 1_000_001),
+            Err(IsoTpError::TimeoutBs)
+        );
+        assert!(!channel.is_transmitting());
+
+        // N_As
+        bus.full = true;
+        assert_eq!(channel.send(&mut bus, &[0; 80], 2_000_000), Ok(None));
+        assert_eq!(channel.poll(&mut bus, 3_000_000), Ok(None));
+        assert_eq!(
+            channel.poll(&mut bus, 3_000_001),
+            Err(IsoTpError::TimeoutAs)
+        );
+        bus.full = false;
+
+        // N_Cr
+        let ff = rx_frame(B, &[0x10, 30, 0, 0, 0, 0, 0, 0]);
+        channel.handle_frame(&mut bus, &ff, 4_000_000).unwrap();
+        assert!(channel.is_receiving());
+        assert_eq!(channel.poll(&mut bus, 5_000_000), Ok(None));
+        assert_eq!(
+            channel.poll(&mut bus, 5_000_001),
+            Err(IsoTpError::TimeoutCr)
+        );
+        assert!(!channel.is_receiving());
+    }
+
+    #[test]
+    fn overflow() {
+        let (mut tx, mut rx) = ([0; 100], [0; 100]);
+        let mut channel = IsoTp::new(IsoTpConfig::new(A, B), &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        let ff = rx_frame(B, &[0x10, 200, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            channel.handle_frame(&mut bus, &ff, 0),
+            Err(IsoTpError::BufferTooSmall)
+        );
+        assert_eq!(bus.take()[0].data[0], 0x32);
+
+        channel.send(&mut bus, &[0; 80], 0).unwrap();
+        let fc = rx_frame(B, &[0x32, 0, 0]);
+        assert_eq!(
+            channel.handle_frame(&mut bus, &fc, 0),
+            Err(IsoTpError::Overflow)
+        );
+        assert_eq!(
+            channel.send(&mut bus, &[0; 101], 0),
+            Err(IsoTpError::MessageTooLong)
+        );
+    }
+}
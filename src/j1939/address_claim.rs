@@ -0,0 +1,424 @@
+//! Address claiming (J1939-81): the NAME, claiming our address & defending it, and moving to
+//! another address or giving up when a node with a higher priority NAME claims it
+
+use super::{
+    parse_request, transmit, J1939Error, J1939Id, ADDRESS_GLOBAL, ADDRESS_NULL, DEFAULT_PRIORITY,
+    PGN_ADDRESS_CLAIMED,
+};
+use crate::{RxFDFrame, Transmitter};
+
+/// How long a claim has to go uncontested before the address can be used
+pub const ADDRESS_CLAIM_TIMEOUT_US: u32 = 250_000;
+
+// Addresses an arbitrary address capable node picks from when it loses its preferred one
+const ARBITRARY_ADDRESS_FIRST: u8 = 128;
+const ARBITRARY_ADDRESS_LAST: u8 = 247;
+
+/// The 64-bit NAME identifying a node, a lower value wins address contention
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Name {
+    /// 21 bits, unique per manufacturer
+    pub identity_number: u32,
+    /// 11 bits
+    pub manufacturer_code: u16,
+    /// 3 bits
+    pub ecu_instance: u8,
+    /// 5 bits
+    pub function_instance: u8,
+    pub function: u8,
+    /// 7 bits
+    pub vehicle_system: u8,
+    /// 4 bits
+    pub vehicle_system_instance: u8,
+    /// 3 bits
+    pub industry_group: u8,
+    pub arbitrary_address_capable: bool,
+}
+
+impl Name {
+    pub fn to_u64(&self) -> u64 {
+        (self.identity_number as u64 & 0x1F_FFFF)
+            | ((self.manufacturer_code as u64 & 0x7FF) << 21)
+            | ((self.ecu_instance as u64 & 0b111) << 32)
+            | ((self.function_instance as u64 & 0x1F) << 35)
+            | ((self.function as u64) << 40)
+            | ((self.vehicle_system as u64 & 0x7F) << 49)
+            | ((self.vehicle_system_instance as u64 & 0xF) << 56)
+            | ((self.industry_group as u64 & 0b111) << 60)
+            | ((self.arbitrary_address_capable as u64) << 63)
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Self {
+            identity_number: (value & 0x1F_FFFF) as u32,
+            manufacturer_code: ((value >> 21) & 0x7FF) as u16,
+            ecu_instance: ((value >> 32) & 0b111) as u8,
+            function_instance: ((value >> 35) & 0x1F) as u8,
+            function: (value >> 40) as u8,
+            vehicle_system: ((value >> 49) & 0x7F) as u8,
+            vehicle_system_instance: ((value >> 56) & 0xF) as u8,
+            industry_group: ((value >> 60) & 0b111) as u8,
+            arbitrary_address_capable: (value >> 63) != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressClaimEvent {
+    /// The claim went uncontested, the address can be used
+    Claimed { address: u8 },
+    /// A node with a higher priority NAME took our address
+    AddressLost { address: u8 },
+    /// No address is left for us, we only answer requests now
+    CannotClaim,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClaimState {
+    Idle,
+    Claiming { since_us: u32 },
+    Claimed,
+    CannotClaim,
+}
+
+pub struct AddressClaimer {
+    name: Name,
+    preferred_address: u8,
+    address: u8,
+    state: ClaimState,
+    taken: [u32; 8], // Addresses claimed by other nodes, one bit each
+}
+
+impl AddressClaimer {
+    pub fn new(name: Name, preferred_address: u8) -> Self {
+        Self {
+            name,
+            preferred_address,
+            address: preferred_address,
+            state: ClaimState::Idle,
+            taken: [0; 8],
+        }
+    }
+
+    pub fn name(&self) -> Name {
+        self.name
+    }
+
+    /// Our address once the claim succeeded, frames can only be sent from it after that
+    pub fn address(&self) -> Option<u8> {
+        match self.state {
+            ClaimState::Claimed => Some(self.address),
+            _ => None,
+        }
+    }
+
+    pub fn cannot_claim(&self) -> bool {
+        self.state == ClaimState::CannotClaim
+    }
+
+    /// Starts claiming the preferred address, done after power up
+    pub fn start<T: Transmitter>(&mut self, can: &mut T, now_us: u32) -> Result<(), J1939Error> {
+        self.address = self.preferred_address;
+        self.claim(can, now_us)
+    }
+
+    /// Feeds a received frame in, handles requests for the address claim & claims of other
+    /// nodes
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<AddressClaimEvent>, J1939Error> {
+        if let Some(request) = parse_request(frame) {
+            if request.pgn == PGN_ADDRESS_CLAIMED
+                && (request.destination_address == ADDRESS_GLOBAL
+                    || request.destination_address == self.address)
+            {
+                match self.state {
+                    ClaimState::Idle => (),
+                    ClaimState::CannotClaim => self.send_claim(can, ADDRESS_NULL)?,
+                    _ => self.send_claim(can, self.address)?,
+                }
+            }
+
+            return Ok(None);
+        }
+
+        let id = match J1939Id::from_id(frame.id) {
+            Some(id) if id.pgn == PGN_ADDRESS_CLAIMED && frame.buffer_len >= 8 => id,
+            _ => return Ok(None),
+        };
+
+        if id.source_address == ADDRESS_NULL {
+            // Another node that couldn't claim an address
+            return Ok(None);
+        }
+
+        self.taken[id.source_address as usize / 32] |= 1 << (id.source_address % 32);
+
+        let contested = match self.state {
+            ClaimState::Claiming { .. } | ClaimState::Claimed => id.source_address == self.address,
+            _ => false,
+        };
+
+        if !contested {
+            return Ok(None);
+        }
+
+        let mut their_name = [0_u8; 8];
+        their_name.copy_from_slice(&frame.buffer[..8]);
+
+        if self.name.to_u64() < u64::from_le_bytes(their_name) {
+            // We win & defend the address
+            self.send_claim(can, self.address)?;
+            return Ok(None);
+        }
+
+        let lost_address = self.address;
+        let was_claimed = self.state == ClaimState::Claimed;
+
+        match self.free_address() {
+            Some(address) if self.name.arbitrary_address_capable => {
+                self.address = address;
+                self.claim(can, now_us)?;
+
+                Ok(if was_claimed {
+                    Some(AddressClaimEvent::AddressLost {
+                        address: lost_address,
+                    })
+                } else {
+                    None
+                })
+            }
+            _ => {
+                // J1939-81 wants a pseudo random delay before this, to avoid collisions with
+                // other nodes giving up at the same time. It's sent right away here.
+                self.state = ClaimState::CannotClaim;
+                self.send_claim(can, ADDRESS_NULL)?;
+
+                Ok(Some(AddressClaimEvent::CannotClaim))
+            }
+        }
+    }
+
+    /// Finishes the claim once it went uncontested for long enough
+    pub fn poll(&mut self, now_us: u32) -> Option<AddressClaimEvent> {
+        if let ClaimState::Claiming { since_us } = self.state {
+            if now_us.wrapping_sub(since_us) >= ADDRESS_CLAIM_TIMEOUT_US {
+                self.state = ClaimState::Claimed;
+
+                return Some(AddressClaimEvent::Claimed {
+                    address: self.address,
+                });
+            }
+        }
+
+        None
+    }
+
+    fn claim<T: Transmitter>(&mut self, can: &mut T, now_us: u32) -> Result<(), J1939Error> {
+        self.state = ClaimState::Claiming { since_us: now_us };
+        self.send_claim(can, self.address)
+    }
+
+    fn free_address(&self) -> Option<u8> {
+        (ARBITRARY_ADDRESS_FIRST..=ARBITRARY_ADDRESS_LAST).find(|address| {
+            *address != self.address
+                && self.taken[*address as usize / 32] & (1 << (address % 32)) == 0
+        })
+    }
+
+    fn send_claim<T: Transmitter>(
+        &self,
+        can: &mut T,
+        source_address: u8,
+    ) -> Result<(), J1939Error> {
+        let id = J1939Id::new(
+            DEFAULT_PRIORITY,
+            PGN_ADDRESS_CLAIMED,
+            source_address,
+            ADDRESS_GLOBAL,
+        );
+
+        transmit(can, id, &self.name.to_u64().to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::j1939::send_request;
+    use crate::mock::{rx_frame, Bus};
+
+    fn name(identity_number: u32, arbitrary_address_capable: bool) -> Name {
+        Name {
+            identity_number,
+            arbitrary_address_capable,
+            ..Default::default()
+        }
+    }
+
+    fn claim_frame(name: Name, address: u8) -> RxFDFrame {
+        let id = J1939Id::new(
+            DEFAULT_PRIORITY,
+            PGN_ADDRESS_CLAIMED,
+            address,
+            ADDRESS_GLOBAL,
+        );
+        rx_frame(id.to_id(), &name.to_u64().to_le_bytes())
+    }
+
+    // The source address & NAME of the last claim sent
+    fn last_claim(bus: &mut Bus) -> (u8, Name) {
+        let frame = bus.take().pop().unwrap();
+        let id = J1939Id::from_id(frame.id).unwrap();
+        let mut name = [0; 8];
+        name.copy_from_slice(&frame.data);
+
+        assert_eq!(id.pgn, PGN_ADDRESS_CLAIMED);
+        (id.source_address, Name::from_u64(u64::from_le_bytes(name)))
+    }
+
+    #[test]
+    fn name_round_trip() {
+        let name = Name {
+            identity_number: 0x1F_FFFF,
+            manufacturer_code: 0x7FF,
+            ecu_instance: 5,
+            function_instance: 0x1F,
+            function: 0x81,
+            vehicle_system: 0x7F,
+            vehicle_system_instance: 0xA,
+            industry_group: 2,
+            arbitrary_address_capable: true,
+        };
+
+        assert_eq!(Name::from_u64(name.to_u64()), name);
+        assert_eq!(name.to_u64() >> 63, 1);
+    }
+
+    #[test]
+    fn uncontested_claim() {
+        let mut bus = Bus::default();
+        let mut claimer = AddressClaimer::new(name(5, false), 0x80);
+
+        claimer.start(&mut bus, 0).unwrap();
+        assert_eq!(last_claim(&mut bus), (0x80, name(5, false)));
+        assert_eq!(claimer.address(), None);
+        assert_eq!(claimer.poll(ADDRESS_CLAIM_TIMEOUT_US - 1), None);
+        assert_eq!(
+            claimer.poll(ADDRESS_CLAIM_TIMEOUT_US),
+            Some(AddressClaimEvent::Claimed { address: 0x80 })
+        );
+        assert_eq!(claimer.address(), Some(0x80));
+
+        // Requests for the address claim are answered, to everyone & to us
+        for destination in [ADDRESS_GLOBAL, 0x80] {
+            send_request(&mut bus, PGN_ADDRESS_CLAIMED, 0x10, destination).unwrap();
+            let request = bus.take().pop().unwrap().to_rx();
+            claimer.handle_frame(&mut bus, &request, 0).unwrap();
+            assert_eq!(last_claim(&mut bus).0, 0x80);
+        }
+    }
+
+    #[test]
+    fn contention() {
+        let mut bus = Bus::default();
+        let mut claimer = AddressClaimer::new(name(5, true), 0x80);
+
+        claimer.start(&mut bus, 0).unwrap();
+        claimer.poll(ADDRESS_CLAIM_TIMEOUT_US);
+        bus.take();
+
+        // A higher NAME claiming our address gets our claim back
+        let event = claimer
+            .handle_frame(&mut bus, &claim_frame(name(9, true), 0x80), 0)
+            .unwrap();
+        assert_eq!(event, None);
+        assert_eq!(last_claim(&mut bus).0, 0x80);
+        assert_eq!(claimer.address(), Some(0x80));
+
+        // Claims for other addresses are noted, so we skip them when moving
+        let event = claimer
+            .handle_frame(&mut bus, &claim_frame(name(9, true), 0x81), 0)
+            .unwrap();
+        assert_eq!(event, None);
+        assert!(bus.sent.is_empty());
+
+        // A lower NAME takes it, we move to the next free address & claim it again
+        let event = claimer
+            .handle_frame(&mut bus, &claim_frame(name(3, true), 0x80), 1_000)
+            .unwrap();
+        assert_eq!(
+            event,
+            Some(AddressClaimEvent::AddressLost { address: 0x80 })
+        );
+        assert_eq!(last_claim(&mut bus).0, 0x82);
+        assert_eq!(claimer.address(), None);
+        assert_eq!(claimer.poll(ADDRESS_CLAIM_TIMEOUT_US), None);
+        assert_eq!(
+            claimer.poll(1_000 + ADDRESS_CLAIM_TIMEOUT_US),
+            Some(AddressClaimEvent::Claimed { address: 0x82 })
+        );
+
+        // Losing while still claiming moves on quietly
+        let event = claimer
+            .handle_frame(&mut bus, &claim_frame(name(1, true), 0x82), 0)
+            .unwrap();
+        assert_eq!(
+            event,
+            Some(AddressClaimEvent::AddressLost { address: 0x82 })
+        );
+        let event = claimer
+            .handle_frame(&mut bus, &claim_frame(name(1, true), 0x83), 0)
+            .unwrap();
+        assert_eq!(event, None);
+        assert_eq!(last_claim(&mut bus).0, 0x84);
+    }
+
+    #[test]
+    fn cannot_claim() {
+        let mut bus = Bus::default();
+        let mut claimer = AddressClaimer::new(name(5, false), 0x80);
+
+        claimer.start(&mut bus, 0).unwrap();
+        bus.take();
+
+        let event = claimer
+            .handle_frame(&mut bus, &claim_frame(name(3, false), 0x80), 0)
+            .unwrap();
+        assert_eq!(event, Some(AddressClaimEvent::CannotClaim));
+        assert_eq!(last_claim(&mut bus), (ADDRESS_NULL, name(5, false)));
+        assert!(claimer.cannot_claim());
+        assert_eq!(claimer.poll(ADDRESS_CLAIM_TIMEOUT_US), None);
+        assert_eq!(claimer.address(), None);
+
+        // Requests still get the cannot claim message
+        send_request(&mut bus, PGN_ADDRESS_CLAIMED, 0x10, ADDRESS_GLOBAL).unwrap();
+        let request = bus.take().pop().unwrap().to_rx();
+        claimer.handle_frame(&mut bus, &request, 0).unwrap();
+        assert_eq!(last_claim(&mut bus).0, ADDRESS_NULL);
+    }
+
+    #[test]
+    fn cannot_claim_with_every_address_taken() {
+        let mut bus = Bus::default();
+        let mut claimer = AddressClaimer::new(name(5, true), 0x80);
+
+        for address in ARBITRARY_ADDRESS_FIRST..=ARBITRARY_ADDRESS_LAST {
+            claimer
+                .handle_frame(&mut bus, &claim_frame(name(9, false), address), 0)
+                .unwrap();
+        }
+
+        claimer.start(&mut bus, 0).unwrap();
+        bus.take();
+
+        let event = claimer
+            .handle_frame(&mut bus, &claim_frame(name(3, false), 0x80), 0)
+            .unwrap();
+        assert_eq!(event, Some(AddressClaimEvent::CannotClaim));
+        assert_eq!(last_claim(&mut bus).0, ADDRESS_NULL);
+    }
+}
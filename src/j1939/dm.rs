@@ -0,0 +1,737 @@
+//! Diagnostic messages (J1939-73): active & previously active DTCs with DM1/DM2, and clearing
+//! them with DM3/DM11
+//!
+//! `Diagnostics` keeps our own DTCs, broadcasts DM1 every second (and right away when the
+//! active DTCs change) and answers requests for DM1/DM2/DM3/DM11. Messages with more than one DTC
+//! don't fit in a frame and go out through the transport protocol.
+
+use super::transport::J1939Transport;
+use super::{
+    parse_request, send_acknowledgement, send_request, AckControl, J1939Error, ADDRESS_GLOBAL,
+    DEFAULT_PRIORITY,
+};
+use crate::{RxFDFrame, Transmitter};
+
+pub const PGN_DM1: u32 = 0xFECA;
+pub const PGN_DM2: u32 = 0xFECB;
+pub const PGN_DM3: u32 = 0xFECC;
+pub const PGN_DM11: u32 = 0xFED3;
+
+pub const DM1_PERIOD_US: u32 = 1_000_000;
+pub const MAX_DTCS: usize = 32;
+
+const DM_MAX_LEN: usize = 2 + 4 * MAX_DTCS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LampStatus {
+    Off,
+    On,
+    Error,
+    NotAvailable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashStatus {
+    Slow, // 1 Hz
+    Fast, // 2 Hz
+    Reserved,
+    Off, // Steady, or not available
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lamp {
+    pub status: LampStatus,
+    pub flash: FlashStatus,
+}
+
+impl Lamp {
+    pub const OFF: Lamp = Lamp {
+        status: LampStatus::Off,
+        flash: FlashStatus::Off,
+    };
+
+    fn from_bits(status: u8, flash: u8) -> Self {
+        Self {
+            status: match status & 0b11 {
+                0b00 => LampStatus::Off,
+                0b01 => LampStatus::On,
+                0b10 => LampStatus::Error,
+                _ => LampStatus::NotAvailable,
+            },
+            flash: match flash & 0b11 {
+                0b00 => FlashStatus::Slow,
+                0b01 => FlashStatus::Fast,
+                0b10 => FlashStatus::Reserved,
+                _ => FlashStatus::Off,
+            },
+        }
+    }
+
+    fn to_bits(self) -> (u8, u8) {
+        let status = match self.status {
+            LampStatus::Off => 0b00,
+            LampStatus::On => 0b01,
+            LampStatus::Error => 0b10,
+            LampStatus::NotAvailable => 0b11,
+        };
+        let flash = match self.flash {
+            FlashStatus::Slow => 0b00,
+            FlashStatus::Fast => 0b01,
+            FlashStatus::Reserved => 0b10,
+            FlashStatus::Off => 0b11,
+        };
+
+        (status, flash)
+    }
+}
+
+/// The lamp status at the start of every DM1/DM2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lamps {
+    pub malfunction_indicator: Lamp,
+    pub red_stop: Lamp,
+    pub amber_warning: Lamp,
+    pub protect: Lamp,
+}
+
+impl Default for Lamps {
+    fn default() -> Self {
+        Self {
+            malfunction_indicator: Lamp::OFF,
+            red_stop: Lamp::OFF,
+            amber_warning: Lamp::OFF,
+            protect: Lamp::OFF,
+        }
+    }
+}
+
+impl Lamps {
+    pub fn to_bytes(&self) -> [u8; 2] {
+        let lamps = [
+            self.malfunction_indicator,
+            self.red_stop,
+            self.amber_warning,
+            self.protect,
+        ];
+        let mut bytes = [0_u8; 2];
+
+        for (position, lamp) in lamps.iter().enumerate() {
+            let (status, flash) = lamp.to_bits();
+            let shift = 6 - position * 2;
+
+            bytes[0] |= status << shift;
+            bytes[1] |= flash << shift;
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; 2]) -> Self {
+        let lamp = |shift: u8| Lamp::from_bits(bytes[0] >> shift, bytes[1] >> shift);
+
+        Self {
+            malfunction_indicator: lamp(6),
+            red_stop: lamp(4),
+            amber_warning: lamp(2),
+            protect: lamp(0),
+        }
+    }
+}
+
+/// A diagnostic trouble code, in the SPN conversion method 4 layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Dtc {
+    pub spn: u32,             // Suspect parameter number, 19 bits
+    pub fmi: u8,              // Failure mode identifier, 5 bits
+    pub occurrence_count: u8, // 7 bits, 127 means it's been saturated
+}
+
+impl Dtc {
+    pub fn new(spn: u32, fmi: u8) -> Self {
+        Self {
+            spn,
+            fmi,
+            occurrence_count: 1,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 4] {
+        [
+            self.spn as u8,
+            (self.spn >> 8) as u8,
+            (((self.spn >> 16) as u8 & 0b111) << 5) | (self.fmi & 0x1F),
+            self.occurrence_count & 0x7F,
+        ]
+    }
+
+    pub fn from_bytes(bytes: [u8; 4]) -> Self {
+        Self {
+            spn: bytes[0] as u32 | (bytes[1] as u32) << 8 | ((bytes[2] >> 5) as u32) << 16,
+            fmi: bytes[2] & 0x1F,
+            occurrence_count: bytes[3] & 0x7F,
+        }
+    }
+
+    fn same_fault(&self, other: &Dtc) -> bool {
+        self.spn == other.spn && self.fmi == other.fmi
+    }
+}
+
+/// Encodes a DM1/DM2 payload, an empty DTC list is sent as the all zero DTC. Returns the
+/// payload length.
+pub fn encode_dm(lamps: &Lamps, dtcs: &[Dtc], buffer: &mut [u8]) -> Result<usize, J1939Error> {
+    let len = 2 + 4 * dtcs.len().max(1);
+
+    if buffer.len() < len {
+        return Err(J1939Error::BufferTooSmall);
+    }
+
+    buffer[..2].copy_from_slice(&lamps.to_bytes());
+
+    if dtcs.is_empty() {
+        buffer[2..6].copy_from_slice(&[0; 4]);
+    }
+
+    for (dtc, bytes) in dtcs.iter().zip(buffer[2..len].chunks_exact_mut(4)) {
+        bytes.copy_from_slice(&dtc.to_bytes());
+    }
+
+    Ok(len)
+}
+
+/// Decodes a DM1/DM2 payload into the lamps & an iterator over its DTCs
+pub fn decode_dm(data: &[u8]) -> Option<(Lamps, DtcIter<'_>)> {
+    if data.len() < 2 {
+        return None;
+    }
+
+    Some((
+        Lamps::from_bytes([data[0], data[1]]),
+        DtcIter { data: &data[2..] },
+    ))
+}
+
+pub struct DtcIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for DtcIter<'a> {
+    type Item = Dtc;
+
+    fn next(&mut self) -> Option<Dtc> {
+        while self.data.len() >= 4 {
+            let bytes = [self.data[0], self.data[1], self.data[2], self.data[3]];
+            self.data = &self.data[4..];
+
+            // Skip the all zero "no DTC" entry & the padding of single frame messages
+            if bytes != [0; 4] && bytes != [0xFF; 4] {
+                return Some(Dtc::from_bytes(bytes));
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmClear {
+    PreviouslyActive, // DM3
+    Active,           // DM11
+}
+
+impl DmClear {
+    pub fn pgn(self) -> u32 {
+        match self {
+            DmClear::PreviouslyActive => PGN_DM3,
+            DmClear::Active => PGN_DM11,
+        }
+    }
+}
+
+/// Asks a node (or every node) to clear its DTCs, it answers with an acknowledgement
+pub fn send_clear_request<T: Transmitter>(
+    can: &mut T,
+    clear: DmClear,
+    source_address: u8,
+    destination_address: u8,
+) -> Result<(), J1939Error> {
+    send_request(can, clear.pgn(), source_address, destination_address)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmEvent {
+    Cleared { clear: DmClear, requester: u8 },
+}
+
+pub struct Diagnostics {
+    lamps: Lamps,
+    active: [Dtc; MAX_DTCS],
+    active_count: usize,
+    previously_active: [Dtc; MAX_DTCS],
+    previously_active_count: usize,
+    dm1_due: bool,
+    last_dm1_us: Option<u32>,
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self {
+            lamps: Lamps::default(),
+            active: [Dtc::default(); MAX_DTCS],
+            active_count: 0,
+            previously_active: [Dtc::default(); MAX_DTCS],
+            previously_active_count: 0,
+            dm1_due: false,
+            last_dm1_us: None,
+        }
+    }
+
+    pub fn lamps(&self) -> Lamps {
+        self.lamps
+    }
+
+    pub fn set_lamps(&mut self, lamps: Lamps) {
+        if lamps != self.lamps {
+            self.lamps = lamps;
+            self.dm1_due = true;
+        }
+    }
+
+    pub fn active(&self) -> &[Dtc] {
+        &self.active[..self.active_count]
+    }
+
+    pub fn previously_active(&self) -> &[Dtc] {
+        &self.previously_active[..self.previously_active_count]
+    }
+
+    /// Makes a fault active. A fault that was active before keeps counting its occurrences.
+    pub fn activate(&mut self, spn: u32, fmi: u8) -> Result<(), J1939Error> {
+        let mut dtc = Dtc::new(spn, fmi);
+
+        if self.active().iter().any(|active| active.same_fault(&dtc)) {
+            return Ok(());
+        }
+
+        if self.active_count >= MAX_DTCS {
+            return Err(J1939Error::NoSpace);
+        }
+
+        if let Some(position) = self
+            .previously_active()
+            .iter()
+            .position(|previous| previous.same_fault(&dtc))
+        {
+            dtc.occurrence_count = (self.previously_active[position].occurrence_count + 1).min(127);
+            remove(
+                &mut self.previously_active,
+                &mut self.previously_active_count,
+                position,
+            );
+        }
+
+        self.active[self.active_count] = dtc;
+        self.active_count += 1;
+        self.dm1_due = true;
+
+        Ok(())
+    }
+
+    /// Moves an active fault to the previously active ones, dropping the oldest of those when
+    /// they're full
+    pub fn deactivate(&mut self, spn: u32, fmi: u8) {
+        let fault = Dtc::new(spn, fmi);
+
+        let position = match self
+            .active()
+            .iter()
+            .position(|active| active.same_fault(&fault))
+        {
+            Some(position) => position,
+            None => return,
+        };
+
+        let dtc = self.active[position];
+        remove(&mut self.active, &mut self.active_count, position);
+
+        if self.previously_active_count >= MAX_DTCS {
+            remove(
+                &mut self.previously_active,
+                &mut self.previously_active_count,
+                0,
+            );
+        }
+
+        self.previously_active[self.previously_active_count] = dtc;
+        self.previously_active_count += 1;
+        self.dm1_due = true;
+    }
+
+    /// What a DM11 does: forgets the active faults, they'll come back if they're still there
+    pub fn clear_active(&mut self) {
+        self.active_count = 0;
+        self.dm1_due = true;
+    }
+
+    /// What a DM3 does
+    pub fn clear_previously_active(&mut self) {
+        self.previously_active_count = 0;
+    }
+
+    /// Broadcasts DM1 every second & when the active faults or lamps changed
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        transport: &mut J1939Transport,
+        now_us: u32,
+    ) -> Result<(), J1939Error> {
+        let due = match self.last_dm1_us {
+            Some(last_us) => self.dm1_due || now_us.wrapping_sub(last_us) >= DM1_PERIOD_US,
+            None => true,
+        };
+
+        // A multi-packet DM1 waits for the transport to be free
+        if !due || transport.is_transmitting() {
+            return Ok(());
+        }
+
+        self.send_dm(can, transport, PGN_DM1, ADDRESS_GLOBAL, now_us)?;
+
+        self.dm1_due = false;
+        self.last_dm1_us = Some(now_us);
+
+        Ok(())
+    }
+
+    /// Feeds a received frame in, answers requests for DM1/DM2 & carries out DM3/DM11
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        transport: &mut J1939Transport,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<DmEvent>, J1939Error> {
+        let request = match parse_request(frame) {
+            Some(request) => request,
+            None => return Ok(None),
+        };
+
+        let address = transport.address();
+
+        if request.destination_address != address && request.destination_address != ADDRESS_GLOBAL {
+            return Ok(None);
+        }
+
+        // Answers to global requests are broadcast
+        let destination = if request.destination_address == ADDRESS_GLOBAL {
+            ADDRESS_GLOBAL
+        } else {
+            request.source_address
+        };
+
+        match request.pgn {
+            PGN_DM1 | PGN_DM2 => {
+                if transport.is_transmitting() {
+                    send_acknowledgement(
+                        can,
+                        AckControl::CannotRespond,
+                        request.pgn,
+                        address,
+                        request.source_address,
+                    )?;
+                } else {
+                    self.send_dm(can, transport, request.pgn, destination, now_us)?;
+                }
+
+                Ok(None)
+            }
+            PGN_DM3 | PGN_DM11 => {
+                let clear = if request.pgn == PGN_DM3 {
+                    self.clear_previously_active();
+                    DmClear::PreviouslyActive
+                } else {
+                    self.clear_active();
+                    DmClear::Active
+                };
+
+                send_acknowledgement(
+                    can,
+                    AckControl::Ack,
+                    request.pgn,
+                    address,
+                    request.source_address,
+                )?;
+
+                Ok(Some(DmEvent::Cleared {
+                    clear,
+                    requester: request.source_address,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn send_dm<T: Transmitter>(
+        &self,
+        can: &mut T,
+        transport: &mut J1939Transport,
+        pgn: u32,
+        destination: u8,
+        now_us: u32,
+    ) -> Result<(), J1939Error> {
+        let dtcs = if pgn == PGN_DM1 {
+            self.active()
+        } else {
+            self.previously_active()
+        };
+
+        let mut buffer = [0_u8; DM_MAX_LEN];
+        let len = encode_dm(&self.lamps, dtcs, &mut buffer)?;
+
+        transport.send(
+            can,
+            DEFAULT_PRIORITY,
+            pgn,
+            destination,
+            &buffer[..len],
+            now_us,
+        )?;
+
+        Ok(())
+    }
+}
+
+fn remove(dtcs: &mut [Dtc; MAX_DTCS], count: &mut usize, position: usize) {
+    dtcs.copy_within(position + 1..*count, position);
+    *count -= 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::j1939::transport::{TransportEvent, BAM_PACKET_INTERVAL_US, TP_MAX_LEN};
+    use crate::j1939::{J1939Id, PGN_ACKNOWLEDGEMENT};
+    use crate::mock::Bus;
+
+    const ADDRESS: u8 = 0x30;
+    const TESTER: u8 = 0xF9;
+
+    fn lamps() -> Lamps {
+        Lamps {
+            malfunction_indicator: Lamp {
+                status: LampStatus::On,
+                flash: FlashStatus::Slow,
+            },
+            red_stop: Lamp::OFF,
+            amber_warning: Lamp {
+                status: LampStatus::On,
+                flash: FlashStatus::Fast,
+            },
+            protect: Lamp {
+                status: LampStatus::NotAvailable,
+                flash: FlashStatus::Off,
+            },
+        }
+    }
+
+    #[test]
+    fn lamp_packing() {
+        // MIL, red stop, amber warning & protect from the top bits down
+        assert_eq!(lamps().to_bytes(), [0b01_00_01_11, 0b00_11_01_11]);
+        assert_eq!(Lamps::from_bytes([0b01_00_01_11, 0b00_11_01_11]), lamps());
+        assert_eq!(Lamps::default().to_bytes(), [0x00, 0xFF]);
+    }
+
+    #[test]
+    fn dtc_packing() {
+        let dtc = Dtc {
+            spn: 0x5_1234,
+            fmi: 3,
+            occurrence_count: 2,
+        };
+        assert_eq!(dtc.to_bytes(), [0x34, 0x12, 0b101_00011, 0x02]);
+        assert_eq!(Dtc::from_bytes(dtc.to_bytes()), dtc);
+
+        let max = Dtc {
+            spn: 0x7_FFFF,
+            fmi: 0x1F,
+            occurrence_count: 127,
+        };
+        assert_eq!(max.to_bytes(), [0xFF, 0xFF, 0xFF, 0x7F]);
+        assert_eq!(Dtc::from_bytes(max.to_bytes()), max);
+
+        // The SPN conversion method bit is ignored
+        assert_eq!(Dtc::from_bytes([0xFF, 0xFF, 0xFF, 0xFF]), max);
+    }
+
+    #[test]
+    fn encode_decode_multiple_dtcs() {
+        let dtcs = [Dtc::new(100, 1), Dtc::new(0x4_0000, 31), Dtc::new(91, 4)];
+        let mut buffer = [0; DM_MAX_LEN];
+
+        let len = encode_dm(&lamps(), &dtcs, &mut buffer).unwrap();
+        assert_eq!(len, 14);
+        assert_eq!(
+            buffer[..len],
+            [
+                0b01_00_01_11,
+                0b00_11_01_11,
+                100,
+                0,
+                1,
+                1,
+                0,
+                0,
+                0b100_11111,
+                1,
+                91,
+                0,
+                4,
+                1
+            ]
+        );
+
+        let (decoded_lamps, decoded) = decode_dm(&buffer[..len]).unwrap();
+        assert_eq!(decoded_lamps, lamps());
+        assert!(decoded.eq(dtcs.iter().copied()));
+
+        assert_eq!(
+            encode_dm(&lamps(), &dtcs, &mut buffer[..13]),
+            Err(J1939Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn encode_decode_no_dtcs() {
+        let mut buffer = [0xAA; 8];
+
+        let len = encode_dm(&Lamps::default(), &[], &mut buffer).unwrap();
+        assert_eq!(buffer[..len], [0x00, 0xFF, 0, 0, 0, 0]);
+
+        // Neither the "no DTC" entry nor the single frame padding are DTCs
+        let (_, mut dtcs) = decode_dm(&[0x00, 0xFF, 0, 0, 0, 0, 0xFF, 0xFF]).unwrap();
+        assert_eq!(dtcs.next(), None);
+        assert!(decode_dm(&[0x00]).is_none());
+    }
+
+    #[test]
+    fn dm1_single_frame() {
+        let (mut tx, mut rx) = ([0; TP_MAX_LEN], [0; TP_MAX_LEN]);
+        let mut transport = J1939Transport::new(ADDRESS, &mut tx, &mut rx);
+        let mut diagnostics = Diagnostics::new();
+        let mut bus = Bus::default();
+
+        diagnostics.activate(190, 2).unwrap();
+        diagnostics.poll(&mut bus, &mut transport, 0).unwrap();
+
+        let sent = bus.take();
+        let id = J1939Id::from_id(sent[0].id).unwrap();
+        assert_eq!((id.pgn, id.source_address), (PGN_DM1, ADDRESS));
+        assert_eq!(sent[0].data, [0x00, 0xFF, 190, 0, 2, 1, 0xFF, 0xFF]);
+
+        // Only again once the period is up
+        diagnostics
+            .poll(&mut bus, &mut transport, DM1_PERIOD_US - 1)
+            .unwrap();
+        assert!(bus.take().is_empty());
+        diagnostics
+            .poll(&mut bus, &mut transport, DM1_PERIOD_US)
+            .unwrap();
+        assert_eq!(bus.take().len(), 1);
+    }
+
+    #[test]
+    fn dm1_multiple_dtcs_over_bam() {
+        let (mut tx, mut rx) = ([0; TP_MAX_LEN], [0; TP_MAX_LEN]);
+        let mut transport = J1939Transport::new(ADDRESS, &mut tx, &mut rx);
+        let (mut tool_tx, mut tool_rx) = ([0; TP_MAX_LEN], [0; TP_MAX_LEN]);
+        let mut tool = J1939Transport::new(TESTER, &mut tool_tx, &mut tool_rx);
+        let mut diagnostics = Diagnostics::new();
+        let mut bus = Bus::default();
+
+        diagnostics.set_lamps(lamps());
+        diagnostics.activate(100, 1).unwrap();
+        diagnostics.activate(0x4_0000, 31).unwrap();
+        diagnostics.poll(&mut bus, &mut transport, 0).unwrap();
+
+        // TP.CM BAM & then two TP.DT packets for the 10 bytes
+        let mut event = None;
+        for packet in 0..3 {
+            let now_us = packet * BAM_PACKET_INTERVAL_US;
+            transport.poll(&mut bus, now_us).unwrap();
+
+            for frame in bus.take() {
+                event = tool.handle_frame(&mut bus, &frame.to_rx(), now_us).unwrap();
+            }
+        }
+
+        assert_eq!(
+            event,
+            Some(TransportEvent::Received {
+                pgn: PGN_DM1,
+                source_address: ADDRESS,
+                destination_address: ADDRESS_GLOBAL,
+                len: 10,
+            })
+        );
+
+        let (received_lamps, dtcs) = decode_dm(tool.received()).unwrap();
+        assert_eq!(received_lamps, lamps());
+        assert!(dtcs.eq([Dtc::new(100, 1), Dtc::new(0x4_0000, 31)]));
+    }
+
+    #[test]
+    fn occurrence_count_and_dm2() {
+        let (mut tx, mut rx) = ([0; TP_MAX_LEN], [0; TP_MAX_LEN]);
+        let mut transport = J1939Transport::new(ADDRESS, &mut tx, &mut rx);
+        let mut diagnostics = Diagnostics::new();
+        let mut bus = Bus::default();
+
+        diagnostics.activate(520, 9).unwrap();
+        diagnostics.deactivate(520, 9);
+        diagnostics.activate(520, 9).unwrap();
+        diagnostics.deactivate(520, 9);
+        assert!(diagnostics.active().is_empty());
+        assert_eq!(diagnostics.previously_active()[0].occurrence_count, 2);
+
+        send_request(&mut bus, PGN_DM2, TESTER, ADDRESS).unwrap();
+        let request = bus.take().pop().unwrap().to_rx();
+        diagnostics
+            .handle_frame(&mut bus, &mut transport, &request, 0)
+            .unwrap();
+
+        let sent = bus.take();
+        assert_eq!(J1939Id::from_id(sent[0].id).unwrap().pgn, PGN_DM2);
+        assert_eq!(sent[0].data[2..6], [0x08, 0x02, 9, 2]);
+    }
+
+    #[test]
+    fn dm11_clears_active() {
+        let (mut tx, mut rx) = ([0; TP_MAX_LEN], [0; TP_MAX_LEN]);
+        let mut transport = J1939Transport::new(ADDRESS, &mut tx, &mut rx);
+        let mut diagnostics = Diagnostics::new();
+        let mut bus = Bus::default();
+
+        diagnostics.activate(84, 0).unwrap();
+        send_request(&mut bus, PGN_DM11, TESTER, ADDRESS_GLOBAL).unwrap();
+        let request = bus.take().pop().unwrap().to_rx();
+
+        assert_eq!(
+            diagnostics.handle_frame(&mut bus, &mut transport, &request, 0),
+            Ok(Some(DmEvent::Cleared {
+                clear: DmClear::Active,
+                requester: TESTER,
+            }))
+        );
+        assert!(diagnostics.active().is_empty());
+
+        let ack = bus.take().pop().unwrap();
+        assert_eq!(J1939Id::from_id(ack.id).unwrap().pgn, PGN_ACKNOWLEDGEMENT);
+    }
+}
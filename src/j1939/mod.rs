@@ -0,0 +1,231 @@
+//! SAE J1939 on top of the driver's 29-bit IDs
+//!
+//! Like the other protocol layers these don't own the bus, received frames are handed to
+//! `handle_frame` and `poll` sends whatever is due. Times are in microseconds from a free running
+//! time base that's allowed to wrap. J1939 frames are 8 bytes long, shorter payloads are padded
+//! with 0xFF.
+
+pub mod address_claim;
+pub mod dm;
+pub mod transport;
+
+use crate::can_error::RxTxError;
+use crate::config::{Id, RxMailboxConfig};
+use crate::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+
+pub const PGN_ACKNOWLEDGEMENT: u32 = 0xE800;
+pub const PGN_REQUEST: u32 = 0xEA00;
+pub const PGN_TP_DT: u32 = 0xEB00;
+pub const PGN_TP_CM: u32 = 0xEC00;
+pub const PGN_ADDRESS_CLAIMED: u32 = 0xEE00;
+
+pub const ADDRESS_NULL: u8 = 0xFE; // Used as the source address before one is claimed
+pub const ADDRESS_GLOBAL: u8 = 0xFF;
+
+pub const DEFAULT_PRIORITY: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum J1939Error {
+    NoAddress,                       // We don't have a claimed address to send from
+    Busy,                            // A transfer is already in progress
+    NoSpace,                         // A fixed size table is full
+    MessageTooLong,                  // Over the 1785 bytes of the transport protocol
+    BufferTooSmall,                  // The message doesn't fit in the Tx buffer
+    Timeout,                         // The other side stopped responding
+    Aborted(transport::AbortReason), // The transfer was aborted, by either side
+    Bus(RxTxError),                  // The driver refused a frame
+}
+
+/// The fields packed into a J1939 29-bit ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct J1939Id {
+    /// 0 - 7, 0 is the highest
+    pub priority: u8,
+    /// Parameter group number, with the PS byte cleared for PDU1
+    pub pgn: u32,
+    pub source_address: u8,
+    /// Only carried by PDU1 PGNs, always global for PDU2
+    pub destination_address: u8,
+}
+
+impl J1939Id {
+    pub fn new(priority: u8, pgn: u32, source_address: u8, destination_address: u8) -> Self {
+        let pgn = pgn & 0x3_FFFF;
+
+        Self {
+            priority: priority.min(7),
+            pgn: if is_pdu1(pgn) { pgn & 0x3_FF00 } else { pgn },
+            source_address,
+            destination_address: if is_pdu1(pgn) {
+                destination_address
+            } else {
+                ADDRESS_GLOBAL
+            },
+        }
+    }
+
+    pub fn to_id(&self) -> Id {
+        let ps = if is_pdu1(self.pgn) {
+            self.destination_address as u32
+        } else {
+            self.pgn & 0xFF
+        };
+
+        Id::Extended(
+            ((self.priority.min(7) as u32) << 26)
+                | ((self.pgn & 0x3_FF00) << 8)
+                | (ps << 8)
+                | self.source_address as u32,
+        )
+    }
+
+    /// Decodes an extended ID, standard IDs aren't J1939
+    pub fn from_id(id: Id) -> Option<Self> {
+        let id = match id {
+            Id::Extended(id) => id,
+            Id::Standard(_) => return None,
+        };
+
+        let pgn = (id >> 8) & 0x3_FFFF;
+        let source_address = id as u8;
+
+        Some(if is_pdu1(pgn) {
+            Self {
+                priority: ((id >> 26) & 0b111) as u8,
+                pgn: pgn & 0x3_FF00,
+                source_address,
+                destination_address: pgn as u8,
+            }
+        } else {
+            Self {
+                priority: ((id >> 26) & 0b111) as u8,
+                pgn,
+                source_address,
+                destination_address: ADDRESS_GLOBAL,
+            }
+        })
+    }
+}
+
+/// PDU1 PGNs (PF below 240) are addressed to a destination, PDU2 ones are broadcast
+pub fn is_pdu1(pgn: u32) -> bool {
+    (pgn >> 8) & 0xFF < 240
+}
+
+/// Rx mailbox filter accepting a PGN from any source address, and for PDU1 PGNs to any
+/// destination address
+pub fn pgn_rx_config(pgn: u32) -> RxMailboxConfig {
+    let mask = if is_pdu1(pgn) { 0x3_FF00 } else { 0x3_FFFF };
+
+    RxMailboxConfig {
+        id: Id::Extended((pgn & mask) << 8),
+        id_mask: mask << 8,
+    }
+}
+
+/// A request for a PGN, as sent with the request PGN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub pgn: u32,
+    pub source_address: u8,
+    pub destination_address: u8,
+}
+
+/// Decodes a received request PGN, None when the frame isn't one
+pub fn parse_request(frame: &RxFDFrame) -> Option<Request> {
+    let id = J1939Id::from_id(frame.id)?;
+
+    if id.pgn != PGN_REQUEST || frame.buffer_len < 3 {
+        return None;
+    }
+
+    Some(Request {
+        pgn: u32::from_le_bytes([frame.buffer[0], frame.buffer[1], frame.buffer[2], 0]),
+        source_address: id.source_address,
+        destination_address: id.destination_address,
+    })
+}
+
+pub fn send_request<T: Transmitter>(
+    can: &mut T,
+    pgn: u32,
+    source_address: u8,
+    destination_address: u8,
+) -> Result<(), J1939Error> {
+    let pgn = pgn.to_le_bytes();
+    let id = J1939Id::new(
+        DEFAULT_PRIORITY,
+        PGN_REQUEST,
+        source_address,
+        destination_address,
+    );
+
+    transmit(can, id, &[pgn[0], pgn[1], pgn[2]])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckControl {
+    Ack,
+    Nack,
+    AccessDenied,
+    CannotRespond,
+}
+
+impl AckControl {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            AckControl::Ack => 0,
+            AckControl::Nack => 1,
+            AckControl::AccessDenied => 2,
+            AckControl::CannotRespond => 3,
+        }
+    }
+}
+
+/// Answers a request with the acknowledgement PGN, usually a NACK for PGNs we don't support
+pub fn send_acknowledgement<T: Transmitter>(
+    can: &mut T,
+    control: AckControl,
+    pgn: u32,
+    source_address: u8,
+    requester_address: u8,
+) -> Result<(), J1939Error> {
+    let pgn = pgn.to_le_bytes();
+    let id = J1939Id::new(
+        DEFAULT_PRIORITY,
+        PGN_ACKNOWLEDGEMENT,
+        source_address,
+        ADDRESS_GLOBAL,
+    );
+
+    transmit(
+        can,
+        id,
+        &[
+            control.to_u8(),
+            0xFF,
+            0xFF,
+            0xFF,
+            requester_address,
+            pgn[0],
+            pgn[1],
+            pgn[2],
+        ],
+    )
+}
+
+/// Sends a single frame, padding it to 8 bytes
+pub fn transmit<T: Transmitter>(can: &mut T, id: J1939Id, data: &[u8]) -> Result<(), J1939Error> {
+    let mut buffer = [0xFF_u8; 8];
+    let len = data.len().min(8);
+    buffer[..len].copy_from_slice(&data[..len]);
+
+    let frame = TxFDFrame {
+        id: id.to_id(),
+        buffer: &buffer,
+        priority: None,
+        format: FrameFormat::Classic,
+    };
+
+    can.transmit(&frame).map_err(J1939Error::Bus)
+}
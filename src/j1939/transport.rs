@@ -0,0 +1,1271 @@
+//! The J1939-21 transport protocol, for messages of 9 - 1785 bytes
+//!
+//! Broadcast messages use BAM, the packets are sent every `BAM_PACKET_INTERVAL_US` without any
+//! handshake. Messages to a destination use RTS/CTS, the receiver paces the sender with CTS
+//! messages and acknowledges the whole message at the end. One message can be sent & one
+//! received at a time, an RTS arriving during a reception is aborted.
+
+use super::{transmit, J1939Error, J1939Id, ADDRESS_GLOBAL, ADDRESS_NULL, PGN_TP_CM, PGN_TP_DT};
+use crate::can_error::RxTxError;
+use crate::{RxFDFrame, Transmitter};
+
+pub const TP_MAX_LEN: usize = 1785;
+pub const BAM_PACKET_INTERVAL_US: u32 = 50_000;
+
+const TP_PRIORITY: u8 = 7;
+const PACKET_LEN: usize = 7;
+
+const CM_RTS: u8 = 16;
+const CM_CTS: u8 = 17;
+const CM_END_OF_MESSAGE_ACK: u8 = 19;
+const CM_BAM: u8 = 32;
+const CM_ABORT: u8 = 255;
+
+// Between received packets
+const TIMEOUT_T1_US: u32 = 750_000;
+// From our CTS to the first packet
+const TIMEOUT_T2_US: u32 = 1_250_000;
+// From the last packet sent to the CTS or end of message ack
+const TIMEOUT_T3_US: u32 = 1_250_000;
+// From a CTS holding the connection open to the next CTS
+const TIMEOUT_T4_US: u32 = 1_050_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    AlreadyInSession,
+    ResourcesNeeded,
+    Timeout,
+    CtsWhileTransferring,
+    MaxRetransmit,
+    UnexpectedDataTransfer,
+    BadSequenceNumber,
+    DuplicateSequenceNumber,
+    MessageTooLarge,
+    Other(u8),
+}
+
+impl AbortReason {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            AbortReason::AlreadyInSession => 1,
+            AbortReason::ResourcesNeeded => 2,
+            AbortReason::Timeout => 3,
+            AbortReason::CtsWhileTransferring => 4,
+            AbortReason::MaxRetransmit => 5,
+            AbortReason::UnexpectedDataTransfer => 6,
+            AbortReason::BadSequenceNumber => 7,
+            AbortReason::DuplicateSequenceNumber => 8,
+            AbortReason::MessageTooLarge => 9,
+            AbortReason::Other(reason) => reason,
+        }
+    }
+
+    pub fn from_u8(reason: u8) -> Self {
+        match reason {
+            1 => AbortReason::AlreadyInSession,
+            2 => AbortReason::ResourcesNeeded,
+            3 => AbortReason::Timeout,
+            4 => AbortReason::CtsWhileTransferring,
+            5 => AbortReason::MaxRetransmit,
+            6 => AbortReason::UnexpectedDataTransfer,
+            7 => AbortReason::BadSequenceNumber,
+            8 => AbortReason::DuplicateSequenceNumber,
+            9 => AbortReason::MessageTooLarge,
+            reason => AbortReason::Other(reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportEvent {
+    Received {
+        pgn: u32,
+        source_address: u8,
+        destination_address: u8, // Global for BAM
+        len: usize,              // The message is waiting in `received()`
+    },
+    TransmitComplete {
+        pgn: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TxState {
+    Idle,
+    Bam {
+        len: usize,
+        packets: u16,
+        next_packet: u16,
+        last_us: u32,
+    },
+    Rts {
+        destination: u8,
+        len: usize,
+        packets: u16,
+        next_packet: u16,
+        window_end: u16, // Last packet the CTS asked for, we wait for a CTS once it's sent
+        hold: bool,      // The receiver sent a CTS for 0 packets
+        last_us: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RxState {
+    Idle,
+    Bam {
+        pgn: u32,
+        source: u8,
+        len: usize,
+        packets: u16,
+        next_packet: u16,
+        last_us: u32,
+    },
+    Rts {
+        pgn: u32,
+        source: u8,
+        len: usize,
+        packets: u16,
+        next_packet: u16,
+        window_end: u16,
+        max_per_cts: u16,
+        cts_sent: bool, // No packet came in since our last CTS
+        last_us: u32,
+    },
+}
+
+pub struct J1939Transport<'a> {
+    address: u8,
+    tx_buffer: &'a mut [u8],
+    rx_buffer: &'a mut [u8],
+    tx_state: TxState,
+    tx_pgn: u32,
+    rx_state: RxState,
+    rx_complete_len: usize,
+}
+
+impl<'a> J1939Transport<'a> {
+    /// Creates the transport for our address, the buffers limit the largest message sent &
+    /// received
+    pub fn new(address: u8, tx_buffer: &'a mut [u8], rx_buffer: &'a mut [u8]) -> Self {
+        Self {
+            address,
+            tx_buffer,
+            rx_buffer,
+            tx_state: TxState::Idle,
+            tx_pgn: 0,
+            rx_state: RxState::Idle,
+            rx_complete_len: 0,
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Changes our address, usually after the address claim moved us
+    pub fn set_address(&mut self, address: u8) {
+        self.address = address;
+        self.tx_state = TxState::Idle;
+        self.rx_state = RxState::Idle;
+    }
+
+    pub fn is_transmitting(&self) -> bool {
+        !matches!(self.tx_state, TxState::Idle)
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        !matches!(self.rx_state, RxState::Idle)
+    }
+
+    /// The last message received, valid until the next one starts arriving
+    pub fn received(&self) -> &[u8] {
+        &self.rx_buffer[..self.rx_complete_len]
+    }
+
+    /// Sends a message, with BAM when `destination_address` is global & RTS/CTS otherwise.
+    /// Messages of up to 8 bytes are sent as a single frame right away.
+    pub fn send<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        priority: u8,
+        pgn: u32,
+        destination_address: u8,
+        data: &[u8],
+        now_us: u32,
+    ) -> Result<Option<TransportEvent>, J1939Error> {
+        if self.address == ADDRESS_NULL {
+            return Err(J1939Error::NoAddress);
+        }
+
+        if self.is_transmitting() {
+            return Err(J1939Error::Busy);
+        }
+
+        let len = data.len();
+
+        if len <= 8 {
+            let id = J1939Id::new(priority, pgn, self.address, destination_address);
+            transmit(can, id, data)?;
+
+            return Ok(Some(TransportEvent::TransmitComplete { pgn }));
+        }
+
+        if len > TP_MAX_LEN {
+            return Err(J1939Error::MessageTooLong);
+        }
+
+        if len > self.tx_buffer.len() {
+            return Err(J1939Error::BufferTooSmall);
+        }
+
+        self.tx_buffer[..len].copy_from_slice(data);
+        self.tx_pgn = pgn;
+
+        let packets = packet_count(len);
+
+        if destination_address == ADDRESS_GLOBAL {
+            self.send_cm(can, ADDRESS_GLOBAL, CM_BAM, len, packets, 0xFF, pgn)?;
+
+            self.tx_state = TxState::Bam {
+                len,
+                packets,
+                next_packet: 1,
+                last_us: now_us,
+            };
+        } else {
+            self.send_cm(can, destination_address, CM_RTS, len, packets, 0xFF, pgn)?;
+
+            self.tx_state = TxState::Rts {
+                destination: destination_address,
+                len,
+                packets,
+                next_packet: 1,
+                window_end: 0,
+                hold: false,
+                last_us: now_us,
+            };
+        }
+
+        Ok(None)
+    }
+
+    /// Stops the message being sent, telling the receiver when it's an RTS/CTS transfer
+    pub fn abort_transmit<T: Transmitter>(&mut self, can: &mut T) -> Result<(), J1939Error> {
+        let state = self.tx_state;
+        self.tx_state = TxState::Idle;
+
+        match state {
+            TxState::Rts { destination, .. } => {
+                // 250 is the reason for anything not in the list
+                self.send_abort(can, destination, AbortReason::Other(250), self.tx_pgn)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Feeds a received frame in, only TP.CM & TP.DT frames to us or global are handled
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<TransportEvent>, J1939Error> {
+        let id = match J1939Id::from_id(frame.id) {
+            Some(id) if (id.pgn == PGN_TP_CM || id.pgn == PGN_TP_DT) && frame.buffer_len >= 8 => id,
+            _ => return Ok(None),
+        };
+
+        if id.destination_address != self.address && id.destination_address != ADDRESS_GLOBAL {
+            return Ok(None);
+        }
+
+        let mut data = [0_u8; 8];
+        data.copy_from_slice(&frame.buffer[..8]);
+
+        if id.pgn == PGN_TP_DT {
+            return self.handle_data(can, id, &data, now_us);
+        }
+
+        let pgn = u32::from_le_bytes([data[5], data[6], data[7], 0]);
+        let source = id.source_address;
+        let to_us = id.destination_address == self.address;
+
+        match data[0] {
+            CM_BAM if !to_us => {
+                let len = u16::from_le_bytes([data[1], data[2]]) as usize;
+                let packets = data[3] as u16;
+
+                let busy = match self.rx_state {
+                    RxState::Idle => false,
+                    // A new BAM from the same node replaces the one in progress
+                    RxState::Bam {
+                        source: current, ..
+                    } => current != source,
+                    RxState::Rts { .. } => true,
+                };
+
+                if !busy
+                    && len <= self.rx_buffer.len()
+                    && len <= TP_MAX_LEN
+                    && packets == packet_count(len)
+                {
+                    self.rx_state = RxState::Bam {
+                        pgn,
+                        source,
+                        len,
+                        packets,
+                        next_packet: 1,
+                        last_us: now_us,
+                    };
+                }
+
+                Ok(None)
+            }
+            CM_RTS if to_us => {
+                let len = u16::from_le_bytes([data[1], data[2]]) as usize;
+                let packets = data[3] as u16;
+                let max_per_cts = data[4] as u16;
+
+                let busy = match self.rx_state {
+                    RxState::Idle => false,
+                    // A new RTS from the same node replaces the one in progress
+                    RxState::Rts {
+                        source: current, ..
+                    } => current != source,
+                    RxState::Bam { .. } => true,
+                };
+
+                if busy {
+                    self.send_abort(can, source, AbortReason::AlreadyInSession, pgn)?;
+                    return Ok(None);
+                }
+
+                if len > TP_MAX_LEN || packets != packet_count(len) {
+                    self.rx_state = RxState::Idle;
+                    self.send_abort(can, source, AbortReason::MessageTooLarge, pgn)?;
+                    return Ok(None);
+                }
+
+                if len > self.rx_buffer.len() {
+                    self.rx_state = RxState::Idle;
+                    self.send_abort(can, source, AbortReason::ResourcesNeeded, pgn)?;
+                    return Ok(None);
+                }
+
+                let window = packets.min(max_per_cts.max(1));
+                self.send_cts(can, source, window, 1, pgn)?;
+
+                self.rx_state = RxState::Rts {
+                    pgn,
+                    source,
+                    len,
+                    packets,
+                    next_packet: 1,
+                    window_end: window,
+                    max_per_cts,
+                    cts_sent: true,
+                    last_us: now_us,
+                };
+
+                Ok(None)
+            }
+            CM_CTS if to_us => {
+                self.handle_cts(can, source, pgn, data[1] as u16, data[2] as u16, now_us)
+            }
+            CM_END_OF_MESSAGE_ACK if to_us => match self.tx_state {
+                TxState::Rts { destination, .. } if destination == source && pgn == self.tx_pgn => {
+                    self.tx_state = TxState::Idle;
+                    Ok(Some(TransportEvent::TransmitComplete { pgn }))
+                }
+                _ => Ok(None),
+            },
+            CM_ABORT if to_us => {
+                let reason = AbortReason::from_u8(data[1]);
+
+                if let TxState::Rts { destination, .. } = self.tx_state {
+                    if destination == source && pgn == self.tx_pgn {
+                        self.tx_state = TxState::Idle;
+                        return Err(J1939Error::Aborted(reason));
+                    }
+                }
+
+                if let RxState::Rts {
+                    source: current,
+                    pgn: current_pgn,
+                    ..
+                } = self.rx_state
+                {
+                    if current == source && current_pgn == pgn {
+                        self.rx_state = RxState::Idle;
+                        return Err(J1939Error::Aborted(reason));
+                    }
+                }
+
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Sends the packets that are due & checks the timeouts
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<Option<TransportEvent>, J1939Error> {
+        match self.rx_state {
+            RxState::Idle => (),
+            RxState::Bam { last_us, .. } => {
+                if now_us.wrapping_sub(last_us) > TIMEOUT_T1_US {
+                    self.rx_state = RxState::Idle;
+                    return Err(J1939Error::Timeout);
+                }
+            }
+            RxState::Rts {
+                source,
+                pgn,
+                cts_sent,
+                last_us,
+                ..
+            } => {
+                let timeout_us = if cts_sent {
+                    TIMEOUT_T2_US
+                } else {
+                    TIMEOUT_T1_US
+                };
+
+                if now_us.wrapping_sub(last_us) > timeout_us {
+                    self.rx_state = RxState::Idle;
+                    self.send_abort(can, source, AbortReason::Timeout, pgn)?;
+                    return Err(J1939Error::Timeout);
+                }
+            }
+        }
+
+        match self.tx_state {
+            TxState::Idle => Ok(None),
+            TxState::Bam {
+                len,
+                packets,
+                next_packet,
+                last_us,
+            } => {
+                if now_us.wrapping_sub(last_us) < BAM_PACKET_INTERVAL_US {
+                    return Ok(None);
+                }
+
+                match self.send_packet(can, ADDRESS_GLOBAL, len, next_packet) {
+                    Err(J1939Error::Bus(RxTxError::MailboxUnavailable)) => return Ok(None),
+                    result => result?,
+                }
+
+                if next_packet >= packets {
+                    self.tx_state = TxState::Idle;
+                    return Ok(Some(TransportEvent::TransmitComplete { pgn: self.tx_pgn }));
+                }
+
+                self.tx_state = TxState::Bam {
+                    len,
+                    packets,
+                    next_packet: next_packet + 1,
+                    last_us: now_us,
+                };
+
+                Ok(None)
+            }
+            TxState::Rts {
+                destination,
+                next_packet,
+                window_end,
+                hold,
+                last_us,
+                ..
+            } => {
+                if next_packet <= window_end {
+                    self.send_window(can, now_us)?;
+                    return Ok(None);
+                }
+
+                let timeout_us = if hold { TIMEOUT_T4_US } else { TIMEOUT_T3_US };
+
+                if now_us.wrapping_sub(last_us) > timeout_us {
+                    self.tx_state = TxState::Idle;
+                    self.send_abort(can, destination, AbortReason::Timeout, self.tx_pgn)?;
+                    return Err(J1939Error::Timeout);
+                }
+
+                Ok(None)
+            }
+        }
+    }
+
+    fn handle_cts<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        source: u8,
+        pgn: u32,
+        count: u16,
+        next: u16,
+        now_us: u32,
+    ) -> Result<Option<TransportEvent>, J1939Error> {
+        if let TxState::Rts {
+            destination,
+            len,
+            packets,
+            next_packet,
+            window_end,
+            ..
+        } = self.tx_state
+        {
+            if destination != source || pgn != self.tx_pgn {
+                return Ok(None);
+            }
+
+            if next_packet <= window_end && next_packet > 1 {
+                self.tx_state = TxState::Idle;
+                self.send_abort(can, destination, AbortReason::CtsWhileTransferring, pgn)?;
+                return Err(J1939Error::Aborted(AbortReason::CtsWhileTransferring));
+            }
+
+            if count == 0 {
+                self.tx_state = TxState::Rts {
+                    destination,
+                    len,
+                    packets,
+                    next_packet,
+                    window_end,
+                    hold: true,
+                    last_us: now_us,
+                };
+
+                return Ok(None);
+            }
+
+            if next == 0 || next > packets {
+                self.tx_state = TxState::Idle;
+                self.send_abort(can, destination, AbortReason::BadSequenceNumber, pgn)?;
+                return Err(J1939Error::Aborted(AbortReason::BadSequenceNumber));
+            }
+
+            self.tx_state = TxState::Rts {
+                destination,
+                len,
+                packets,
+                next_packet: next,
+                window_end: (next + count - 1).min(packets),
+                hold: false,
+                last_us: now_us,
+            };
+
+            self.send_window(can, now_us)?;
+        }
+
+        Ok(None)
+    }
+
+    fn handle_data<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        id: J1939Id,
+        data: &[u8; 8],
+        now_us: u32,
+    ) -> Result<Option<TransportEvent>, J1939Error> {
+        let sequence = data[0] as u16;
+
+        match self.rx_state {
+            RxState::Bam {
+                pgn,
+                source,
+                len,
+                packets,
+                next_packet,
+                ..
+            } if source == id.source_address && id.destination_address == ADDRESS_GLOBAL => {
+                if sequence != next_packet {
+                    self.rx_state = RxState::Idle;
+                    return Err(J1939Error::Aborted(AbortReason::BadSequenceNumber));
+                }
+
+                self.copy_packet(len, sequence, data);
+
+                if sequence >= packets {
+                    self.rx_state = RxState::Idle;
+                    self.rx_complete_len = len;
+
+                    return Ok(Some(TransportEvent::Received {
+                        pgn,
+                        source_address: source,
+                        destination_address: ADDRESS_GLOBAL,
+                        len,
+                    }));
+                }
+
+                self.rx_state = RxState::Bam {
+                    pgn,
+                    source,
+                    len,
+                    packets,
+                    next_packet: next_packet + 1,
+                    last_us: now_us,
+                };
+
+                Ok(None)
+            }
+            RxState::Rts {
+                pgn,
+                source,
+                len,
+                packets,
+                next_packet,
+                window_end,
+                max_per_cts,
+                ..
+            } if source == id.source_address && id.destination_address == self.address => {
+                if sequence != next_packet {
+                    let reason = if sequence < next_packet {
+                        AbortReason::DuplicateSequenceNumber
+                    } else {
+                        AbortReason::BadSequenceNumber
+                    };
+
+                    self.rx_state = RxState::Idle;
+                    self.send_abort(can, source, reason, pgn)?;
+                    return Err(J1939Error::Aborted(reason));
+                }
+
+                self.copy_packet(len, sequence, data);
+
+                if sequence >= packets {
+                    self.rx_state = RxState::Idle;
+                    self.rx_complete_len = len;
+                    self.send_cm(can, source, CM_END_OF_MESSAGE_ACK, len, packets, 0xFF, pgn)?;
+
+                    return Ok(Some(TransportEvent::Received {
+                        pgn,
+                        source_address: source,
+                        destination_address: self.address,
+                        len,
+                    }));
+                }
+
+                let mut window_end = window_end;
+                let cts_sent = sequence >= window_end;
+
+                if cts_sent {
+                    let window = (packets - sequence).min(max_per_cts.max(1));
+                    window_end = sequence + window;
+                    self.send_cts(can, source, window, sequence + 1, pgn)?;
+                }
+
+                self.rx_state = RxState::Rts {
+                    pgn,
+                    source,
+                    len,
+                    packets,
+                    next_packet: next_packet + 1,
+                    window_end,
+                    max_per_cts,
+                    cts_sent,
+                    last_us: now_us,
+                };
+
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn copy_packet(&mut self, len: usize, sequence: u16, data: &[u8; 8]) {
+        let offset = (sequence as usize - 1) * PACKET_LEN;
+        let end = (offset + PACKET_LEN).min(len);
+
+        self.rx_buffer[offset..end].copy_from_slice(&data[1..1 + end - offset]);
+    }
+
+    /// Sends the packets of the current CTS window until they're all out or the mailboxes are
+    /// full
+    fn send_window<T: Transmitter>(&mut self, can: &mut T, now_us: u32) -> Result<(), J1939Error> {
+        if let TxState::Rts {
+            destination,
+            len,
+            packets,
+            mut next_packet,
+            window_end,
+            hold,
+            mut last_us,
+        } = self.tx_state
+        {
+            while next_packet <= window_end {
+                match self.send_packet(can, destination, len, next_packet) {
+                    Ok(()) => next_packet += 1,
+                    Err(J1939Error::Bus(RxTxError::MailboxUnavailable)) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            if next_packet > window_end {
+                last_us = now_us;
+            }
+
+            self.tx_state = TxState::Rts {
+                destination,
+                len,
+                packets,
+                next_packet,
+                window_end,
+                hold,
+                last_us,
+            };
+        }
+
+        Ok(())
+    }
+
+    fn send_packet<T: Transmitter>(
+        &self,
+        can: &mut T,
+        destination: u8,
+        len: usize,
+        sequence: u16,
+    ) -> Result<(), J1939Error> {
+        let offset = (sequence as usize - 1) * PACKET_LEN;
+        let end = (offset + PACKET_LEN).min(len);
+
+        let mut data = [0xFF_u8; 8];
+        data[0] = sequence as u8;
+        data[1..1 + end - offset].copy_from_slice(&self.tx_buffer[offset..end]);
+
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_DT, self.address, destination);
+        transmit(can, id, &data)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send_cm<T: Transmitter>(
+        &self,
+        can: &mut T,
+        destination: u8,
+        control: u8,
+        len: usize,
+        packets: u16,
+        byte_4: u8,
+        pgn: u32,
+    ) -> Result<(), J1939Error> {
+        let len = (len as u16).to_le_bytes();
+        let pgn = pgn.to_le_bytes();
+
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_CM, self.address, destination);
+        transmit(
+            can,
+            id,
+            &[
+                control,
+                len[0],
+                len[1],
+                packets as u8,
+                byte_4,
+                pgn[0],
+                pgn[1],
+                pgn[2],
+            ],
+        )
+    }
+
+    fn send_cts<T: Transmitter>(
+        &self,
+        can: &mut T,
+        destination: u8,
+        count: u16,
+        next: u16,
+        pgn: u32,
+    ) -> Result<(), J1939Error> {
+        let pgn = pgn.to_le_bytes();
+
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_CM, self.address, destination);
+        transmit(
+            can,
+            id,
+            &[
+                CM_CTS,
+                count as u8,
+                next as u8,
+                0xFF,
+                0xFF,
+                pgn[0],
+                pgn[1],
+                pgn[2],
+            ],
+        )
+    }
+
+    fn send_abort<T: Transmitter>(
+        &self,
+        can: &mut T,
+        destination: u8,
+        reason: AbortReason,
+        pgn: u32,
+    ) -> Result<(), J1939Error> {
+        let pgn = pgn.to_le_bytes();
+
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_CM, self.address, destination);
+        transmit(
+            can,
+            id,
+            &[
+                CM_ABORT,
+                reason.to_u8(),
+                0xFF,
+                0xFF,
+                0xFF,
+                pgn[0],
+                pgn[1],
+                pgn[2],
+            ],
+        )
+    }
+}
+
+fn packet_count(len: usize) -> u16 {
+    len.div_ceil(PACKET_LEN) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{rx_frame, Bus};
+
+    const SENDER: u8 = 0x10;
+    const RECEIVER: u8 = 0x20;
+    const PGN: u32 = 0xEF00;
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13) as u8).collect()
+    }
+
+    fn cm_frame(source: u8, destination: u8, data: [u8; 8]) -> RxFDFrame {
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_CM, source, destination);
+        rx_frame(id.to_id(), &data)
+    }
+
+    fn dt_frame(source: u8, destination: u8, sequence: u8) -> RxFDFrame {
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_DT, source, destination);
+        rx_frame(id.to_id(), &[sequence, 1, 2, 3, 4, 5, 6, 7])
+    }
+
+    fn rts(len: u16, max_per_cts: u8) -> RxFDFrame {
+        let [len_low, len_high] = len.to_le_bytes();
+        let packets = packet_count(len as usize) as u8;
+        cm_frame(
+            SENDER,
+            RECEIVER,
+            [
+                CM_RTS,
+                len_low,
+                len_high,
+                packets,
+                max_per_cts,
+                0x00,
+                0xEF,
+                0x00,
+            ],
+        )
+    }
+
+    fn cts(count: u8, next: u8) -> RxFDFrame {
+        cm_frame(
+            RECEIVER,
+            SENDER,
+            [CM_CTS, count, next, 0xFF, 0xFF, 0x00, 0xEF, 0x00],
+        )
+    }
+
+    // The control byte & source address of a TP.CM frame sent
+    fn control(frame: &crate::mock::SentFrame) -> (u8, u8) {
+        let id = J1939Id::from_id(frame.id).unwrap();
+        assert_eq!(id.pgn, PGN_TP_CM);
+        (frame.data[0], id.source_address)
+    }
+
+    // Sends a message between two nodes a step at a time, returning the frames sent & the events
+    fn transfer(
+        len: usize,
+        destination: u8,
+        step_us: u32,
+    ) -> (Vec<crate::mock::SentFrame>, Vec<TransportEvent>) {
+        let (mut tx_a, mut rx_a) = ([0; TP_MAX_LEN], [0; TP_MAX_LEN]);
+        let (mut tx_b, mut rx_b) = ([0; TP_MAX_LEN], [0; TP_MAX_LEN]);
+        let mut sender = J1939Transport::new(SENDER, &mut tx_a, &mut rx_a);
+        let mut receiver = J1939Transport::new(RECEIVER, &mut tx_b, &mut rx_b);
+        let mut bus = Bus::default();
+        let data = message(len);
+        let mut frames = Vec::new();
+        let mut events = Vec::new();
+        let mut now_us = 0;
+
+        events.extend(
+            sender
+                .send(&mut bus, 6, PGN, destination, &data, 0)
+                .unwrap(),
+        );
+
+        while sender.is_transmitting() || receiver.is_receiving() || !bus.sent.is_empty() {
+            assert!(now_us < 100_000_000, "stalled sending {} bytes", len);
+            now_us += step_us;
+
+            for frame in bus.take() {
+                let rx = frame.to_rx();
+                let from_sender = J1939Id::from_id(frame.id).unwrap().source_address == SENDER;
+                frames.push(frame);
+
+                if from_sender {
+                    events.extend(receiver.handle_frame(&mut bus, &rx, now_us).unwrap());
+                } else {
+                    events.extend(sender.handle_frame(&mut bus, &rx, now_us).unwrap());
+                }
+            }
+
+            events.extend(sender.poll(&mut bus, now_us).unwrap());
+            events.extend(receiver.poll(&mut bus, now_us).unwrap());
+        }
+
+        if len > 8 {
+            assert_eq!(receiver.received(), &data[..]);
+        }
+
+        (frames, events)
+    }
+
+    #[test]
+    fn single_frames() {
+        let (frames, events) = transfer(8, RECEIVER, 1_000);
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(events, [TransportEvent::TransmitComplete { pgn: PGN }]);
+    }
+
+    #[test]
+    fn bam() {
+        for len in [9, 14, 15, 100, TP_MAX_LEN] {
+            let (frames, events) = transfer(len, ADDRESS_GLOBAL, 10_000);
+            let packets = packet_count(len) as usize;
+
+            assert_eq!(frames.len(), 1 + packets);
+            assert_eq!(control(&frames[0]), (CM_BAM, SENDER));
+            assert_eq!(
+                events,
+                [
+                    TransportEvent::TransmitComplete { pgn: PGN },
+                    TransportEvent::Received {
+                        pgn: PGN,
+                        source_address: SENDER,
+                        destination_address: ADDRESS_GLOBAL,
+                        len,
+                    },
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn bam_packet_interval() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut sender = J1939Transport::new(SENDER, &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        sender
+            .send(&mut bus, 6, PGN, ADDRESS_GLOBAL, &message(20), 0)
+            .unwrap();
+        assert_eq!(bus.take().len(), 1);
+
+        sender.poll(&mut bus, BAM_PACKET_INTERVAL_US - 1).unwrap();
+        assert!(bus.sent.is_empty());
+        sender.poll(&mut bus, BAM_PACKET_INTERVAL_US).unwrap();
+        assert_eq!(bus.take()[0].data[0], 1);
+
+        // A full mailbox retries on the next poll
+        bus.full = true;
+        sender.poll(&mut bus, 2 * BAM_PACKET_INTERVAL_US).unwrap();
+        bus.full = false;
+        sender
+            .poll(&mut bus, 2 * BAM_PACKET_INTERVAL_US + 1)
+            .unwrap();
+        assert_eq!(bus.take()[0].data[0], 2);
+    }
+
+    #[test]
+    fn bam_missing_packet_and_timeout() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut receiver = J1939Transport::new(RECEIVER, &mut tx, &mut rx);
+        let mut bus = Bus::default();
+        let bam = cm_frame(
+            SENDER,
+            ADDRESS_GLOBAL,
+            [CM_BAM, 20, 0, 3, 0xFF, 0x00, 0xEF, 0x00],
+        );
+
+        receiver.handle_frame(&mut bus, &bam, 0).unwrap();
+        assert!(receiver.is_receiving());
+        receiver
+            .handle_frame(&mut bus, &dt_frame(SENDER, ADDRESS_GLOBAL, 1), 0)
+            .unwrap();
+        assert_eq!(
+            receiver.handle_frame(&mut bus, &dt_frame(SENDER, ADDRESS_GLOBAL, 3), 0),
+            Err(J1939Error::Aborted(AbortReason::BadSequenceNumber))
+        );
+        assert!(!receiver.is_receiving());
+
+        // BAM is never answered, not even an abort
+        receiver.handle_frame(&mut bus, &bam, 0).unwrap();
+        receiver
+            .handle_frame(&mut bus, &dt_frame(SENDER, ADDRESS_GLOBAL, 1), 100)
+            .unwrap();
+        assert_eq!(receiver.poll(&mut bus, 100 + TIMEOUT_T1_US), Ok(None));
+        assert_eq!(
+            receiver.poll(&mut bus, 101 + TIMEOUT_T1_US),
+            Err(J1939Error::Timeout)
+        );
+        assert!(bus.sent.is_empty());
+    }
+
+    #[test]
+    fn rts_cts() {
+        for len in [9, 14, 15, 100, TP_MAX_LEN] {
+            let (frames, events) = transfer(len, RECEIVER, 1_000);
+            let packets = packet_count(len) as usize;
+
+            // RTS, CTS, the packets & the end of message ack
+            assert_eq!(frames.len(), 3 + packets);
+            assert_eq!(control(&frames[0]), (CM_RTS, SENDER));
+            assert_eq!(control(&frames[1]), (CM_CTS, RECEIVER));
+            assert_eq!(
+                control(&frames[2 + packets]),
+                (CM_END_OF_MESSAGE_ACK, RECEIVER)
+            );
+            assert_eq!(
+                events,
+                [
+                    TransportEvent::Received {
+                        pgn: PGN,
+                        source_address: SENDER,
+                        destination_address: RECEIVER,
+                        len,
+                    },
+                    TransportEvent::TransmitComplete { pgn: PGN },
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn receiver_limits_packets_per_cts() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut receiver = J1939Transport::new(RECEIVER, &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        receiver.handle_frame(&mut bus, &rts(30, 2), 0).unwrap();
+        assert_eq!(bus.take()[0].data[..3], [CM_CTS, 2, 1]);
+
+        receiver
+            .handle_frame(&mut bus, &dt_frame(SENDER, RECEIVER, 1), 0)
+            .unwrap();
+        assert!(bus.sent.is_empty());
+        receiver
+            .handle_frame(&mut bus, &dt_frame(SENDER, RECEIVER, 2), 0)
+            .unwrap();
+        assert_eq!(bus.take()[0].data[..3], [CM_CTS, 2, 3]);
+
+        // A repeated packet aborts
+        assert_eq!(
+            receiver.handle_frame(&mut bus, &dt_frame(SENDER, RECEIVER, 2), 0),
+            Err(J1939Error::Aborted(AbortReason::DuplicateSequenceNumber))
+        );
+        assert_eq!(
+            bus.take()[0].data[..2],
+            [CM_ABORT, AbortReason::DuplicateSequenceNumber.to_u8()]
+        );
+    }
+
+    #[test]
+    fn receiver_aborts() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut receiver = J1939Transport::new(RECEIVER, &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        // More than the Rx buffer holds
+        receiver.handle_frame(&mut bus, &rts(65, 0xFF), 0).unwrap();
+        assert_eq!(
+            bus.take()[0].data[..2],
+            [CM_ABORT, AbortReason::ResourcesNeeded.to_u8()]
+        );
+
+        // A packet count that doesn't match the length
+        let mut bad_rts = rts(20, 0xFF);
+        bad_rts.buffer[3] = 4;
+        receiver.handle_frame(&mut bus, &bad_rts, 0).unwrap();
+        assert_eq!(
+            bus.take()[0].data[..2],
+            [CM_ABORT, AbortReason::MessageTooLarge.to_u8()]
+        );
+
+        // Another node's RTS while one is in progress
+        receiver.handle_frame(&mut bus, &rts(20, 0xFF), 0).unwrap();
+        bus.take();
+        let other = J1939Id::new(TP_PRIORITY, PGN_TP_CM, 0x30, RECEIVER);
+        let other_rts = rx_frame(other.to_id(), &rts(20, 0xFF).buffer[..8]);
+        receiver.handle_frame(&mut bus, &other_rts, 0).unwrap();
+        let abort = bus.take().pop().unwrap();
+        assert_eq!(
+            abort.data[..2],
+            [CM_ABORT, AbortReason::AlreadyInSession.to_u8()]
+        );
+        assert_eq!(
+            J1939Id::from_id(abort.id).unwrap().destination_address,
+            0x30
+        );
+        assert!(receiver.is_receiving());
+
+        // The sender giving up
+        let abort = cm_frame(
+            SENDER,
+            RECEIVER,
+            [CM_ABORT, 250, 0xFF, 0xFF, 0xFF, 0x00, 0xEF, 0x00],
+        );
+        assert_eq!(
+            receiver.handle_frame(&mut bus, &abort, 0),
+            Err(J1939Error::Aborted(AbortReason::Other(250)))
+        );
+        assert!(!receiver.is_receiving());
+    }
+
+    #[test]
+    fn sender_aborts() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut sender = J1939Transport::new(SENDER, &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        // The receiver refusing the RTS
+        sender
+            .send(&mut bus, 6, PGN, RECEIVER, &message(20), 0)
+            .unwrap();
+        let abort = cm_frame(
+            RECEIVER,
+            SENDER,
+            [CM_ABORT, 2, 0xFF, 0xFF, 0xFF, 0x00, 0xEF, 0x00],
+        );
+        assert_eq!(
+            sender.handle_frame(&mut bus, &abort, 0),
+            Err(J1939Error::Aborted(AbortReason::ResourcesNeeded))
+        );
+        assert!(!sender.is_transmitting());
+
+        // Packets the mailboxes refused go out on the next poll, a later CTS can ask for some again
+        sender
+            .send(&mut bus, 6, PGN, RECEIVER, &message(20), 0)
+            .unwrap();
+        bus.take();
+        bus.full = true;
+        sender.handle_frame(&mut bus, &cts(3, 1), 0).unwrap();
+        bus.full = false;
+        sender.poll(&mut bus, 0).unwrap();
+        assert_eq!(bus.take().len(), 3);
+        sender.handle_frame(&mut bus, &cts(2, 2), 0).unwrap();
+        let sequences: Vec<u8> = bus.take().iter().map(|frame| frame.data[0]).collect();
+        assert_eq!(sequences, [2, 3]);
+
+        // A CTS for packets that don't exist
+        sender.handle_frame(&mut bus, &cts(1, 9), 0).unwrap_err();
+        assert_eq!(
+            bus.take()[0].data[..2],
+            [CM_ABORT, AbortReason::BadSequenceNumber.to_u8()]
+        );
+
+        // Our own abort
+        sender
+            .send(&mut bus, 6, PGN, RECEIVER, &message(20), 0)
+            .unwrap();
+        bus.take();
+        sender.abort_transmit(&mut bus).unwrap();
+        assert_eq!(bus.take()[0].data[..2], [CM_ABORT, 250]);
+        assert!(!sender.is_transmitting());
+    }
+
+    #[test]
+    fn receiver_timeouts() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut receiver = J1939Transport::new(RECEIVER, &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        // T2 from our CTS to the first packet
+        receiver.handle_frame(&mut bus, &rts(30, 2), 0).unwrap();
+        bus.take();
+        assert_eq!(receiver.poll(&mut bus, TIMEOUT_T2_US), Ok(None));
+        assert_eq!(
+            receiver.poll(&mut bus, TIMEOUT_T2_US + 1),
+            Err(J1939Error::Timeout)
+        );
+        assert_eq!(
+            bus.take()[0].data[..2],
+            [CM_ABORT, AbortReason::Timeout.to_u8()]
+        );
+
+        // T1 between packets
+        receiver.handle_frame(&mut bus, &rts(30, 2), 0).unwrap();
+        receiver
+            .handle_frame(&mut bus, &dt_frame(SENDER, RECEIVER, 1), 0)
+            .unwrap();
+        bus.take();
+        assert_eq!(receiver.poll(&mut bus, TIMEOUT_T1_US), Ok(None));
+        assert_eq!(
+            receiver.poll(&mut bus, TIMEOUT_T1_US + 1),
+            Err(J1939Error::Timeout)
+        );
+        assert_eq!(bus.take()[0].data[0], CM_ABORT);
+
+        // T2 again after the CTS for the next window
+        receiver.handle_frame(&mut bus, &rts(30, 2), 0).unwrap();
+        for sequence in 1..=2 {
+            receiver
+                .handle_frame(&mut bus, &dt_frame(SENDER, RECEIVER, sequence), 0)
+                .unwrap();
+        }
+        bus.take();
+        assert_eq!(receiver.poll(&mut bus, TIMEOUT_T2_US), Ok(None));
+        assert_eq!(
+            receiver.poll(&mut bus, TIMEOUT_T2_US + 1),
+            Err(J1939Error::Timeout)
+        );
+    }
+
+    #[test]
+    fn sender_timeouts() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut sender = J1939Transport::new(SENDER, &mut tx, &mut rx);
+        let mut bus = Bus::default();
+
+        // T3 waiting for the CTS
+        sender
+            .send(&mut bus, 6, PGN, RECEIVER, &message(20), 0)
+            .unwrap();
+        bus.take();
+        assert_eq!(sender.poll(&mut bus, TIMEOUT_T3_US), Ok(None));
+        assert_eq!(
+            sender.poll(&mut bus, TIMEOUT_T3_US + 1),
+            Err(J1939Error::Timeout)
+        );
+        assert_eq!(
+            bus.take()[0].data[..2],
+            [CM_ABORT, AbortReason::Timeout.to_u8()]
+        );
+
+        // T4 while the receiver holds the connection open
+        sender
+            .send(&mut bus, 6, PGN, RECEIVER, &message(20), 0)
+            .unwrap();
+        sender.handle_frame(&mut bus, &cts(0, 1), 0).unwrap();
+        bus.take();
+        assert_eq!(sender.poll(&mut bus, TIMEOUT_T4_US), Ok(None));
+        assert_eq!(
+            sender.poll(&mut bus, TIMEOUT_T4_US + 1),
+            Err(J1939Error::Timeout)
+        );
+    }
+}
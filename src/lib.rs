@@ -2,31 +2,69 @@
 //! Author: David Allen (hbddallen@gmail.com)
 //!
 
-#![no_std]
-
+#![cfg_attr(not(test), no_std)]
+// Without the driver, the register helpers, counters & imports it shares with the rest go unused
+#![cfg_attr(
+    not(feature = "hardware"),
+    allow(dead_code, unused_imports, unused_macros)
+)]
+
+pub mod asc;
+pub mod bus_load;
 pub mod can_error;
+pub mod candump;
+pub mod canopen;
 pub mod config;
+pub mod cyphal;
+#[cfg(feature = "hardware")]
 mod init;
+#[cfg(feature = "hardware")]
 mod interrupt;
+pub mod isotp;
+pub mod j1939;
+#[cfg(feature = "hardware")]
 mod mailbox;
+pub mod mdf4;
 pub(crate) mod message_buffer;
+#[cfg(test)]
+mod mock;
+pub mod nmea2000;
+pub mod obd;
+pub mod pcapng;
 pub(crate) mod receive;
+pub mod replay;
+pub mod signal;
+pub mod slcan;
+pub mod socketcan;
+pub mod stats;
 pub(crate) mod transfer;
+pub mod uds;
 pub(crate) mod util;
+pub mod xcp;
 
 pub use receive::RxFDFrame;
-pub use transfer::TxFDFrame;
+pub use stats::Stats;
+pub use transfer::{FrameFormat, Transmitter, TxFDFrame};
 
+#[cfg(feature = "hardware")]
 use can_error::RxTxError;
+#[cfg(feature = "hardware")]
 use core::cell::UnsafeCell;
+#[cfg(feature = "hardware")]
 use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "hardware")]
 use cortex_m::interrupt as cortex_m_interrupt;
+#[cfg(feature = "hardware")]
 use cortex_m::interrupt::CriticalSection;
+#[cfg(feature = "hardware")]
 use imxrt_ral as ral;
+#[cfg(feature = "hardware")]
 use teensy4_bsp::interrupt::CAN3;
 
+#[cfg(feature = "hardware")]
 struct CANFDCS(UnsafeCell<Option<CANFD>>);
 
+#[cfg(feature = "hardware")]
 impl CANFDCS {
     pub(crate) fn exec<F>(&self, _cs: &CriticalSection, f: F)
     where
@@ -40,22 +78,29 @@ impl CANFDCS {
     }
 }
 
+#[cfg(feature = "hardware")]
 unsafe impl Sync for CANFDCS {}
 
+#[cfg(feature = "hardware")]
 static TAKEN: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "hardware")]
 pub(crate) static CANFD_INSTANCE: CANFDCS = CANFDCS(UnsafeCell::new(None));
 
+#[cfg(feature = "hardware")]
 pub(crate) struct CANFD {
     instance: ral::can3::Instance,
     config: config::Config,
     mailbox_configs: [config::MailboxConfig; 64],
     rx_callback: Option<fn(&CriticalSection, RxFDFrame)>,
+    stats: stats::StatsCounters,
 }
 
+#[cfg(feature = "hardware")]
 pub struct CAN3FD {
     _0: (),
 }
 
+#[cfg(feature = "hardware")]
 impl CAN3FD {
     pub fn transfer_blocking(
         &mut self,
@@ -100,10 +145,67 @@ impl CAN3FD {
             }
         }
     }
+
+    pub fn stats(&self, _cs: &CriticalSection) -> Stats {
+        let mut result = Stats::default();
+
+        unsafe {
+            if let Some(canfd) = &(*CANFD_INSTANCE.0.get()) {
+                result = canfd.stats.snapshot();
+            }
+        }
+
+        result
+    }
+
+    pub fn reset_stats(&mut self, _cs: &CriticalSection) {
+        unsafe {
+            if let Some(canfd) = &(*CANFD_INSTANCE.0.get()) {
+                canfd.stats.reset();
+            }
+        }
+    }
+
+    /// Changes both bitrates, anything being sent or received at the time is lost
+    pub fn set_timing(
+        &mut self,
+        _cs: &CriticalSection,
+        timing_classical: config::TimingConfig,
+        timing_fd: config::TimingConfig,
+    ) {
+        unsafe {
+            if let Some(canfd) = &mut (*CANFD_INSTANCE.0.get()) {
+                canfd.set_timing(timing_classical, timing_fd);
+            }
+        }
+    }
+
+    /// The free-running timer frames get timestamped with, in bit times at the nominal bitrate.
+    /// `asc::TimestampExtender` widens it.
+    pub fn timer(&self, _cs: &CriticalSection) -> u16 {
+        let mut result = 0;
+
+        unsafe {
+            if let Some(canfd) = &(*CANFD_INSTANCE.0.get()) {
+                result = ral::read_reg!(ral::can3, &canfd.instance, TIMER) as u16;
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(feature = "hardware")]
+impl Transmitter for CAN3FD {
+    fn transmit(&mut self, frame: &TxFDFrame) -> Result<(), RxTxError> {
+        cortex_m_interrupt::free(|cs| self.transfer_nb(cs, frame))
+    }
 }
 
+#[cfg(feature = "hardware")]
 pub struct CANFDBuilder {}
 
+#[cfg(feature = "hardware")]
 impl CANFDBuilder {
     pub fn take() -> Option<Self> {
         let mut result: Option<Self> = None;
@@ -125,6 +227,7 @@ impl CANFDBuilder {
             config: can_config,
             mailbox_configs: [config::MailboxConfig::Unconfigured; 64],
             rx_callback: None,
+            stats: stats::StatsCounters::new(),
         };
 
         canfd.init_clocks();
@@ -6,6 +6,7 @@ use imxrt_ral as ral;
 
 use crate::config::{Id, MailboxConfig, RegionConfig, RxMailboxConfig};
 use crate::message_buffer::*;
+use crate::util::debug_info;
 use crate::CANFD;
 
 impl CANFD {
@@ -44,6 +45,15 @@ impl CANFD {
     }
 
     fn configure_mailbox(&mut self, mb_index: u32, config: &MailboxConfig) {
+        // A frame still waiting in a Tx mailbox is lost when it gets reconfigured
+        if let MailboxConfig::Tx = self.mailbox_configs[mb_index as usize] {
+            let cs_reg = read_cs_reg(self.get_mailbox_data_offset(mb_index));
+
+            if cs_reg.read_field(CSField::CODE) == CS_CODE_TX_DATA_OR_REMOTE {
+                self.stats.count_tx_abort();
+            }
+        }
+
         match config {
             MailboxConfig::Tx => self.configure_tx_mailbox(mb_index),
             MailboxConfig::Rx { rx_config } => self.configure_rx_mailbox(mb_index, rx_config),
@@ -56,15 +66,13 @@ impl CANFD {
     fn configure_tx_mailbox(&mut self, mb_index: u32) {
         let mb_data_offset = self.get_mailbox_data_offset(mb_index);
 
-        if cfg!(feature = "debuginfo") {
-            log::info!(
-                "TXConf | Index: {}, Offset: {}, Size: {}, Max bound: {}",
-                mb_index,
-                mb_data_offset,
-                self.get_mailbox_size(mb_index),
-                mb_data_offset + self.get_mailbox_size(mb_index)
-            );
-        }
+        debug_info!(
+            "TXConf | Index: {}, Offset: {}, Size: {}, Max bound: {}",
+            mb_index,
+            mb_data_offset,
+            self.get_mailbox_size(mb_index),
+            mb_data_offset + self.get_mailbox_size(mb_index)
+        );
 
         self.write_iflag_bit(mb_index);
         self.set_imask_bit(mb_index, false);
@@ -83,15 +91,13 @@ impl CANFD {
     fn configure_rx_mailbox(&mut self, mb_index: u32, config: &RxMailboxConfig) {
         let mb_data_offset = self.get_mailbox_data_offset(mb_index);
 
-        if cfg!(feature = "debuginfo") {
-            log::info!(
-                "RXConf | Index: {}, Offset: {}, Size: {}, Max bound: {}",
-                mb_index,
-                mb_data_offset,
-                self.get_mailbox_size(mb_index),
-                mb_data_offset + self.get_mailbox_size(mb_index)
-            );
-        }
+        debug_info!(
+            "RXConf | Index: {}, Offset: {}, Size: {}, Max bound: {}",
+            mb_index,
+            mb_data_offset,
+            self.get_mailbox_size(mb_index),
+            mb_data_offset + self.get_mailbox_size(mb_index)
+        );
 
         self.write_iflag_bit(mb_index);
         self.set_imask_bit(mb_index, true);
@@ -0,0 +1,751 @@
+//! ASAM MDF 4.1 measurement files, in the bus logging layout
+//!
+//! `write_header` writes every block up front: the ID & header blocks, a data group with a
+//! `CAN_DataFrame` & a `CAN_ErrorFrame` channel group (each with a `Timestamp` master channel &
+//! the bus logging channels under a composition channel), and the start of the data block. Every
+//! frame after that is one fixed length record appended to the data block, so the file streams to
+//! a `Sink` without ever going back.
+//!
+//! Both groups share the data block with a 1 byte record ID in front of each record, in the order
+//! they came in, so they stay sorted by time. That means the data block's length & the groups'
+//! record counts can't be filled in, the file is marked unfinished (`UnFinMF`) for readers to
+//! work those out from the file's size, which asammdf & CANape do when they open it.
+
+use crate::config::Id;
+use crate::pcapng::Sink;
+use crate::util::len_to_dlc;
+use crate::{RxFDFrame, TxFDFrame};
+use core::convert::Infallible;
+
+pub const DATA_FRAME_RECORD_LEN: usize = 1 + 8 + 8 + 64;
+pub const ERROR_FRAME_RECORD_LEN: usize = 1 + 8 + 2;
+
+const RECORD_ID_DATA_FRAME: u8 = 1;
+const RECORD_ID_ERROR_FRAME: u8 = 2;
+
+// Cycle counters & the last data block's length need updating
+const UNFINISHED_FLAGS: u16 = 0x0001 | 0x0004;
+
+const CN_TYPE_FIXED: u8 = 0;
+const CN_TYPE_MASTER: u8 = 2;
+const SYNC_TIME: u8 = 1;
+const DATA_TYPE_UNSIGNED: u8 = 0;
+const DATA_TYPE_FLOAT: u8 = 4;
+const DATA_TYPE_BYTES: u8 = 10;
+const CN_FLAG_BUS_EVENT: u32 = 1 << 10;
+const CG_FLAG_BUS_EVENT: u16 = 1 << 1;
+const CG_FLAG_PLAIN_BUS_EVENT: u16 = 1 << 2;
+const SI_TYPE_BUS: u8 = 2;
+const SI_BUS_TYPE_CAN: u8 = 2;
+
+const FILE_HISTORY: &str = concat!(
+    "<FHcomment><TX>Bus logging</TX><tool_id>teensy4-canfd</tool_id>",
+    "<tool_vendor>teensy4-canfd</tool_vendor><tool_version>",
+    env!("CARGO_PKG_VERSION"),
+    "</tool_version></FHcomment>",
+);
+
+/// The bus logging standard's error types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorType {
+    Unknown = 0,
+    Bit = 1,
+    Form = 2,
+    BitStuffing = 3,
+    Crc = 4,
+    Ack = 5,
+}
+
+pub struct Mdf4Writer {
+    start_ns: u64,
+    bus_channel: u8,
+}
+
+impl Mdf4Writer {
+    /// `start_ns` is when the measurement started, on the same clock as the frames' timestamps.
+    /// `bus_channel` goes into every record's `BusChannel`.
+    pub fn new(start_ns: u64, bus_channel: u8) -> Self {
+        Self {
+            start_ns,
+            bus_channel,
+        }
+    }
+
+    /// Writes everything before the first record, `start_time_ns` is the wall clock time the
+    /// measurement started at, in nanoseconds since 1970 (UTC)
+    pub fn write_header<S: Sink>(&self, sink: &mut S, start_time_ns: u64) -> Result<(), S::Error> {
+        // A dry run first, to find out where the data group ends up for the header to link to
+        let data_group =
+            write_blocks(&mut DrySink, start_time_ns, 0).unwrap_or_else(|e| match e {});
+
+        write_blocks(sink, start_time_ns, data_group)?;
+
+        Ok(())
+    }
+
+    pub fn write_rx_frame<S: Sink>(
+        &self,
+        sink: &mut S,
+        timestamp_ns: u64,
+        frame: &RxFDFrame,
+    ) -> Result<(), S::Error> {
+        let len = (frame.buffer_len as usize).min(64);
+        let fd_frame = frame.fd_frame || len > 8;
+
+        self.write_data_frame(
+            sink,
+            timestamp_ns,
+            frame.id,
+            &frame.buffer[..len],
+            [false, fd_frame, frame.bitrate_switch, frame.error_state],
+        )
+    }
+
+    /// Remote frames are logged as empty classical frames
+    pub fn write_tx_frame<S: Sink>(
+        &self,
+        sink: &mut S,
+        timestamp_ns: u64,
+        frame: &TxFDFrame,
+    ) -> Result<(), S::Error> {
+        self.write_data_frame(
+            sink,
+            timestamp_ns,
+            frame.id,
+            frame.payload(),
+            [
+                true,
+                frame.format.is_fd(),
+                frame.format.bitrate_switch(),
+                false,
+            ],
+        )
+    }
+
+    /// For bus errors, e.g. when `Stats::error_interrupts` goes up
+    pub fn write_error_frame<S: Sink>(
+        &self,
+        sink: &mut S,
+        timestamp_ns: u64,
+        error_type: ErrorType,
+    ) -> Result<(), S::Error> {
+        let mut record = [0; ERROR_FRAME_RECORD_LEN];
+        record[0] = RECORD_ID_ERROR_FRAME;
+        record[1..9].copy_from_slice(&self.seconds(timestamp_ns).to_le_bytes());
+        record[9] = self.bus_channel;
+        record[10] = error_type as u8;
+
+        sink.write(&record)
+    }
+
+    // `bits` are Dir (set for Tx), EDL, BRS & ESI
+    fn write_data_frame<S: Sink>(
+        &self,
+        sink: &mut S,
+        timestamp_ns: u64,
+        id: Id,
+        data: &[u8],
+        bits: [bool; 4],
+    ) -> Result<(), S::Error> {
+        let (id, ide) = match id {
+            Id::Standard(id) => (id & 0x7FF, 0),
+            Id::Extended(id) => (id & 0x1FFF_FFFF, 1 << 31),
+        };
+
+        let dlc = if bits[1] {
+            len_to_dlc(data.len() as u32)
+        } else {
+            data.len() as u32
+        };
+
+        let mut record = [0; DATA_FRAME_RECORD_LEN];
+        record[0] = RECORD_ID_DATA_FRAME;
+        record[1..9].copy_from_slice(&self.seconds(timestamp_ns).to_le_bytes());
+        record[9] = self.bus_channel;
+        record[10..14].copy_from_slice(&(id | ide).to_le_bytes());
+        record[14] = dlc as u8;
+        record[15] = data.len() as u8;
+
+        for (bit, set) in bits.iter().enumerate() {
+            if *set {
+                record[16] |= 1 << bit;
+            }
+        }
+
+        record[17..17 + data.len()].copy_from_slice(data);
+
+        sink.write(&record)
+    }
+
+    fn seconds(&self, timestamp_ns: u64) -> f64 {
+        timestamp_ns.saturating_sub(self.start_ns) as f64 / 1e9
+    }
+}
+
+// Where a channel's value is in a record (after the record ID)
+struct Channel {
+    name: &'static str,
+    cn_type: u8,
+    sync_type: u8,
+    data_type: u8,
+    byte_offset: u32,
+    bit_offset: u8,
+    bit_count: u32,
+    flags: u32,
+}
+
+// The master channel, the composition channel & then what's in the composition
+struct Group {
+    name: &'static str,
+    record_id: u8,
+    record_len: usize,
+    channels: &'static [Channel],
+}
+
+const fn timestamp() -> Channel {
+    Channel {
+        name: "Timestamp",
+        cn_type: CN_TYPE_MASTER,
+        sync_type: SYNC_TIME,
+        data_type: DATA_TYPE_FLOAT,
+        byte_offset: 0,
+        bit_offset: 0,
+        bit_count: 64,
+        flags: 0,
+    }
+}
+
+const fn composition(name: &'static str, len: u32) -> Channel {
+    Channel {
+        name,
+        cn_type: CN_TYPE_FIXED,
+        sync_type: 0,
+        data_type: DATA_TYPE_BYTES,
+        byte_offset: 8,
+        bit_offset: 0,
+        bit_count: len * 8,
+        flags: CN_FLAG_BUS_EVENT,
+    }
+}
+
+const fn field(
+    name: &'static str,
+    data_type: u8,
+    byte_offset: u32,
+    bit_offset: u8,
+    bit_count: u32,
+) -> Channel {
+    Channel {
+        name,
+        cn_type: CN_TYPE_FIXED,
+        sync_type: 0,
+        data_type,
+        byte_offset,
+        bit_offset,
+        bit_count,
+        flags: 0,
+    }
+}
+
+const DATA_FRAME: Group = Group {
+    name: "CAN_DataFrame",
+    record_id: RECORD_ID_DATA_FRAME,
+    record_len: DATA_FRAME_RECORD_LEN - 1,
+    channels: &[
+        timestamp(),
+        composition("CAN_DataFrame", 8 + 64),
+        field("CAN_DataFrame.BusChannel", DATA_TYPE_UNSIGNED, 8, 0, 8),
+        field("CAN_DataFrame.ID", DATA_TYPE_UNSIGNED, 9, 0, 29),
+        field("CAN_DataFrame.IDE", DATA_TYPE_UNSIGNED, 12, 7, 1),
+        field("CAN_DataFrame.DLC", DATA_TYPE_UNSIGNED, 13, 0, 4),
+        field("CAN_DataFrame.DataLength", DATA_TYPE_UNSIGNED, 14, 0, 8),
+        field("CAN_DataFrame.Dir", DATA_TYPE_UNSIGNED, 15, 0, 1),
+        field("CAN_DataFrame.EDL", DATA_TYPE_UNSIGNED, 15, 1, 1),
+        field("CAN_DataFrame.BRS", DATA_TYPE_UNSIGNED, 15, 2, 1),
+        field("CAN_DataFrame.ESI", DATA_TYPE_UNSIGNED, 15, 3, 1),
+        field("CAN_DataFrame.DataBytes", DATA_TYPE_BYTES, 16, 0, 64 * 8),
+    ],
+};
+
+const ERROR_FRAME: Group = Group {
+    name: "CAN_ErrorFrame",
+    record_id: RECORD_ID_ERROR_FRAME,
+    record_len: ERROR_FRAME_RECORD_LEN - 1,
+    channels: &[
+        timestamp(),
+        composition("CAN_ErrorFrame", 2),
+        field("CAN_ErrorFrame.BusChannel", DATA_TYPE_UNSIGNED, 8, 0, 8),
+        field("CAN_ErrorFrame.ErrorType", DATA_TYPE_UNSIGNED, 9, 0, 8),
+    ],
+};
+
+// Returns the data group's offset. Everything but the header block only links backwards, so
+// the only offset that has to be known up front is `data_group`'s.
+fn write_blocks<S: Sink>(
+    sink: &mut S,
+    start_time_ns: u64,
+    data_group: u64,
+) -> Result<u64, S::Error> {
+    let mut emitter = Emitter { sink, position: 0 };
+
+    let mut id = [0; 64];
+    id[..8].copy_from_slice(b"UnFinMF ");
+    id[8..16].copy_from_slice(b"4.10    ");
+    id[16..24].copy_from_slice(b"T4CANFD ");
+    id[28..30].copy_from_slice(&410_u16.to_le_bytes());
+    id[60..62].copy_from_slice(&UNFINISHED_FLAGS.to_le_bytes());
+    emitter.write(&id)?;
+
+    // Time zone, daylight saving, time flags & class, flags, then the start angle & distance
+    let mut data = Data::new();
+    data.push(&start_time_ns.to_le_bytes());
+    data.push(&[0; 8 + 8 + 8]);
+
+    let history = emitter.position + block_len(6, data.len);
+    emitter.block(b"##HD", &[data_group, history, 0, 0, 0, 0], data.bytes())?;
+
+    let mut data = Data::new();
+    data.push(&start_time_ns.to_le_bytes());
+    data.push(&[0; 8]);
+
+    let comment = emitter.position + block_len(2, data.len);
+    emitter.block(b"##FH", &[0, comment], data.bytes())?;
+    emitter.text(b"##MD", FILE_HISTORY)?;
+
+    let bus = emitter.text(b"##TX", "CAN")?;
+    let source = emitter.block(
+        b"##SI",
+        &[bus, 0, 0],
+        &[SI_TYPE_BUS, SI_BUS_TYPE_CAN, 0, 0, 0, 0, 0, 0],
+    )?;
+    let seconds = emitter.text(b"##TX", "s")?;
+
+    let error_frame = emitter.channel_group(&ERROR_FRAME, 0, source, seconds)?;
+    let data_frame = emitter.channel_group(&DATA_FRAME, error_frame, source, seconds)?;
+
+    // The data block comes right after, records are 1 byte IDs & then the group's data
+    let data = emitter.position + block_len(4, 8);
+    let data_group = emitter.block(
+        b"##DG",
+        &[0, data_frame, data, 0],
+        &[1, 0, 0, 0, 0, 0, 0, 0],
+    )?;
+    emitter.block(b"##DT", &[], &[])?;
+
+    Ok(data_group)
+}
+
+fn block_len(links: usize, data_len: usize) -> u64 {
+    (24 + links * 8 + data_len.div_ceil(8) * 8) as u64
+}
+
+// Block contents being put together, the channel block's data is the biggest
+struct Data {
+    bytes: [u8; 72],
+    len: usize,
+}
+
+impl Data {
+    fn new() -> Self {
+        Self {
+            bytes: [0; 72],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+// Throws the bytes away for the dry run, the emitter does the counting
+struct DrySink;
+
+impl Sink for DrySink {
+    type Error = Infallible;
+
+    fn write(&mut self, _bytes: &[u8]) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+struct Emitter<'a, S: Sink> {
+    sink: &'a mut S,
+    position: u64,
+}
+
+impl<'a, S: Sink> Emitter<'a, S> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), S::Error> {
+        self.sink.write(bytes)?;
+        self.position += bytes.len() as u64;
+
+        Ok(())
+    }
+
+    // Returns the block's offset
+    fn block(&mut self, id: &[u8; 4], links: &[u64], data: &[u8]) -> Result<u64, S::Error> {
+        self.padded_block(id, links, data, data.len())
+    }
+
+    // The text & a terminating zero
+    fn text(&mut self, id: &[u8; 4], text: &str) -> Result<u64, S::Error> {
+        self.padded_block(id, &[], text.as_bytes(), text.len() + 1)
+    }
+
+    // `data` followed by zeros up to `data_len`, then up to a multiple of 8
+    fn padded_block(
+        &mut self,
+        id: &[u8; 4],
+        links: &[u64],
+        data: &[u8],
+        data_len: usize,
+    ) -> Result<u64, S::Error> {
+        let offset = self.position;
+        let len = block_len(links.len(), data_len);
+
+        let mut header = [0; 24];
+        header[..4].copy_from_slice(id);
+        header[8..16].copy_from_slice(&len.to_le_bytes());
+        header[16..24].copy_from_slice(&(links.len() as u64).to_le_bytes());
+        self.write(&header)?;
+
+        for link in links.iter() {
+            self.write(&link.to_le_bytes())?;
+        }
+
+        self.write(data)?;
+
+        while self.position < offset + len {
+            let padding = ((offset + len - self.position) as usize).min(8);
+            self.write(&[0; 8][..padding])?;
+        }
+
+        Ok(offset)
+    }
+
+    fn channel(
+        &mut self,
+        channel: &Channel,
+        next: u64,
+        composition: u64,
+        unit: u64,
+    ) -> Result<u64, S::Error> {
+        let name = self.text(b"##TX", channel.name)?;
+
+        let mut data = Data::new();
+        data.push(&[
+            channel.cn_type,
+            channel.sync_type,
+            channel.data_type,
+            channel.bit_offset,
+        ]);
+        data.push(&channel.byte_offset.to_le_bytes());
+        data.push(&channel.bit_count.to_le_bytes());
+        data.push(&channel.flags.to_le_bytes());
+        data.push(&[0; 4]); // Invalidation bit position
+        data.push(&[0; 4]); // Precision, reserved & attachment count
+        data.push(&[0; 6 * 8]); // Value range & limits
+
+        // Next, composition, name, source, conversion, data, unit & comment
+        self.block(
+            b"##CN",
+            &[next, composition, name, 0, 0, 0, unit, 0],
+            data.bytes(),
+        )
+    }
+
+    fn channel_group(
+        &mut self,
+        group: &Group,
+        next: u64,
+        source: u64,
+        seconds: u64,
+    ) -> Result<u64, S::Error> {
+        // Last to first, so each one can link to the one after it
+        let mut next_field = 0;
+
+        for channel in group.channels[2..].iter().rev() {
+            next_field = self.channel(channel, next_field, 0, 0)?;
+        }
+
+        let composition = self.channel(&group.channels[1], 0, next_field, 0)?;
+        let master = self.channel(&group.channels[0], composition, 0, seconds)?;
+
+        let mut data = Data::new();
+        data.push(&(group.record_id as u64).to_le_bytes());
+        data.push(&0_u64.to_le_bytes()); // Cycle count, not known
+        data.push(&(CG_FLAG_BUS_EVENT | CG_FLAG_PLAIN_BUS_EVENT).to_le_bytes());
+        data.push(&(b'.' as u16).to_le_bytes()); // Path separator
+        data.push(&[0; 4]);
+        data.push(&(group.record_len as u32).to_le_bytes());
+        data.push(&0_u32.to_le_bytes()); // Invalidation bytes
+
+        // The acquisition name is the kind of event, the source is the bus
+        let name = self.text(b"##TX", group.name)?;
+
+        // Next, first channel, acquisition name & source, sample reduction & comment
+        self.block(b"##CG", &[next, master, name, source, 0, 0], data.bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::rx_frame;
+    use crate::FrameFormat;
+    use core::convert::TryInto;
+
+    const START_TIME_NS: u64 = 1_760_000_000_000_000_000;
+
+    struct Block<'a> {
+        id: &'a [u8],
+        links: Vec<u64>,
+        data: &'a [u8],
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn block(file: &[u8], offset: u64) -> Block<'_> {
+        let offset = offset as usize;
+        assert_eq!(offset % 8, 0);
+        assert_eq!(file[offset..offset + 2], *b"##");
+
+        let len = read_u64(file, offset + 8) as usize;
+        let links = read_u64(file, offset + 16) as usize;
+        assert_eq!(len % 8, 0);
+
+        Block {
+            id: &file[offset + 2..offset + 4],
+            links: (0..links)
+                .map(|i| read_u64(file, offset + 24 + i * 8))
+                .collect(),
+            data: &file[offset + 24 + links * 8..offset + len],
+        }
+    }
+
+    fn text(file: &[u8], offset: u64) -> &str {
+        let block = block(file, offset);
+        assert!(block.id == b"TX" || block.id == b"MD");
+
+        let end = block.data.iter().position(|c| *c == 0).unwrap();
+        core::str::from_utf8(&block.data[..end]).unwrap()
+    }
+
+    fn header() -> Vec<u8> {
+        let mut file = Vec::new();
+        Mdf4Writer::new(0, 0)
+            .write_header(&mut file, START_TIME_NS)
+            .unwrap();
+        file
+    }
+
+    #[test]
+    fn id_block() {
+        let file = header();
+
+        assert_eq!(file[..8], *b"UnFinMF ");
+        assert_eq!(file[8..16], *b"4.10    ");
+        assert_eq!(file[16..24], *b"T4CANFD ");
+        assert_eq!(file[24..28], [0; 4]);
+        assert_eq!(file[28..30], 410_u16.to_le_bytes());
+        assert_eq!(file[30..60], [0; 30]);
+        assert_eq!(file[60..62], UNFINISHED_FLAGS.to_le_bytes());
+        assert_eq!(file[62..64], [0; 2]);
+    }
+
+    #[test]
+    fn header_links() {
+        let file = header();
+        let hd = block(&file, 64);
+        assert_eq!(hd.id, b"HD");
+        assert_eq!(hd.links.len(), 6);
+        assert_eq!(read_u64(hd.data, 0), START_TIME_NS);
+
+        // The link the dry run worked out lands on the data group
+        let dg = block(&file, hd.links[0]);
+        assert_eq!(dg.id, b"DG");
+        assert_eq!(dg.links, [0, dg.links[1], dg.links[2], 0]);
+        assert_eq!(dg.data[0], 1); // Record ID size
+
+        // With the data block right after it, open ended at the end of the header
+        let dt = dg.links[2] as usize;
+        assert_eq!(dt, hd.links[0] as usize + 24 + 4 * 8 + 8);
+        assert_eq!(file[dt..dt + 4], *b"##DT");
+        assert_eq!(read_u64(&file, dt + 8), 24);
+        assert_eq!(dt + 24, file.len());
+
+        let fh = block(&file, hd.links[1]);
+        assert_eq!(fh.id, b"FH");
+        assert_eq!(read_u64(fh.data, 0), START_TIME_NS);
+        assert_eq!(text(&file, fh.links[1]), FILE_HISTORY);
+
+        // The same no matter when the measurement started
+        let mut other = Vec::new();
+        Mdf4Writer::new(0, 0).write_header(&mut other, 0).unwrap();
+        assert_eq!(block(&other, 64).links[0], hd.links[0]);
+    }
+
+    #[test]
+    fn channel_groups() {
+        let file = header();
+        let dg = block(&file, block(&file, 64).links[0]);
+        let mut groups = Vec::new();
+        let mut next = dg.links[1];
+
+        while next != 0 {
+            let cg = block(&file, next);
+            assert_eq!(cg.id, b"CG");
+            assert_eq!(cg.data.len(), 32);
+
+            let source = block(&file, cg.links[3]);
+            assert_eq!(source.id, b"SI");
+            assert_eq!(source.data[..2], [SI_TYPE_BUS, SI_BUS_TYPE_CAN]);
+            assert_eq!(text(&file, source.links[0]), "CAN");
+
+            assert_eq!(
+                cg.data[16..18],
+                (CG_FLAG_BUS_EVENT | CG_FLAG_PLAIN_BUS_EVENT).to_le_bytes()
+            );
+            assert_eq!(cg.data[18..20], (b'.' as u16).to_le_bytes());
+            assert_eq!(read_u32(cg.data, 28), 0);
+
+            let record_id = read_u64(cg.data, 0) as u8;
+            let data_bytes = read_u32(cg.data, 24);
+
+            let master = block(&file, cg.links[1]);
+            assert_eq!(text(&file, master.links[2]), "Timestamp");
+            assert_eq!(
+                master.data[..4],
+                [CN_TYPE_MASTER, SYNC_TIME, DATA_TYPE_FLOAT, 0]
+            );
+            assert_eq!(text(&file, master.links[6]), "s");
+
+            let composition = block(&file, master.links[0]);
+            assert_eq!(composition.links[0], 0);
+            assert_eq!(read_u32(composition.data, 12), CN_FLAG_BUS_EVENT);
+
+            // Name, byte offset, bit offset & bit count of the master, composition & fields
+            let mut channels = Vec::new();
+            let mut next_channel = cg.links[1];
+
+            while next_channel != 0 {
+                let cn = block(&file, next_channel);
+                assert_eq!(cn.id, b"CN");
+                assert_eq!(cn.data.len(), 72);
+
+                let byte_offset = read_u32(cn.data, 4);
+                let bit_offset = cn.data[3] as u32;
+                let bit_count = read_u32(cn.data, 8);
+                assert!(byte_offset * 8 + bit_offset + bit_count <= data_bytes * 8);
+
+                channels.push((
+                    text(&file, cn.links[2]).to_string(),
+                    byte_offset,
+                    bit_offset,
+                    bit_count,
+                ));
+
+                next_channel = if cn.links[1] != 0 {
+                    cn.links[1]
+                } else {
+                    cn.links[0]
+                };
+            }
+
+            groups.push((
+                text(&file, cg.links[2]).to_string(),
+                record_id,
+                data_bytes,
+                channels,
+            ));
+            next = cg.links[0];
+        }
+
+        assert_eq!(groups.len(), 2);
+
+        for ((name, record_id, data_bytes, channels), group) in
+            groups.iter().zip([DATA_FRAME, ERROR_FRAME].iter())
+        {
+            assert_eq!(name, group.name);
+            assert_eq!(*record_id, group.record_id);
+            assert_eq!(*data_bytes as usize, group.record_len);
+            assert_eq!(channels.len(), group.channels.len());
+
+            for (read, channel) in channels.iter().zip(group.channels.iter()) {
+                assert_eq!(
+                    *read,
+                    (
+                        channel.name.to_string(),
+                        channel.byte_offset,
+                        channel.bit_offset as u32,
+                        channel.bit_count
+                    )
+                );
+            }
+        }
+
+        assert_eq!(groups[0].2 as usize, DATA_FRAME_RECORD_LEN - 1);
+        assert_eq!(groups[1].2 as usize, ERROR_FRAME_RECORD_LEN - 1);
+    }
+
+    #[test]
+    fn records() {
+        let writer = Mdf4Writer::new(1_000, 3);
+        let mut file = Vec::new();
+
+        writer
+            .write_rx_frame(
+                &mut file,
+                1_000 + 1_500_000_000,
+                &rx_frame(Id::Standard(0x123), &[1, 2, 3]),
+            )
+            .unwrap();
+
+        let frame = TxFDFrame {
+            id: Id::Extended(0x01AB_CDEF),
+            buffer: &[7; 12],
+            priority: None,
+            format: FrameFormat::default(),
+        };
+        writer
+            .write_tx_frame(&mut file, 2_000_001_000, &frame)
+            .unwrap();
+        writer
+            .write_error_frame(&mut file, 2_500_001_000, ErrorType::Crc)
+            .unwrap();
+
+        assert_eq!(
+            file.len(),
+            2 * DATA_FRAME_RECORD_LEN + ERROR_FRAME_RECORD_LEN
+        );
+
+        let (record, rest) = file.split_at(DATA_FRAME_RECORD_LEN);
+        assert_eq!(record[0], RECORD_ID_DATA_FRAME);
+        assert_eq!(f64::from_le_bytes(record[1..9].try_into().unwrap()), 1.5);
+        assert_eq!(record[9], 3);
+        assert_eq!(read_u32(record, 10), 0x123);
+        assert_eq!(record[14..17], [3, 3, 0]);
+        assert_eq!(record[17..21], [1, 2, 3, 0]);
+
+        // Extended ID flag, DLC 9 for 12 bytes & Dir, EDL, BRS set
+        let (record, rest) = rest.split_at(DATA_FRAME_RECORD_LEN);
+        assert_eq!(read_u32(record, 10), 0x8000_0000 | 0x01AB_CDEF);
+        assert_eq!(record[14..17], [9, 12, 0b0111]);
+        assert_eq!(record[17..29], [7; 12]);
+
+        assert_eq!(rest[0], RECORD_ID_ERROR_FRAME);
+        assert_eq!(f64::from_le_bytes(rest[1..9].try_into().unwrap()), 2.5);
+        assert_eq!(rest[9..], [3, ErrorType::Crc as u8]);
+    }
+}
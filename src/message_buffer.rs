@@ -17,7 +17,7 @@ pub const _CS_CODE_RX_RANSWER: u32 = 0xA;
 pub const _CS_CODE_RX_NOTUSED: u32 = 0xF;
 
 pub const CS_CODE_TX_INACTIVE: u32 = 0x8;
-pub const _CS_CODE_TX_ABORT: u32 = 0x9;
+pub const CS_CODE_TX_ABORT: u32 = 0x9;
 pub const CS_CODE_TX_DATA_OR_REMOTE: u32 = 0xC;
 pub const _CS_CODE_TX_ANSWER: u32 = 0xE;
 pub const _CS_CODE_TX_NOT_USED: u32 = 0xF;
@@ -106,7 +106,7 @@ pub enum CSField {
     CODE,
     SSR,
     IDE,
-    _RTR,
+    RTR,
     DLC,
     TIMESTAMP,
 }
@@ -120,7 +120,7 @@ impl CSField {
             CSField::CODE => 0xF00_0000,
             CSField::SSR => 0x40_0000,
             CSField::IDE => 0x20_0000,
-            CSField::_RTR => 0x10_0000,
+            CSField::RTR => 0x10_0000,
             CSField::DLC => 0xF_0000,
             CSField::TIMESTAMP => 0xFFFF,
         }
@@ -134,7 +134,7 @@ impl CSField {
             CSField::CODE => 24,
             CSField::SSR => 22,
             CSField::IDE => 21,
-            CSField::_RTR => 20,
+            CSField::RTR => 20,
             CSField::DLC => 16,
             CSField::TIMESTAMP => 0,
         }
@@ -157,10 +157,6 @@ impl CSRegisterBitfield {
     pub fn read_field(&self, field: CSField) -> u32 {
         (self.val & field.mask()) >> field.shift()
     }
-
-    pub fn serialize(&self) -> u32 {
-        self.val
-    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -205,8 +201,4 @@ impl IDRegisterBitfield {
     pub fn read_field(&self, field: IDField) -> u32 {
         (self.val & field.mask()) >> field.shift()
     }
-
-    pub fn serialize(&self) -> u32 {
-        self.val
-    }
 }
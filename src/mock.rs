@@ -0,0 +1,81 @@
+//! A stand-in for `CAN3FD` for testing the protocol layers on the host
+
+use crate::can_error::RxTxError;
+use crate::config::Id;
+use crate::pcapng::Sink;
+use crate::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SentFrame {
+    pub id: Id,
+    pub data: Vec<u8>,
+    pub format: FrameFormat,
+}
+
+impl SentFrame {
+    /// The frame as the other end of the bus receives it
+    pub fn to_rx(&self) -> RxFDFrame {
+        let mut frame = rx_frame(self.id, &self.data);
+        frame.fd_frame = self.format.is_fd();
+        frame.bitrate_switch = self.format.bitrate_switch();
+
+        frame
+    }
+}
+
+/// Keeps everything sent, or refuses it all with `MailboxUnavailable` while `full`
+#[derive(Debug, Default)]
+pub(crate) struct Bus {
+    pub sent: Vec<SentFrame>,
+    pub full: bool,
+}
+
+impl Bus {
+    pub fn take(&mut self) -> Vec<SentFrame> {
+        core::mem::take(&mut self.sent)
+    }
+}
+
+impl Transmitter for Bus {
+    fn transmit(&mut self, frame: &TxFDFrame) -> Result<(), RxTxError> {
+        if self.full {
+            return Err(RxTxError::MailboxUnavailable);
+        }
+
+        self.sent.push(SentFrame {
+            id: frame.id,
+            data: frame.buffer.to_vec(),
+            format: frame.format,
+        });
+
+        Ok(())
+    }
+}
+
+/// Collects everything written, for the loggers & SLCAN
+impl Sink for Vec<u8> {
+    type Error = core::convert::Infallible;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+pub(crate) fn rx_frame(id: Id, data: &[u8]) -> RxFDFrame {
+    let mut buffer = [0; 64];
+    buffer[..data.len()].copy_from_slice(data);
+
+    RxFDFrame {
+        id,
+        buffer_len: data.len() as u32,
+        buffer,
+        timestamp: 0,
+        error_state: false,
+        fd_frame: data.len() > 8,
+        bitrate_switch: data.len() > 8,
+        overrun: false,
+        mailbox_index: 0,
+        priority: 0,
+    }
+}
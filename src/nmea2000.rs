@@ -0,0 +1,479 @@
+//! NMEA 2000 fast-packet protocol, for PGNs of up to 223 bytes
+//!
+//! NMEA 2000 uses the J1939 29-bit IDs. A fast-packet message is split into frames carrying a
+//! 3-bit sequence counter (which message this is) and a 5-bit frame counter (which frame of the
+//! message). The first frame has the total length and 6 bytes of data, the following ones 7
+//! bytes each. Which PGNs are fast-packet is defined per PGN, so the list is given when creating
+//! the `FastPacket`. Messages from different sources are reassembled in parallel, one slot each.
+
+use crate::can_error::RxTxError;
+use crate::j1939::J1939Id;
+use crate::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+
+pub const FAST_PACKET_MAX_LEN: usize = 6 + 31 * 7;
+pub const FAST_PACKET_SLOTS: usize = 8;
+pub const FAST_PACKET_TIMEOUT_US: u32 = 750_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nmea2000Error {
+    Busy,           // A message is already being sent
+    MessageTooLong, // Over the 223 bytes a fast-packet message can carry
+    FrameLost,      // A frame of a message being reassembled was missed, the message is dropped
+    Timeout,        // A message being reassembled stopped arriving
+    Bus(RxTxError), // The driver refused a frame
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastPacketEvent {
+    Received {
+        pgn: u32,
+        source_address: u8,
+        len: usize, // The message is waiting in `received()`
+    },
+    TransmitComplete {
+        pgn: u32,
+    },
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    active: bool,
+    pgn: u32,
+    source_address: u8,
+    sequence: u8,
+    next_frame: u8,
+    len: usize,
+    received: usize,
+    last_us: u32,
+    buffer: [u8; FAST_PACKET_MAX_LEN],
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        active: false,
+        pgn: 0,
+        source_address: 0,
+        sequence: 0,
+        next_frame: 0,
+        len: 0,
+        received: 0,
+        last_us: 0,
+        buffer: [0; FAST_PACKET_MAX_LEN],
+    };
+}
+
+pub struct FastPacket<'a> {
+    pgns: &'a [u32],
+    slots: [Slot; FAST_PACKET_SLOTS],
+    complete_slot: Option<usize>,
+    tx_buffer: [u8; FAST_PACKET_MAX_LEN],
+    tx_id: Option<J1939Id>,
+    tx_len: usize,
+    tx_sequence: u8,
+    tx_next_frame: u8,
+}
+
+impl<'a> FastPacket<'a> {
+    /// Creates the fast-packet layer for the given PGNs, frames of any other PGN are ignored
+    pub fn new(pgns: &'a [u32]) -> Self {
+        Self {
+            pgns,
+            slots: [Slot::EMPTY; FAST_PACKET_SLOTS],
+            complete_slot: None,
+            tx_buffer: [0; FAST_PACKET_MAX_LEN],
+            tx_id: None,
+            tx_len: 0,
+            tx_sequence: 0,
+            tx_next_frame: 0,
+        }
+    }
+
+    pub fn is_fast_packet(&self, pgn: u32) -> bool {
+        self.pgns.contains(&pgn)
+    }
+
+    pub fn is_transmitting(&self) -> bool {
+        self.tx_id.is_some()
+    }
+
+    /// The last message received, valid until the next frame is handled
+    pub fn received(&self) -> &[u8] {
+        match self.complete_slot {
+            Some(slot) => &self.slots[slot].buffer[..self.slots[slot].len],
+            None => &[],
+        }
+    }
+
+    /// Starts sending a message, as many frames as there are free Tx mailboxes for go out right
+    /// away & `poll` sends the rest
+    pub fn send<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        id: J1939Id,
+        data: &[u8],
+    ) -> Result<Option<FastPacketEvent>, Nmea2000Error> {
+        if self.is_transmitting() {
+            return Err(Nmea2000Error::Busy);
+        }
+
+        if data.len() > FAST_PACKET_MAX_LEN {
+            return Err(Nmea2000Error::MessageTooLong);
+        }
+
+        self.tx_buffer[..data.len()].copy_from_slice(data);
+        self.tx_id = Some(id);
+        self.tx_len = data.len();
+        self.tx_sequence = (self.tx_sequence + 1) & 0b111;
+        self.tx_next_frame = 0;
+
+        self.send_frames(can)
+    }
+
+    /// Sends the frames still waiting for a mailbox & drops reassemblies that timed out
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u32,
+    ) -> Result<Option<FastPacketEvent>, Nmea2000Error> {
+        let mut timed_out = false;
+
+        for slot in self.slots.iter_mut().filter(|slot| slot.active) {
+            if now_us.wrapping_sub(slot.last_us) > FAST_PACKET_TIMEOUT_US {
+                slot.active = false;
+                timed_out = true;
+            }
+        }
+
+        let event = self.send_frames(can)?;
+
+        if timed_out {
+            return Err(Nmea2000Error::Timeout);
+        }
+
+        Ok(event)
+    }
+
+    /// Feeds a received frame in, returns the event when it completes a message
+    pub fn handle_frame(
+        &mut self,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<FastPacketEvent>, Nmea2000Error> {
+        self.complete_slot = None;
+
+        let id = match J1939Id::from_id(frame.id) {
+            Some(id) if self.is_fast_packet(id.pgn) && frame.buffer_len >= 2 => id,
+            _ => return Ok(None),
+        };
+
+        let data = &frame.buffer[..(frame.buffer_len as usize).min(8)];
+        let sequence = data[0] >> 5;
+        let frame_counter = data[0] & 0x1F;
+        let existing = self.slots.iter().position(|slot| {
+            slot.active && slot.source_address == id.source_address && slot.pgn == id.pgn
+        });
+
+        let position = if frame_counter == 0 {
+            let len = data[1] as usize;
+
+            if len > FAST_PACKET_MAX_LEN {
+                return Ok(None);
+            }
+
+            // A new first frame restarts the message from that source, otherwise take a free
+            // slot, or the one that's been waiting the longest
+            let position = existing
+                .or_else(|| self.slots.iter().position(|slot| !slot.active))
+                .unwrap_or_else(|| self.oldest_slot(now_us));
+
+            let copied = (data.len() - 2).min(6).min(len);
+            let slot = &mut self.slots[position];
+            slot.active = true;
+            slot.pgn = id.pgn;
+            slot.source_address = id.source_address;
+            slot.sequence = sequence;
+            slot.next_frame = 1;
+            slot.len = len;
+            slot.buffer[..copied].copy_from_slice(&data[2..2 + copied]);
+            slot.received = copied;
+            slot.last_us = now_us;
+
+            position
+        } else {
+            let position = match existing {
+                Some(position) => position,
+                None => return Ok(None),
+            };
+
+            let slot = &mut self.slots[position];
+
+            if slot.sequence != sequence || slot.next_frame != frame_counter {
+                slot.active = false;
+                return Err(Nmea2000Error::FrameLost);
+            }
+
+            let copied = (data.len() - 1).min(slot.len - slot.received);
+            slot.buffer[slot.received..slot.received + copied]
+                .copy_from_slice(&data[1..1 + copied]);
+            slot.received += copied;
+            slot.next_frame += 1;
+            slot.last_us = now_us;
+
+            position
+        };
+
+        let slot = &mut self.slots[position];
+
+        if slot.received < slot.len {
+            return Ok(None);
+        }
+
+        slot.active = false;
+        self.complete_slot = Some(position);
+
+        Ok(Some(FastPacketEvent::Received {
+            pgn: slot.pgn,
+            source_address: slot.source_address,
+            len: slot.len,
+        }))
+    }
+
+    fn oldest_slot(&self, now_us: u32) -> usize {
+        let mut oldest = 0;
+
+        for (position, slot) in self.slots.iter().enumerate() {
+            if now_us.wrapping_sub(slot.last_us) > now_us.wrapping_sub(self.slots[oldest].last_us) {
+                oldest = position;
+            }
+        }
+
+        oldest
+    }
+
+    fn send_frames<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+    ) -> Result<Option<FastPacketEvent>, Nmea2000Error> {
+        let id = match self.tx_id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        loop {
+            let mut data = [0xFF_u8; 8];
+            data[0] = (self.tx_sequence << 5) | self.tx_next_frame;
+
+            let (start, end) = if self.tx_next_frame == 0 {
+                data[1] = self.tx_len as u8;
+                (0, self.tx_len.min(6))
+            } else {
+                let start = 6 + (self.tx_next_frame as usize - 1) * 7;
+                (start, (start + 7).min(self.tx_len))
+            };
+
+            let offset = if self.tx_next_frame == 0 { 2 } else { 1 };
+            data[offset..offset + end - start].copy_from_slice(&self.tx_buffer[start..end]);
+
+            let frame = TxFDFrame {
+                id: id.to_id(),
+                buffer: &data,
+                priority: None,
+                format: FrameFormat::Classic,
+            };
+
+            match can.transmit(&frame) {
+                Ok(()) => (),
+                Err(RxTxError::MailboxUnavailable) => return Ok(None),
+                Err(err) => {
+                    self.tx_id = None;
+                    return Err(Nmea2000Error::Bus(err));
+                }
+            }
+
+            if end >= self.tx_len {
+                self.tx_id = None;
+                return Ok(Some(FastPacketEvent::TransmitComplete { pgn: id.pgn }));
+            }
+
+            self.tx_next_frame += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{rx_frame, Bus};
+
+    const POSITION: u32 = 129029;
+    const HEADING: u32 = 127250;
+    const PGNS: [u32; 2] = [POSITION, 126996];
+
+    fn frame(source_address: u8, data: [u8; 8]) -> RxFDFrame {
+        rx_frame(
+            J1939Id::new(3, POSITION, source_address, 0xFF).to_id(),
+            &data,
+        )
+    }
+
+    fn received(source_address: u8, len: usize) -> Option<FastPacketEvent> {
+        Some(FastPacketEvent::Received {
+            pgn: POSITION,
+            source_address,
+            len,
+        })
+    }
+
+    #[test]
+    fn in_order() {
+        for len in [0, 1, 6, 7, 13, 100, FAST_PACKET_MAX_LEN] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut sender = FastPacket::new(&PGNS);
+            let mut receiver = FastPacket::new(&PGNS);
+            let mut bus = Bus::default();
+            let id = J1939Id::new(3, POSITION, 0x23, 0xFF);
+            let mut frames = Vec::new();
+
+            // Only the first frame fits, the rest go out as the mailboxes free up
+            bus.full = true;
+            assert_eq!(sender.send(&mut bus, id, &data), Ok(None));
+            bus.full = false;
+            let sent = sender.poll(&mut bus, 0).unwrap();
+
+            assert_eq!(
+                sent,
+                Some(FastPacketEvent::TransmitComplete { pgn: POSITION })
+            );
+            assert!(!sender.is_transmitting());
+
+            for frame in bus.take() {
+                assert_eq!(frame.data.len(), 8);
+                frames.push(receiver.handle_frame(&frame.to_rx(), 0).unwrap());
+            }
+
+            // The first frame carries 6 bytes, the others 7
+            let count = if len <= 6 { 1 } else { 1 + (len - 6).div_ceil(7) };
+            assert_eq!(frames.len(), count, "{} bytes", len);
+            assert_eq!(frames.pop().unwrap(), received(0x23, len));
+            assert!(frames.iter().all(Option::is_none));
+            assert_eq!(receiver.received(), &data[..]);
+        }
+    }
+
+    #[test]
+    fn send_limits() {
+        let mut sender = FastPacket::new(&PGNS);
+        let mut bus = Bus::default();
+        let id = J1939Id::new(3, POSITION, 0x23, 0xFF);
+
+        assert_eq!(
+            sender.send(&mut bus, id, &[0; FAST_PACKET_MAX_LEN + 1]),
+            Err(Nmea2000Error::MessageTooLong)
+        );
+
+        bus.full = true;
+        sender.send(&mut bus, id, &[0; 20]).unwrap();
+        assert_eq!(
+            sender.send(&mut bus, id, &[0; 20]),
+            Err(Nmea2000Error::Busy)
+        );
+
+        // The sequence counter moves on with every message
+        bus.full = false;
+        sender.poll(&mut bus, 0).unwrap();
+        sender.send(&mut bus, id, &[0; 20]).unwrap();
+        let counters: Vec<u8> = bus.take().iter().map(|frame| frame.data[0]).collect();
+        assert_eq!(counters, [0x20, 0x21, 0x22, 0x40, 0x41, 0x42]);
+    }
+
+    #[test]
+    fn interleaved_sequences() {
+        let mut receiver = FastPacket::new(&PGNS);
+
+        // Two sources at once
+        let first = [0x20, 10, 1, 2, 3, 4, 5, 6];
+        assert_eq!(receiver.handle_frame(&frame(1, first), 0), Ok(None));
+        let first = [0x40, 10, 9, 9, 9, 9, 9, 9];
+        assert_eq!(receiver.handle_frame(&frame(2, first), 0), Ok(None));
+        let last = [0x21, 7, 8, 9, 10, 0xFF, 0xFF, 0xFF];
+        assert_eq!(
+            receiver.handle_frame(&frame(1, last), 0),
+            Ok(received(1, 10))
+        );
+        assert_eq!(receiver.received(), [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        let last = [0x41, 9, 9, 9, 9, 0xFF, 0xFF, 0xFF];
+        assert_eq!(
+            receiver.handle_frame(&frame(2, last), 0),
+            Ok(received(2, 10))
+        );
+        assert_eq!(receiver.received(), [9; 10]);
+
+        // A new first frame from a source restarts its message
+        let first = [0x60, 10, 1, 1, 1, 1, 1, 1];
+        assert_eq!(receiver.handle_frame(&frame(1, first), 0), Ok(None));
+        let first = [0x80, 8, 2, 2, 2, 2, 2, 2];
+        assert_eq!(receiver.handle_frame(&frame(1, first), 0), Ok(None));
+        let last = [0x81, 2, 2, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(
+            receiver.handle_frame(&frame(1, last), 0),
+            Ok(received(1, 8))
+        );
+        assert_eq!(receiver.received(), [2; 8]);
+
+        // Other PGNs aren't fast-packet
+        let heading = J1939Id::new(2, HEADING, 1, 0xFF).to_id();
+        assert_eq!(
+            receiver.handle_frame(&rx_frame(heading, &first), 0),
+            Ok(None)
+        );
+        assert_eq!(receiver.received(), []);
+    }
+
+    #[test]
+    fn missing_frame() {
+        let mut receiver = FastPacket::new(&PGNS);
+
+        let first = [0x20, 20, 1, 2, 3, 4, 5, 6];
+        receiver.handle_frame(&frame(1, first), 0).unwrap();
+        let third = [0x22, 1, 1, 1, 1, 1, 1, 1];
+        assert_eq!(
+            receiver.handle_frame(&frame(1, third), 0),
+            Err(Nmea2000Error::FrameLost)
+        );
+
+        // The rest of the message is ignored
+        let fourth = [0x23, 1, 1, 1, 1, 1, 1, 1];
+        assert_eq!(receiver.handle_frame(&frame(1, fourth), 0), Ok(None));
+
+        // A frame of a different sequence is lost too
+        receiver.handle_frame(&frame(1, first), 0).unwrap();
+        let second = [0x41, 1, 1, 1, 1, 1, 1, 1];
+        assert_eq!(
+            receiver.handle_frame(&frame(1, second), 0),
+            Err(Nmea2000Error::FrameLost)
+        );
+    }
+
+    #[test]
+    fn timeout() {
+        let mut receiver = FastPacket::new(&PGNS);
+        let mut bus = Bus::default();
+
+        let first = [0x20, 20, 1, 2, 3, 4, 5, 6];
+        receiver.handle_frame(&frame(1, first), 0).unwrap();
+        let second = [0x21, 1, 1, 1, 1, 1, 1, 1];
+        receiver.handle_frame(&frame(1, second), 1_000).unwrap();
+
+        assert_eq!(
+            receiver.poll(&mut bus, 1_000 + FAST_PACKET_TIMEOUT_US),
+            Ok(None)
+        );
+        assert_eq!(
+            receiver.poll(&mut bus, 1_001 + FAST_PACKET_TIMEOUT_US),
+            Err(Nmea2000Error::Timeout)
+        );
+
+        let third = [0x22, 1, 1, 1, 1, 1, 1, 1];
+        assert_eq!(receiver.handle_frame(&frame(1, third), 0), Ok(None));
+    }
+}
@@ -0,0 +1,1048 @@
+//! OBD-II (SAE J1979 / ISO 15765-4) requests & responses on 11-bit IDs
+//!
+//! Requests go out either functionally to every emissions ECU on 0x7DF, or physically to one
+//! ECU on 0x7E0 - 0x7E7, the ECUs answer on 0x7E8 - 0x7EF. Responses that don't fit in a single
+//! frame, such as the VIN, arrive over ISO-TP, so every ECU we want to hear from gets a channel
+//! made with `channel_config`. The channels are handed in with every call, like for UDS.
+//!
+//! `ObdResponder` is the other side, for simulating an ECU on a bench.
+
+use crate::can_error::RxTxError;
+use crate::config::Id;
+use crate::isotp::{IsoTp, IsoTpConfig, IsoTpError, IsoTpEvent};
+use crate::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+
+pub const ID_FUNCTIONAL: u32 = 0x7DF;
+/// Physical requests to ECU n go to 0x7E0 + n
+pub const ID_REQUEST_BASE: u32 = 0x7E0;
+/// Responses of ECU n come from 0x7E8 + n
+pub const ID_RESPONSE_BASE: u32 = 0x7E8;
+pub const MAX_ECUS: u8 = 8;
+
+pub const MODE_CURRENT_DATA: u8 = 0x01;
+pub const MODE_VEHICLE_INFO: u8 = 0x09;
+pub const PID_VIN: u8 = 0x02; // Mode 09
+
+/// P2 of J1979
+pub const DEFAULT_TIMEOUT_US: u32 = 50_000;
+/// P2* after a response pending
+pub const PENDING_TIMEOUT_US: u32 = 5_000_000;
+
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+const NRC_RESPONSE_PENDING: u8 = 0x78;
+const NRC_REQUEST_OUT_OF_RANGE: u8 = 0x31;
+const PADDING: u8 = 0xCC;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObdError {
+    Busy,              // Still waiting for the responses to the last request
+    RequestTooLong,    // Requests have to fit in a single frame
+    InvalidMode,       // Modes are 0x00 - 0x3F, the positive response adds 0x40
+    InvalidEcu,        // ECUs are numbered 0 - 7
+    IsoTp(IsoTpError), // A channel failed receiving or sending a response
+    Bus(RxTxError),    // The driver refused a frame
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObdTarget {
+    Functional,
+    Physical(u8), // ECU 0 - 7
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObdEvent {
+    Response { ecu: u8, len: usize }, // A positive response is waiting in `received()`
+    NegativeResponse { ecu: u8, code: u8 },
+    Complete { responses: u8 }, // The timeout passed, or the physically addressed ECU answered
+}
+
+/// The ISO-TP channel to use for talking to ECU n, for the client's end
+pub fn channel_config(ecu: u8) -> IsoTpConfig {
+    let mut config = IsoTpConfig::new(
+        Id::Standard(ID_REQUEST_BASE + ecu as u32),
+        Id::Standard(ID_RESPONSE_BASE + ecu as u32),
+    );
+
+    config.tx_dl = 8;
+    config.padding = Some(PADDING);
+    config
+}
+
+/// The ISO-TP channel for ECU n when simulating it
+pub fn responder_channel_config(ecu: u8) -> IsoTpConfig {
+    let mut config = IsoTpConfig::new(
+        Id::Standard(ID_RESPONSE_BASE + ecu as u32),
+        Id::Standard(ID_REQUEST_BASE + ecu as u32),
+    );
+
+    config.tx_dl = 8;
+    config.padding = Some(PADDING);
+    config
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientState {
+    Idle,
+    Waiting {
+        mode: u8,
+        target: ObdTarget,
+        since_us: u32,
+        timeout_us: u32,
+        responses: u8,
+        done: bool,
+    },
+}
+
+pub struct ObdClient<'a> {
+    response: &'a mut [u8],
+    response_len: usize,
+    timeout_us: u32,
+    state: ClientState,
+}
+
+impl<'a> ObdClient<'a> {
+    /// Responses are copied into `response` so they can be read after the channel moves on
+    pub fn new(response: &'a mut [u8]) -> Self {
+        Self {
+            response,
+            response_len: 0,
+            timeout_us: DEFAULT_TIMEOUT_US,
+            state: ClientState::Idle,
+        }
+    }
+
+    /// How long to wait for responses, J1979 allows ECUs 50 ms
+    pub fn set_timeout(&mut self, timeout_us: u32) {
+        self.timeout_us = timeout_us;
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.state != ClientState::Idle
+    }
+
+    /// The last positive response, starting with the mode + 0x40
+    pub fn received(&self) -> &[u8] {
+        &self.response[..self.response_len]
+    }
+
+    /// Sends a request, `data` is the PIDs (up to 6 for mode 01) or whatever the mode expects
+    pub fn request<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        target: ObdTarget,
+        mode: u8,
+        data: &[u8],
+        now_us: u32,
+    ) -> Result<(), ObdError> {
+        if self.is_busy() {
+            return Err(ObdError::Busy);
+        }
+
+        if data.len() > 6 {
+            return Err(ObdError::RequestTooLong);
+        }
+
+        if mode >= POSITIVE_RESPONSE_OFFSET {
+            return Err(ObdError::InvalidMode);
+        }
+
+        let id = match target {
+            ObdTarget::Functional => ID_FUNCTIONAL,
+            ObdTarget::Physical(ecu) if ecu < MAX_ECUS => ID_REQUEST_BASE + ecu as u32,
+            ObdTarget::Physical(_) => return Err(ObdError::InvalidEcu),
+        };
+
+        let mut buffer = [PADDING; 8];
+        buffer[0] = data.len() as u8 + 1;
+        buffer[1] = mode;
+        buffer[2..2 + data.len()].copy_from_slice(data);
+
+        let frame = TxFDFrame {
+            id: Id::Standard(id),
+            buffer: &buffer,
+            priority: None,
+            format: FrameFormat::Classic,
+        };
+
+        can.transmit(&frame).map_err(ObdError::Bus)?;
+
+        self.state = ClientState::Waiting {
+            mode,
+            target,
+            since_us: now_us,
+            timeout_us: self.timeout_us,
+            responses: 0,
+            done: false,
+        };
+
+        Ok(())
+    }
+
+    /// Feeds a received frame to the channels & picks up the responses
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        channels: &mut [IsoTp],
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<ObdEvent>, ObdError> {
+        let channel = match channels
+            .iter_mut()
+            .find(|channel| channel.config().rx_id == frame.id)
+        {
+            Some(channel) => channel,
+            None => return Ok(None),
+        };
+
+        let ecu = match frame.id {
+            Id::Standard(id) if (ID_RESPONSE_BASE..ID_RESPONSE_BASE + 8).contains(&id) => {
+                (id - ID_RESPONSE_BASE) as u8
+            }
+            _ => return Ok(None),
+        };
+
+        match channel.handle_frame(can, frame, now_us) {
+            Ok(Some(IsoTpEvent::Received { .. })) => (),
+            Ok(_) => return Ok(None),
+            Err(err) => return Err(ObdError::IsoTp(err)),
+        }
+
+        let (mode, target, responses) = match &mut self.state {
+            ClientState::Waiting {
+                mode,
+                target,
+                responses,
+                ..
+            } => (*mode, *target, responses),
+            ClientState::Idle => return Ok(None),
+        };
+
+        if let ObdTarget::Physical(physical_ecu) = target {
+            if physical_ecu != ecu {
+                return Ok(None);
+            }
+        }
+
+        let data = channel.received();
+
+        if data.len() >= 3 && data[0] == NEGATIVE_RESPONSE && data[1] == mode {
+            if data[2] == NRC_RESPONSE_PENDING {
+                // The ECU needs longer, the wait restarts with P2*
+                if let ClientState::Waiting {
+                    since_us,
+                    timeout_us,
+                    ..
+                } = &mut self.state
+                {
+                    *since_us = now_us;
+                    *timeout_us = PENDING_TIMEOUT_US;
+                }
+
+                return Ok(None);
+            }
+
+            *responses += 1;
+            self.finish_physical();
+
+            return Ok(Some(ObdEvent::NegativeResponse { ecu, code: data[2] }));
+        }
+
+        if data.is_empty() || data[0] != mode + POSITIVE_RESPONSE_OFFSET {
+            return Ok(None);
+        }
+
+        *responses += 1;
+
+        let len = data.len().min(self.response.len());
+        self.response[..len].copy_from_slice(&data[..len]);
+        self.response_len = len;
+        self.finish_physical();
+
+        Ok(Some(ObdEvent::Response { ecu, len }))
+    }
+
+    /// Checks the channels' timeouts & finishes the request once the responses are in
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        channels: &mut [IsoTp],
+        now_us: u32,
+    ) -> Result<Option<ObdEvent>, ObdError> {
+        let mut receiving = false;
+
+        for channel in channels.iter_mut() {
+            channel.poll(can, now_us).map_err(ObdError::IsoTp)?;
+            receiving |= channel.is_receiving();
+        }
+
+        if let ClientState::Waiting {
+            since_us,
+            timeout_us,
+            responses,
+            done,
+            ..
+        } = self.state
+        {
+            // A response that's still arriving gets to finish, ISO-TP times it out otherwise
+            if done || (now_us.wrapping_sub(since_us) >= timeout_us && !receiving) {
+                self.state = ClientState::Idle;
+
+                return Ok(Some(ObdEvent::Complete { responses }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn finish_physical(&mut self) {
+        if let ClientState::Waiting {
+            target: ObdTarget::Physical(_),
+            done,
+            ..
+        } = &mut self.state
+        {
+            *done = true;
+        }
+    }
+}
+
+/// Data bytes each mode 01 PID answers with, None for PIDs not known here
+pub fn pid_len(pid: u8) -> Option<usize> {
+    match pid {
+        0x00 | 0x20 | 0x40 | 0x60 | 0x80 | 0xA0 | 0xC0 => Some(4),
+        0x01 => Some(4),
+        0x04 | 0x05 | 0x0B | 0x0D | 0x0F | 0x11 | 0x2F | 0x33 | 0x46 | 0x5C => Some(1),
+        0x0C | 0x10 | 0x1F | 0x42 => Some(2),
+        _ => None,
+    }
+}
+
+/// A decoded mode 01 PID, in the units J1979 defines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PidValue {
+    SupportedPids(u32), // Bit 31 is the PID after the one requested, bit 0 the 32nd
+    MonitorStatus { mil_on: bool, dtc_count: u8 },
+    EngineLoad(f32),         // %
+    CoolantTemperature(i16), // °C
+    IntakePressure(u8),      // kPa
+    EngineSpeed(f32),        // rpm
+    VehicleSpeed(u8),        // km/h
+    IntakeTemperature(i16),  // °C
+    MassAirFlow(f32),        // g/s
+    ThrottlePosition(f32),   // %
+    RunTime(u16),            // s
+    FuelLevel(f32),          // %
+    BarometricPressure(u8),  // kPa
+    ModuleVoltage(f32),      // V
+    AmbientTemperature(i16), // °C
+    OilTemperature(i16),     // °C
+}
+
+/// Decodes the data bytes of a mode 01 PID, without the PID itself
+pub fn decode_pid(pid: u8, data: &[u8]) -> Option<PidValue> {
+    if data.len() < pid_len(pid)? {
+        return None;
+    }
+
+    let a = data[0];
+    let ab = || u16::from_be_bytes([data[0], data[1]]);
+    let percent = || a as f32 * 100.0 / 255.0;
+    let temperature = || a as i16 - 40;
+
+    Some(match pid {
+        0x00 | 0x20 | 0x40 | 0x60 | 0x80 | 0xA0 | 0xC0 => {
+            PidValue::SupportedPids(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+        }
+        0x01 => PidValue::MonitorStatus {
+            mil_on: a & 0x80 != 0,
+            dtc_count: a & 0x7F,
+        },
+        0x04 => PidValue::EngineLoad(percent()),
+        0x05 => PidValue::CoolantTemperature(temperature()),
+        0x0B => PidValue::IntakePressure(a),
+        0x0C => PidValue::EngineSpeed(ab() as f32 / 4.0),
+        0x0D => PidValue::VehicleSpeed(a),
+        0x0F => PidValue::IntakeTemperature(temperature()),
+        0x10 => PidValue::MassAirFlow(ab() as f32 / 100.0),
+        0x11 => PidValue::ThrottlePosition(percent()),
+        0x1F => PidValue::RunTime(ab()),
+        0x2F => PidValue::FuelLevel(percent()),
+        0x33 => PidValue::BarometricPressure(a),
+        0x42 => PidValue::ModuleVoltage(ab() as f32 / 1000.0),
+        0x46 => PidValue::AmbientTemperature(temperature()),
+        0x5C => PidValue::OilTemperature(temperature()),
+        _ => return None,
+    })
+}
+
+/// Iterates the PIDs of a mode 01 response, with their data bytes. Stops at the first PID whose
+/// length isn't known.
+pub fn mode01_pids(response: &[u8]) -> Mode01Iter<'_> {
+    let data = match response.split_first() {
+        Some((mode, data)) if *mode == MODE_CURRENT_DATA + POSITIVE_RESPONSE_OFFSET => data,
+        _ => &[],
+    };
+
+    Mode01Iter { data }
+}
+
+pub struct Mode01Iter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Mode01Iter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (pid, rest) = self.data.split_first()?;
+        let len = pid_len(*pid)?;
+
+        if rest.len() < len {
+            self.data = &[];
+            return None;
+        }
+
+        let (data, rest) = rest.split_at(len);
+        self.data = rest;
+
+        Some((*pid, data))
+    }
+}
+
+/// Picks the 17 character VIN out of a mode 09 PID 02 response
+pub fn decode_vin(response: &[u8]) -> Option<[u8; 17]> {
+    // The mode, PID & the number of data items come first
+    if response.len() < 20
+        || response[0] != MODE_VEHICLE_INFO + POSITIVE_RESPONSE_OFFSET
+        || response[1] != PID_VIN
+    {
+        return None;
+    }
+
+    let mut vin = [0_u8; 17];
+    vin.copy_from_slice(&response[response.len() - 17..]);
+
+    Some(vin)
+}
+
+/// A PID the simulated ECU supports, `read` writes its data bytes & returns how many
+#[derive(Clone, Copy)]
+pub struct ObdPid {
+    pub mode: u8,
+    pub pid: u8,
+    pub read: fn(&mut [u8]) -> usize,
+}
+
+/// Answers OBD requests like an ECU would, from a table of PIDs. The supported PID bitmaps
+/// (PIDs 0x00, 0x20, ...) are built from the table.
+pub struct ObdResponder<'a> {
+    ecu: u8,
+    pids: &'a [ObdPid],
+    response: &'a mut [u8],
+}
+
+impl<'a> ObdResponder<'a> {
+    pub fn new(ecu: u8, pids: &'a [ObdPid], response: &'a mut [u8]) -> Result<Self, ObdError> {
+        if ecu >= MAX_ECUS {
+            return Err(ObdError::InvalidEcu);
+        }
+
+        Ok(Self {
+            ecu,
+            pids,
+            response,
+        })
+    }
+
+    /// Feeds a received frame in, functional requests are picked up directly & physical ones
+    /// through the channel
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        isotp: &mut IsoTp,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<(), ObdError> {
+        let mut request = [0_u8; 7];
+
+        let (len, functional) = if frame.id == Id::Standard(ID_FUNCTIONAL) {
+            // Functional requests are single frames, the low nibble is the length
+            let len = (frame.buffer[0] & 0x0F) as usize;
+
+            if frame.buffer[0] >> 4 != 0 || len == 0 || len > 7 || len >= frame.buffer_len as usize
+            {
+                return Ok(());
+            }
+
+            request[..len].copy_from_slice(&frame.buffer[1..1 + len]);
+            (len, true)
+        } else if frame.id == Id::Standard(ID_REQUEST_BASE + self.ecu as u32) {
+            match isotp.handle_frame(can, frame, now_us) {
+                Ok(Some(IsoTpEvent::Received { len })) if len <= 7 => {
+                    request[..len].copy_from_slice(isotp.received());
+                    (len, false)
+                }
+                Ok(_) => return Ok(()),
+                Err(err) => return Err(ObdError::IsoTp(err)),
+            }
+        } else {
+            return Ok(());
+        };
+
+        let mode = request[0];
+        let response_len = self.respond(mode, &request[1..len]);
+
+        let response_len = match response_len {
+            Some(response_len) => response_len,
+            // Functional requests for nothing we support go unanswered
+            None if functional => return Ok(()),
+            None => {
+                self.response[..3].copy_from_slice(&[
+                    NEGATIVE_RESPONSE,
+                    mode,
+                    NRC_REQUEST_OUT_OF_RANGE,
+                ]);
+                3
+            }
+        };
+
+        isotp
+            .send(can, &self.response[..response_len], now_us)
+            .map(|_| ())
+            .map_err(ObdError::IsoTp)
+    }
+
+    /// Sends the rest of multi-frame responses
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        isotp: &mut IsoTp,
+        now_us: u32,
+    ) -> Result<(), ObdError> {
+        isotp.poll(can, now_us).map(|_| ()).map_err(ObdError::IsoTp)
+    }
+
+    // Builds the positive response, None when none of the PIDs are supported
+    fn respond(&mut self, mode: u8, pids: &[u8]) -> Option<usize> {
+        if self.response.is_empty() {
+            return None;
+        }
+
+        self.response[0] = mode.wrapping_add(POSITIVE_RESPONSE_OFFSET);
+        let mut len = 1;
+
+        // Mode 09 takes a single PID, mode 01 up to 6
+        let pids = if mode == MODE_CURRENT_DATA {
+            pids
+        } else {
+            &pids[..pids.len().min(1)]
+        };
+
+        for pid in pids {
+            let mut data = [0_u8; 64];
+
+            let data_len = if pid % 0x20 == 0 {
+                let supported = self.supported(mode, *pid);
+
+                if supported == 0 && *pid != 0 {
+                    continue;
+                }
+
+                data[..4].copy_from_slice(&supported.to_be_bytes());
+                4
+            } else {
+                match self
+                    .pids
+                    .iter()
+                    .find(|entry| entry.mode == mode && entry.pid == *pid)
+                {
+                    Some(entry) => (entry.read)(&mut data).min(data.len()),
+                    None => continue,
+                }
+            };
+
+            if len + 1 + data_len > self.response.len() {
+                break;
+            }
+
+            self.response[len] = *pid;
+            self.response[len + 1..len + 1 + data_len].copy_from_slice(&data[..data_len]);
+            len += 1 + data_len;
+        }
+
+        if len == 1 {
+            None
+        } else {
+            Some(len)
+        }
+    }
+
+    // The bitmap of supported PIDs after `base`, including the next bitmap PID when anything past
+    // it is supported
+    fn supported(&self, mode: u8, base: u8) -> u32 {
+        let mut supported = 0_u32;
+
+        for entry in self.pids.iter().filter(|entry| entry.mode == mode) {
+            if entry.pid > base && entry.pid as u16 <= base as u16 + 0x20 {
+                supported |= 1 << (0x20 - (entry.pid - base) as u32);
+            } else if entry.pid as u16 > base as u16 + 0x20 {
+                supported |= 1;
+            }
+        }
+
+        supported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{rx_frame, Bus};
+
+    const VIN: &[u8; 17] = b"1G1JC5444R7252367";
+
+    fn read_rpm(data: &mut [u8]) -> usize {
+        data[..2].copy_from_slice(&[0x1A, 0xF8]);
+        2
+    }
+
+    fn read_speed(data: &mut [u8]) -> usize {
+        data[0] = 60;
+        1
+    }
+
+    fn read_voltage(data: &mut [u8]) -> usize {
+        data[..2].copy_from_slice(&[0x31, 0x9C]);
+        2
+    }
+
+    fn read_vin(data: &mut [u8]) -> usize {
+        data[0] = 1;
+        data[1..18].copy_from_slice(VIN);
+        18
+    }
+
+    const PIDS: [ObdPid; 4] = [
+        ObdPid {
+            mode: MODE_CURRENT_DATA,
+            pid: 0x0C,
+            read: read_rpm,
+        },
+        ObdPid {
+            mode: MODE_CURRENT_DATA,
+            pid: 0x0D,
+            read: read_speed,
+        },
+        ObdPid {
+            mode: MODE_CURRENT_DATA,
+            pid: 0x42,
+            read: read_voltage,
+        },
+        ObdPid {
+            mode: MODE_VEHICLE_INFO,
+            pid: PID_VIN,
+            read: read_vin,
+        },
+    ];
+
+    // Passes frames between the client & a simulated ECU 0 until the bus goes quiet
+    fn exchange(
+        bus: &mut Bus,
+        client: &mut ObdClient,
+        channels: &mut [IsoTp],
+        responder: &mut ObdResponder,
+        responder_isotp: &mut IsoTp,
+        now_us: u32,
+    ) -> Vec<ObdEvent> {
+        let mut events = Vec::new();
+
+        loop {
+            responder.poll(bus, responder_isotp, now_us).unwrap();
+            events.extend(client.poll(bus, channels, now_us).unwrap());
+
+            let frames = bus.take();
+            if frames.is_empty() {
+                return events;
+            }
+
+            for frame in frames {
+                let frame = frame.to_rx();
+
+                if frame.id == Id::Standard(ID_RESPONSE_BASE) {
+                    events.extend(client.handle_frame(bus, channels, &frame, now_us).unwrap());
+                } else {
+                    responder
+                        .handle_frame(bus, responder_isotp, &frame, now_us)
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_pids() {
+        assert_eq!(
+            decode_pid(0x0C, &[0x1A, 0xF8]),
+            Some(PidValue::EngineSpeed(1726.0))
+        );
+        assert_eq!(
+            decode_pid(0x05, &[0x7B]),
+            Some(PidValue::CoolantTemperature(83))
+        );
+        assert_eq!(
+            decode_pid(0x46, &[0x00]),
+            Some(PidValue::AmbientTemperature(-40))
+        );
+        assert_eq!(decode_pid(0x04, &[0xFF]), Some(PidValue::EngineLoad(100.0)));
+        assert_eq!(
+            decode_pid(0x10, &[0x01, 0x90]),
+            Some(PidValue::MassAirFlow(4.0))
+        );
+        assert_eq!(
+            decode_pid(0x42, &[0x31, 0x9C]),
+            Some(PidValue::ModuleVoltage(12.7))
+        );
+        assert_eq!(
+            decode_pid(0x00, &[0xBE, 0x1F, 0xA8, 0x13]),
+            Some(PidValue::SupportedPids(0xBE1F_A813))
+        );
+
+        // Too short, & a PID we don't know
+        assert_eq!(decode_pid(0x0C, &[0x1A]), None);
+        assert_eq!(decode_pid(0x03, &[0x01, 0x02]), None);
+    }
+
+    #[test]
+    fn decode_monitor_status() {
+        assert_eq!(
+            decode_pid(0x01, &[0x83, 0x07, 0xE5, 0x00]),
+            Some(PidValue::MonitorStatus {
+                mil_on: true,
+                dtc_count: 3,
+            })
+        );
+        assert_eq!(
+            decode_pid(0x01, &[0x00, 0x07, 0xE5, 0x00]),
+            Some(PidValue::MonitorStatus {
+                mil_on: false,
+                dtc_count: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn mode01_response_pids() {
+        let response = [0x41, 0x0C, 0x1A, 0xF8, 0x0D, 0x3C, 0x05, 0x7B];
+        let pids: Vec<_> = mode01_pids(&response).collect();
+        assert_eq!(
+            pids,
+            [
+                (0x0C, &[0x1A, 0xF8][..]),
+                (0x0D, &[0x3C][..]),
+                (0x05, &[0x7B][..])
+            ]
+        );
+
+        // Stops at unknown & truncated PIDs, & ignores other modes
+        assert_eq!(mode01_pids(&[0x41, 0x0D, 0x3C, 0x03, 0x00]).count(), 1);
+        assert_eq!(mode01_pids(&[0x41, 0x0C, 0x1A]).count(), 0);
+        assert_eq!(mode01_pids(&[0x49, 0x0D, 0x3C]).count(), 0);
+    }
+
+    #[test]
+    fn vin() {
+        let mut response = vec![0x49, PID_VIN, 0x01];
+        response.extend_from_slice(VIN);
+
+        assert_eq!(decode_vin(&response), Some(*VIN));
+        assert_eq!(decode_vin(&response[..19]), None);
+        response[1] = 0x04;
+        assert_eq!(decode_vin(&response), None);
+    }
+
+    #[test]
+    fn request_framing() {
+        let mut buffer = [0; 64];
+        let mut client = ObdClient::new(&mut buffer);
+        let mut bus = Bus::default();
+
+        client
+            .request(
+                &mut bus,
+                ObdTarget::Functional,
+                MODE_CURRENT_DATA,
+                &[0x0C, 0x0D],
+                0,
+            )
+            .unwrap();
+
+        let sent = bus.take();
+        assert_eq!(sent[0].id, Id::Standard(ID_FUNCTIONAL));
+        assert_eq!(
+            sent[0].data,
+            [0x03, 0x01, 0x0C, 0x0D, PADDING, PADDING, PADDING, PADDING]
+        );
+        assert_eq!(sent[0].format, FrameFormat::Classic);
+
+        assert_eq!(
+            client.request(&mut bus, ObdTarget::Functional, 0x01, &[], 0),
+            Err(ObdError::Busy)
+        );
+        assert_eq!(
+            client.poll(&mut bus, &mut [], DEFAULT_TIMEOUT_US - 1),
+            Ok(None)
+        );
+        assert_eq!(
+            client.poll(&mut bus, &mut [], DEFAULT_TIMEOUT_US),
+            Ok(Some(ObdEvent::Complete { responses: 0 }))
+        );
+
+        assert_eq!(
+            client.request(&mut bus, ObdTarget::Functional, 0x01, &[0; 7], 0),
+            Err(ObdError::RequestTooLong)
+        );
+        assert_eq!(
+            client.request(&mut bus, ObdTarget::Functional, 0x41, &[], 0),
+            Err(ObdError::InvalidMode)
+        );
+        assert_eq!(
+            client.request(&mut bus, ObdTarget::Physical(8), 0x01, &[], 0),
+            Err(ObdError::InvalidEcu)
+        );
+
+        client
+            .request(
+                &mut bus,
+                ObdTarget::Physical(3),
+                MODE_CURRENT_DATA,
+                &[0x00],
+                0,
+            )
+            .unwrap();
+        assert_eq!(bus.take()[0].id, Id::Standard(ID_REQUEST_BASE + 3));
+    }
+
+    #[test]
+    fn functional_request() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut channels = [IsoTp::new(channel_config(0), &mut tx, &mut rx)];
+        let (mut responder_tx, mut responder_rx) = ([0; 64], [0; 64]);
+        let mut responder_isotp = IsoTp::new(
+            responder_channel_config(0),
+            &mut responder_tx,
+            &mut responder_rx,
+        );
+        let mut response = [0; 64];
+        let mut responder = ObdResponder::new(0, &PIDS, &mut response).unwrap();
+        let mut buffer = [0; 64];
+        let mut client = ObdClient::new(&mut buffer);
+        let mut bus = Bus::default();
+
+        client
+            .request(
+                &mut bus,
+                ObdTarget::Functional,
+                MODE_CURRENT_DATA,
+                &[0x00, 0x0C, 0x0D],
+                0,
+            )
+            .unwrap();
+
+        let events = exchange(
+            &mut bus,
+            &mut client,
+            &mut channels,
+            &mut responder,
+            &mut responder_isotp,
+            0,
+        );
+        assert_eq!(events, [ObdEvent::Response { ecu: 0, len: 11 }]);
+
+        // 0x0C, 0x0D & the next bitmap for 0x42 are supported
+        assert_eq!(
+            client.received(),
+            [0x41, 0x00, 0x00, 0x18, 0x00, 0x01, 0x0C, 0x1A, 0xF8, 0x0D, 60]
+        );
+        let values: Vec<_> = mode01_pids(client.received())
+            .filter_map(|(pid, data)| decode_pid(pid, data))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                PidValue::SupportedPids(0x0018_0001),
+                PidValue::EngineSpeed(1726.0),
+                PidValue::VehicleSpeed(60)
+            ]
+        );
+
+        // Functional requests wait out the timeout for other ECUs
+        assert_eq!(client.poll(&mut bus, &mut channels, 1), Ok(None));
+        assert_eq!(
+            client.poll(&mut bus, &mut channels, DEFAULT_TIMEOUT_US),
+            Ok(Some(ObdEvent::Complete { responses: 1 }))
+        );
+
+        // Nothing supported, nothing answered
+        client
+            .request(
+                &mut bus,
+                ObdTarget::Functional,
+                MODE_CURRENT_DATA,
+                &[0x05],
+                0,
+            )
+            .unwrap();
+        let events = exchange(
+            &mut bus,
+            &mut client,
+            &mut channels,
+            &mut responder,
+            &mut responder_isotp,
+            0,
+        );
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn physical_vin_request() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut channels = [IsoTp::new(channel_config(0), &mut tx, &mut rx)];
+        let (mut responder_tx, mut responder_rx) = ([0; 64], [0; 64]);
+        let mut responder_isotp = IsoTp::new(
+            responder_channel_config(0),
+            &mut responder_tx,
+            &mut responder_rx,
+        );
+        let mut response = [0; 64];
+        let mut responder = ObdResponder::new(0, &PIDS, &mut response).unwrap();
+        let mut buffer = [0; 64];
+        let mut client = ObdClient::new(&mut buffer);
+        let mut bus = Bus::default();
+
+        client
+            .request(
+                &mut bus,
+                ObdTarget::Physical(0),
+                MODE_VEHICLE_INFO,
+                &[PID_VIN],
+                0,
+            )
+            .unwrap();
+
+        // Single frame request, the 20 byte response comes over a first & consecutive frames
+        let events = exchange(
+            &mut bus,
+            &mut client,
+            &mut channels,
+            &mut responder,
+            &mut responder_isotp,
+            0,
+        );
+        // The physically addressed ECU answered, so there's no waiting for the timeout
+        assert_eq!(
+            events,
+            [
+                ObdEvent::Response { ecu: 0, len: 20 },
+                ObdEvent::Complete { responses: 1 }
+            ]
+        );
+        assert_eq!(decode_vin(client.received()), Some(*VIN));
+    }
+
+    #[test]
+    fn negative_and_pending_responses() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut channels = [IsoTp::new(channel_config(0), &mut tx, &mut rx)];
+        let mut buffer = [0; 64];
+        let mut client = ObdClient::new(&mut buffer);
+        let mut bus = Bus::default();
+        let response = |data: &[u8]| {
+            let mut frame = [PADDING; 8];
+            frame[0] = data.len() as u8;
+            frame[1..1 + data.len()].copy_from_slice(data);
+            rx_frame(Id::Standard(ID_RESPONSE_BASE), &frame)
+        };
+
+        client
+            .request(
+                &mut bus,
+                ObdTarget::Physical(0),
+                MODE_CURRENT_DATA,
+                &[0x0D],
+                0,
+            )
+            .unwrap();
+
+        // Response pending stretches the wait to P2*
+        let pending = response(&[NEGATIVE_RESPONSE, MODE_CURRENT_DATA, NRC_RESPONSE_PENDING]);
+        assert_eq!(
+            client.handle_frame(&mut bus, &mut channels, &pending, 1000),
+            Ok(None)
+        );
+        assert_eq!(
+            client.poll(&mut bus, &mut channels, 1000 + DEFAULT_TIMEOUT_US),
+            Ok(None)
+        );
+
+        let negative = response(&[
+            NEGATIVE_RESPONSE,
+            MODE_CURRENT_DATA,
+            NRC_REQUEST_OUT_OF_RANGE,
+        ]);
+        assert_eq!(
+            client.handle_frame(&mut bus, &mut channels, &negative, 2000),
+            Ok(Some(ObdEvent::NegativeResponse {
+                ecu: 0,
+                code: NRC_REQUEST_OUT_OF_RANGE
+            }))
+        );
+        assert_eq!(
+            client.poll(&mut bus, &mut channels, 2000),
+            Ok(Some(ObdEvent::Complete { responses: 1 }))
+        );
+    }
+
+    #[test]
+    fn responder_rejects_unsupported_physical_requests() {
+        let (mut tx, mut rx) = ([0; 64], [0; 64]);
+        let mut isotp = IsoTp::new(responder_channel_config(2), &mut tx, &mut rx);
+        let mut response = [0; 64];
+        let mut responder = ObdResponder::new(2, &PIDS, &mut response).unwrap();
+        let mut bus = Bus::default();
+
+        let request = rx_frame(
+            Id::Standard(ID_REQUEST_BASE + 2),
+            &[
+                0x02,
+                MODE_CURRENT_DATA,
+                0x05,
+                PADDING,
+                PADDING,
+                PADDING,
+                PADDING,
+                PADDING,
+            ],
+        );
+        responder
+            .handle_frame(&mut bus, &mut isotp, &request, 0)
+            .unwrap();
+
+        let sent = bus.take();
+        assert_eq!(sent[0].id, Id::Standard(ID_RESPONSE_BASE + 2));
+        assert_eq!(
+            sent[0].data[..4],
+            [
+                0x03,
+                NEGATIVE_RESPONSE,
+                MODE_CURRENT_DATA,
+                NRC_REQUEST_OUT_OF_RANGE
+            ]
+        );
+
+        assert!(matches!(
+            ObdResponder::new(MAX_ECUS, &PIDS, &mut [0; 8]),
+            Err(ObdError::InvalidEcu)
+        ));
+    }
+}
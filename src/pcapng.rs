@@ -0,0 +1,432 @@
+//! PCAPNG captures for Wireshark, with frames in the SocketCAN link type
+//!
+//! The writer doesn't own where the bytes go, everything is written to a `Sink` handed to each
+//! call, so the capture can be streamed over USB serial as it happens. The section header & the
+//! interface description go out before the first frame. Timestamps are in nanoseconds, from
+//! whatever epoch the caller likes (Wireshark shows them as time since 1970). Little endian, like
+//! the section header says.
+
+use crate::socketcan::{CanFdFrame, CanFrame, CANFD_MTU};
+use crate::{RxFDFrame, TxFDFrame};
+
+pub const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPTION_END: u16 = 0;
+const OPTION_IF_TSRESOL: u16 = 9;
+const OPTION_EPB_FLAGS: u16 = 2;
+
+const TSRESOL_NANOSECONDS: u8 = 9;
+const EPB_FLAGS_INBOUND: u32 = 0b01;
+const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+
+// An enhanced packet block with a full FD frame & the flags option
+const MAX_BLOCK_LEN: usize = 28 + CANFD_MTU + 8 + 4 + 4;
+
+/// Anything the capture can be written to
+pub trait Sink {
+    type Error;
+
+    /// Takes all the bytes or none of them
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferFull;
+
+/// A sink that collects the bytes in a buffer, to be sent on in chunks
+pub struct BufferSink<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> BufferSink<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<'a> Sink for BufferSink<'a> {
+    type Error = BufferFull;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), BufferFull> {
+        let end = self.len + bytes.len();
+
+        match self.buffer.get_mut(self.len..end) {
+            Some(space) => {
+                space.copy_from_slice(bytes);
+                self.len = end;
+                Ok(())
+            }
+            None => Err(BufferFull),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Transmitted,
+}
+
+pub struct PcapngWriter {
+    header_written: bool,
+}
+
+impl Default for PcapngWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PcapngWriter {
+    pub fn new() -> Self {
+        Self {
+            header_written: false,
+        }
+    }
+
+    /// Starts a new section, for when the sink has been switched to a new file
+    pub fn restart(&mut self) {
+        self.header_written = false;
+    }
+
+    pub fn write_rx_frame<S: Sink>(
+        &mut self,
+        sink: &mut S,
+        timestamp_ns: u64,
+        frame: &RxFDFrame,
+    ) -> Result<(), S::Error> {
+        match CanFrame::from_rx_frame(frame) {
+            Some(classical) => self.write_packet(
+                sink,
+                timestamp_ns,
+                Direction::Received,
+                &classical.to_bytes(),
+            ),
+            None => self.write_packet(
+                sink,
+                timestamp_ns,
+                Direction::Received,
+                &CanFdFrame::from_rx_frame(frame).to_bytes(),
+            ),
+        }
+    }
+
+    pub fn write_tx_frame<S: Sink>(
+        &mut self,
+        sink: &mut S,
+        timestamp_ns: u64,
+        frame: &TxFDFrame,
+    ) -> Result<(), S::Error> {
+        match CanFrame::from_tx_frame(frame) {
+            Some(classical) => self.write_packet(
+                sink,
+                timestamp_ns,
+                Direction::Transmitted,
+                &classical.to_bytes(),
+            ),
+            None => self.write_packet(
+                sink,
+                timestamp_ns,
+                Direction::Transmitted,
+                &CanFdFrame::from_tx_frame(frame).to_bytes(),
+            ),
+        }
+    }
+
+    /// Writes a frame already in the SocketCAN layout (`socketcan::CanFrame::to_bytes` or
+    /// `CanFdFrame::to_bytes`)
+    pub fn write_packet<S: Sink>(
+        &mut self,
+        sink: &mut S,
+        timestamp_ns: u64,
+        direction: Direction,
+        socketcan_frame: &[u8],
+    ) -> Result<(), S::Error> {
+        if !self.header_written {
+            write_header(sink)?;
+            self.header_written = true;
+        }
+
+        let packet = &socketcan_frame[..socketcan_frame.len().min(CANFD_MTU)];
+        let padded_len = packet.len().div_ceil(4) * 4;
+        let flags = match direction {
+            Direction::Received => EPB_FLAGS_INBOUND,
+            Direction::Transmitted => EPB_FLAGS_OUTBOUND,
+        };
+
+        let mut block = Block::new(BLOCK_ENHANCED_PACKET);
+        block.push_u32(0); // Interface ID
+        block.push_u32((timestamp_ns >> 32) as u32);
+        block.push_u32(timestamp_ns as u32);
+        block.push_u32(packet.len() as u32); // Captured length
+        block.push_u32(packet.len() as u32); // Original length
+
+        // The link type wants the ID in network byte order, unlike the kernel's structs
+        let start = block.len;
+        block.push(packet);
+
+        if packet.len() >= 4 {
+            block.bytes[start..start + 4].reverse();
+        }
+
+        block.push(&[0; 3][..padded_len - packet.len()]);
+        block.push_u16(OPTION_EPB_FLAGS);
+        block.push_u16(4);
+        block.push_u32(flags);
+        block.push_u16(OPTION_END);
+        block.push_u16(0);
+
+        sink.write(block.finish())
+    }
+}
+
+fn write_header<S: Sink>(sink: &mut S) -> Result<(), S::Error> {
+    let mut block = Block::new(BLOCK_SECTION_HEADER);
+    block.push_u32(BYTE_ORDER_MAGIC);
+    block.push_u16(1); // Major version
+    block.push_u16(0); // Minor version
+    block.push(&u64::MAX.to_le_bytes()); // Section length, not known
+    sink.write(block.finish())?;
+
+    let mut block = Block::new(BLOCK_INTERFACE_DESCRIPTION);
+    block.push_u16(LINKTYPE_CAN_SOCKETCAN);
+    block.push_u16(0); // Reserved
+    block.push_u32(CANFD_MTU as u32); // Snap length
+    block.push_u16(OPTION_IF_TSRESOL);
+    block.push_u16(1);
+    block.push(&[TSRESOL_NANOSECONDS, 0, 0, 0]);
+    block.push_u16(OPTION_END);
+    block.push_u16(0);
+    sink.write(block.finish())
+}
+
+// A block being put together, the total length goes at both ends once it's known
+struct Block {
+    bytes: [u8; MAX_BLOCK_LEN],
+    len: usize,
+}
+
+impl Block {
+    fn new(block_type: u32) -> Self {
+        let mut block = Self {
+            bytes: [0; MAX_BLOCK_LEN],
+            len: 0,
+        };
+
+        block.push_u32(block_type);
+        block.push_u32(0); // Block total length
+
+        block
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.bytes[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn push_u16(&mut self, value: u16) {
+        self.push(&value.to_le_bytes());
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.push(&value.to_le_bytes());
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        let total_len = (self.len + 4) as u32;
+        self.bytes[4..8].copy_from_slice(&total_len.to_le_bytes());
+        self.push_u32(total_len);
+
+        &self.bytes[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Id;
+    use crate::mock::rx_frame;
+    use crate::FrameFormat;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ])
+    }
+
+    /// Walks the blocks, checking both lengths match, returns each one's type & bytes
+    fn blocks(bytes: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+
+        while offset < bytes.len() {
+            let len = read_u32(bytes, offset + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(read_u32(bytes, offset + len - 4) as usize, len);
+
+            blocks.push((read_u32(bytes, offset), &bytes[offset..offset + len]));
+            offset += len;
+        }
+
+        assert_eq!(offset, bytes.len());
+
+        blocks
+    }
+
+    #[test]
+    fn header() {
+        let mut buffer = [0; 256];
+        let mut sink = BufferSink::new(&mut buffer);
+        let mut writer = PcapngWriter::new();
+        writer
+            .write_rx_frame(&mut sink, 0, &rx_frame(Id::Standard(1), &[]))
+            .unwrap();
+
+        let blocks = blocks(sink.bytes());
+        assert_eq!(blocks.len(), 3);
+
+        let (block_type, shb) = blocks[0];
+        assert_eq!(block_type, BLOCK_SECTION_HEADER);
+        assert_eq!(shb.len(), 28);
+        assert_eq!(read_u32(shb, 8), BYTE_ORDER_MAGIC);
+        assert_eq!(shb[12..16], [1, 0, 0, 0]);
+        assert_eq!(shb[16..24], [0xFF; 8]);
+
+        let (block_type, idb) = blocks[1];
+        assert_eq!(block_type, BLOCK_INTERFACE_DESCRIPTION);
+        assert_eq!(idb.len(), 32);
+        assert_eq!(idb[8..10], LINKTYPE_CAN_SOCKETCAN.to_le_bytes());
+        assert_eq!(read_u32(idb, 12), CANFD_MTU as u32);
+        // if_tsresol of 10^-9, then opt_endofopt
+        assert_eq!(idb[16..28], [9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+
+        // Only the first frame brings the header, until a restart
+        sink.clear();
+        writer
+            .write_rx_frame(&mut sink, 0, &rx_frame(Id::Standard(1), &[]))
+            .unwrap();
+        assert_eq!(read_u32(sink.bytes(), 0), BLOCK_ENHANCED_PACKET);
+
+        sink.clear();
+        writer.restart();
+        writer
+            .write_rx_frame(&mut sink, 0, &rx_frame(Id::Standard(1), &[]))
+            .unwrap();
+        assert_eq!(read_u32(sink.bytes(), 0), BLOCK_SECTION_HEADER);
+    }
+
+    #[test]
+    fn packets() {
+        let mut buffer = [0; 512];
+        let mut sink = BufferSink::new(&mut buffer);
+        let mut writer = PcapngWriter::new();
+
+        writer
+            .write_rx_frame(
+                &mut sink,
+                0x1_0000_0002,
+                &rx_frame(Id::Standard(0x123), &[1, 2, 3]),
+            )
+            .unwrap();
+
+        let frame = TxFDFrame {
+            id: Id::Extended(0x0123_4567),
+            buffer: &[9; 12],
+            priority: None,
+            format: FrameFormat::default(),
+        };
+        writer.write_tx_frame(&mut sink, 5, &frame).unwrap();
+
+        let frame = TxFDFrame {
+            id: Id::Standard(0x7FF),
+            buffer: &[],
+            priority: None,
+            format: FrameFormat::Remote { dlc: 4 },
+        };
+        writer.write_tx_frame(&mut sink, 6, &frame).unwrap();
+
+        let blocks = blocks(sink.bytes());
+        assert_eq!(blocks.len(), 5);
+
+        // A classical frame as a 16 byte `can_frame`
+        let (block_type, epb) = blocks[2];
+        assert_eq!(block_type, BLOCK_ENHANCED_PACKET);
+        assert_eq!(epb.len(), 28 + 16 + 12 + 4);
+        assert_eq!(read_u32(epb, 8), 0);
+        assert_eq!(read_u32(epb, 12), 1);
+        assert_eq!(read_u32(epb, 16), 2);
+        assert_eq!(read_u32(epb, 20), 16);
+        assert_eq!(read_u32(epb, 24), 16);
+        // The ID in network byte order, then the length
+        assert_eq!(epb[28..33], [0x00, 0x00, 0x01, 0x23, 3]);
+        assert_eq!(epb[36..39], [1, 2, 3]);
+        assert_eq!(epb[44..56], [2, 0, 4, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+
+        // An FD frame as a 72 byte `canfd_frame`, with the extended flag in the ID
+        let (_, epb) = blocks[3];
+        assert_eq!(epb.len(), 28 + 72 + 12 + 4);
+        assert_eq!(read_u32(epb, 20), 72);
+        assert_eq!(epb[28..33], [0x81, 0x23, 0x45, 0x67, 12]);
+        assert_eq!(epb[36..48], [9; 12]);
+        assert_eq!(epb[100..104], [2, 0, 4, 0]);
+        assert_eq!(read_u32(epb, 104), EPB_FLAGS_OUTBOUND);
+
+        // A remote frame, classical with the RTR flag
+        let (_, epb) = blocks[4];
+        assert_eq!(read_u32(epb, 20), 16);
+        assert_eq!(epb[28..33], [0x40, 0x00, 0x07, 0xFF, 4]);
+    }
+
+    #[test]
+    fn padding() {
+        let mut buffer = [0; 1024];
+        let mut sink = BufferSink::new(&mut buffer);
+        let mut writer = PcapngWriter::new();
+
+        for len in 0..8 {
+            writer
+                .write_packet(&mut sink, 0, Direction::Received, &[0xAA; 7][..len])
+                .unwrap();
+        }
+
+        let blocks = blocks(sink.bytes());
+
+        for (len, (_, epb)) in blocks[2..].iter().enumerate() {
+            let padded_len = len.div_ceil(4) * 4;
+
+            assert_eq!(epb.len(), 28 + padded_len + 12 + 4);
+            assert_eq!(read_u32(epb, 20) as usize, len);
+            assert!(epb[28 + len..28 + padded_len].iter().all(|b| *b == 0));
+            assert_eq!(epb[28 + padded_len..30 + padded_len], [2, 0]);
+        }
+    }
+
+    #[test]
+    fn buffer_full() {
+        let mut buffer = [0; 40];
+        let mut sink = BufferSink::new(&mut buffer);
+        let result =
+            PcapngWriter::new().write_rx_frame(&mut sink, 0, &rx_frame(Id::Standard(1), &[]));
+
+        // Only whole blocks make it into the sink
+        assert_eq!(result, Err(BufferFull));
+        assert_eq!(sink.bytes().len(), 28);
+    }
+}
@@ -1,19 +1,63 @@
-use crate::util::dlc_to_len;
+use crate::util::{debug_info, dlc_to_len};
+#[cfg(feature = "hardware")]
 use imxrt_ral as ral;
 
 use crate::config::Id;
 use crate::message_buffer::*;
+#[cfg(feature = "hardware")]
 use crate::CANFD;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct RxFDFrame {
     pub id: Id,
     pub buffer_len: u32,
     pub buffer: [u8; 64],
     pub timestamp: u16,
     pub error_state: bool,
+    /// Sent in the CAN FD format (EDL), otherwise classical CAN
+    pub fd_frame: bool,
+    /// Data phase was sent at the FD bitrate (BRS)
+    pub bitrate_switch: bool,
+    /// The mailbox was overwritten before being read, a frame was lost
+    pub overrun: bool,
+    /// The mailbox (and so the filter) that received this frame
+    pub mailbox_index: u32,
+    /// Local priority (PRIO) from the ID register
+    pub priority: u8,
 }
 
+impl RxFDFrame {
+    /// The frame a full Rx message buffer holds, from its CS & ID words and the data already read
+    /// out of it
+    pub(crate) fn from_registers(
+        cs_reg: &CSRegisterBitfield,
+        id_reg: &IDRegisterBitfield,
+        buffer: [u8; 64],
+        mailbox_index: u32,
+    ) -> Self {
+        let extended = cs_reg.read_field(CSField::IDE) == 0b1;
+
+        RxFDFrame {
+            id: if extended {
+                Id::Extended(id_reg.read_field(IDField::ID_EXT))
+            } else {
+                Id::Standard(id_reg.read_field(IDField::ID_STD))
+            },
+            buffer_len: dlc_to_len(cs_reg.read_field(CSField::DLC)),
+            buffer,
+            timestamp: cs_reg.read_field(CSField::TIMESTAMP) as u16,
+            error_state: cs_reg.read_field(CSField::ESI) == 0b1,
+            fd_frame: cs_reg.read_field(CSField::EDL) == 0b1,
+            bitrate_switch: cs_reg.read_field(CSField::BRS) == 0b1,
+            overrun: cs_reg.read_field(CSField::CODE) == CS_CODE_RX_OVERRUN,
+            mailbox_index,
+            priority: id_reg.read_field(IDField::PRIO) as u8,
+        }
+    }
+}
+
+#[cfg(feature = "hardware")]
 impl CANFD {
     pub(crate) fn receive(&self, mb_index: u32) -> Option<RxFDFrame> {
         let mb_data_offset = self.get_mailbox_data_offset(mb_index);
@@ -27,21 +71,10 @@ impl CANFD {
         // Read the message buffer and store the data in an RxFDFrame
 
         let id_reg = read_id_reg(mb_data_offset);
-
-        let extended = cs_reg.read_field(CSField::IDE) == 0b1;
         let buffer_len = dlc_to_len(cs_reg.read_field(CSField::DLC));
-
-        let frame = RxFDFrame {
-            id: if extended {
-                Id::Extended(id_reg.read_field(IDField::ID_EXT))
-            } else {
-                Id::Standard(id_reg.read_field(IDField::ID_STD))
-            },
-            buffer_len,
-            buffer: read_message_buffer(mb_data_offset, buffer_len),
-            timestamp: cs_reg.read_field(CSField::TIMESTAMP) as u16,
-            error_state: cs_reg.read_field(CSField::ESI) == 0b1,
-        };
+        let buffer = read_message_buffer(mb_data_offset, buffer_len);
+        let frame = RxFDFrame::from_registers(&cs_reg, &id_reg, buffer, mb_index);
+        let extended = cs_reg.read_field(CSField::IDE) == 0b1;
 
         // Reconfigure the message buffer to receive more messages
         cs_reg.write_field(CSField::CODE, CS_CODE_RX_EMPTY);
@@ -59,19 +92,70 @@ impl CANFD {
                 self.config.region_2_config.size_bytes()
             };
 
-            log::info!(
-                "Received {}-byte message w/ ID {} ({}) on MB #{} (Region #{} @ {}-bytes); CS: {}, ID: {}",
+            debug_info!(
+                "Received {}-byte message w/ ID {} (extended: {}) on MB #{} (Region #{} @ {}-bytes); CS: CODE {}, EDL {}, BRS {}, ESI {}, DLC {}, TIMESTAMP {}; ID: PRIO {}",
                 frame.buffer_len,
-                id_reg.read_field(IDField::ID_STD),
+                id_reg.read_field(if extended { IDField::ID_EXT } else { IDField::ID_STD }),
                 extended,
                 mb_index,
                 region_index,
                 region_size,
-                cs_reg.serialize(),
-                id_reg.serialize(),
+                cs_reg_code,
+                cs_reg.read_field(CSField::EDL),
+                cs_reg.read_field(CSField::BRS),
+                cs_reg.read_field(CSField::ESI),
+                cs_reg.read_field(CSField::DLC),
+                cs_reg.read_field(CSField::TIMESTAMP),
+                id_reg.read_field(IDField::PRIO),
             );
         }
 
         Some(frame)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_registers() {
+        // An FD frame with every flag set, into a mailbox that overran
+        let mut cs_reg = CSRegisterBitfield::new();
+        cs_reg.write_field(CSField::EDL, 1);
+        cs_reg.write_field(CSField::BRS, 1);
+        cs_reg.write_field(CSField::ESI, 1);
+        cs_reg.write_field(CSField::CODE, CS_CODE_RX_OVERRUN);
+        cs_reg.write_field(CSField::IDE, 1);
+        cs_reg.write_field(CSField::DLC, 9);
+        cs_reg.write_field(CSField::TIMESTAMP, 0xBEEF);
+
+        let mut id_reg = IDRegisterBitfield::new();
+        id_reg.write_field(IDField::PRIO, 5);
+        id_reg.write_field(IDField::ID_EXT, 0x1234_5678);
+
+        let frame = RxFDFrame::from_registers(&cs_reg, &id_reg, [0xAA; 64], 17);
+        assert_eq!(frame.id, Id::Extended(0x1234_5678));
+        assert_eq!(frame.buffer_len, 12);
+        assert_eq!(frame.timestamp, 0xBEEF);
+        assert!(frame.fd_frame && frame.bitrate_switch && frame.error_state && frame.overrun);
+        assert_eq!(frame.mailbox_index, 17);
+        assert_eq!(frame.priority, 5);
+
+        // A classical frame with a standard ID, nothing set
+        let mut cs_reg = CSRegisterBitfield::new();
+        cs_reg.write_field(CSField::CODE, CS_CODE_RX_FULL);
+        cs_reg.write_field(CSField::DLC, 8);
+
+        let mut id_reg = IDRegisterBitfield::new();
+        id_reg.write_field(IDField::ID_STD, 0x7FF);
+
+        let frame = RxFDFrame::from_registers(&cs_reg, &id_reg, [0; 64], 0);
+        assert_eq!(frame.id, Id::Standard(0x7FF));
+        assert_eq!(frame.buffer_len, 8);
+        assert!(!frame.fd_frame && !frame.bitrate_switch && !frame.error_state);
+        assert!(!frame.overrun);
+        assert_eq!(frame.mailbox_index, 0);
+        assert_eq!(frame.priority, 0);
+    }
+}
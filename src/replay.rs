@@ -0,0 +1,551 @@
+//! Replays recorded traffic onto the bus with its original timing
+//!
+//! Frames come from a `FrameSource` (a candump or ASC log, or a slice of frames) and go out
+//! through any `Transmitter` when they're due. The first frame goes out on the first `poll`, every
+//! frame after it at the same distance from the first as in the log, scaled by the speed. `now_us`
+//! can be any microsecond clock, the driver's own time base being
+//! `TimestampExtender::extend(can.timer(cs))`.
+//!
+//! A frame that went out later than `late_after_us` after it was due counts as late, `poll` has to
+//! be called often enough for that not to happen.
+
+use crate::asc::{AscReader, Event, LogEntry};
+use crate::can_error::RxTxError;
+use crate::candump;
+use crate::config::Id;
+use crate::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+use core::str::Lines;
+
+#[derive(Debug, Clone)]
+pub struct TimedFrame {
+    pub timestamp_us: u64,
+    pub frame: RxFDFrame,
+}
+
+/// Where the frames to replay come from
+pub trait FrameSource {
+    fn next_frame(&mut self) -> Option<TimedFrame>;
+
+    /// Goes back to the first frame for looping, false if it can't
+    fn rewind(&mut self) -> bool;
+}
+
+pub struct SliceSource<'a> {
+    frames: &'a [TimedFrame],
+    index: usize,
+}
+
+impl<'a> SliceSource<'a> {
+    pub fn new(frames: &'a [TimedFrame]) -> Self {
+        Self { frames, index: 0 }
+    }
+}
+
+impl<'a> FrameSource for SliceSource<'a> {
+    fn next_frame(&mut self) -> Option<TimedFrame> {
+        let frame = self.frames.get(self.index)?;
+        self.index += 1;
+
+        Some(frame.clone())
+    }
+
+    fn rewind(&mut self) -> bool {
+        self.index = 0;
+        true
+    }
+}
+
+/// A `candump -l` log, lines that don't parse are skipped
+pub struct CandumpSource<'a> {
+    log: &'a str,
+    lines: Lines<'a>,
+}
+
+impl<'a> CandumpSource<'a> {
+    pub fn new(log: &'a str) -> Self {
+        Self {
+            log,
+            lines: log.lines(),
+        }
+    }
+}
+
+impl<'a> FrameSource for CandumpSource<'a> {
+    fn next_frame(&mut self) -> Option<TimedFrame> {
+        self.lines.find_map(|line| {
+            let entry = candump::parse_line(line).ok()?;
+
+            Some(TimedFrame {
+                timestamp_us: entry.timestamp_us,
+                frame: entry.frame,
+            })
+        })
+    }
+
+    fn rewind(&mut self) -> bool {
+        self.lines = self.log.lines();
+        true
+    }
+}
+
+/// An ASC log, frames in either direction go out & everything else is skipped
+pub struct AscSource<'a> {
+    log: &'a str,
+    lines: Lines<'a>,
+    reader: AscReader,
+}
+
+impl<'a> AscSource<'a> {
+    pub fn new(log: &'a str) -> Self {
+        Self {
+            log,
+            lines: log.lines(),
+            reader: AscReader::new(),
+        }
+    }
+}
+
+impl<'a> FrameSource for AscSource<'a> {
+    fn next_frame(&mut self) -> Option<TimedFrame> {
+        let reader = &mut self.reader;
+
+        self.lines
+            .find_map(|line| match reader.parse_line(line).ok()?? {
+                LogEntry {
+                    timestamp_us,
+                    event: Event::Frame { frame, .. },
+                    ..
+                } => Some(TimedFrame {
+                    timestamp_us,
+                    frame,
+                }),
+                _ => None,
+            })
+    }
+
+    fn rewind(&mut self) -> bool {
+        self.lines = self.log.lines();
+        self.reader = AscReader::new();
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayConfig {
+    pub speed_percent: u32, // 100 replays in real time, 200 twice as fast
+    pub looped: bool,       // Start over once the source runs out
+    pub late_after_us: u32, // How long after it's due a frame can go out without being late
+
+    /// Filters & remaps IDs, None drops the frame
+    pub map_id: Option<fn(Id) -> Option<Id>>,
+
+    /// Gets the late frames & how late they went out, in microseconds
+    pub late_callback: Option<fn(&TxFDFrame, u64)>,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            speed_percent: 100,
+            looped: false,
+            late_after_us: 1000,
+            map_id: None,
+            late_callback: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplayStats {
+    pub frames_sent: u32,
+    pub frames_dropped: u32, // Filtered out by `map_id`
+    pub late_frames: u32,
+    pub max_late_us: u64,
+    pub loops: u32, // Times the source was started over
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayState {
+    Waiting(u64), // Until the next frame is due at this time
+    Finished,
+}
+
+pub struct Replay<S: FrameSource> {
+    source: S,
+    config: ReplayConfig,
+    next: Option<TimedFrame>,
+    origin: Option<(u64, u64)>, // Log time & the time it's replayed at
+    last_due_us: u64,
+    stats: ReplayStats,
+}
+
+impl<S: FrameSource> Replay<S> {
+    pub fn new(source: S, config: ReplayConfig) -> Self {
+        Self {
+            source,
+            config,
+            next: None,
+            origin: None,
+            last_due_us: 0,
+            stats: ReplayStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ReplayStats {
+        self.stats
+    }
+
+    /// Starts over from the first frame, the timing starts again from the next `poll`
+    pub fn restart(&mut self) {
+        self.source.rewind();
+        self.next = None;
+        self.origin = None;
+        self.stats = ReplayStats::default();
+    }
+
+    /// Sends every frame that's due. A frame that can't be sent stays due & the error is returned,
+    /// the next `poll` tries it again.
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        now_us: u64,
+    ) -> Result<ReplayState, RxTxError> {
+        loop {
+            if self.next.is_none() {
+                self.next = self.take_frame();
+            }
+
+            let timed = match &self.next {
+                Some(timed) => timed,
+                None => return Ok(ReplayState::Finished),
+            };
+
+            let (log_origin, origin) = *self.origin.get_or_insert((timed.timestamp_us, now_us));
+            let elapsed = timed.timestamp_us.saturating_sub(log_origin);
+            let due =
+                origin + elapsed.saturating_mul(100) / self.config.speed_percent.max(1) as u64;
+
+            if due > now_us {
+                return Ok(ReplayState::Waiting(due));
+            }
+
+            let len = (timed.frame.buffer_len as usize).min(64);
+            let frame = TxFDFrame {
+                id: timed.frame.id,
+                buffer: &timed.frame.buffer[..len],
+                priority: None,
+                format: if timed.frame.fd_frame {
+                    FrameFormat::Fd {
+                        bitrate_switch: timed.frame.bitrate_switch,
+                    }
+                } else {
+                    FrameFormat::Classic
+                },
+            };
+
+            can.transmit(&frame)?;
+
+            let late_us = now_us - due;
+
+            if late_us > self.config.late_after_us as u64 {
+                self.stats.late_frames += 1;
+                self.stats.max_late_us = self.stats.max_late_us.max(late_us);
+
+                if let Some(late_callback) = self.config.late_callback {
+                    late_callback(&frame, late_us);
+                }
+            }
+
+            self.stats.frames_sent += 1;
+            self.last_due_us = due;
+            self.next = None;
+        }
+    }
+
+    // The next frame that makes it through `map_id`, starting over at the end when looping
+    fn take_frame(&mut self) -> Option<TimedFrame> {
+        let mut rewound = false;
+
+        loop {
+            let mut timed = match self.source.next_frame() {
+                Some(timed) => timed,
+                // Stop rather than spin if there's nothing to send even after starting over
+                None if self.config.looped && !rewound && self.source.rewind() => {
+                    rewound = true;
+                    self.stats.loops += 1;
+                    continue;
+                }
+                None => return None,
+            };
+
+            if let Some(map_id) = self.config.map_id {
+                match map_id(timed.frame.id) {
+                    Some(id) => timed.frame.id = id,
+                    None => {
+                        self.stats.frames_dropped += 1;
+                        continue;
+                    }
+                }
+            }
+
+            // The first frame of a new loop goes out right after the last one
+            if rewound && self.stats.frames_sent > 0 {
+                self.origin = Some((timed.timestamp_us, self.last_due_us));
+            }
+
+            return Some(timed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{rx_frame, Bus};
+    use std::cell::RefCell;
+
+    std::thread_local! {
+        static LATE: RefCell<Vec<(Id, u64)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_late(frame: &TxFDFrame, late_us: u64) {
+        LATE.with(|late| late.borrow_mut().push((frame.id, late_us)));
+    }
+
+    // Three frames, 10 & 30 ms after the first one
+    fn log() -> [TimedFrame; 3] {
+        [(1_000_000, 0x1), (1_010_000, 0x2), (1_030_000, 0x3)].map(|(timestamp_us, id)| {
+            TimedFrame {
+                timestamp_us,
+                frame: rx_frame(Id::Standard(id), &[id as u8]),
+            }
+        })
+    }
+
+    // Stands in for the microsecond timer, moving on by `step_us` between polls
+    struct Clock {
+        now_us: u64,
+        step_us: u64,
+    }
+
+    impl Clock {
+        fn new(step_us: u64) -> Self {
+            Self { now_us: 0, step_us }
+        }
+
+        // Polls until the replay finishes or `until_us`, returning when each frame went out
+        fn run<S: FrameSource>(
+            &mut self,
+            replay: &mut Replay<S>,
+            bus: &mut Bus,
+            until_us: u64,
+        ) -> Vec<(u64, Id)> {
+            let mut sent = Vec::new();
+
+            while self.now_us <= until_us {
+                let state = replay.poll(bus, self.now_us).unwrap();
+                sent.extend(bus.take().into_iter().map(|frame| (self.now_us, frame.id)));
+
+                if state == ReplayState::Finished {
+                    break;
+                }
+
+                self.now_us += self.step_us;
+            }
+
+            sent
+        }
+    }
+
+    fn sent_at(times_us: &[u64]) -> Vec<(u64, Id)> {
+        times_us
+            .iter()
+            .zip(1..)
+            .map(|(time_us, id)| (*time_us, Id::Standard(id)))
+            .collect()
+    }
+
+    #[test]
+    fn original_timing() {
+        let log = log();
+        let mut replay = Replay::new(SliceSource::new(&log), ReplayConfig::default());
+        let mut bus = Bus::default();
+
+        assert_eq!(
+            Clock::new(100).run(&mut replay, &mut bus, 1_000_000),
+            sent_at(&[0, 10_000, 30_000])
+        );
+        assert_eq!(replay.stats().frames_sent, 3);
+        assert_eq!(replay.stats().late_frames, 0);
+    }
+
+    #[test]
+    fn speed_scaling() {
+        let log = log();
+        let mut bus = Bus::default();
+
+        for (speed_percent, times_us) in [
+            (200, [0, 5_000, 15_000]),
+            (50, [0, 20_000, 60_000]),
+            (1000, [0, 1_000, 3_000]),
+        ] {
+            let config = ReplayConfig {
+                speed_percent,
+                ..Default::default()
+            };
+            let mut replay = Replay::new(SliceSource::new(&log), config);
+
+            assert_eq!(
+                Clock::new(100).run(&mut replay, &mut bus, 1_000_000),
+                sent_at(&times_us),
+                "{}%",
+                speed_percent
+            );
+        }
+    }
+
+    #[test]
+    fn waiting_until_due() {
+        let log = log();
+        let mut replay = Replay::new(SliceSource::new(&log), ReplayConfig::default());
+        let mut bus = Bus::default();
+
+        // The timing starts from the first poll, not from 0
+        assert_eq!(replay.poll(&mut bus, 500), Ok(ReplayState::Waiting(10_500)));
+        assert_eq!(
+            replay.poll(&mut bus, 10_499),
+            Ok(ReplayState::Waiting(10_500))
+        );
+        assert_eq!(bus.take().len(), 1);
+        assert_eq!(
+            replay.poll(&mut bus, 10_500),
+            Ok(ReplayState::Waiting(30_500))
+        );
+        assert_eq!(replay.poll(&mut bus, 30_500), Ok(ReplayState::Finished));
+        assert_eq!(bus.take().len(), 2);
+    }
+
+    #[test]
+    fn map_id_filters_and_remaps() {
+        fn map_id(id: Id) -> Option<Id> {
+            match id {
+                Id::Standard(0x2) => None,
+                Id::Standard(0x3) => Some(Id::Extended(0x300)),
+                id => Some(id),
+            }
+        }
+
+        let log = log();
+        let config = ReplayConfig {
+            map_id: Some(map_id),
+            ..Default::default()
+        };
+        let mut replay = Replay::new(SliceSource::new(&log), config);
+        let mut bus = Bus::default();
+
+        assert_eq!(
+            Clock::new(100).run(&mut replay, &mut bus, 1_000_000),
+            [(0, Id::Standard(0x1)), (30_000, Id::Extended(0x300))]
+        );
+        assert_eq!(replay.stats().frames_sent, 2);
+        assert_eq!(replay.stats().frames_dropped, 1);
+    }
+
+    #[test]
+    fn looping() {
+        let log = log();
+        let config = ReplayConfig {
+            looped: true,
+            ..Default::default()
+        };
+        let mut replay = Replay::new(SliceSource::new(&log), config);
+        let mut bus = Bus::default();
+
+        // The next loop starts right as the last frame of the one before goes out
+        let mut expected = sent_at(&[0, 10_000, 30_000]);
+        expected.extend(sent_at(&[30_000, 40_000, 60_000]));
+        expected.extend(sent_at(&[60_000, 70_000]));
+
+        assert_eq!(Clock::new(100).run(&mut replay, &mut bus, 75_000), expected);
+        assert_eq!(replay.stats().loops, 2);
+        assert_eq!(replay.stats().frames_sent, 8);
+
+        // Nothing to send at all doesn't spin forever
+        let config = ReplayConfig {
+            looped: true,
+            map_id: Some(|_| None),
+            ..Default::default()
+        };
+        let mut replay = Replay::new(SliceSource::new(&log), config);
+        assert_eq!(replay.poll(&mut bus, 0), Ok(ReplayState::Finished));
+    }
+
+    #[test]
+    fn late_frames() {
+        let log = log();
+        let config = ReplayConfig {
+            late_after_us: 1000,
+            late_callback: Some(record_late),
+            ..Default::default()
+        };
+        let mut replay = Replay::new(SliceSource::new(&log), config);
+        let mut bus = Bus::default();
+
+        // Polled too rarely, the second frame goes out 5 ms late & the third on time
+        replay.poll(&mut bus, 0).unwrap();
+        replay.poll(&mut bus, 15_000).unwrap();
+        replay.poll(&mut bus, 30_500).unwrap();
+
+        assert_eq!(bus.take().len(), 3);
+        assert_eq!(LATE.with(|late| late.take()), [(Id::Standard(0x2), 5_000)]);
+        assert_eq!(replay.stats().late_frames, 1);
+        assert_eq!(replay.stats().max_late_us, 5_000);
+    }
+
+    #[test]
+    fn refused_frames_stay_due() {
+        let log = log();
+        let mut replay = Replay::new(SliceSource::new(&log), ReplayConfig::default());
+        let mut bus = Bus {
+            full: true,
+            ..Default::default()
+        };
+
+        assert_eq!(replay.poll(&mut bus, 0), Err(RxTxError::MailboxUnavailable));
+
+        bus.full = false;
+        assert_eq!(replay.poll(&mut bus, 100), Ok(ReplayState::Waiting(10_000)));
+        assert_eq!(bus.take()[0].id, Id::Standard(0x1));
+    }
+
+    #[test]
+    fn restart() {
+        let log = log();
+        let mut replay = Replay::new(SliceSource::new(&log), ReplayConfig::default());
+        let mut bus = Bus::default();
+
+        Clock::new(100).run(&mut replay, &mut bus, 1_000_000);
+        replay.restart();
+        assert_eq!(replay.stats(), ReplayStats::default());
+
+        let mut clock = Clock {
+            now_us: 50_000,
+            step_us: 100,
+        };
+        let sent = clock.run(&mut replay, &mut bus, 1_000_000);
+        assert_eq!(sent[0], (50_000, Id::Standard(0x1)));
+        assert_eq!(sent[2], (80_000, Id::Standard(0x3)));
+    }
+
+    #[test]
+    fn candump_source() {
+        let log = "(1.000000) can0 123#01\ngarbage\n(1.002500) can0 456#02\n";
+        let mut replay = Replay::new(CandumpSource::new(log), ReplayConfig::default());
+        let mut bus = Bus::default();
+
+        assert_eq!(
+            Clock::new(100).run(&mut replay, &mut bus, 1_000_000),
+            [(0, Id::Standard(0x123)), (2_500, Id::Standard(0x456))]
+        );
+    }
+}
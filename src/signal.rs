@@ -0,0 +1,502 @@
+//! Signals packed into frame payloads, with the bit numbering DBC files use
+//!
+//! Bit `n` is bit `n % 8` of byte `n / 8`. A little endian (Intel) signal starts at its least
+//! significant bit and goes up, a big endian (Motorola) one starts at its most significant bit and
+//! goes down, carrying on at bit 7 of the next byte once it gets to bit 0 of a byte. Bits past the
+//! end of the payload read as 0 and writes to them are dropped.
+//!
+//! `Signal` checks the signal fits the payload (and the value fits the signal) before touching
+//! anything. The free functions don't, they're what the code generated from DBC files by
+//! `teensy4-canfd-dbc` runs on, where the layout was already checked at build time.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    LittleEndian, // Intel, `@1` in a DBC
+    BigEndian,    // Motorola, `@0` in a DBC
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    InvalidLength, // 0 or over 64 bits, or a float signal that isn't 32/64 bits
+    OutOfBounds,   // The signal reaches past the end of the payload
+    OutOfRange,    // The value doesn't fit in the signal's bits
+}
+
+/// Where a signal sits in a payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signal {
+    pub start_bit: u16, // The least significant bit if little endian, the most significant if big
+    pub len: u8,
+    pub order: ByteOrder,
+}
+
+impl Signal {
+    pub const fn little_endian(start_bit: u16, len: u8) -> Self {
+        Self {
+            start_bit,
+            len,
+            order: ByteOrder::LittleEndian,
+        }
+    }
+
+    pub const fn big_endian(start_bit: u16, len: u8) -> Self {
+        Self {
+            start_bit,
+            len,
+            order: ByteOrder::BigEndian,
+        }
+    }
+
+    /// How long a payload has to be for the signal to fit
+    pub fn bytes_needed(&self) -> usize {
+        let start_byte = self.start_bit as usize / 8;
+        let len = self.len as usize;
+
+        match self.order {
+            ByteOrder::LittleEndian => (self.start_bit as usize + len).div_ceil(8),
+            ByteOrder::BigEndian => {
+                // The first byte holds the bits from the start bit down to bit 0
+                let remaining = len.saturating_sub(self.start_bit as usize % 8 + 1);
+                start_byte + 1 + remaining.div_ceil(8)
+            }
+        }
+    }
+
+    pub fn read_unsigned(&self, data: &[u8]) -> Result<u64, SignalError> {
+        self.check(data.len())?;
+
+        Ok(read_raw(data, self.start_bit, self.len, self.order))
+    }
+
+    pub fn read_signed(&self, data: &[u8]) -> Result<i64, SignalError> {
+        Ok(sign_extend(self.read_unsigned(data)?, self.len))
+    }
+
+    pub fn read_f32(&self, data: &[u8]) -> Result<f32, SignalError> {
+        self.check_float(32)?;
+
+        Ok(f32::from_bits(self.read_unsigned(data)? as u32))
+    }
+
+    pub fn read_f64(&self, data: &[u8]) -> Result<f64, SignalError> {
+        self.check_float(64)?;
+
+        Ok(f64::from_bits(self.read_unsigned(data)?))
+    }
+
+    /// Writes the value, leaving the rest of the payload as it was
+    pub fn write_unsigned(&self, data: &mut [u8], value: u64) -> Result<(), SignalError> {
+        self.check(data.len())?;
+
+        if value > mask(self.len) {
+            return Err(SignalError::OutOfRange);
+        }
+
+        write_raw(data, self.start_bit, self.len, self.order, value);
+
+        Ok(())
+    }
+
+    pub fn write_signed(&self, data: &mut [u8], value: i64) -> Result<(), SignalError> {
+        self.check(data.len())?;
+
+        let (min, max) = signed_range(self.len);
+
+        if value < min || value > max {
+            return Err(SignalError::OutOfRange);
+        }
+
+        write_raw(
+            data,
+            self.start_bit,
+            self.len,
+            self.order,
+            value as u64 & mask(self.len),
+        );
+
+        Ok(())
+    }
+
+    pub fn write_f32(&self, data: &mut [u8], value: f32) -> Result<(), SignalError> {
+        self.check_float(32)?;
+        self.write_unsigned(data, value.to_bits() as u64)
+    }
+
+    pub fn write_f64(&self, data: &mut [u8], value: f64) -> Result<(), SignalError> {
+        self.check_float(64)?;
+        self.write_unsigned(data, value.to_bits())
+    }
+
+    fn check(&self, data_len: usize) -> Result<(), SignalError> {
+        if self.len == 0 || self.len > 64 {
+            return Err(SignalError::InvalidLength);
+        }
+
+        if self.bytes_needed() > data_len {
+            return Err(SignalError::OutOfBounds);
+        }
+
+        Ok(())
+    }
+
+    fn check_float(&self, len: u8) -> Result<(), SignalError> {
+        if self.len != len {
+            return Err(SignalError::InvalidLength);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the raw bits of a signal, `len` is at most 64
+pub fn read_raw(data: &[u8], start_bit: u16, len: u8, order: ByteOrder) -> u64 {
+    let mut raw = 0;
+    let mut position = start_bit as usize;
+
+    for bit in 0..len.min(64) {
+        let value = match data.get(position / 8) {
+            Some(byte) => ((byte >> (position % 8)) & 0b1) as u64,
+            None => 0,
+        };
+
+        match order {
+            ByteOrder::LittleEndian => {
+                raw |= value << bit;
+                position += 1;
+            }
+            ByteOrder::BigEndian => {
+                raw = (raw << 1) | value;
+                position = next_big_endian(position);
+            }
+        }
+    }
+
+    raw
+}
+
+/// Writes the raw bits of a signal, anything in `raw` above `len` bits is ignored
+pub fn write_raw(data: &mut [u8], start_bit: u16, len: u8, order: ByteOrder, raw: u64) {
+    let len = len.min(64);
+    let mut position = start_bit as usize;
+
+    for bit in 0..len {
+        let value = match order {
+            ByteOrder::LittleEndian => (raw >> bit) & 0b1,
+            ByteOrder::BigEndian => (raw >> (len - 1 - bit)) & 0b1,
+        };
+
+        if let Some(byte) = data.get_mut(position / 8) {
+            *byte = (*byte & !(1 << (position % 8))) | ((value as u8) << (position % 8));
+        }
+
+        position = match order {
+            ByteOrder::LittleEndian => position + 1,
+            ByteOrder::BigEndian => next_big_endian(position),
+        };
+    }
+}
+
+/// The two's complement value of a raw signal of `len` bits
+pub fn sign_extend(raw: u64, len: u8) -> i64 {
+    match len {
+        0 => 0,
+        1..=63 => ((raw << (64 - len)) as i64) >> (64 - len),
+        _ => raw as i64,
+    }
+}
+
+/// Saturates a value to what fits in `len` bits
+pub fn unsigned_to_raw(value: u64, len: u8) -> u64 {
+    value.min(mask(len))
+}
+
+/// Saturates a value to what fits in `len` bits of two's complement
+pub fn signed_to_raw(value: i64, len: u8) -> u64 {
+    let (min, max) = signed_range(len);
+
+    value.clamp(min, max) as u64 & mask(len)
+}
+
+/// Scales a physical value into a raw one, `raw = (physical - offset) / factor`, rounded to the
+/// nearest & saturated
+pub fn physical_to_raw(value: f64, factor: f64, offset: f64, len: u8, signed: bool) -> u64 {
+    let scaled = (value - offset) / factor;
+
+    // No `round` without std, casting truncates towards zero (& saturates)
+    let rounded = if scaled < 0.0 {
+        scaled - 0.5
+    } else {
+        scaled + 0.5
+    };
+
+    if signed {
+        signed_to_raw(rounded as i64, len)
+    } else {
+        unsigned_to_raw(rounded as u64, len)
+    }
+}
+
+/// Scales a raw value into a physical one, `physical = raw * factor + offset`
+pub fn raw_to_physical(raw: u64, factor: f64, offset: f64, len: u8, signed: bool) -> f64 {
+    let raw = if signed {
+        sign_extend(raw, len) as f64
+    } else {
+        raw as f64
+    };
+
+    raw * factor + offset
+}
+
+fn signed_range(len: u8) -> (i64, i64) {
+    match len {
+        0 => (0, 0),
+        1..=63 => (-(1_i64 << (len - 1)), (1_i64 << (len - 1)) - 1),
+        _ => (i64::MIN, i64::MAX),
+    }
+}
+
+fn mask(len: u8) -> u64 {
+    match len {
+        0 => 0,
+        1..=63 => (1 << len) - 1,
+        _ => u64::MAX,
+    }
+}
+
+// The bit after `position` in a big endian signal, towards its least significant bit
+fn next_big_endian(position: usize) -> usize {
+    if position.is_multiple_of(8) {
+        position + 15
+    } else {
+        position - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intel_numbering() {
+        let mut data = [0; 4];
+        Signal::little_endian(4, 12)
+            .write_unsigned(&mut data, 0xABC)
+            .unwrap();
+        assert_eq!(data, [0xC0, 0xAB, 0, 0]);
+
+        // Across three bytes
+        let mut data = [0; 4];
+        let signal = Signal::little_endian(12, 16);
+        signal.write_unsigned(&mut data, 0xBEEF).unwrap();
+        assert_eq!(data, [0, 0xF0, 0xEE, 0x0B]);
+        assert_eq!(signal.read_unsigned(&data), Ok(0xBEEF));
+        assert_eq!(signal.bytes_needed(), 4);
+    }
+
+    #[test]
+    fn motorola_numbering() {
+        let mut data = [0; 4];
+        Signal::big_endian(7, 16)
+            .write_unsigned(&mut data, 0x1234)
+            .unwrap();
+        assert_eq!(data, [0x12, 0x34, 0, 0]);
+
+        // The low nibble of byte 0, then the high one of byte 1
+        let mut data = [0; 4];
+        let signal = Signal::big_endian(3, 8);
+        signal.write_unsigned(&mut data, 0xA5).unwrap();
+        assert_eq!(data, [0x0A, 0x50, 0, 0]);
+        assert_eq!(signal.read_unsigned(&data), Ok(0xA5));
+        assert_eq!(signal.bytes_needed(), 2);
+
+        let mut data = [0; 4];
+        let signal = Signal::big_endian(19, 12);
+        signal.write_unsigned(&mut data, 0x123).unwrap();
+        assert_eq!(data, [0, 0, 0x01, 0x23]);
+        assert_eq!(signal.bytes_needed(), 4);
+        assert_eq!(Signal::big_endian(7, 8).bytes_needed(), 1);
+    }
+
+    #[test]
+    fn other_bits_are_kept() {
+        let mut data = [0xFF; 3];
+        Signal::little_endian(4, 12)
+            .write_unsigned(&mut data, 0)
+            .unwrap();
+        assert_eq!(data, [0x0F, 0x00, 0xFF]);
+
+        let mut data = [0xFF; 3];
+        Signal::big_endian(3, 8)
+            .write_unsigned(&mut data, 0)
+            .unwrap();
+        assert_eq!(data, [0xF0, 0x0F, 0xFF]);
+    }
+
+    #[test]
+    fn signed_round_trips() {
+        for signal in [Signal::little_endian(6, 12), Signal::big_endian(13, 12)] {
+            let mut data = [0; 4];
+
+            for value in [-2048, -1, 0, 1, 2047] {
+                signal.write_signed(&mut data, value).unwrap();
+                assert_eq!(signal.read_signed(&data), Ok(value));
+            }
+
+            signal.write_signed(&mut data, -1).unwrap();
+            assert_eq!(signal.read_unsigned(&data), Ok(0xFFF));
+        }
+
+        let mut data = [0; 1];
+        let bit = Signal::little_endian(0, 1);
+        bit.write_signed(&mut data, -1).unwrap();
+        assert_eq!((data[0], bit.read_signed(&data)), (1, Ok(-1)));
+    }
+
+    #[test]
+    fn float_round_trips() {
+        let mut data = [0; 8];
+        let single = Signal::little_endian(16, 32);
+        single.write_f32(&mut data, -1.25).unwrap();
+        assert_eq!(data[2..6], (-1.25_f32).to_le_bytes());
+        assert_eq!(single.read_f32(&data), Ok(-1.25));
+
+        let single = Signal::big_endian(15, 32);
+        single.write_f32(&mut data, 3.5).unwrap();
+        assert_eq!(data[1..5], 3.5_f32.to_be_bytes());
+        assert_eq!(single.read_f32(&data), Ok(3.5));
+
+        let double = Signal::little_endian(0, 64);
+        double.write_f64(&mut data, -2.5e300).unwrap();
+        assert_eq!(data, (-2.5e300_f64).to_le_bytes());
+        assert_eq!(double.read_f64(&data), Ok(-2.5e300));
+
+        let double = Signal::big_endian(7, 64);
+        double.write_f64(&mut data, 1.0e-300).unwrap();
+        assert_eq!(data, 1.0e-300_f64.to_be_bytes());
+        assert_eq!(double.read_f64(&data), Ok(1.0e-300));
+    }
+
+    #[test]
+    fn sixty_four_bits() {
+        // Starting mid-byte, so it takes 9 bytes
+        let mut data = [0; 9];
+        let intel = Signal::little_endian(4, 64);
+        assert_eq!(intel.bytes_needed(), 9);
+        intel.write_unsigned(&mut data, u64::MAX).unwrap();
+        assert_eq!(data, [0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+        assert_eq!(intel.read_unsigned(&data), Ok(u64::MAX));
+        intel.write_signed(&mut data, i64::MIN).unwrap();
+        assert_eq!(intel.read_signed(&data), Ok(i64::MIN));
+
+        let mut data = [0; 9];
+        let motorola = Signal::big_endian(3, 64);
+        assert_eq!(motorola.bytes_needed(), 9);
+        motorola
+            .write_unsigned(&mut data, 0x0123_4567_89AB_CDEF)
+            .unwrap();
+        assert_eq!(data, [0x00, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]);
+        assert_eq!(motorola.read_unsigned(&data), Ok(0x0123_4567_89AB_CDEF));
+        motorola.write_signed(&mut data, i64::MAX).unwrap();
+        assert_eq!(motorola.read_signed(&data), Ok(i64::MAX));
+    }
+
+    #[test]
+    fn errors() {
+        let mut data = [0x5A; 8];
+
+        for len in [0, 65] {
+            let signal = Signal::little_endian(0, len);
+            assert_eq!(signal.read_unsigned(&data), Err(SignalError::InvalidLength));
+            assert_eq!(signal.read_signed(&data), Err(SignalError::InvalidLength));
+            assert_eq!(
+                signal.write_unsigned(&mut data, 0),
+                Err(SignalError::InvalidLength)
+            );
+            assert_eq!(
+                signal.write_signed(&mut data, 0),
+                Err(SignalError::InvalidLength)
+            );
+        }
+
+        let half = Signal::little_endian(0, 16);
+        assert_eq!(half.read_f32(&data), Err(SignalError::InvalidLength));
+        assert_eq!(
+            half.write_f32(&mut data, 1.0),
+            Err(SignalError::InvalidLength)
+        );
+        let single = Signal::little_endian(0, 32);
+        assert_eq!(single.read_f64(&data), Err(SignalError::InvalidLength));
+        assert_eq!(
+            single.write_f64(&mut data, 1.0),
+            Err(SignalError::InvalidLength)
+        );
+
+        for signal in [Signal::little_endian(60, 8), Signal::big_endian(59, 8)] {
+            assert_eq!(signal.read_unsigned(&data), Err(SignalError::OutOfBounds));
+            assert_eq!(
+                signal.write_unsigned(&mut data, 0),
+                Err(SignalError::OutOfBounds)
+            );
+            assert_eq!(
+                signal.write_signed(&mut data, 0),
+                Err(SignalError::OutOfBounds)
+            );
+        }
+
+        assert_eq!(
+            Signal::big_endian(7, 32).write_f32(&mut data[..3], 1.0),
+            Err(SignalError::OutOfBounds)
+        );
+        assert_eq!(
+            Signal::little_endian(0, 64).read_f64(&data[..7]),
+            Err(SignalError::OutOfBounds)
+        );
+
+        let byte = Signal::little_endian(8, 8);
+        assert_eq!(
+            byte.write_unsigned(&mut data, 0x100),
+            Err(SignalError::OutOfRange)
+        );
+        assert_eq!(
+            byte.write_signed(&mut data, 128),
+            Err(SignalError::OutOfRange)
+        );
+        assert_eq!(
+            byte.write_signed(&mut data, -129),
+            Err(SignalError::OutOfRange)
+        );
+
+        // Nothing was written
+        assert_eq!(data, [0x5A; 8]);
+    }
+
+    #[test]
+    fn raw_functions() {
+        // Past the end reads as 0, writes there are dropped
+        let mut data = [0xFF; 2];
+        assert_eq!(read_raw(&data, 12, 8, ByteOrder::LittleEndian), 0x0F);
+        write_raw(&mut data, 12, 8, ByteOrder::LittleEndian, 0);
+        assert_eq!(data, [0xFF, 0x0F]);
+
+        assert_eq!(sign_extend(0b100, 3), -4);
+        assert_eq!(sign_extend(0b011, 3), 3);
+        assert_eq!(sign_extend(u64::MAX, 64), -1);
+        assert_eq!(sign_extend(1, 0), 0);
+
+        assert_eq!(unsigned_to_raw(300, 8), 255);
+        assert_eq!(signed_to_raw(-300, 8), 0x80);
+        assert_eq!(signed_to_raw(-1, 8), 0xFF);
+        assert_eq!(signed_to_raw(i64::MIN, 64), i64::MIN as u64);
+
+        // Rounded to the nearest, then saturated
+        assert_eq!(physical_to_raw(12.34, 0.01, 0.0, 16, false), 1234);
+        assert_eq!(physical_to_raw(-40.26, 0.5, 0.0, 8, true), 0xAF);
+        assert_eq!(physical_to_raw(-50.0, 1.0, -40.0, 8, false), 0);
+        assert_eq!(physical_to_raw(1.0e9, 1.0, 0.0, 8, false), 255);
+        assert_eq!(physical_to_raw(1.0e9, 1.0, 0.0, 8, true), 127);
+
+        assert_eq!(raw_to_physical(0xB0, 0.5, 0.0, 8, true), -40.0);
+        assert_eq!(raw_to_physical(0xB0, 0.5, 0.0, 8, false), 88.0);
+        assert_eq!(raw_to_physical(0, 1.0, -40.0, 8, false), -40.0);
+    }
+}
@@ -0,0 +1,753 @@
+//! The SLCAN (Lawicel) ASCII protocol, for using the Teensy as a USB to CAN FD adapter
+//!
+//! Commands come in a byte at a time from the host (`handle_byte`), replies & received frames go
+//! out to a `Sink`. Opening the channel hands back the timings for the bitrates the host picked,
+//! for `CAN3FD::set_timing`. Frames go out in the format their command asks for, received frames
+//! come back the same way. `RxFDFrame` doesn't carry the RTR bit, so received remote frames come
+//! back as `t` / `T` data frames of their DLC's length.
+//!
+//! | Command | |
+//! |---|---|
+//! | `S0` - `S8` | Nominal bitrate, 10k, 20k, 50k, 100k, 125k, 250k, 500k, 800k & 1M |
+//! | `Y1`, `Y2`, `Y4`, `Y5`, `Y8` | Data bitrate in Mbit/s |
+//! | `O` / `C` | Open & close the channel |
+//! | `tiiil..`, `Tiiiiiiiil..` | Classical frames with a standard & extended ID |
+//! | `riiil`, `Riiiiiiiil` | Remote frames |
+//! | `diiil..`, `Diiiiiiiil..` | FD frames without the bitrate switched |
+//! | `biiil..`, `Biiiiiiiil..` | FD frames with the bitrate switched |
+//! | `F` | Status flags since the last `F` |
+//! | `Z0` / `Z1` | Millisecond timestamps on received frames off & on |
+//! | `V`, `N` | Version & serial number |
+
+use crate::config::{Clock, Id, TimingConfig};
+use crate::pcapng::Sink;
+use crate::util::{dlc_to_len, len_to_dlc};
+use crate::{FrameFormat, RxFDFrame, Stats, Transmitter, TxFDFrame};
+
+/// Longest command, an FD frame with an extended ID & 64 bytes
+pub const MAX_COMMAND_LEN: usize = 1 + 8 + 1 + 128;
+
+/// Longest line `write_frame` makes, with a timestamp & the carriage return
+pub const MAX_FRAME_LINE_LEN: usize = MAX_COMMAND_LEN + 4 + 1;
+
+const BITRATES: [u32; 9] = [
+    10_000, 20_000, 50_000, 100_000, 125_000, 250_000, 500_000, 800_000, 1_000_000,
+];
+
+const OK: &[u8] = b"\r";
+const ERROR: &[u8] = b"\x07";
+
+// Status flag bits for `F`
+const STATUS_TX_FULL: u8 = 1 << 1;
+const STATUS_ERROR_WARNING: u8 = 1 << 2;
+const STATUS_DATA_OVERRUN: u8 = 1 << 3;
+const STATUS_BUS_ERROR: u8 = 1 << 7;
+
+#[derive(Debug, Clone)]
+pub enum SlcanEvent {
+    Open {
+        timing_classical: TimingConfig,
+        timing_fd: TimingConfig,
+    },
+    Close,
+}
+
+pub struct Slcan {
+    clock: Clock,
+    command: [u8; MAX_COMMAND_LEN],
+    len: usize,
+    overflowed: bool,
+    open: bool,
+    timestamps: bool,
+    nominal_bitrate: Option<u32>,
+    data_bitrate: Option<u32>,
+    status: u8,
+    last_stats: Option<Stats>,
+    serial_number: [u8; 4],
+}
+
+impl Slcan {
+    /// `clock` is the one the driver runs from, `serial_number` is what `N` answers with
+    pub fn new(clock: Clock, serial_number: [u8; 4]) -> Self {
+        Self {
+            clock,
+            command: [0; MAX_COMMAND_LEN],
+            len: 0,
+            overflowed: false,
+            open: false,
+            timestamps: false,
+            nominal_bitrate: None,
+            data_bitrate: None,
+            status: 0,
+            last_stats: None,
+            serial_number,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Takes a byte from the host, a command runs once its carriage return comes in
+    pub fn handle_byte<T: Transmitter, S: Sink>(
+        &mut self,
+        can: &mut T,
+        out: &mut S,
+        byte: u8,
+    ) -> Result<Option<SlcanEvent>, S::Error> {
+        match byte {
+            b'\r' => {}
+            b'\n' => return Ok(None),
+            _ => {
+                match self.command.get_mut(self.len) {
+                    Some(slot) => {
+                        *slot = byte;
+                        self.len += 1;
+                    }
+                    None => self.overflowed = true,
+                }
+
+                return Ok(None);
+            }
+        }
+
+        let len = core::mem::replace(&mut self.len, 0);
+
+        if core::mem::replace(&mut self.overflowed, false) {
+            out.write(ERROR)?;
+            return Ok(None);
+        }
+
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let mut command = [0; MAX_COMMAND_LEN];
+        command[..len].copy_from_slice(&self.command[..len]);
+        let mut reply = [0; 8];
+
+        match self.run(can, &command[..len], &mut reply) {
+            Ok((reply_len, event)) => {
+                out.write(&reply[..reply_len])?;
+                Ok(event)
+            }
+            Err(()) => {
+                out.write(ERROR)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Writes a received frame for the host, nothing while the channel is closed. `now_ms` is only
+    /// used with timestamps on, they wrap every minute.
+    pub fn write_frame<S: Sink>(
+        &self,
+        out: &mut S,
+        frame: &RxFDFrame,
+        now_ms: u64,
+    ) -> Result<(), S::Error> {
+        if !self.open {
+            return Ok(());
+        }
+
+        let len = (frame.buffer_len as usize).min(64);
+        let fd_frame = frame.fd_frame || len > 8;
+        let mut line = Line {
+            bytes: [0; MAX_FRAME_LINE_LEN],
+            len: 0,
+        };
+
+        let kind = match (fd_frame, frame.bitrate_switch) {
+            (false, _) => b't',
+            (true, false) => b'd',
+            (true, true) => b'b',
+        };
+
+        match frame.id {
+            Id::Standard(id) => {
+                line.push(kind);
+                line.push_hex(id & 0x7FF, 3);
+            }
+            Id::Extended(id) => {
+                line.push(kind.to_ascii_uppercase());
+                line.push_hex(id & 0x1FFF_FFFF, 8);
+            }
+        }
+
+        line.push_hex(len_to_dlc(len as u32), 1);
+
+        for byte in frame.buffer[..len].iter() {
+            line.push_hex(*byte as u32, 2);
+        }
+
+        if self.timestamps {
+            line.push_hex((now_ms % 60_000) as u32, 4);
+        }
+
+        line.push(b'\r');
+
+        out.write(&line.bytes[..line.len])
+    }
+
+    /// Picks up errors for the `F` flags from the driver's statistics, call it every so often
+    pub fn update_status(&mut self, stats: &Stats) {
+        if let Some(last) = &self.last_stats {
+            if stats.rx_overruns != last.rx_overruns {
+                self.status |= STATUS_DATA_OVERRUN;
+            }
+
+            if stats.tx_queue_full != last.tx_queue_full {
+                self.status |= STATUS_TX_FULL;
+            }
+
+            if stats.error_interrupts != last.error_interrupts {
+                self.status |= STATUS_BUS_ERROR | STATUS_ERROR_WARNING;
+            }
+
+            if stats.bus_off_events != last.bus_off_events {
+                self.status |= STATUS_BUS_ERROR;
+            }
+        }
+
+        self.last_stats = Some(*stats);
+    }
+
+    // The reply's length & an event, or an error for the bell
+    fn run<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        command: &[u8],
+        reply: &mut [u8; 8],
+    ) -> Result<(usize, Option<SlcanEvent>), ()> {
+        let argument = &command[1..];
+
+        match command[0] {
+            b'S' if !self.open => {
+                let index = single_digit(argument).ok_or(())?;
+                self.nominal_bitrate = Some(*BITRATES.get(index as usize).ok_or(())?);
+            }
+            b'Y' if !self.open => {
+                self.data_bitrate = match single_digit(argument) {
+                    Some(mbits @ (1 | 2 | 4 | 5 | 8)) => Some(mbits as u32 * 1_000_000),
+                    _ => return Err(()),
+                };
+            }
+            b'O' if !self.open && argument.is_empty() => {
+                let nominal_bitrate = self.nominal_bitrate.ok_or(())?;
+                let data_bitrate = self.data_bitrate.unwrap_or(nominal_bitrate);
+                let timing_classical =
+                    TimingConfig::from_bitrate(self.clock, nominal_bitrate, false).ok_or(())?;
+                let timing_fd =
+                    TimingConfig::from_bitrate(self.clock, data_bitrate, true).ok_or(())?;
+
+                self.open = true;
+                self.status = 0;
+                reply[0] = b'\r';
+
+                return Ok((
+                    1,
+                    Some(SlcanEvent::Open {
+                        timing_classical,
+                        timing_fd,
+                    }),
+                ));
+            }
+            b'C' if self.open && argument.is_empty() => {
+                self.open = false;
+                reply[0] = b'\r';
+
+                return Ok((1, Some(SlcanEvent::Close)));
+            }
+            kind @ (b't' | b'T' | b'r' | b'R' | b'd' | b'D' | b'b' | b'B') if self.open => {
+                self.transmit(can, kind, argument)?;
+
+                let acknowledge = if kind.is_ascii_uppercase() {
+                    b'Z'
+                } else {
+                    b'z'
+                };
+                reply[..2].copy_from_slice(&[acknowledge, b'\r']);
+
+                return Ok((2, None));
+            }
+            b'F' if argument.is_empty() => {
+                let status = core::mem::replace(&mut self.status, 0);
+                reply[0] = b'F';
+                reply[1] = hex_digit(status >> 4);
+                reply[2] = hex_digit(status & 0xF);
+                reply[3] = b'\r';
+
+                return Ok((4, None));
+            }
+            b'Z' => {
+                self.timestamps = match single_digit(argument) {
+                    Some(0) => false,
+                    Some(1) => true,
+                    _ => return Err(()),
+                };
+            }
+            b'V' if argument.is_empty() => {
+                reply[..6].copy_from_slice(b"V1013\r");
+                return Ok((6, None));
+            }
+            b'N' if argument.is_empty() => {
+                reply[0] = b'N';
+                reply[1..5].copy_from_slice(&self.serial_number);
+                reply[5] = b'\r';
+
+                return Ok((6, None));
+            }
+            // Acceptance filters, listen only & anything we don't know
+            _ => return Err(()),
+        }
+
+        reply[..OK.len()].copy_from_slice(OK);
+
+        Ok((OK.len(), None))
+    }
+
+    fn transmit<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        kind: u8,
+        argument: &[u8],
+    ) -> Result<(), ()> {
+        let id_digits = if kind.is_ascii_lowercase() { 3 } else { 8 };
+
+        let id = parse_hex(argument.get(..id_digits).ok_or(())?).ok_or(())?;
+        let id = match id_digits {
+            3 if id <= 0x7FF => Id::Standard(id),
+            8 if id <= 0x1FFF_FFFF => Id::Extended(id),
+            _ => return Err(()),
+        };
+
+        let dlc = parse_hex(argument.get(id_digits..id_digits + 1).ok_or(())?).ok_or(())?;
+        let format = match kind.to_ascii_lowercase() {
+            b't' if dlc <= 8 => FrameFormat::Classic,
+            b'r' if dlc <= 8 => FrameFormat::Remote { dlc: dlc as u8 },
+            b'd' => FrameFormat::Fd {
+                bitrate_switch: false,
+            },
+            b'b' => FrameFormat::Fd {
+                bitrate_switch: true,
+            },
+            _ => return Err(()),
+        };
+
+        // Remote frames only have the DLC, no data
+        let len = match format {
+            FrameFormat::Classic => dlc,
+            FrameFormat::Remote { .. } => 0,
+            FrameFormat::Fd { .. } => dlc_to_len(dlc),
+        } as usize;
+
+        let data = &argument[id_digits + 1..];
+
+        if data.len() != len * 2 {
+            return Err(());
+        }
+
+        let mut buffer = [0; 64];
+
+        for (byte, digits) in buffer.iter_mut().zip(data.chunks(2)) {
+            *byte = parse_hex(digits).ok_or(())? as u8;
+        }
+
+        let frame = TxFDFrame {
+            id,
+            buffer: &buffer[..len],
+            priority: None,
+            format,
+        };
+
+        can.transmit(&frame).map_err(|_| {
+            self.status |= STATUS_TX_FULL;
+        })
+    }
+}
+
+fn single_digit(argument: &[u8]) -> Option<u8> {
+    match argument {
+        [digit @ b'0'..=b'9'] => Some(digit - b'0'),
+        _ => None,
+    }
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+
+    digits.iter().try_fold(0, |value, digit| {
+        let digit = (*digit as char).to_digit(16)?;
+        Some((value << 4) | digit)
+    })
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789ABCDEF"[(value & 0xF) as usize]
+}
+
+struct Line {
+    bytes: [u8; MAX_FRAME_LINE_LEN],
+    len: usize,
+}
+
+impl Line {
+    fn push(&mut self, byte: u8) {
+        self.bytes[self.len] = byte;
+        self.len += 1;
+    }
+
+    fn push_hex(&mut self, value: u32, digits: u32) {
+        for digit in (0..digits).rev() {
+            self.push(hex_digit((value >> (digit * 4)) as u8));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{rx_frame, Bus, SentFrame};
+
+    const CLOCK: Clock = Clock::Clock80Mhz;
+
+    /// Sends a command with its carriage return, returns the reply
+    fn command(slcan: &mut Slcan, bus: &mut Bus, text: &str) -> (Vec<u8>, Option<SlcanEvent>) {
+        let mut out = Vec::new();
+        let mut event = None;
+
+        for byte in text.bytes().chain(Some(b'\r')) {
+            if let Some(new_event) = slcan.handle_byte(bus, &mut out, byte).unwrap() {
+                event = Some(new_event);
+            }
+        }
+
+        (out, event)
+    }
+
+    fn reply(slcan: &mut Slcan, bus: &mut Bus, text: &str) -> Vec<u8> {
+        command(slcan, bus, text).0
+    }
+
+    fn open() -> (Slcan, Bus) {
+        let mut slcan = Slcan::new(CLOCK, *b"T4CF");
+        let mut bus = Bus::default();
+        assert_eq!(reply(&mut slcan, &mut bus, "S6"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "O"), OK);
+
+        (slcan, bus)
+    }
+
+    #[test]
+    fn open_close() {
+        let mut slcan = Slcan::new(CLOCK, *b"T4CF");
+        let mut bus = Bus::default();
+
+        // No bitrate yet, & nothing to close
+        assert_eq!(reply(&mut slcan, &mut bus, "O"), ERROR);
+        assert_eq!(reply(&mut slcan, &mut bus, "C"), ERROR);
+
+        for bad in ["S9", "S", "S10", "Y3", "Y", "Y12"].iter() {
+            assert_eq!(reply(&mut slcan, &mut bus, bad), ERROR, "{}", bad);
+        }
+
+        assert_eq!(reply(&mut slcan, &mut bus, "S8"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "S6"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "Y2"), OK);
+
+        match command(&mut slcan, &mut bus, "O") {
+            (
+                out,
+                Some(SlcanEvent::Open {
+                    timing_classical,
+                    timing_fd,
+                }),
+            ) => {
+                assert_eq!(out, OK);
+                assert_eq!(timing_classical.bitrate(CLOCK), 500_000);
+                assert_eq!(timing_fd.bitrate(CLOCK), 2_000_000);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        assert!(slcan.is_open());
+
+        // Bitrates only change while closed
+        assert_eq!(reply(&mut slcan, &mut bus, "S4"), ERROR);
+        assert_eq!(reply(&mut slcan, &mut bus, "Y5"), ERROR);
+        assert_eq!(reply(&mut slcan, &mut bus, "O"), ERROR);
+
+        let (out, event) = command(&mut slcan, &mut bus, "C");
+        assert_eq!(out, OK);
+        assert!(matches!(event, Some(SlcanEvent::Close)));
+        assert!(!slcan.is_open());
+
+        // Without `Y` the data phase runs at the nominal bitrate
+        let mut slcan = Slcan::new(CLOCK, *b"T4CF");
+        assert_eq!(reply(&mut slcan, &mut bus, "S8"), OK);
+
+        match command(&mut slcan, &mut bus, "O") {
+            (_, Some(SlcanEvent::Open { timing_fd, .. })) => {
+                assert_eq!(timing_fd.bitrate(CLOCK), 1_000_000);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // 24 MHz doesn't divide into 5 Mbit/s
+        let mut slcan = Slcan::new(Clock::Clock24Mhz, *b"T4CF");
+        assert_eq!(reply(&mut slcan, &mut bus, "S6"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "Y5"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "O"), ERROR);
+        assert!(!slcan.is_open());
+    }
+
+    #[test]
+    fn transmit() {
+        let (mut slcan, mut bus) = open();
+
+        let cases = [
+            (
+                "t1230",
+                b"z\r",
+                Id::Standard(0x123),
+                &[][..],
+                FrameFormat::Classic,
+            ),
+            (
+                "t7FF3AABBCC",
+                b"z\r",
+                Id::Standard(0x7FF),
+                &[0xAA, 0xBB, 0xCC][..],
+                FrameFormat::Classic,
+            ),
+            (
+                "T1ABCDEF8821222324252627FF",
+                b"Z\r",
+                Id::Extended(0x1ABC_DEF8),
+                &[0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0xFF][..],
+                FrameFormat::Classic,
+            ),
+            (
+                "r1234",
+                b"z\r",
+                Id::Standard(0x123),
+                &[][..],
+                FrameFormat::Remote { dlc: 4 },
+            ),
+            (
+                "R000000010",
+                b"Z\r",
+                Id::Extended(1),
+                &[][..],
+                FrameFormat::Remote { dlc: 0 },
+            ),
+            (
+                "d0019000102030405060708090A0B",
+                b"z\r",
+                Id::Standard(1),
+                &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11][..],
+                FrameFormat::Fd {
+                    bitrate_switch: false,
+                },
+            ),
+            (
+                "D1FFFFFFF0",
+                b"Z\r",
+                Id::Extended(0x1FFF_FFFF),
+                &[][..],
+                FrameFormat::Fd {
+                    bitrate_switch: false,
+                },
+            ),
+            (
+                "b4562ABCD",
+                b"z\r",
+                Id::Standard(0x456),
+                &[0xAB, 0xCD][..],
+                FrameFormat::Fd {
+                    bitrate_switch: true,
+                },
+            ),
+        ];
+
+        for (text, expected, id, data, format) in cases.iter() {
+            assert_eq!(reply(&mut slcan, &mut bus, text), *expected, "{}", text);
+            assert_eq!(
+                bus.take(),
+                [SentFrame {
+                    id: *id,
+                    data: data.to_vec(),
+                    format: *format,
+                }],
+                "{}",
+                text
+            );
+        }
+
+        // The longest command, an extended ID & 64 bytes with the bitrate switched
+        let mut text = String::from("B0ABCDEF0F");
+        text.extend(core::iter::repeat_n("5A", 64));
+        assert_eq!(text.len(), MAX_COMMAND_LEN);
+        assert_eq!(reply(&mut slcan, &mut bus, &text), b"Z\r");
+
+        let sent = bus.take();
+        assert_eq!(sent[0].data, [0x5A; 64]);
+        assert_eq!(
+            sent[0].format,
+            FrameFormat::Fd {
+                bitrate_switch: true
+            }
+        );
+
+        let invalid = [
+            // Classical & remote frames stop at 8 bytes
+            "t1239000102030405060708090A0B",
+            "r1239",
+            // Data that doesn't go with the DLC
+            "t1232AA",
+            "t1231AABB",
+            "d1239AABB",
+            "r1231AA",
+            // IDs out of range or short
+            "t8000",
+            "T200000000",
+            "t12",
+            "b123GAA",
+            "t1231GG",
+        ];
+
+        for text in invalid.iter() {
+            assert_eq!(reply(&mut slcan, &mut bus, text), ERROR, "{}", text);
+        }
+
+        assert!(bus.take().is_empty());
+
+        // Nothing goes out while closed
+        assert_eq!(reply(&mut slcan, &mut bus, "C"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "t1230"), ERROR);
+        assert!(bus.sent.is_empty());
+    }
+
+    #[test]
+    fn status_flags() {
+        let (mut slcan, mut bus) = open();
+        assert_eq!(reply(&mut slcan, &mut bus, "F"), b"F00\r");
+
+        // A full mailbox rings the bell & shows up in the flags
+        bus.full = true;
+        assert_eq!(reply(&mut slcan, &mut bus, "t1230"), ERROR);
+        assert_eq!(reply(&mut slcan, &mut bus, "F"), b"F02\r");
+        assert_eq!(reply(&mut slcan, &mut bus, "F"), b"F00\r");
+
+        let mut stats = Stats::default();
+        slcan.update_status(&stats);
+        assert_eq!(reply(&mut slcan, &mut bus, "F"), b"F00\r");
+
+        stats.rx_overruns += 1;
+        stats.error_interrupts += 1;
+        stats.bus_off_events += 1;
+        slcan.update_status(&stats);
+        assert_eq!(reply(&mut slcan, &mut bus, "F"), b"F8C\r");
+
+        // Flags are cleared by reading them & by opening the channel
+        stats.tx_queue_full += 1;
+        slcan.update_status(&stats);
+        assert_eq!(reply(&mut slcan, &mut bus, "C"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "O"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "F"), b"F00\r");
+
+        assert_eq!(reply(&mut slcan, &mut bus, "F1"), ERROR);
+    }
+
+    #[test]
+    fn receive() {
+        let mut slcan = Slcan::new(CLOCK, *b"T4CF");
+        let mut bus = Bus::default();
+        let mut out = Vec::new();
+        let frame = rx_frame(Id::Standard(0x123), &[0xAA, 0xBB]);
+
+        // Nothing while the channel is closed
+        slcan.write_frame(&mut out, &frame, 0).unwrap();
+        assert!(out.is_empty());
+
+        assert_eq!(reply(&mut slcan, &mut bus, "S6"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "O"), OK);
+
+        let mut fd_frame = rx_frame(Id::Extended(0x0123_4567), &[1; 12]);
+        fd_frame.bitrate_switch = false;
+
+        let mut brs_frame = rx_frame(Id::Standard(0x7FF), &[2; 3]);
+        brs_frame.fd_frame = true;
+        brs_frame.bitrate_switch = true;
+
+        let cases = [
+            (frame, &b"t1232AABB\r"[..]),
+            (
+                rx_frame(Id::Extended(0x1ABC_DEF8), &[]),
+                &b"T1ABCDEF80\r"[..],
+            ),
+            (fd_frame, &b"D012345679010101010101010101010101\r"[..]),
+            (brs_frame, &b"b7FF3020202\r"[..]),
+        ];
+
+        for (frame, expected) in cases.iter() {
+            let mut out = Vec::new();
+            slcan.write_frame(&mut out, frame, 0).unwrap();
+            assert_eq!(out, *expected);
+        }
+
+        // Timestamps in milliseconds, wrapping every minute
+        assert_eq!(reply(&mut slcan, &mut bus, "Z1"), OK);
+
+        let mut out = Vec::new();
+        slcan
+            .write_frame(&mut out, &cases[0].0, 60_000 + 0x1234)
+            .unwrap();
+        assert_eq!(out, b"t1232AABB1234\r");
+
+        assert_eq!(reply(&mut slcan, &mut bus, "Z0"), OK);
+        assert_eq!(reply(&mut slcan, &mut bus, "Z2"), ERROR);
+
+        let mut out = Vec::new();
+        slcan.write_frame(&mut out, &cases[0].0, 1).unwrap();
+        assert_eq!(out, b"t1232AABB\r");
+
+        // The longest line, with every byte & a timestamp
+        assert_eq!(reply(&mut slcan, &mut bus, "Z1"), OK);
+
+        let mut out = Vec::new();
+        let frame = rx_frame(Id::Extended(0x1FFF_FFFF), &[0xFF; 64]);
+        slcan.write_frame(&mut out, &frame, 0).unwrap();
+        assert_eq!(out.len(), MAX_FRAME_LINE_LEN);
+    }
+
+    #[test]
+    fn other_commands() {
+        let mut slcan = Slcan::new(CLOCK, *b"T4CF");
+        let mut bus = Bus::default();
+
+        assert_eq!(reply(&mut slcan, &mut bus, "V"), b"V1013\r");
+        assert_eq!(reply(&mut slcan, &mut bus, "N"), b"NT4CF\r");
+        assert_eq!(reply(&mut slcan, &mut bus, "V1"), ERROR);
+        assert_eq!(reply(&mut slcan, &mut bus, "N1"), ERROR);
+
+        // Filters, listen only & made up commands
+        for text in ["M00000000", "m00000000", "L", "X", "\x07"].iter() {
+            assert_eq!(reply(&mut slcan, &mut bus, text), ERROR, "{}", text);
+        }
+
+        // Empty lines & newlines after the carriage return are ignored
+        assert!(reply(&mut slcan, &mut bus, "").is_empty());
+
+        let mut out = Vec::new();
+        slcan.handle_byte(&mut bus, &mut out, b'\n').unwrap();
+        assert!(out.is_empty());
+
+        // A command that doesn't fit rings the bell, the next one works again
+        let long = "t".repeat(MAX_COMMAND_LEN + 1);
+        assert_eq!(reply(&mut slcan, &mut bus, &long), ERROR);
+        assert_eq!(reply(&mut slcan, &mut bus, "V"), b"V1013\r");
+    }
+}
@@ -0,0 +1,506 @@
+//! Linux SocketCAN frame layouts, `struct can_frame` & `struct canfd_frame`
+//!
+//! `to_bytes`/`from_bytes` give the exact bytes the kernel reads & writes on a raw CAN socket
+//! (16 bytes for classical frames, 72 for FD), so frames can be streamed to a host and written to
+//! a socket as they are. `can_id` is in little endian, like on every host this is likely to be
+//! plugged into.
+
+use crate::config::Id;
+use crate::util::{dlc_to_len, len_to_dlc};
+use crate::{FrameFormat, RxFDFrame, TxFDFrame};
+
+pub const CAN_MTU: usize = 16;
+pub const CANFD_MTU: usize = 72;
+
+pub const CAN_EFF_FLAG: u32 = 0x8000_0000; // Extended frame format
+pub const CAN_RTR_FLAG: u32 = 0x4000_0000; // Remote transmission request
+pub const CAN_ERR_FLAG: u32 = 0x2000_0000; // Error message frame
+pub const CAN_SFF_MASK: u32 = 0x0000_07FF;
+pub const CAN_EFF_MASK: u32 = 0x1FFF_FFFF;
+
+pub const CANFD_BRS: u8 = 0x01; // Bitrate switch
+pub const CANFD_ESI: u8 = 0x02; // Error state indicator of the transmitter
+pub const CANFD_FDF: u8 = 0x04; // Marks an FD frame, set by newer kernels
+
+/// `struct can_frame`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanFrame {
+    pub can_id: u32,  // ID & the EFF/RTR/ERR flags
+    pub len: u8,      // 0 - 8
+    pub len8_dlc: u8, // The DLC when it's 9 - 15 with a length of 8, otherwise 0
+    pub data: [u8; 8],
+}
+
+/// `struct canfd_frame`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CanFdFrame {
+    pub can_id: u32, // ID & the EFF/RTR/ERR flags
+    pub len: u8,     // 0 - 64
+    pub flags: u8,   // CANFD_BRS, CANFD_ESI & CANFD_FDF
+    pub data: [u8; 64],
+}
+
+impl CanFrame {
+    /// None for FD frames & anything over 8 bytes
+    pub fn from_rx_frame(frame: &RxFDFrame) -> Option<Self> {
+        if frame.fd_frame || frame.buffer_len > 8 {
+            return None;
+        }
+
+        let mut data = [0; 8];
+        data.copy_from_slice(&frame.buffer[..8]);
+
+        Some(Self {
+            can_id: id_to_can_id(frame.id),
+            len: frame.buffer_len as u8,
+            len8_dlc: 0,
+            data,
+        })
+    }
+
+    /// None for FD frames & anything over 8 bytes
+    pub fn from_tx_frame(frame: &TxFDFrame) -> Option<Self> {
+        match frame.format {
+            FrameFormat::Classic if frame.buffer.len() <= 8 => {
+                let mut data = [0; 8];
+                data[..frame.buffer.len()].copy_from_slice(frame.buffer);
+
+                Some(Self {
+                    can_id: id_to_can_id(frame.id),
+                    len: frame.buffer.len() as u8,
+                    len8_dlc: 0,
+                    data,
+                })
+            }
+            FrameFormat::Remote { dlc } if dlc <= 8 => Some(Self {
+                can_id: id_to_can_id(frame.id) | CAN_RTR_FLAG,
+                len: dlc,
+                len8_dlc: 0,
+                data: [0; 8],
+            }),
+            _ => None,
+        }
+    }
+
+    /// None for error frames, the driver can't send those
+    pub fn to_tx_frame(&self) -> Option<TxFDFrame<'_>> {
+        if self.can_id & CAN_RTR_FLAG != 0 {
+            return Some(TxFDFrame {
+                id: can_id_to_id(self.can_id & !CAN_RTR_FLAG)?,
+                buffer: &[],
+                priority: None,
+                format: FrameFormat::Remote {
+                    dlc: self.len.min(8),
+                },
+            });
+        }
+
+        Some(TxFDFrame {
+            id: can_id_to_id(self.can_id)?,
+            buffer: &self.data[..(self.len as usize).min(8)],
+            priority: None,
+            format: FrameFormat::Classic,
+        })
+    }
+
+    /// None for remote & error frames
+    pub fn to_rx_frame(&self) -> Option<RxFDFrame> {
+        let mut buffer = [0; 64];
+        buffer[..8].copy_from_slice(&self.data);
+
+        Some(rx_frame(
+            can_id_to_id(self.can_id)?,
+            (self.len as u32).min(8),
+            buffer,
+            false,
+            0,
+        ))
+    }
+
+    pub fn to_bytes(&self) -> [u8; CAN_MTU] {
+        let mut bytes = [0; CAN_MTU];
+        bytes[..4].copy_from_slice(&self.can_id.to_le_bytes());
+        bytes[4] = self.len;
+        bytes[7] = self.len8_dlc;
+        bytes[8..].copy_from_slice(&self.data);
+
+        bytes
+    }
+
+    /// None if there are less than 16 bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..CAN_MTU)?;
+        let mut data = [0; 8];
+        data.copy_from_slice(&bytes[8..]);
+
+        Some(Self {
+            can_id: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            len: bytes[4],
+            len8_dlc: bytes[7],
+            data,
+        })
+    }
+}
+
+impl CanFdFrame {
+    /// Classical frames get an FD layout too, without CANFD_FDF
+    pub fn from_rx_frame(frame: &RxFDFrame) -> Self {
+        let mut flags = 0;
+
+        if frame.fd_frame {
+            flags |= CANFD_FDF;
+        }
+
+        if frame.bitrate_switch {
+            flags |= CANFD_BRS;
+        }
+
+        if frame.error_state {
+            flags |= CANFD_ESI;
+        }
+
+        Self {
+            can_id: id_to_can_id(frame.id),
+            len: frame.buffer_len.min(64) as u8,
+            flags,
+            data: frame.buffer,
+        }
+    }
+
+    /// Remote frames have no `canfd_frame` form & come out as empty classical frames, use
+    /// `CanFrame::from_tx_frame` for those. The length is rounded up to what the DLC the driver
+    /// sends it with stands for, the padding is zeros.
+    pub fn from_tx_frame(frame: &TxFDFrame) -> Self {
+        let payload = frame.payload();
+        let mut data = [0; 64];
+        data[..payload.len()].copy_from_slice(payload);
+
+        let flags = match frame.format {
+            FrameFormat::Fd {
+                bitrate_switch: true,
+            } => CANFD_FDF | CANFD_BRS,
+            FrameFormat::Fd {
+                bitrate_switch: false,
+            } => CANFD_FDF,
+            FrameFormat::Classic | FrameFormat::Remote { .. } => 0,
+        };
+
+        Self {
+            can_id: id_to_can_id(frame.id),
+            len: dlc_to_len(len_to_dlc(payload.len() as u32)) as u8,
+            flags,
+            data,
+        }
+    }
+
+    /// None for remote & error frames. Frames count as FD the same way as in `to_rx_frame`.
+    pub fn to_tx_frame(&self) -> Option<TxFDFrame<'_>> {
+        let format = if self.flags & (CANFD_FDF | CANFD_BRS) != 0 || self.len > 8 {
+            FrameFormat::Fd {
+                bitrate_switch: self.flags & CANFD_BRS != 0,
+            }
+        } else {
+            FrameFormat::Classic
+        };
+
+        Some(TxFDFrame {
+            id: can_id_to_id(self.can_id)?,
+            buffer: &self.data[..(self.len as usize).min(64)],
+            priority: None,
+            format,
+        })
+    }
+
+    /// None for remote & error frames. Older kernels don't set CANFD_FDF, so frames without it
+    /// still count as FD if they're switching bitrate or are over 8 bytes.
+    pub fn to_rx_frame(&self) -> Option<RxFDFrame> {
+        let fd_frame = self.flags & (CANFD_FDF | CANFD_BRS) != 0 || self.len > 8;
+
+        Some(rx_frame(
+            can_id_to_id(self.can_id)?,
+            (self.len as u32).min(64),
+            self.data,
+            fd_frame,
+            self.flags,
+        ))
+    }
+
+    pub fn to_bytes(&self) -> [u8; CANFD_MTU] {
+        let mut bytes = [0; CANFD_MTU];
+        bytes[..4].copy_from_slice(&self.can_id.to_le_bytes());
+        bytes[4] = self.len;
+        bytes[5] = self.flags;
+        bytes[8..].copy_from_slice(&self.data);
+
+        bytes
+    }
+
+    /// None if there are less than 72 bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..CANFD_MTU)?;
+        let mut data = [0; 64];
+        data.copy_from_slice(&bytes[8..]);
+
+        Some(Self {
+            can_id: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            len: bytes[4],
+            flags: bytes[5],
+            data,
+        })
+    }
+}
+
+/// Writes a received frame the way a raw socket would hand it out, as a `can_frame` if it's
+/// classical & a `canfd_frame` if it's FD. Returns the number of bytes written, None if `out` is
+/// too short.
+pub fn write_rx_frame(frame: &RxFDFrame, out: &mut [u8]) -> Option<usize> {
+    match CanFrame::from_rx_frame(frame) {
+        Some(classical) => {
+            out.get_mut(..CAN_MTU)?
+                .copy_from_slice(&classical.to_bytes());
+            Some(CAN_MTU)
+        }
+        None => {
+            out.get_mut(..CANFD_MTU)?
+                .copy_from_slice(&CanFdFrame::from_rx_frame(frame).to_bytes());
+            Some(CANFD_MTU)
+        }
+    }
+}
+
+pub fn id_to_can_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => id & CAN_SFF_MASK,
+        Id::Extended(id) => (id & CAN_EFF_MASK) | CAN_EFF_FLAG,
+    }
+}
+
+/// None for remote & error frames
+pub fn can_id_to_id(can_id: u32) -> Option<Id> {
+    if can_id & (CAN_RTR_FLAG | CAN_ERR_FLAG) != 0 {
+        None
+    } else if can_id & CAN_EFF_FLAG != 0 {
+        Some(Id::Extended(can_id & CAN_EFF_MASK))
+    } else {
+        Some(Id::Standard(can_id & CAN_SFF_MASK))
+    }
+}
+
+fn rx_frame(id: Id, buffer_len: u32, buffer: [u8; 64], fd_frame: bool, flags: u8) -> RxFDFrame {
+    RxFDFrame {
+        id,
+        buffer_len,
+        buffer,
+        timestamp: 0,
+        error_state: flags & CANFD_ESI != 0,
+        fd_frame,
+        bitrate_switch: flags & CANFD_BRS != 0,
+        overrun: false,
+        mailbox_index: 0,
+        priority: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::rx_frame;
+
+    // What survives the trip through the SocketCAN layouts
+    fn assert_same(left: &RxFDFrame, right: &RxFDFrame) {
+        assert_eq!(left.id, right.id);
+        assert_eq!(left.buffer_len, right.buffer_len);
+        assert_eq!(left.buffer, right.buffer);
+        assert_eq!(left.fd_frame, right.fd_frame);
+        assert_eq!(left.bitrate_switch, right.bitrate_switch);
+        assert_eq!(left.error_state, right.error_state);
+    }
+
+    #[test]
+    fn can_frame_layout() {
+        let frame = CanFrame {
+            can_id: 0x1234_5678 | CAN_EFF_FLAG,
+            len: 3,
+            len8_dlc: 0,
+            data: [1, 2, 3, 0, 0, 0, 0, 0],
+        };
+        let bytes = frame.to_bytes();
+
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(bytes[..4], [0x78, 0x56, 0x34, 0x92]);
+        assert_eq!(bytes[4..8], [3, 0, 0, 0]);
+        assert_eq!(bytes[8..11], [1, 2, 3]);
+        assert_eq!(CanFrame::from_bytes(&bytes), Some(frame));
+        assert_eq!(CanFrame::from_bytes(&bytes[..15]), None);
+
+        let len8_dlc = CanFrame {
+            len: 8,
+            len8_dlc: 12,
+            ..frame
+        };
+        assert_eq!(len8_dlc.to_bytes()[7], 12);
+        assert_eq!(CanFrame::from_bytes(&len8_dlc.to_bytes()), Some(len8_dlc));
+    }
+
+    #[test]
+    fn canfd_frame_layout() {
+        let mut data = [0; 64];
+        data[0] = 0xAA;
+        data[63] = 0x55;
+
+        let frame = CanFdFrame {
+            can_id: 0x123,
+            len: 64,
+            flags: CANFD_FDF | CANFD_BRS,
+            data,
+        };
+        let bytes = frame.to_bytes();
+
+        assert_eq!(bytes.len(), 72);
+        assert_eq!(bytes[..8], [0x23, 0x01, 0, 0, 64, 0x05, 0, 0]);
+        assert_eq!((bytes[8], bytes[71]), (0xAA, 0x55));
+        assert_eq!(CanFdFrame::from_bytes(&bytes), Some(frame));
+        assert_eq!(CanFdFrame::from_bytes(&bytes[..71]), None);
+    }
+
+    #[test]
+    fn can_ids() {
+        assert_eq!(id_to_can_id(Id::Standard(0x7FF)), 0x7FF);
+        assert_eq!(id_to_can_id(Id::Extended(0x1FFF_FFFF)), 0x9FFF_FFFF);
+        assert_eq!(can_id_to_id(0x9FFF_FFFF), Some(Id::Extended(0x1FFF_FFFF)));
+        assert_eq!(can_id_to_id(0x0000_0123), Some(Id::Standard(0x123)));
+        assert_eq!(can_id_to_id(0x123 | CAN_RTR_FLAG), None);
+        assert_eq!(can_id_to_id(CAN_ERR_FLAG), None);
+    }
+
+    #[test]
+    fn classic_round_trip() {
+        let rx = rx_frame(Id::Extended(0x18FE_F100), &[1, 2, 3, 4, 5]);
+        let frame = CanFrame::from_rx_frame(&rx).unwrap();
+
+        assert_eq!(frame.can_id, 0x98FE_F100);
+        assert_eq!(frame.len, 5);
+        assert_same(&frame.to_rx_frame().unwrap(), &rx);
+
+        let tx = frame.to_tx_frame().unwrap();
+        assert_eq!(tx.id, Id::Extended(0x18FE_F100));
+        assert_eq!(tx.buffer, [1, 2, 3, 4, 5]);
+        assert_eq!(tx.format, FrameFormat::Classic);
+        assert_eq!(CanFrame::from_tx_frame(&tx), Some(frame));
+
+        // FD frames don't fit
+        assert!(CanFrame::from_rx_frame(&rx_frame(Id::Standard(1), &[0; 12])).is_none());
+    }
+
+    #[test]
+    fn remote_frames() {
+        let tx = TxFDFrame {
+            id: Id::Standard(0x321),
+            buffer: &[],
+            priority: None,
+            format: FrameFormat::Remote { dlc: 4 },
+        };
+        let frame = CanFrame::from_tx_frame(&tx).unwrap();
+
+        assert_eq!(frame.can_id, 0x321 | CAN_RTR_FLAG);
+        assert_eq!(frame.len, 4);
+        assert!(frame.to_rx_frame().is_none());
+
+        let back = frame.to_tx_frame().unwrap();
+        assert_eq!(back.id, Id::Standard(0x321));
+        assert_eq!(back.format, FrameFormat::Remote { dlc: 4 });
+    }
+
+    #[test]
+    fn fd_flags() {
+        let mut rx = rx_frame(Id::Standard(0x100), &[7; 16]);
+        rx.error_state = true;
+        let frame = CanFdFrame::from_rx_frame(&rx);
+
+        assert_eq!(frame.flags, CANFD_FDF | CANFD_BRS | CANFD_ESI);
+        assert_same(&frame.to_rx_frame().unwrap(), &rx);
+
+        // Classical frames in the FD layout, & FD frames from kernels without CANFD_FDF
+        let classic = CanFdFrame::from_rx_frame(&rx_frame(Id::Standard(0x100), &[1]));
+        assert_eq!(classic.flags, 0);
+        assert!(!classic.to_rx_frame().unwrap().fd_frame);
+
+        let old_kernel = CanFdFrame {
+            flags: CANFD_BRS,
+            ..classic
+        };
+        assert!(old_kernel.to_rx_frame().unwrap().fd_frame);
+        assert_eq!(
+            old_kernel.to_tx_frame().unwrap().format,
+            FrameFormat::Fd {
+                bitrate_switch: true
+            }
+        );
+    }
+
+    #[test]
+    fn fd_tx_round_trip() {
+        for (format, flags) in [
+            (
+                FrameFormat::Fd {
+                    bitrate_switch: true,
+                },
+                CANFD_FDF | CANFD_BRS,
+            ),
+            (
+                FrameFormat::Fd {
+                    bitrate_switch: false,
+                },
+                CANFD_FDF,
+            ),
+            (FrameFormat::Classic, 0),
+        ] {
+            let tx = TxFDFrame {
+                id: Id::Extended(0x42),
+                buffer: &[9; 8],
+                priority: None,
+                format,
+            };
+            let frame = CanFdFrame::from_tx_frame(&tx);
+
+            assert_eq!(frame.can_id, 0x42 | CAN_EFF_FLAG);
+            assert_eq!(frame.flags, flags);
+
+            let back = frame.to_tx_frame().unwrap();
+            assert_eq!(
+                (back.id, back.buffer, back.format),
+                (tx.id, tx.buffer, tx.format)
+            );
+        }
+    }
+
+    #[test]
+    fn fd_tx_padded_to_dlc_length() {
+        let tx = TxFDFrame {
+            id: Id::Standard(0x10),
+            buffer: &[0xFF; 33],
+            priority: None,
+            format: FrameFormat::default(),
+        };
+        let frame = CanFdFrame::from_tx_frame(&tx);
+
+        // 33 bytes go out with DLC 14, 48 bytes
+        assert_eq!(frame.len, 48);
+        assert_eq!(frame.data[..33], [0xFF; 33]);
+        assert_eq!(frame.data[33..48], [0; 15]);
+    }
+
+    #[test]
+    fn write_rx_frames() {
+        let mut out = [0; CANFD_MTU];
+
+        let classic = rx_frame(Id::Standard(0x1), &[1, 2]);
+        assert_eq!(write_rx_frame(&classic, &mut out), Some(CAN_MTU));
+        assert_eq!(out[4], 2);
+
+        let fd = rx_frame(Id::Standard(0x1), &[3; 20]);
+        assert_eq!(write_rx_frame(&fd, &mut out), Some(CANFD_MTU));
+        assert_eq!((out[4], out[5]), (20, CANFD_FDF | CANFD_BRS));
+        assert_eq!(write_rx_frame(&fd, &mut out[..CANFD_MTU - 1]), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Driver statistics, counted from the interrupt handler and the transfer paths
+//!
+//! The counters are atomics so they can be bumped through the `&CANFD` the interrupt handler
+//! gets, the public API hands out plain `Stats` snapshots.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Frames loaded into each Tx mailbox for transmission
+    pub tx_frames: [u32; 64],
+    /// Frames read out of each Rx mailbox
+    pub rx_frames: [u32; 64],
+    /// Frames lost because a mailbox was overwritten before being read
+    pub rx_overruns: u32,
+    /// Transmissions aborted before they made it onto the bus
+    pub tx_aborts: u32,
+    /// Non-blocking transfers rejected because no Tx mailbox was free
+    pub tx_queue_full: u32,
+    /// Bus errors (bit, stuff, form, CRC, ack) in either phase
+    pub error_interrupts: u32,
+    /// Times the controller went bus off
+    pub bus_off_events: u32,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            tx_frames: [0; 64],
+            rx_frames: [0; 64],
+            rx_overruns: 0,
+            tx_aborts: 0,
+            tx_queue_full: 0,
+            error_interrupts: 0,
+            bus_off_events: 0,
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) struct StatsCounters {
+    tx_frames: [AtomicU32; 64],
+    rx_frames: [AtomicU32; 64],
+    rx_overruns: AtomicU32,
+    tx_aborts: AtomicU32,
+    tx_queue_full: AtomicU32,
+    error_interrupts: AtomicU32,
+    bus_off_events: AtomicU32,
+}
+
+impl StatsCounters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            tx_frames: [ZERO; 64],
+            rx_frames: [ZERO; 64],
+            rx_overruns: ZERO,
+            tx_aborts: ZERO,
+            tx_queue_full: ZERO,
+            error_interrupts: ZERO,
+            bus_off_events: ZERO,
+        }
+    }
+
+    pub(crate) fn count_tx_frame(&self, mb_index: u32) {
+        if let Some(counter) = self.tx_frames.get(mb_index as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn count_rx_frame(&self, mb_index: u32) {
+        if let Some(counter) = self.rx_frames.get(mb_index as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn count_rx_overrun(&self) {
+        self.rx_overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_tx_abort(&self) {
+        self.tx_aborts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_tx_queue_full(&self) {
+        self.tx_queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_error_interrupt(&self) {
+        self.error_interrupts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_bus_off(&self) {
+        self.bus_off_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Stats {
+        let mut stats = Stats::default();
+
+        for (value, counter) in stats.tx_frames.iter_mut().zip(self.tx_frames.iter()) {
+            *value = counter.load(Ordering::Relaxed);
+        }
+
+        for (value, counter) in stats.rx_frames.iter_mut().zip(self.rx_frames.iter()) {
+            *value = counter.load(Ordering::Relaxed);
+        }
+
+        stats.rx_overruns = self.rx_overruns.load(Ordering::Relaxed);
+        stats.tx_aborts = self.tx_aborts.load(Ordering::Relaxed);
+        stats.tx_queue_full = self.tx_queue_full.load(Ordering::Relaxed);
+        stats.error_interrupts = self.error_interrupts.load(Ordering::Relaxed);
+        stats.bus_off_events = self.bus_off_events.load(Ordering::Relaxed);
+
+        stats
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in self.tx_frames.iter().chain(self.rx_frames.iter()) {
+            counter.store(0, Ordering::Relaxed);
+        }
+
+        self.rx_overruns.store(0, Ordering::Relaxed);
+        self.tx_aborts.store(0, Ordering::Relaxed);
+        self.tx_queue_full.store(0, Ordering::Relaxed);
+        self.error_interrupts.store(0, Ordering::Relaxed);
+        self.bus_off_events.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_show_up_in_snapshot() {
+        let counters = StatsCounters::new();
+
+        counters.count_tx_frame(3);
+        counters.count_tx_frame(3);
+        counters.count_rx_frame(63);
+        counters.count_rx_overrun();
+        counters.count_tx_abort();
+        counters.count_tx_abort();
+        counters.count_tx_queue_full();
+        counters.count_error_interrupt();
+        counters.count_bus_off();
+
+        let stats = counters.snapshot();
+
+        assert_eq!(stats.tx_frames[3], 2);
+        assert_eq!(stats.tx_frames.iter().sum::<u32>(), 2);
+        assert_eq!(stats.rx_frames[63], 1);
+        assert_eq!(stats.rx_overruns, 1);
+        assert_eq!(stats.tx_aborts, 2);
+        assert_eq!(stats.tx_queue_full, 1);
+        assert_eq!(stats.error_interrupts, 1);
+        assert_eq!(stats.bus_off_events, 1);
+    }
+
+    #[test]
+    fn out_of_range_mailbox_is_ignored() {
+        let counters = StatsCounters::new();
+
+        counters.count_tx_frame(64);
+        counters.count_rx_frame(u32::MAX);
+
+        assert_eq!(counters.snapshot(), Stats::default());
+    }
+
+    #[test]
+    fn reset_clears_everything() {
+        let counters = StatsCounters::new();
+
+        counters.count_tx_frame(0);
+        counters.count_rx_frame(10);
+        counters.count_rx_overrun();
+        counters.count_tx_abort();
+        counters.count_tx_queue_full();
+        counters.count_error_interrupt();
+        counters.count_bus_off();
+        counters.reset();
+
+        assert_eq!(counters.snapshot(), Stats::default());
+
+        counters.count_tx_abort();
+        assert_eq!(counters.snapshot().tx_aborts, 1);
+    }
+}
@@ -1,21 +1,76 @@
-use crate::util::len_to_dlc;
+use crate::util::{debug_info, len_to_dlc};
 
 use crate::can_error::RxTxError;
 use crate::config::{Id, MailboxConfig};
 use crate::message_buffer::*;
+#[cfg(feature = "hardware")]
 use crate::CANFD;
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct TxFDFrame<'a> {
     pub id: Id,
     pub buffer: &'a [u8],
     pub priority: Option<u8>,
+    pub format: FrameFormat,
 }
 
+/// What kind of frame goes out on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameFormat {
+    /// Classical CAN data frame, at most 8 bytes
+    Classic,
+    /// CAN FD data frame, with the data phase at the data bitrate when `bitrate_switch` is set
+    Fd { bitrate_switch: bool },
+    /// Classical remote frame asking for `dlc` bytes, the buffer isn't sent
+    Remote { dlc: u8 },
+}
+
+impl<'a> TxFDFrame<'a> {
+    /// The bytes that go in the data field, nothing for remote frames
+    pub fn payload(&self) -> &'a [u8] {
+        match self.format {
+            FrameFormat::Remote { .. } => &[],
+            _ => &self.buffer[..self.buffer.len().min(64)],
+        }
+    }
+}
+
+impl Default for FrameFormat {
+    fn default() -> Self {
+        FrameFormat::Fd {
+            bitrate_switch: true,
+        }
+    }
+}
+
+impl FrameFormat {
+    pub fn is_fd(&self) -> bool {
+        matches!(self, FrameFormat::Fd { .. })
+    }
+
+    pub fn bitrate_switch(&self) -> bool {
+        matches!(
+            self,
+            FrameFormat::Fd {
+                bitrate_switch: true
+            }
+        )
+    }
+}
+
+/// Anything frames can be handed to for sending. `CAN3FD` implements this, the protocol layers
+/// are written against it so they don't care what actually puts the frames on the bus.
+pub trait Transmitter {
+    fn transmit(&mut self, frame: &TxFDFrame) -> Result<(), RxTxError>;
+}
+
+#[cfg(feature = "hardware")]
 impl CANFD {
     pub fn transfer_blocking(&mut self, frame: &TxFDFrame) -> Result<(), RxTxError> {
         loop {
-            match self.transfer_nb(frame) {
+            match self.queue_transfer(frame) {
                 Ok(()) => return Ok(()),
                 Err(err) => match err {
                     RxTxError::MailboxUnavailable => continue,
@@ -26,9 +81,28 @@ impl CANFD {
     }
 
     pub fn transfer_nb(&mut self, frame: &TxFDFrame) -> Result<(), RxTxError> {
+        let result = self.queue_transfer(frame);
+
+        if let Err(RxTxError::MailboxUnavailable) = result {
+            self.stats.count_tx_queue_full();
+        }
+
+        result
+    }
+
+    fn queue_transfer(&mut self, frame: &TxFDFrame) -> Result<(), RxTxError> {
         // TODO Better logic for selecting mailbox (smallest size, etc)
 
-        let buffer_len: u32 = frame.buffer.len() as u32;
+        let buffer_len: u32 = match frame.format {
+            FrameFormat::Remote { .. } => 0,
+            _ => frame.buffer.len() as u32,
+        };
+
+        match frame.format {
+            FrameFormat::Classic if buffer_len > 8 => return Err(RxTxError::FrameTooBigForFormat),
+            FrameFormat::Remote { dlc } if dlc > 8 => return Err(RxTxError::FrameTooBigForFormat),
+            _ => {}
+        }
 
         if buffer_len > self.config.region_1_config.size_bytes()
             && buffer_len > self.config.region_2_config.size_bytes()
@@ -85,6 +159,8 @@ impl CANFD {
         let attempt_transfer = |index: usize, mailbox: &MailboxConfig| -> Result<(), RxTxError> {
             if let MailboxConfig::Tx = mailbox {
                 if let Ok(()) = self.transfer(index as u32, frame, buffer_len) {
+                    self.stats.count_tx_frame(index as u32);
+
                     // Wait for IFLAG to set to indicate a transmission
                     while self.read_iflag_bit(index as u32) {}
 
@@ -100,12 +176,21 @@ impl CANFD {
                             self.config.region_2_config.size_bytes()
                         };
 
-                        log::info!(
-                            "Sent {}-byte message on MB #{} (Region #{} @ {}-bytes)",
+                        let (id, extended) = match frame.id {
+                            Id::Standard(id) => (id, false),
+                            Id::Extended(id) => (id, true),
+                        };
+
+                        debug_info!(
+                            "Sent {}-byte message w/ ID {} (extended: {}) on MB #{} (Region #{} @ {}-bytes); CS: DLC {}; ID: PRIO {}",
                             buffer_len,
+                            id,
+                            extended,
                             index,
                             region_index,
-                            region_size
+                            region_size,
+                            frame_dlc(frame, buffer_len),
+                            frame.priority.unwrap_or(0)
                         );
                     }
 
@@ -140,8 +225,10 @@ impl CANFD {
 
         // Ensure the mailbox can transfer
         let mut cs_reg = read_cs_reg(mb_data_offset);
-        if cs_reg.read_field(CSField::CODE) == CS_CODE_TX_DATA_OR_REMOTE {
-            return Err(RxTxError::MailboxUnavailable);
+        match cs_reg.read_field(CSField::CODE) {
+            CS_CODE_TX_DATA_OR_REMOTE => return Err(RxTxError::MailboxUnavailable),
+            CS_CODE_TX_ABORT => self.stats.count_tx_abort(),
+            _ => (),
         }
 
         self.write_iflag_bit(mb_index);
@@ -164,14 +251,20 @@ impl CANFD {
 
         write_id_reg(mb_data_offset, id_reg);
 
-        write_message_buffer(mb_data_offset, frame.buffer);
+        if buffer_len > 0 {
+            write_message_buffer(mb_data_offset, frame.buffer);
+        }
 
         // Configure CS register for transmitting
         let mut cs_reg = CSRegisterBitfield::new();
         cs_reg.write_field(CSField::CODE, CS_CODE_TX_DATA_OR_REMOTE);
-        cs_reg.write_field(CSField::EDL, 0b1); // CAN FD Frame
-        cs_reg.write_field(CSField::BRS, 0b1); // Bitrate switch
-        cs_reg.write_field(CSField::DLC, len_to_dlc(buffer_len));
+        cs_reg.write_field(CSField::EDL, frame.format.is_fd() as u32); // CAN FD Frame
+        cs_reg.write_field(CSField::BRS, frame.format.bitrate_switch() as u32); // Bitrate switch
+        cs_reg.write_field(
+            CSField::RTR,
+            matches!(frame.format, FrameFormat::Remote { .. }) as u32,
+        );
+        cs_reg.write_field(CSField::DLC, frame_dlc(frame, buffer_len));
 
         match frame.id {
             Id::Standard(_) => {
@@ -189,3 +282,11 @@ impl CANFD {
         Ok(())
     }
 }
+
+#[cfg(feature = "hardware")]
+fn frame_dlc(frame: &TxFDFrame, buffer_len: u32) -> u32 {
+    match frame.format {
+        FrameFormat::Remote { dlc } => dlc as u32,
+        _ => len_to_dlc(buffer_len),
+    }
+}
@@ -0,0 +1,1189 @@
+//! UDS (ISO 14229) diagnostic server on top of an ISO-TP channel
+//!
+//! Supports diagnostic session control, ECU reset, security access, tester present, read & write
+//! data by identifier and routine control. Data identifiers and routines are registered in
+//! tables of callbacks, a callback answers with a negative response code to refuse a request.
+//!
+//! Like the J1939 diagnostics, the server doesn't own its channel, it's handed in with every call.
+//! A routine that can't finish within P2 returns `ResponsePending`, the server then keeps telling
+//! the tester to wait with NRC 0x78 & calls the routine again from `poll` until it's done.
+
+use crate::isotp::{IsoTp, IsoTpError, IsoTpEvent};
+use crate::{RxFDFrame, Transmitter};
+
+pub const SID_DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+pub const SID_ECU_RESET: u8 = 0x11;
+pub const SID_READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+pub const SID_SECURITY_ACCESS: u8 = 0x27;
+pub const SID_WRITE_DATA_BY_IDENTIFIER: u8 = 0x2E;
+pub const SID_ROUTINE_CONTROL: u8 = 0x31;
+pub const SID_TESTER_PRESENT: u8 = 0x3E;
+
+const POSITIVE_RESPONSE_OFFSET: u8 = 0x40;
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+pub const MAX_SEED_LEN: usize = 16;
+pub const MAX_ROUTINE_OPTION_LEN: usize = 64; // Kept for calling a pending routine again
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegativeResponse {
+    GeneralReject,
+    ServiceNotSupported,
+    SubFunctionNotSupported,
+    IncorrectMessageLength,
+    ResponseTooLong,
+    BusyRepeatRequest,
+    ConditionsNotCorrect,
+    RequestSequenceError,
+    RequestOutOfRange,
+    SecurityAccessDenied,
+    InvalidKey,
+    ExceededNumberOfAttempts,
+    RequiredTimeDelayNotExpired,
+    GeneralProgrammingFailure,
+    ResponsePending,
+    SubFunctionNotSupportedInActiveSession,
+    ServiceNotSupportedInActiveSession,
+    Other(u8),
+}
+
+impl NegativeResponse {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            NegativeResponse::GeneralReject => 0x10,
+            NegativeResponse::ServiceNotSupported => 0x11,
+            NegativeResponse::SubFunctionNotSupported => 0x12,
+            NegativeResponse::IncorrectMessageLength => 0x13,
+            NegativeResponse::ResponseTooLong => 0x14,
+            NegativeResponse::BusyRepeatRequest => 0x21,
+            NegativeResponse::ConditionsNotCorrect => 0x22,
+            NegativeResponse::RequestSequenceError => 0x24,
+            NegativeResponse::RequestOutOfRange => 0x31,
+            NegativeResponse::SecurityAccessDenied => 0x33,
+            NegativeResponse::InvalidKey => 0x35,
+            NegativeResponse::ExceededNumberOfAttempts => 0x36,
+            NegativeResponse::RequiredTimeDelayNotExpired => 0x37,
+            NegativeResponse::GeneralProgrammingFailure => 0x72,
+            NegativeResponse::ResponsePending => 0x78,
+            NegativeResponse::SubFunctionNotSupportedInActiveSession => 0x7E,
+            NegativeResponse::ServiceNotSupportedInActiveSession => 0x7F,
+            NegativeResponse::Other(code) => code,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x10 => NegativeResponse::GeneralReject,
+            0x11 => NegativeResponse::ServiceNotSupported,
+            0x12 => NegativeResponse::SubFunctionNotSupported,
+            0x13 => NegativeResponse::IncorrectMessageLength,
+            0x14 => NegativeResponse::ResponseTooLong,
+            0x21 => NegativeResponse::BusyRepeatRequest,
+            0x22 => NegativeResponse::ConditionsNotCorrect,
+            0x24 => NegativeResponse::RequestSequenceError,
+            0x31 => NegativeResponse::RequestOutOfRange,
+            0x33 => NegativeResponse::SecurityAccessDenied,
+            0x35 => NegativeResponse::InvalidKey,
+            0x36 => NegativeResponse::ExceededNumberOfAttempts,
+            0x37 => NegativeResponse::RequiredTimeDelayNotExpired,
+            0x72 => NegativeResponse::GeneralProgrammingFailure,
+            0x78 => NegativeResponse::ResponsePending,
+            0x7E => NegativeResponse::SubFunctionNotSupportedInActiveSession,
+            0x7F => NegativeResponse::ServiceNotSupportedInActiveSession,
+            code => NegativeResponse::Other(code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdsError {
+    ResponseTooLong, // A response doesn't fit in the response buffer
+    IsoTp(IsoTpError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Session {
+    Default,
+    Programming,
+    Extended,
+}
+
+impl Session {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Session::Default => 0x01,
+            Session::Programming => 0x02,
+            Session::Extended => 0x03,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Session::Default),
+            0x02 => Some(Session::Programming),
+            0x03 => Some(Session::Extended),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetType {
+    Hard,
+    KeyOffOn,
+    Soft,
+}
+
+impl ResetType {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ResetType::Hard => 0x01,
+            ResetType::KeyOffOn => 0x02,
+            ResetType::Soft => 0x03,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(ResetType::Hard),
+            0x02 => Some(ResetType::KeyOffOn),
+            0x03 => Some(ResetType::Soft),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutineControl {
+    Start,
+    Stop,
+    RequestResults,
+}
+
+impl RoutineControl {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            RoutineControl::Start => 0x01,
+            RoutineControl::Stop => 0x02,
+            RoutineControl::RequestResults => 0x03,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(RoutineControl::Start),
+            0x02 => Some(RoutineControl::Stop),
+            0x03 => Some(RoutineControl::RequestResults),
+            _ => None,
+        }
+    }
+}
+
+/// Reads write the value into the buffer & return its length, writes get the value
+pub type ReadHandler = fn(&mut [u8]) -> Result<usize, NegativeResponse>;
+pub type WriteHandler = fn(&[u8]) -> Result<(), NegativeResponse>;
+
+/// Routines get the option record & write their status record into the buffer, returning its
+/// length
+pub type RoutineHandler = fn(&[u8], &mut [u8]) -> Result<usize, NegativeResponse>;
+
+#[derive(Clone, Copy)]
+pub struct DataIdentifier {
+    pub id: u16,
+    pub read: Option<ReadHandler>,
+    pub write: Option<WriteHandler>,
+    pub write_security_level: Option<u8>, // Level that has to be unlocked to write, None for any
+}
+
+#[derive(Clone, Copy)]
+pub struct Routine {
+    pub id: u16,
+    pub start: Option<RoutineHandler>,
+    pub stop: Option<RoutineHandler>,
+    pub results: Option<RoutineHandler>,
+    pub security_level: Option<u8>, // Level that has to be unlocked to control it, None for any
+}
+
+/// Seed & key calculation for security access. Levels are counted from 1, for the request seed
+/// sub-functions 0x01, 0x03 and so on.
+#[derive(Clone, Copy)]
+pub struct SecurityAccess {
+    pub get_seed: fn(u8, &mut [u8; MAX_SEED_LEN]) -> usize, // Writes a seed for the level
+    pub check_key: fn(u8, &[u8], &[u8]) -> bool,            // Level, the seed & the key received
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UdsConfig {
+    pub p2_us: u32,       // How fast we respond, reported to the tester
+    pub p2_star_us: u32,  // How long the tester waits after a response pending
+    pub s3_us: u32,       // Non-default sessions drop back to default without requests
+    pub max_attempts: u8, // Invalid keys before security access gets locked out
+    pub lockout_us: u32,  // How long it stays locked out
+}
+
+impl Default for UdsConfig {
+    fn default() -> Self {
+        Self {
+            p2_us: 50_000,
+            p2_star_us: 5_000_000,
+            s3_us: 5_000_000,
+            max_attempts: 3,
+            lockout_us: 10_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdsEvent {
+    SessionChanged(Session), // Including the fall back to default after S3
+    EcuReset(ResetType),     // The response went out, the ECU should reset now
+    SecurityUnlocked { level: u8 },
+}
+
+#[derive(Clone, Copy)]
+struct PendingRoutine {
+    handler: RoutineHandler,
+    control: RoutineControl,
+    id: u16,
+    option: [u8; MAX_ROUTINE_OPTION_LEN],
+    option_len: usize,
+    last_pending_us: u32, // When we last sent a response pending
+}
+
+pub struct UdsServer<'a> {
+    config: UdsConfig,
+    response: &'a mut [u8],
+    data_identifiers: &'a [DataIdentifier],
+    routines: &'a [Routine],
+    security: Option<SecurityAccess>,
+
+    session: Session,
+    last_request_us: u32,
+    security_level: Option<u8>, // Unlocked level
+    seed_level: Option<u8>,     // Level a seed was sent for, waiting for the key
+    seed: [u8; MAX_SEED_LEN],
+    seed_len: usize,
+    failed_attempts: u8,
+    locked_out_since: Option<u32>,
+
+    pending_routine: Option<PendingRoutine>,
+    pending_response: Option<usize>, // Waiting for the channel to be done with the last one
+    reset: Option<ResetType>,        // Reported once the response went out
+    event: Option<UdsEvent>,
+}
+
+impl<'a> UdsServer<'a> {
+    /// Creates a server in the default session, responses are built in `response` so it limits
+    /// their size
+    pub fn new(
+        config: UdsConfig,
+        response: &'a mut [u8],
+        data_identifiers: &'a [DataIdentifier],
+        routines: &'a [Routine],
+    ) -> Self {
+        Self {
+            config,
+            response,
+            data_identifiers,
+            routines,
+            security: None,
+            session: Session::Default,
+            last_request_us: 0,
+            security_level: None,
+            seed_level: None,
+            seed: [0; MAX_SEED_LEN],
+            seed_len: 0,
+            failed_attempts: 0,
+            locked_out_since: None,
+            pending_routine: None,
+            pending_response: None,
+            reset: None,
+            event: None,
+        }
+    }
+
+    /// Enables security access, without it the service isn't supported
+    pub fn set_security_access(&mut self, security: Option<SecurityAccess>) {
+        self.security = security;
+    }
+
+    pub fn session(&self) -> Session {
+        self.session
+    }
+
+    pub fn security_level(&self) -> Option<u8> {
+        self.security_level
+    }
+
+    /// Feeds a received frame to the channel & answers the request once it's complete
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        isotp: &mut IsoTp,
+        frame: &RxFDFrame,
+        now_us: u32,
+    ) -> Result<Option<UdsEvent>, UdsError> {
+        match isotp.handle_frame(can, frame, now_us) {
+            Ok(Some(IsoTpEvent::Received { .. })) => (),
+            Ok(Some(IsoTpEvent::TransmitComplete)) => return Ok(self.transmit_complete()),
+            Ok(None) => return Ok(None),
+            Err(err) => return Err(UdsError::IsoTp(err)),
+        }
+
+        self.last_request_us = now_us;
+
+        let request = isotp.received();
+        let service = match request.first() {
+            Some(service) => *service,
+            None => return Ok(None),
+        };
+
+        let result = if self.pending_routine.is_some() {
+            Err(NegativeResponse::BusyRepeatRequest)
+        } else {
+            self.process(request, now_us)
+        };
+
+        match result {
+            Ok(Some(len)) => self.respond(can, isotp, len, now_us)?,
+            Ok(None) => {
+                // Suppressed positive response, a reset happens right away
+                if let Some(reset) = self.reset.take() {
+                    self.event = Some(UdsEvent::EcuReset(reset));
+                }
+            }
+            Err(code) => self.respond_negative(can, isotp, service, code, now_us)?,
+        }
+
+        Ok(self.event.take())
+    }
+
+    /// Keeps pending routines going, sends responses that had to wait & drops back to the default
+    /// session after S3
+    pub fn poll<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        isotp: &mut IsoTp,
+        now_us: u32,
+    ) -> Result<Option<UdsEvent>, UdsError> {
+        match isotp.poll(can, now_us) {
+            Ok(Some(IsoTpEvent::TransmitComplete)) => {
+                if let Some(event) = self.transmit_complete() {
+                    return Ok(Some(event));
+                }
+            }
+            Ok(_) => (),
+            Err(err) => return Err(UdsError::IsoTp(err)),
+        }
+
+        if let Some(len) = self.pending_response {
+            if isotp.is_transmitting() {
+                return Ok(None);
+            }
+
+            self.pending_response = None;
+            self.send(can, isotp, len, now_us)?;
+        }
+
+        if let Some(pending) = self.pending_routine {
+            self.poll_routine(can, isotp, pending, now_us)?;
+        }
+
+        if self.session != Session::Default
+            && self.pending_routine.is_none()
+            && now_us.wrapping_sub(self.last_request_us) > self.config.s3_us
+        {
+            self.change_session(Session::Default);
+        }
+
+        Ok(self.event.take())
+    }
+
+    fn process(&mut self, request: &[u8], now_us: u32) -> Result<Option<usize>, NegativeResponse> {
+        let service = request[0];
+
+        let len = match service {
+            SID_DIAGNOSTIC_SESSION_CONTROL => self.session_control(request)?,
+            SID_ECU_RESET => self.ecu_reset(request)?,
+            SID_SECURITY_ACCESS => self.security_access(request, now_us)?,
+            SID_TESTER_PRESENT => {
+                if request.len() != 2 {
+                    return Err(NegativeResponse::IncorrectMessageLength);
+                }
+
+                if request[1] & !SUPPRESS_POSITIVE_RESPONSE != 0 {
+                    return Err(NegativeResponse::SubFunctionNotSupported);
+                }
+
+                self.response_header(service, &[0])?
+            }
+            SID_READ_DATA_BY_IDENTIFIER => self.read_data_by_identifier(request)?,
+            SID_WRITE_DATA_BY_IDENTIFIER => self.write_data_by_identifier(request)?,
+            SID_ROUTINE_CONTROL => self.routine_control(request, now_us)?,
+            _ => return Err(NegativeResponse::ServiceNotSupported),
+        };
+
+        let has_sub_function =
+            service != SID_READ_DATA_BY_IDENTIFIER && service != SID_WRITE_DATA_BY_IDENTIFIER;
+
+        if has_sub_function && request[1] & SUPPRESS_POSITIVE_RESPONSE != 0 {
+            Ok(None)
+        } else {
+            Ok(Some(len))
+        }
+    }
+
+    fn session_control(&mut self, request: &[u8]) -> Result<usize, NegativeResponse> {
+        if request.len() != 2 {
+            return Err(NegativeResponse::IncorrectMessageLength);
+        }
+
+        let session = Session::from_u8(request[1] & !SUPPRESS_POSITIVE_RESPONSE)
+            .ok_or(NegativeResponse::SubFunctionNotSupported)?;
+
+        self.change_session(session);
+
+        let p2_ms = (self.config.p2_us / 1000) as u16;
+        let p2_star_10ms = (self.config.p2_star_us / 10_000) as u16;
+
+        self.response_header(
+            SID_DIAGNOSTIC_SESSION_CONTROL,
+            &[
+                session.to_u8(),
+                (p2_ms >> 8) as u8,
+                p2_ms as u8,
+                (p2_star_10ms >> 8) as u8,
+                p2_star_10ms as u8,
+            ],
+        )
+    }
+
+    fn ecu_reset(&mut self, request: &[u8]) -> Result<usize, NegativeResponse> {
+        if request.len() != 2 {
+            return Err(NegativeResponse::IncorrectMessageLength);
+        }
+
+        let reset = ResetType::from_u8(request[1] & !SUPPRESS_POSITIVE_RESPONSE)
+            .ok_or(NegativeResponse::SubFunctionNotSupported)?;
+
+        self.reset = Some(reset);
+
+        self.response_header(SID_ECU_RESET, &[reset.to_u8()])
+    }
+
+    fn security_access(&mut self, request: &[u8], now_us: u32) -> Result<usize, NegativeResponse> {
+        let security = self.security.ok_or(NegativeResponse::ServiceNotSupported)?;
+
+        if self.session == Session::Default {
+            return Err(NegativeResponse::ServiceNotSupportedInActiveSession);
+        }
+
+        if request.len() < 2 {
+            return Err(NegativeResponse::IncorrectMessageLength);
+        }
+
+        let sub_function = request[1] & !SUPPRESS_POSITIVE_RESPONSE;
+
+        if sub_function == 0 || sub_function > 0x7E {
+            return Err(NegativeResponse::SubFunctionNotSupported);
+        }
+
+        if let Some(since_us) = self.locked_out_since {
+            if now_us.wrapping_sub(since_us) < self.config.lockout_us {
+                return Err(NegativeResponse::RequiredTimeDelayNotExpired);
+            }
+
+            self.locked_out_since = None;
+            self.failed_attempts = 0;
+        }
+
+        if sub_function % 2 == 1 {
+            // Request seed
+            if request.len() != 2 {
+                return Err(NegativeResponse::IncorrectMessageLength);
+            }
+
+            let level = sub_function.div_ceil(2);
+            self.seed_len = (security.get_seed)(level, &mut self.seed).min(MAX_SEED_LEN);
+
+            if self.security_level == Some(level) {
+                // Already unlocked, that's answered with a zero seed
+                self.seed = [0; MAX_SEED_LEN];
+                self.seed_level = None;
+            } else {
+                self.seed_level = Some(level);
+            }
+
+            let seed = self.seed;
+            let len = self.response_header(SID_SECURITY_ACCESS, &[sub_function])?;
+
+            return self.append(len, &seed[..self.seed_len]);
+        }
+
+        // Send key
+        let level = sub_function / 2;
+
+        if self.seed_level != Some(level) {
+            return Err(NegativeResponse::RequestSequenceError);
+        }
+
+        self.seed_level = None;
+
+        if !(security.check_key)(level, &self.seed[..self.seed_len], &request[2..]) {
+            self.failed_attempts += 1;
+
+            if self.failed_attempts >= self.config.max_attempts {
+                self.locked_out_since = Some(now_us);
+                return Err(NegativeResponse::ExceededNumberOfAttempts);
+            }
+
+            return Err(NegativeResponse::InvalidKey);
+        }
+
+        self.failed_attempts = 0;
+        self.security_level = Some(level);
+        self.event = Some(UdsEvent::SecurityUnlocked { level });
+
+        self.response_header(SID_SECURITY_ACCESS, &[sub_function])
+    }
+
+    fn read_data_by_identifier(&mut self, request: &[u8]) -> Result<usize, NegativeResponse> {
+        if request.len() < 3 || request.len().is_multiple_of(2) {
+            return Err(NegativeResponse::IncorrectMessageLength);
+        }
+
+        let mut len = self.response_header(SID_READ_DATA_BY_IDENTIFIER, &[])?;
+
+        for id in request[1..].chunks(2) {
+            let id = u16::from_be_bytes([id[0], id[1]]);
+            let read = self
+                .data_identifiers
+                .iter()
+                .find(|data_identifier| data_identifier.id == id)
+                .and_then(|data_identifier| data_identifier.read)
+                .ok_or(NegativeResponse::RequestOutOfRange)?;
+
+            len = self.append(len, &id.to_be_bytes())?;
+
+            let buffer = &mut self.response[len..];
+
+            if buffer.is_empty() {
+                return Err(NegativeResponse::ResponseTooLong);
+            }
+
+            match read(buffer)? {
+                read_len if read_len <= buffer.len() => len += read_len,
+                _ => return Err(NegativeResponse::ResponseTooLong),
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn write_data_by_identifier(&mut self, request: &[u8]) -> Result<usize, NegativeResponse> {
+        if request.len() < 4 {
+            return Err(NegativeResponse::IncorrectMessageLength);
+        }
+
+        let id = u16::from_be_bytes([request[1], request[2]]);
+        let data_identifier = self
+            .data_identifiers
+            .iter()
+            .find(|data_identifier| data_identifier.id == id)
+            .ok_or(NegativeResponse::RequestOutOfRange)?;
+
+        let write = data_identifier
+            .write
+            .ok_or(NegativeResponse::RequestOutOfRange)?;
+
+        self.check_security(data_identifier.write_security_level)?;
+
+        write(&request[3..])?;
+
+        self.response_header(SID_WRITE_DATA_BY_IDENTIFIER, &request[1..3])
+    }
+
+    fn routine_control(&mut self, request: &[u8], now_us: u32) -> Result<usize, NegativeResponse> {
+        if request.len() < 4 {
+            return Err(NegativeResponse::IncorrectMessageLength);
+        }
+
+        let control = RoutineControl::from_u8(request[1] & !SUPPRESS_POSITIVE_RESPONSE)
+            .ok_or(NegativeResponse::SubFunctionNotSupported)?;
+
+        let id = u16::from_be_bytes([request[2], request[3]]);
+        let routine = self
+            .routines
+            .iter()
+            .find(|routine| routine.id == id)
+            .ok_or(NegativeResponse::RequestOutOfRange)?;
+
+        let handler = match control {
+            RoutineControl::Start => routine.start,
+            RoutineControl::Stop => routine.stop,
+            RoutineControl::RequestResults => routine.results,
+        }
+        .ok_or(NegativeResponse::SubFunctionNotSupported)?;
+
+        self.check_security(routine.security_level)?;
+
+        let option = &request[4..];
+
+        match self.run_routine(handler, control, id, option) {
+            Err(NegativeResponse::ResponsePending) => {
+                if option.len() > MAX_ROUTINE_OPTION_LEN {
+                    return Err(NegativeResponse::GeneralReject);
+                }
+
+                let mut pending = PendingRoutine {
+                    handler,
+                    control,
+                    id,
+                    option: [0; MAX_ROUTINE_OPTION_LEN],
+                    option_len: option.len(),
+                    last_pending_us: now_us,
+                };
+
+                pending.option[..option.len()].copy_from_slice(option);
+                self.pending_routine = Some(pending);
+
+                Err(NegativeResponse::ResponsePending)
+            }
+            result => result,
+        }
+    }
+
+    fn run_routine(
+        &mut self,
+        handler: RoutineHandler,
+        control: RoutineControl,
+        id: u16,
+        option: &[u8],
+    ) -> Result<usize, NegativeResponse> {
+        let id = id.to_be_bytes();
+        let len = self.response_header(SID_ROUTINE_CONTROL, &[control.to_u8(), id[0], id[1]])?;
+
+        Ok(len + handler(option, &mut self.response[len..])?)
+    }
+
+    fn poll_routine<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        isotp: &mut IsoTp,
+        pending: PendingRoutine,
+        now_us: u32,
+    ) -> Result<(), UdsError> {
+        let option = &pending.option[..pending.option_len];
+
+        match self.run_routine(pending.handler, pending.control, pending.id, option) {
+            Ok(len) => {
+                self.pending_routine = None;
+                self.respond(can, isotp, len, now_us)
+            }
+            Err(NegativeResponse::ResponsePending) => {
+                // Another response pending before the tester's P2* runs out
+                let interval_us = self.config.p2_star_us.saturating_sub(self.config.p2_us);
+
+                if now_us.wrapping_sub(pending.last_pending_us) >= interval_us {
+                    if let Some(pending) = self.pending_routine.as_mut() {
+                        pending.last_pending_us = now_us;
+                    }
+
+                    self.respond_negative(
+                        can,
+                        isotp,
+                        SID_ROUTINE_CONTROL,
+                        NegativeResponse::ResponsePending,
+                        now_us,
+                    )?;
+                }
+
+                Ok(())
+            }
+            Err(code) => {
+                self.pending_routine = None;
+                self.respond_negative(can, isotp, SID_ROUTINE_CONTROL, code, now_us)
+            }
+        }
+    }
+
+    fn check_security(&self, level: Option<u8>) -> Result<(), NegativeResponse> {
+        match level {
+            Some(level) if self.security_level != Some(level) => {
+                Err(NegativeResponse::SecurityAccessDenied)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn change_session(&mut self, session: Session) {
+        // Every session change locks security access again
+        self.security_level = None;
+        self.seed_level = None;
+
+        if session != self.session {
+            self.session = session;
+            self.event = Some(UdsEvent::SessionChanged(session));
+        }
+    }
+
+    fn transmit_complete(&mut self) -> Option<UdsEvent> {
+        self.reset.take().map(UdsEvent::EcuReset)
+    }
+
+    // Writes the positive response SID followed by `data`, returns the length so far
+    fn response_header(&mut self, service: u8, data: &[u8]) -> Result<usize, NegativeResponse> {
+        if self.response.is_empty() {
+            return Err(NegativeResponse::ResponseTooLong);
+        }
+
+        self.response[0] = service + POSITIVE_RESPONSE_OFFSET;
+        self.append(1, data)
+    }
+
+    fn append(&mut self, len: usize, data: &[u8]) -> Result<usize, NegativeResponse> {
+        if len + data.len() > self.response.len() {
+            return Err(NegativeResponse::ResponseTooLong);
+        }
+
+        self.response[len..len + data.len()].copy_from_slice(data);
+
+        Ok(len + data.len())
+    }
+
+    fn respond_negative<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        isotp: &mut IsoTp,
+        service: u8,
+        code: NegativeResponse,
+        now_us: u32,
+    ) -> Result<(), UdsError> {
+        if self.response.len() < 3 {
+            return Err(UdsError::ResponseTooLong);
+        }
+
+        // The reset only happens with a positive response
+        self.reset = None;
+        self.response[..3].copy_from_slice(&[NEGATIVE_RESPONSE, service, code.to_u8()]);
+        self.respond(can, isotp, 3, now_us)
+    }
+
+    fn respond<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        isotp: &mut IsoTp,
+        len: usize,
+        now_us: u32,
+    ) -> Result<(), UdsError> {
+        if isotp.is_transmitting() {
+            self.pending_response = Some(len);
+            return Ok(());
+        }
+
+        self.send(can, isotp, len, now_us)
+    }
+
+    fn send<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        isotp: &mut IsoTp,
+        len: usize,
+        now_us: u32,
+    ) -> Result<(), UdsError> {
+        match isotp.send(can, &self.response[..len], now_us) {
+            Ok(Some(IsoTpEvent::TransmitComplete)) => {
+                if let Some(event) = self.transmit_complete() {
+                    self.event = Some(event);
+                }
+
+                Ok(())
+            }
+            Ok(_) => Ok(()),
+            Err(err) => Err(UdsError::IsoTp(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Id;
+    use crate::isotp::IsoTpConfig;
+    use crate::mock::Bus;
+    use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    const TESTER: Id = Id::Standard(0x7E0);
+    const ECU: Id = Id::Standard(0x7E8);
+    const KEY_MASK: u8 = 0xFF;
+
+    static VALUE: AtomicU32 = AtomicU32::new(0x1122_3344);
+    static ERASE_DONE: AtomicBool = AtomicBool::new(false);
+
+    fn read_value(buffer: &mut [u8]) -> Result<usize, NegativeResponse> {
+        buffer
+            .get_mut(..4)
+            .ok_or(NegativeResponse::ResponseTooLong)?
+            .copy_from_slice(&VALUE.load(Ordering::SeqCst).to_be_bytes());
+        Ok(4)
+    }
+
+    fn write_value(data: &[u8]) -> Result<(), NegativeResponse> {
+        match data {
+            [a, b, c, d] => {
+                VALUE.store(u32::from_be_bytes([*a, *b, *c, *d]), Ordering::SeqCst);
+                Ok(())
+            }
+            _ => Err(NegativeResponse::IncorrectMessageLength),
+        }
+    }
+
+    // Fills whatever space is left, like a log dump
+    fn read_all(buffer: &mut [u8]) -> Result<usize, NegativeResponse> {
+        buffer.iter_mut().for_each(|byte| *byte = 0xAB);
+        Ok(buffer.len())
+    }
+
+    fn erase(option: &[u8], status: &mut [u8]) -> Result<usize, NegativeResponse> {
+        if !ERASE_DONE.load(Ordering::SeqCst) {
+            return Err(NegativeResponse::ResponsePending);
+        }
+
+        status[0] = option.len() as u8;
+        Ok(1)
+    }
+
+    fn check(_option: &[u8], status: &mut [u8]) -> Result<usize, NegativeResponse> {
+        status[0] = 0xAA;
+        Ok(1)
+    }
+
+    fn get_seed(_level: u8, seed: &mut [u8; MAX_SEED_LEN]) -> usize {
+        seed[..2].copy_from_slice(&[0x12, 0x34]);
+        2
+    }
+
+    fn check_key(level: u8, seed: &[u8], key: &[u8]) -> bool {
+        key == [seed[0] ^ KEY_MASK, seed[1] ^ level]
+    }
+
+    const DATA_IDENTIFIERS: [DataIdentifier; 3] = [
+        DataIdentifier {
+            id: 0xF190,
+            read: Some(read_value),
+            write: Some(write_value),
+            write_security_level: Some(1),
+        },
+        DataIdentifier {
+            id: 0xF191,
+            read: Some(read_value),
+            write: None,
+            write_security_level: None,
+        },
+        DataIdentifier {
+            id: 0x0100,
+            read: Some(read_all),
+            write: None,
+            write_security_level: None,
+        },
+    ];
+
+    const ROUTINES: [Routine; 2] = [
+        Routine {
+            id: 0xFF00,
+            start: Some(erase),
+            stop: None,
+            results: None,
+            security_level: None,
+        },
+        Routine {
+            id: 0x0202,
+            start: Some(check),
+            stop: None,
+            results: Some(check),
+            security_level: Some(1),
+        },
+    ];
+
+    // A tester on the other end of an ISO-TP channel, with classical frames so long responses
+    // are segmented
+    struct Tester<'a> {
+        bus: Bus,
+        tester: IsoTp<'a>,
+        ecu: IsoTp<'a>,
+        server: UdsServer<'a>,
+        now_us: u32,
+        events: Vec<UdsEvent>,
+    }
+
+    impl<'a> Tester<'a> {
+        // Runs the bus for `duration_us` in steps of `step_us`, returning the responses
+        fn run(&mut self, duration_us: u32, step_us: u32) -> Vec<Vec<u8>> {
+            let mut responses = Vec::new();
+            let end_us = self.now_us + duration_us;
+
+            while self.now_us < end_us {
+                self.now_us += step_us;
+
+                while !self.bus.sent.is_empty() {
+                    for frame in self.bus.take() {
+                        let rx = frame.to_rx();
+
+                        if frame.id == TESTER {
+                            let event = self
+                                .server
+                                .handle_frame(&mut self.bus, &mut self.ecu, &rx, self.now_us)
+                                .unwrap();
+                            self.events.extend(event);
+                        } else if let Ok(Some(IsoTpEvent::Received { .. })) =
+                            self.tester.handle_frame(&mut self.bus, &rx, self.now_us)
+                        {
+                            responses.push(self.tester.received().to_vec());
+                        }
+                    }
+                }
+
+                let event = self
+                    .server
+                    .poll(&mut self.bus, &mut self.ecu, self.now_us)
+                    .unwrap();
+                self.events.extend(event);
+                self.tester.poll(&mut self.bus, self.now_us).unwrap();
+            }
+
+            responses
+        }
+
+        fn request(&mut self, request: &[u8]) -> Vec<Vec<u8>> {
+            self.tester
+                .send(&mut self.bus, request, self.now_us)
+                .unwrap();
+            self.run(100_000, 1_000)
+        }
+
+        // Sends a request that gets exactly one response
+        fn ask(&mut self, request: &[u8]) -> Vec<u8> {
+            let mut responses = self.request(request);
+            assert_eq!(responses.len(), 1, "{:02X?} -> {:02X?}", request, responses);
+            responses.pop().unwrap()
+        }
+
+        fn unlock(&mut self) {
+            let seed = self.ask(&[0x27, 0x01]);
+            let key = [seed[2] ^ KEY_MASK, seed[3] ^ 1];
+            assert_eq!(self.ask(&[0x27, 0x02, key[0], key[1]]), [0x67, 0x02]);
+        }
+    }
+
+    macro_rules! tester {
+        ($tester:ident, $response_len:expr) => {
+            let (mut tester_tx, mut tester_rx) = ([0; 512], [0; 512]);
+            let (mut ecu_tx, mut ecu_rx) = ([0; 512], [0; 512]);
+            let mut response = [0; $response_len];
+            let mut config = IsoTpConfig::new(TESTER, ECU);
+            config.tx_dl = 8;
+            let tester = IsoTp::new(config, &mut tester_tx, &mut tester_rx);
+            let mut config = IsoTpConfig::new(ECU, TESTER);
+            config.tx_dl = 8;
+            let ecu = IsoTp::new(config, &mut ecu_tx, &mut ecu_rx);
+            let mut server = UdsServer::new(
+                UdsConfig::default(),
+                &mut response,
+                &DATA_IDENTIFIERS,
+                &ROUTINES,
+            );
+            server.set_security_access(Some(SecurityAccess {
+                get_seed,
+                check_key,
+            }));
+
+            let mut $tester = Tester {
+                bus: Bus::default(),
+                tester,
+                ecu,
+                server,
+                now_us: 0,
+                events: Vec::new(),
+            };
+        };
+    }
+
+    #[test]
+    fn sessions_and_s3() {
+        tester!(t, 512);
+
+        // P2 of 50 ms & P2* of 5 s
+        assert_eq!(t.ask(&[0x10, 0x03]), [0x50, 0x03, 0x00, 0x32, 0x01, 0xF4]);
+        assert_eq!(t.events, [UdsEvent::SessionChanged(Session::Extended)]);
+        assert_eq!(t.ask(&[0x10, 0x04]), [0x7F, 0x10, 0x12]);
+        assert_eq!(t.ask(&[0x10, 0x03, 0x00]), [0x7F, 0x10, 0x13]);
+        t.unlock();
+        t.events.clear();
+
+        // Tester present keeps the session going
+        for _ in 0..3 {
+            t.run(4_000_000, 100_000);
+            assert_eq!(t.ask(&[0x3E, 0x00]), [0x7E, 0x00]);
+        }
+
+        assert_eq!(t.server.session(), Session::Extended);
+        assert_eq!(t.server.security_level(), Some(1));
+
+        // Without requests it falls back to default after S3, locking security access again
+        t.run(5_200_000, 100_000);
+        assert_eq!(t.events, [UdsEvent::SessionChanged(Session::Default)]);
+        assert_eq!(t.server.session(), Session::Default);
+        assert_eq!(t.server.security_level(), None);
+
+        // The default session doesn't time out
+        t.events.clear();
+        t.run(10_000_000, 100_000);
+        assert!(t.events.is_empty());
+    }
+
+    #[test]
+    fn security_access() {
+        tester!(t, 512);
+
+        // Not in the default session
+        assert_eq!(t.ask(&[0x27, 0x01]), [0x7F, 0x27, 0x7F]);
+        t.ask(&[0x10, 0x03]);
+
+        // Writing needs level 1 unlocked
+        assert_eq!(t.ask(&[0x2E, 0xF1, 0x90, 1, 2, 3, 4]), [0x7F, 0x2E, 0x33]);
+        assert_eq!(t.ask(&[0x31, 0x01, 0x02, 0x02]), [0x7F, 0x31, 0x33]);
+
+        // A key without a seed
+        assert_eq!(t.ask(&[0x27, 0x02, 0xED, 0x35]), [0x7F, 0x27, 0x24]);
+
+        assert_eq!(t.ask(&[0x27, 0x01]), [0x67, 0x01, 0x12, 0x34]);
+        assert_eq!(t.ask(&[0x27, 0x02, 0xED, 0x35]), [0x67, 0x02]);
+        assert!(t.events.contains(&UdsEvent::SecurityUnlocked { level: 1 }));
+
+        // An unlocked level answers with a zero seed
+        assert_eq!(t.ask(&[0x27, 0x01]), [0x67, 0x01, 0x00, 0x00]);
+        assert_eq!(
+            t.ask(&[0x31, 0x01, 0x02, 0x02]),
+            [0x71, 0x01, 0x02, 0x02, 0xAA]
+        );
+
+        // A session change locks it again
+        t.ask(&[0x10, 0x03]);
+        assert_eq!(t.server.security_level(), None);
+    }
+
+    #[test]
+    fn security_lockout() {
+        tester!(t, 512);
+
+        t.ask(&[0x10, 0x03]);
+
+        for _ in 0..2 {
+            t.ask(&[0x27, 0x01]);
+            assert_eq!(t.ask(&[0x27, 0x02, 0, 0]), [0x7F, 0x27, 0x35]);
+        }
+
+        t.ask(&[0x27, 0x01]);
+        assert_eq!(t.ask(&[0x27, 0x02, 0, 0]), [0x7F, 0x27, 0x36]);
+
+        // Locked out, even with the right key
+        assert_eq!(t.ask(&[0x27, 0x01]), [0x7F, 0x27, 0x37]);
+        t.run(4_000_000, 100_000);
+        t.ask(&[0x3E, 0x00]);
+        t.run(4_000_000, 100_000);
+        t.ask(&[0x3E, 0x00]);
+        assert_eq!(t.ask(&[0x27, 0x01]), [0x7F, 0x27, 0x37]);
+
+        // Until the delay is over
+        t.run(2_000_000, 100_000);
+        t.unlock();
+        assert_eq!(t.server.security_level(), Some(1));
+    }
+
+    #[test]
+    fn data_identifiers() {
+        tester!(t, 512);
+
+        assert_eq!(
+            t.ask(&[0x22, 0xF1, 0x91]),
+            [0x62, 0xF1, 0x91, 0x11, 0x22, 0x33, 0x44]
+        );
+        assert_eq!(t.ask(&[0x22, 0x12, 0x34]), [0x7F, 0x22, 0x31]);
+        assert_eq!(t.ask(&[0x22, 0xF1]), [0x7F, 0x22, 0x13]);
+        assert_eq!(t.ask(&[0x22, 0xF1, 0x91, 0x01]), [0x7F, 0x22, 0x13]);
+        assert_eq!(t.ask(&[0x2E, 0xF1, 0x91, 1, 2, 3, 4]), [0x7F, 0x2E, 0x31]);
+
+        t.ask(&[0x10, 0x03]);
+        t.unlock();
+        assert_eq!(t.ask(&[0x2E, 0xF1, 0x90, 1, 2, 3]), [0x7F, 0x2E, 0x13]);
+        assert_eq!(t.ask(&[0x2E, 0xF1, 0x90, 1, 2, 3, 4]), [0x6E, 0xF1, 0x90]);
+        assert_eq!(
+            t.ask(&[0x22, 0xF1, 0x90, 0xF1, 0x91]),
+            [0x62, 0xF1, 0x90, 1, 2, 3, 4, 0xF1, 0x91, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn response_too_long() {
+        tester!(t, 9);
+
+        assert_eq!(t.ask(&[0x22, 0xF1, 0x91]).len(), 7);
+
+        // The second identifier fills the buffer, leaving no space for its value
+        assert_eq!(t.ask(&[0x22, 0xF1, 0x91, 0xF1, 0x91]), [0x7F, 0x22, 0x14]);
+
+        // A value taking up the rest of the buffer, then one more identifier
+        assert_eq!(t.ask(&[0x22, 0x01, 0x00]).len(), 9);
+        assert_eq!(t.ask(&[0x22, 0x01, 0x00, 0xF1, 0x91]), [0x7F, 0x22, 0x14]);
+    }
+
+    #[test]
+    fn response_pending() {
+        tester!(t, 512);
+
+        ERASE_DONE.store(false, Ordering::SeqCst);
+
+        // The first response pending goes out within P2, then again before P2* runs out
+        let responses = t.request(&[0x31, 0x01, 0xFF, 0x00, 9, 9]);
+        assert_eq!(responses, [[0x7F, 0x31, 0x78]]);
+
+        let responses = t.run(4_800_000, 10_000);
+        assert!(responses.is_empty());
+        let responses = t.run(200_000, 10_000);
+        assert_eq!(responses, [[0x7F, 0x31, 0x78]]);
+
+        // Other requests are turned away meanwhile
+        assert_eq!(t.request(&[0x22, 0xF1, 0x91]), [[0x7F, 0x22, 0x21]]);
+
+        ERASE_DONE.store(true, Ordering::SeqCst);
+        assert_eq!(t.run(100_000, 10_000), [[0x71, 0x01, 0xFF, 0x00, 2]]);
+
+        // And the session doesn't time out while a routine is pending
+        assert_eq!(t.server.session(), Session::Default);
+    }
+
+    #[test]
+    fn suppress_positive_response() {
+        tester!(t, 512);
+
+        assert!(t.request(&[0x3E, 0x80]).is_empty());
+        assert!(t.request(&[0x10, 0x83]).is_empty());
+        assert_eq!(t.server.session(), Session::Extended);
+        t.unlock();
+        assert!(t.request(&[0x31, 0x81, 0x02, 0x02]).is_empty());
+
+        // Negative responses still go out
+        assert_eq!(t.ask(&[0x3E, 0x81]), [0x7F, 0x3E, 0x12]);
+        assert_eq!(t.ask(&[0x10, 0x84]), [0x7F, 0x10, 0x12]);
+        assert_eq!(t.ask(&[0x85, 0x00]), [0x7F, 0x85, 0x11]);
+
+        // A suppressed reset happens right away, otherwise once the response went out
+        t.events.clear();
+        assert_eq!(t.ask(&[0x11, 0x01]), [0x51, 0x01]);
+        assert_eq!(t.events, [UdsEvent::EcuReset(ResetType::Hard)]);
+        t.events.clear();
+        assert!(t.request(&[0x11, 0x83]).is_empty());
+        assert_eq!(t.events, [UdsEvent::EcuReset(ResetType::Soft)]);
+    }
+}
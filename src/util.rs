@@ -1,8 +1,11 @@
 //! Kind of a misc for various CAN related things
 
+#[cfg(feature = "hardware")]
 use super::CANFD;
+#[cfg(feature = "hardware")]
 use imxrt_ral as ral;
 
+#[cfg(feature = "hardware")]
 impl CANFD {
     pub fn enable(&mut self, state: bool) {
         ral::modify_reg!(ral::can3, self.instance, MCR, MDIS: if state { 0b0 } else { 0b1 });
@@ -263,3 +266,18 @@ pub(crate) fn len_to_dlc(len: u32) -> u32 {
         8
     }
 }
+
+/// The traces behind the `debuginfo` feature, through defmt with the `defmt` feature & log
+/// otherwise. Arguments have to be formattable by both, so plain integers & bools.
+macro_rules! debug_info {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "debuginfo") {
+            #[cfg(feature = "defmt")]
+            defmt::info!($($arg)*);
+            #[cfg(not(feature = "defmt"))]
+            log::info!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use debug_info;
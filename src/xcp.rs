@@ -0,0 +1,1067 @@
+//! XCP on CAN (FD) slave, for measurement & calibration tools
+//!
+//! Handles the standard commands for connecting, memory access (SET_MTA/UPLOAD/SHORT_UPLOAD/
+//! DOWNLOAD) and dynamic DAQ lists. Memory goes through the read/write callbacks, so the
+//! application decides what addresses mean & which ones a tool may touch. DAQ lists are sampled
+//! when the application calls `event` for their event channel, each ODT goes out as one DTO
+//! frame identified by its absolute ODT number. Multi-byte values use Intel byte order.
+
+use crate::can_error::RxTxError;
+use crate::config::Id;
+use crate::util::{dlc_to_len, len_to_dlc};
+use crate::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+
+pub const CMD_CONNECT: u8 = 0xFF;
+pub const CMD_DISCONNECT: u8 = 0xFE;
+pub const CMD_GET_STATUS: u8 = 0xFD;
+pub const CMD_SYNCH: u8 = 0xFC;
+pub const CMD_SET_MTA: u8 = 0xF6;
+pub const CMD_UPLOAD: u8 = 0xF5;
+pub const CMD_SHORT_UPLOAD: u8 = 0xF4;
+pub const CMD_DOWNLOAD: u8 = 0xF0;
+pub const CMD_SET_DAQ_PTR: u8 = 0xE2;
+pub const CMD_WRITE_DAQ: u8 = 0xE1;
+pub const CMD_SET_DAQ_LIST_MODE: u8 = 0xE0;
+pub const CMD_START_STOP_DAQ_LIST: u8 = 0xDE;
+pub const CMD_START_STOP_SYNCH: u8 = 0xDD;
+pub const CMD_GET_DAQ_PROCESSOR_INFO: u8 = 0xDA;
+pub const CMD_FREE_DAQ: u8 = 0xD6;
+pub const CMD_ALLOC_DAQ: u8 = 0xD5;
+pub const CMD_ALLOC_ODT: u8 = 0xD4;
+pub const CMD_ALLOC_ODT_ENTRY: u8 = 0xD3;
+
+const PID_RESPONSE: u8 = 0xFF;
+const PID_ERROR: u8 = 0xFE;
+
+pub const MAX_DAQ_LISTS: usize = 8;
+/// Shared by all DAQ lists
+pub const MAX_ODTS: usize = 32;
+/// Shared by all ODTs
+pub const MAX_ODT_ENTRIES: usize = 128;
+
+// CONNECT response
+const RESOURCE_CAL_PAG: u8 = 0x01;
+const RESOURCE_DAQ: u8 = 0x04;
+const PROTOCOL_LAYER_VERSION: u8 = 0x01;
+const TRANSPORT_LAYER_VERSION: u8 = 0x01;
+
+// GET_STATUS session status
+const SESSION_DAQ_RUNNING: u8 = 0x40;
+
+// GET_DAQ_PROCESSOR_INFO properties
+const DAQ_CONFIG_DYNAMIC: u8 = 0x01;
+const DAQ_PRESCALER_SUPPORTED: u8 = 0x02;
+
+// SET_DAQ_LIST_MODE bits we don't support: STIM, timestamps & no PID
+const DAQ_MODE_UNSUPPORTED: u8 = 0x02 | 0x10 | 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XcpErrorCode {
+    CmdSynch,
+    CmdBusy,
+    DaqActive,
+    CmdUnknown,
+    CmdSyntax,
+    OutOfRange,
+    WriteProtected,
+    AccessDenied,
+    ModeNotValid,
+    Sequence,
+    DaqConfig,
+    MemoryOverflow,
+    Generic,
+}
+
+impl XcpErrorCode {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            XcpErrorCode::CmdSynch => 0x00,
+            XcpErrorCode::CmdBusy => 0x10,
+            XcpErrorCode::DaqActive => 0x11,
+            XcpErrorCode::CmdUnknown => 0x20,
+            XcpErrorCode::CmdSyntax => 0x21,
+            XcpErrorCode::OutOfRange => 0x22,
+            XcpErrorCode::WriteProtected => 0x23,
+            XcpErrorCode::AccessDenied => 0x24,
+            XcpErrorCode::ModeNotValid => 0x27,
+            XcpErrorCode::Sequence => 0x29,
+            XcpErrorCode::DaqConfig => 0x2A,
+            XcpErrorCode::MemoryOverflow => 0x30,
+            XcpErrorCode::Generic => 0x31,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XcpError {
+    DaqOverrun,     // No Tx mailbox was free for a DTO, the rest of the sample was dropped
+    Bus(RxTxError), // The driver refused a frame
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XcpEvent {
+    Connected,
+    Disconnected,
+    Downloaded {
+        address: u32, // Where a tool wrote to memory
+        extension: u8,
+        len: usize,
+    },
+}
+
+/// Reads from (address, extension) into the buffer, false refuses the access
+pub type MemoryRead = fn(u32, u8, &mut [u8]) -> bool;
+/// Writes the data to (address, extension), false refuses the access
+pub type MemoryWrite = fn(u32, u8, &[u8]) -> bool;
+
+/// Memory access for the tools, addresses are whatever the application makes of them
+#[derive(Clone, Copy)]
+pub struct XcpMemory {
+    pub read: MemoryRead,
+    pub write: Option<MemoryWrite>, // None makes everything read only
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct XcpConfig {
+    pub cmd_id: Id,          // Commands from the master
+    pub res_id: Id,          // Responses, errors & events to the master
+    pub daq_id: Id,          // DTOs, usually the same as `res_id`
+    pub max_cto: u8,         // Largest command/response, 8 for classical frames up to 64
+    pub max_dto: u16,        // Largest DTO, 8 for classical frames up to 64
+    pub event_channels: u16, // How many event channels the application samples
+}
+
+impl XcpConfig {
+    pub fn new(cmd_id: Id, res_id: Id) -> Self {
+        Self {
+            cmd_id,
+            res_id,
+            daq_id: res_id,
+            max_cto: 8,
+            max_dto: 8,
+            event_channels: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct DaqList {
+    first_odt: usize,
+    odt_count: usize,
+    event_channel: u16,
+    prescaler: u8,
+    prescaler_counter: u8,
+    selected: bool,
+    running: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Odt {
+    first_entry: usize,
+    entry_count: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct OdtEntry {
+    address: u32,
+    extension: u8,
+    size: u8,
+}
+
+pub struct XcpSlave {
+    config: XcpConfig,
+    memory: XcpMemory,
+    connected: bool,
+    mta_address: u32,
+    mta_extension: u8,
+
+    daq_lists: [DaqList; MAX_DAQ_LISTS],
+    odts: [Odt; MAX_ODTS],
+    entries: [OdtEntry; MAX_ODT_ENTRIES],
+    daq_count: usize,
+    odt_count: usize,
+    entry_count: usize,
+    daq_ptr: Option<(usize, usize)>, // ODT & entry WRITE_DAQ writes to next, absolute
+
+    response: [u8; 64],
+    response_pending: Option<usize>, // Waiting for a free Tx mailbox
+    event: Option<XcpEvent>,
+}
+
+impl XcpSlave {
+    pub fn new(config: XcpConfig, memory: XcpMemory) -> Self {
+        let mut config = config;
+        config.max_cto = config.max_cto.clamp(8, 64);
+        config.max_dto = config.max_dto.clamp(8, 64);
+
+        Self {
+            config,
+            memory,
+            connected: false,
+            mta_address: 0,
+            mta_extension: 0,
+            daq_lists: [DaqList::default(); MAX_DAQ_LISTS],
+            odts: [Odt::default(); MAX_ODTS],
+            entries: [OdtEntry::default(); MAX_ODT_ENTRIES],
+            daq_count: 0,
+            odt_count: 0,
+            entry_count: 0,
+            daq_ptr: None,
+            response: [0; 64],
+            response_pending: None,
+            event: None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn is_daq_running(&self) -> bool {
+        self.daq_lists[..self.daq_count]
+            .iter()
+            .any(|daq_list| daq_list.running)
+    }
+
+    /// Feeds a received frame in, commands get answered right away
+    pub fn handle_frame<T: Transmitter>(
+        &mut self,
+        can: &mut T,
+        frame: &RxFDFrame,
+    ) -> Result<Option<XcpEvent>, XcpError> {
+        if frame.id != self.config.cmd_id || frame.buffer_len == 0 {
+            return Ok(None);
+        }
+
+        let len = (frame.buffer_len as usize).min(self.config.max_cto as usize);
+        let command = &frame.buffer[..len];
+
+        // A slave that isn't connected ignores everything else
+        if !self.connected && command[0] != CMD_CONNECT {
+            return Ok(None);
+        }
+
+        let len = match self.process(command) {
+            Ok(len) => {
+                self.response[0] = PID_RESPONSE;
+                len
+            }
+            Err(code) => {
+                self.response[0] = PID_ERROR;
+                self.response[1] = code.to_u8();
+                2
+            }
+        };
+
+        self.send_response(can, len)?;
+
+        Ok(self.event.take())
+    }
+
+    /// Sends a response that had to wait for a Tx mailbox
+    pub fn poll<T: Transmitter>(&mut self, can: &mut T) -> Result<(), XcpError> {
+        match self.response_pending {
+            Some(len) => self.send_response(can, len),
+            None => Ok(()),
+        }
+    }
+
+    /// Samples the running DAQ lists of an event channel & sends their ODTs, called by the
+    /// application whenever the event happens (e.g. every control loop cycle)
+    pub fn event<T: Transmitter>(&mut self, can: &mut T, channel: u16) -> Result<(), XcpError> {
+        if !self.connected {
+            return Ok(());
+        }
+
+        for index in 0..self.daq_count {
+            let daq_list = &mut self.daq_lists[index];
+
+            if !daq_list.running || daq_list.event_channel != channel {
+                continue;
+            }
+
+            daq_list.prescaler_counter += 1;
+
+            if daq_list.prescaler_counter < daq_list.prescaler {
+                continue;
+            }
+
+            daq_list.prescaler_counter = 0;
+
+            let (first_odt, odt_count) = (daq_list.first_odt, daq_list.odt_count);
+
+            for odt in first_odt..first_odt + odt_count {
+                self.send_odt(can, odt)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Carries out a command, returns the length of the positive response
+    fn process(&mut self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        match command[0] {
+            CMD_CONNECT => {
+                if !self.connected {
+                    self.event = Some(XcpEvent::Connected);
+                }
+
+                self.connected = true;
+                self.connect()
+            }
+            CMD_DISCONNECT => {
+                self.connected = false;
+                self.stop_all();
+                self.event = Some(XcpEvent::Disconnected);
+                Ok(1)
+            }
+            CMD_GET_STATUS => {
+                let status = if self.is_daq_running() {
+                    SESSION_DAQ_RUNNING
+                } else {
+                    0
+                };
+
+                self.response[1..6].copy_from_slice(&[status, 0, 0, 0, 0]);
+                Ok(6)
+            }
+            CMD_SYNCH => Err(XcpErrorCode::CmdSynch),
+            CMD_SET_MTA => {
+                let address = read_u32(command, 4)?;
+                self.mta_extension = *command.get(3).ok_or(XcpErrorCode::CmdSyntax)?;
+                self.mta_address = address;
+                Ok(1)
+            }
+            CMD_UPLOAD => {
+                let len = *command.get(1).ok_or(XcpErrorCode::CmdSyntax)? as usize;
+                self.upload(len)
+            }
+            CMD_SHORT_UPLOAD => {
+                let len = *command.get(1).ok_or(XcpErrorCode::CmdSyntax)? as usize;
+                self.mta_address = read_u32(command, 4)?;
+                self.mta_extension = command[3];
+                self.upload(len)
+            }
+            CMD_DOWNLOAD => {
+                let len = *command.get(1).ok_or(XcpErrorCode::CmdSyntax)? as usize;
+                let data = command.get(2..2 + len).ok_or(XcpErrorCode::CmdSyntax)?;
+                let write = self.memory.write.ok_or(XcpErrorCode::WriteProtected)?;
+
+                if !write(self.mta_address, self.mta_extension, data) {
+                    Err(XcpErrorCode::AccessDenied)
+                } else {
+                    self.event = Some(XcpEvent::Downloaded {
+                        address: self.mta_address,
+                        extension: self.mta_extension,
+                        len,
+                    });
+
+                    self.mta_address = self.mta_address.wrapping_add(len as u32);
+                    Ok(1)
+                }
+            }
+            CMD_GET_DAQ_PROCESSOR_INFO => {
+                let max_daq = (MAX_DAQ_LISTS as u16).to_le_bytes();
+                let event_channels = self.config.event_channels.to_le_bytes();
+
+                self.response[1..8].copy_from_slice(&[
+                    DAQ_CONFIG_DYNAMIC | DAQ_PRESCALER_SUPPORTED,
+                    max_daq[0],
+                    max_daq[1],
+                    event_channels[0],
+                    event_channels[1],
+                    0, // No predefined DAQ lists
+                    0, // Absolute ODT numbers as the PID, no optimisation
+                ]);
+                Ok(8)
+            }
+            CMD_FREE_DAQ => {
+                self.free_daq();
+                Ok(1)
+            }
+            CMD_ALLOC_DAQ => self.alloc_daq(command),
+            CMD_ALLOC_ODT => self.alloc_odt(command),
+            CMD_ALLOC_ODT_ENTRY => self.alloc_odt_entry(command),
+            CMD_SET_DAQ_PTR => self.set_daq_ptr(command),
+            CMD_WRITE_DAQ => self.write_daq(command),
+            CMD_SET_DAQ_LIST_MODE => self.set_daq_list_mode(command),
+            CMD_START_STOP_DAQ_LIST => self.start_stop_daq_list(command),
+            CMD_START_STOP_SYNCH => self.start_stop_synch(command),
+            _ => Err(XcpErrorCode::CmdUnknown),
+        }
+    }
+
+    fn connect(&mut self) -> Result<usize, XcpErrorCode> {
+        let max_dto = self.config.max_dto.to_le_bytes();
+
+        self.response[1..8].copy_from_slice(&[
+            RESOURCE_CAL_PAG | RESOURCE_DAQ,
+            0, // Intel byte order, byte granularity
+            self.config.max_cto,
+            max_dto[0],
+            max_dto[1],
+            PROTOCOL_LAYER_VERSION,
+            TRANSPORT_LAYER_VERSION,
+        ]);
+
+        Ok(8)
+    }
+
+    fn upload(&mut self, len: usize) -> Result<usize, XcpErrorCode> {
+        if len == 0 || len > self.config.max_cto as usize - 1 {
+            return Err(XcpErrorCode::OutOfRange);
+        }
+
+        if !(self.memory.read)(
+            self.mta_address,
+            self.mta_extension,
+            &mut self.response[1..1 + len],
+        ) {
+            return Err(XcpErrorCode::AccessDenied);
+        }
+
+        self.mta_address = self.mta_address.wrapping_add(len as u32);
+
+        Ok(1 + len)
+    }
+
+    fn alloc_daq(&mut self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let count = read_u16(command, 2)? as usize;
+
+        if self.odt_count != 0 {
+            return Err(XcpErrorCode::Sequence);
+        }
+
+        if count > MAX_DAQ_LISTS {
+            return Err(XcpErrorCode::MemoryOverflow);
+        }
+
+        self.free_daq();
+        self.daq_count = count;
+
+        Ok(1)
+    }
+
+    // Forgets every DAQ list, ODT & entry, so nothing of an old configuration gets sampled
+    fn free_daq(&mut self) {
+        self.daq_lists = [DaqList::default(); MAX_DAQ_LISTS];
+        self.odts = [Odt::default(); MAX_ODTS];
+        self.entries = [OdtEntry::default(); MAX_ODT_ENTRIES];
+        self.daq_count = 0;
+        self.odt_count = 0;
+        self.entry_count = 0;
+        self.daq_ptr = None;
+    }
+
+    fn alloc_odt(&mut self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let daq = self.daq_index(command)?;
+        let count = *command.get(4).ok_or(XcpErrorCode::CmdSyntax)? as usize;
+
+        // ODTs are handed out in order, so a list's ODTs stay contiguous
+        if self.entry_count != 0 || self.daq_lists[daq].odt_count != 0 {
+            return Err(XcpErrorCode::Sequence);
+        }
+
+        if self.odt_count + count > MAX_ODTS {
+            return Err(XcpErrorCode::MemoryOverflow);
+        }
+
+        for odt in self.odts[self.odt_count..self.odt_count + count].iter_mut() {
+            *odt = Odt::default();
+        }
+
+        self.daq_lists[daq].first_odt = self.odt_count;
+        self.daq_lists[daq].odt_count = count;
+        self.odt_count += count;
+
+        Ok(1)
+    }
+
+    fn alloc_odt_entry(&mut self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let odt = self.odt_index(command)?;
+        let count = *command.get(5).ok_or(XcpErrorCode::CmdSyntax)? as usize;
+
+        if self.odts[odt].entry_count != 0 {
+            return Err(XcpErrorCode::Sequence);
+        }
+
+        if self.entry_count + count > MAX_ODT_ENTRIES {
+            return Err(XcpErrorCode::MemoryOverflow);
+        }
+
+        // Entries that haven't been written yet stay empty & aren't sampled
+        for entry in self.entries[self.entry_count..self.entry_count + count].iter_mut() {
+            *entry = OdtEntry::default();
+        }
+
+        self.odts[odt] = Odt {
+            first_entry: self.entry_count,
+            entry_count: count,
+        };
+        self.entry_count += count;
+
+        Ok(1)
+    }
+
+    fn set_daq_ptr(&mut self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let odt = self.odt_index(command)?;
+        let entry = *command.get(5).ok_or(XcpErrorCode::CmdSyntax)? as usize;
+
+        if self.daq_lists[self.daq_index(command)?].running {
+            return Err(XcpErrorCode::DaqActive);
+        }
+
+        if entry >= self.odts[odt].entry_count {
+            return Err(XcpErrorCode::OutOfRange);
+        }
+
+        self.daq_ptr = Some((odt, entry));
+
+        Ok(1)
+    }
+
+    fn write_daq(&mut self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let (odt, entry) = self.daq_ptr.ok_or(XcpErrorCode::Sequence)?;
+        let address = read_u32(command, 4)?;
+        let size = command[2];
+
+        if command[1] != 0xFF {
+            // Bit offsets aren't supported
+            return Err(XcpErrorCode::OutOfRange);
+        }
+
+        let first_entry = self.odts[odt].first_entry;
+        let other_sizes: usize = self.entries
+            [first_entry..first_entry + self.odts[odt].entry_count]
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != entry)
+            .map(|(_, entry)| entry.size as usize)
+            .sum();
+
+        // The PID takes the first byte of the DTO
+        if size == 0 || other_sizes + size as usize > self.config.max_dto as usize - 1 {
+            return Err(XcpErrorCode::OutOfRange);
+        }
+
+        self.entries[first_entry + entry] = OdtEntry {
+            address,
+            extension: command[3],
+            size,
+        };
+
+        // The pointer moves on to the next entry by itself
+        self.daq_ptr = if entry + 1 < self.odts[odt].entry_count {
+            Some((odt, entry + 1))
+        } else {
+            None
+        };
+
+        Ok(1)
+    }
+
+    fn set_daq_list_mode(&mut self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let daq = self.daq_index(command)?;
+        let mode = command[1];
+        let event_channel = read_u16(command, 4)?;
+        let prescaler = *command.get(6).ok_or(XcpErrorCode::CmdSyntax)?;
+
+        if self.daq_lists[daq].running {
+            return Err(XcpErrorCode::DaqActive);
+        }
+
+        if mode & DAQ_MODE_UNSUPPORTED != 0 {
+            return Err(XcpErrorCode::ModeNotValid);
+        }
+
+        if event_channel >= self.config.event_channels {
+            return Err(XcpErrorCode::OutOfRange);
+        }
+
+        self.daq_lists[daq].event_channel = event_channel;
+        self.daq_lists[daq].prescaler = prescaler.max(1);
+
+        Ok(1)
+    }
+
+    fn start_stop_daq_list(&mut self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let daq = self.daq_index(command)?;
+        let daq_list = &mut self.daq_lists[daq];
+
+        match command[1] {
+            0 => daq_list.running = false,
+            1 => {
+                daq_list.running = true;
+                daq_list.prescaler_counter = 0;
+            }
+            2 => daq_list.selected = true,
+            _ => return Err(XcpErrorCode::ModeNotValid),
+        }
+
+        // First PID of the list, the master needs it to tell the DTOs apart
+        self.response[1] = daq_list.first_odt as u8;
+
+        Ok(2)
+    }
+
+    fn start_stop_synch(&mut self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let mode = *command.get(1).ok_or(XcpErrorCode::CmdSyntax)?;
+
+        if mode > 2 {
+            return Err(XcpErrorCode::ModeNotValid);
+        }
+
+        for daq_list in self.daq_lists[..self.daq_count].iter_mut() {
+            match mode {
+                0 => daq_list.running = false,
+                1 if daq_list.selected => {
+                    daq_list.running = true;
+                    daq_list.prescaler_counter = 0;
+                }
+                2 if daq_list.selected => daq_list.running = false,
+                _ => (),
+            }
+
+            if mode != 0 {
+                daq_list.selected = false;
+            }
+        }
+
+        Ok(1)
+    }
+
+    fn stop_all(&mut self) {
+        for daq_list in self.daq_lists.iter_mut() {
+            daq_list.running = false;
+            daq_list.selected = false;
+        }
+    }
+
+    fn daq_index(&self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let daq = read_u16(command, 2)? as usize;
+
+        if daq >= self.daq_count {
+            return Err(XcpErrorCode::OutOfRange);
+        }
+
+        Ok(daq)
+    }
+
+    // The absolute ODT a command addresses with its DAQ list & relative ODT number
+    fn odt_index(&self, command: &[u8]) -> Result<usize, XcpErrorCode> {
+        let daq_list = &self.daq_lists[self.daq_index(command)?];
+        let odt = *command.get(4).ok_or(XcpErrorCode::CmdSyntax)? as usize;
+
+        if odt >= daq_list.odt_count {
+            return Err(XcpErrorCode::OutOfRange);
+        }
+
+        Ok(daq_list.first_odt + odt)
+    }
+
+    fn send_odt<T: Transmitter>(&mut self, can: &mut T, odt: usize) -> Result<(), XcpError> {
+        let mut buffer = [0_u8; 64];
+        let mut len = 1;
+        buffer[0] = odt as u8;
+
+        let first_entry = self.odts[odt].first_entry;
+        let max_dto = self.config.max_dto as usize;
+
+        for entry in self.entries[first_entry..first_entry + self.odts[odt].entry_count].iter() {
+            let size = entry.size as usize;
+
+            // WRITE_DAQ keeps the ODTs within MAX_DTO, this only guards against stale entries
+            if size == 0 || len + size > max_dto {
+                continue;
+            }
+
+            if (self.memory.read)(entry.address, entry.extension, &mut buffer[len..len + size]) {
+                len += size;
+            }
+        }
+
+        // Classical frames are always padded to 8 bytes, FD ones to the next valid length
+        let len = dlc_to_len(len_to_dlc(len.max(8) as u32)) as usize;
+        let frame = TxFDFrame {
+            id: self.config.daq_id,
+            buffer: &buffer[..len],
+            priority: None,
+            format: frame_format(self.config.max_dto as usize),
+        };
+
+        match can.transmit(&frame) {
+            Ok(()) => Ok(()),
+            Err(RxTxError::MailboxUnavailable) => Err(XcpError::DaqOverrun),
+            Err(err) => Err(XcpError::Bus(err)),
+        }
+    }
+
+    fn send_response<T: Transmitter>(&mut self, can: &mut T, len: usize) -> Result<(), XcpError> {
+        let padded_len = dlc_to_len(len_to_dlc(len.max(8) as u32)) as usize;
+
+        for byte in self.response[len..padded_len].iter_mut() {
+            *byte = 0;
+        }
+
+        let len = padded_len;
+        let frame = TxFDFrame {
+            id: self.config.res_id,
+            buffer: &self.response[..len],
+            priority: None,
+            format: frame_format(self.config.max_cto as usize),
+        };
+
+        match can.transmit(&frame) {
+            Ok(()) => {
+                self.response_pending = None;
+                Ok(())
+            }
+            Err(RxTxError::MailboxUnavailable) => {
+                self.response_pending = Some(len);
+                Ok(())
+            }
+            Err(err) => {
+                self.response_pending = None;
+                Err(XcpError::Bus(err))
+            }
+        }
+    }
+}
+
+fn read_u16(command: &[u8], offset: usize) -> Result<u16, XcpErrorCode> {
+    match command.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_le_bytes([bytes[0], bytes[1]])),
+        None => Err(XcpErrorCode::CmdSyntax),
+    }
+}
+
+fn read_u32(command: &[u8], offset: usize) -> Result<u32, XcpErrorCode> {
+    match command.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+        None => Err(XcpErrorCode::CmdSyntax),
+    }
+}
+
+/// Classical frames when the packets fit in 8 bytes, like the MAX_CTO/MAX_DTO of 8 promise
+fn frame_format(max_len: usize) -> FrameFormat {
+    if max_len <= 8 {
+        FrameFormat::Classic
+    } else {
+        FrameFormat::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{rx_frame, Bus};
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    const CMD_ID: Id = Id::Standard(0x100);
+    const RES_ID: Id = Id::Standard(0x101);
+
+    static WRITTEN: AtomicU32 = AtomicU32::new(0);
+
+    // Every address below 0x100 reads back as its own low byte
+    fn read(address: u32, _extension: u8, buffer: &mut [u8]) -> bool {
+        if address as usize + buffer.len() > 0x100 {
+            return false;
+        }
+
+        for (offset, byte) in buffer.iter_mut().enumerate() {
+            *byte = (address as usize + offset) as u8;
+        }
+
+        true
+    }
+
+    // Only the first 0x80 bytes are writable
+    fn write(address: u32, _extension: u8, data: &[u8]) -> bool {
+        if address as usize + data.len() > 0x80 {
+            return false;
+        }
+
+        let mut bytes = [0; 4];
+        let len = data.len().min(4);
+        bytes[..len].copy_from_slice(&data[..len]);
+        WRITTEN.store(u32::from_le_bytes(bytes), Ordering::Relaxed);
+
+        true
+    }
+
+    fn slave(max_packet: u8) -> XcpSlave {
+        let mut config = XcpConfig::new(CMD_ID, RES_ID);
+        config.max_cto = max_packet;
+        config.max_dto = max_packet as u16;
+
+        XcpSlave::new(
+            config,
+            XcpMemory {
+                read,
+                write: Some(write),
+            },
+        )
+    }
+
+    // What the master gets back for a command, empty when nothing was sent
+    fn command(slave: &mut XcpSlave, bus: &mut Bus, data: &[u8]) -> Vec<u8> {
+        slave.handle_frame(bus, &rx_frame(CMD_ID, data)).unwrap();
+
+        match bus.take().as_slice() {
+            [] => vec![],
+            [response] => {
+                assert_eq!(response.id, RES_ID);
+                assert_eq!(response.format, frame_format(slave.config.max_cto as usize));
+                response.data.clone()
+            }
+            sent => panic!("{} frames for one command", sent.len()),
+        }
+    }
+
+    fn ok(slave: &mut XcpSlave, bus: &mut Bus, data: &[u8]) {
+        assert_eq!(command(slave, bus, data)[0], PID_RESPONSE, "{:02X?}", data);
+    }
+
+    fn error(slave: &mut XcpSlave, bus: &mut Bus, data: &[u8]) -> u8 {
+        let response = command(slave, bus, data);
+        assert_eq!(response[0], PID_ERROR, "{:02X?}", data);
+        response[1]
+    }
+
+    fn dtos(bus: &mut Bus) -> Vec<Vec<u8>> {
+        bus.take()
+            .into_iter()
+            .map(|dto| {
+                assert_eq!(dto.id, RES_ID);
+                dto.data
+            })
+            .collect()
+    }
+
+    // Two DAQ lists on event channel 0, the first with ODT 0 (4 bytes @ 0x40, 2 @ 0x50) &
+    // ODT 1 (7 bytes @ 0x60), the second with ODT 2 (1 byte @ 0x70)
+    fn configure_daq(slave: &mut XcpSlave, bus: &mut Bus) {
+        ok(slave, bus, &[CMD_FREE_DAQ]);
+        ok(slave, bus, &[CMD_ALLOC_DAQ, 0, 2, 0]);
+        ok(slave, bus, &[CMD_ALLOC_ODT, 0, 0, 0, 2]);
+        ok(slave, bus, &[CMD_ALLOC_ODT, 0, 1, 0, 1]);
+        ok(slave, bus, &[CMD_ALLOC_ODT_ENTRY, 0, 0, 0, 0, 2]);
+        ok(slave, bus, &[CMD_ALLOC_ODT_ENTRY, 0, 0, 0, 1, 1]);
+        ok(slave, bus, &[CMD_ALLOC_ODT_ENTRY, 0, 1, 0, 0, 1]);
+
+        ok(slave, bus, &[CMD_SET_DAQ_PTR, 0, 0, 0, 0, 0]);
+        ok(slave, bus, &[CMD_WRITE_DAQ, 0xFF, 4, 0, 0x40, 0, 0, 0]);
+        ok(slave, bus, &[CMD_WRITE_DAQ, 0xFF, 2, 0, 0x50, 0, 0, 0]);
+        ok(slave, bus, &[CMD_SET_DAQ_PTR, 0, 0, 0, 1, 0]);
+        ok(slave, bus, &[CMD_WRITE_DAQ, 0xFF, 7, 0, 0x60, 0, 0, 0]);
+        ok(slave, bus, &[CMD_SET_DAQ_PTR, 0, 1, 0, 0, 0]);
+        ok(slave, bus, &[CMD_WRITE_DAQ, 0xFF, 1, 0, 0x70, 0, 0, 0]);
+
+        ok(slave, bus, &[CMD_SET_DAQ_LIST_MODE, 0, 0, 0, 0, 0, 1, 0]);
+        ok(slave, bus, &[CMD_SET_DAQ_LIST_MODE, 0, 1, 0, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn connect_and_memory() {
+        let mut bus = Bus::default();
+        let mut slave = slave(8);
+
+        // Nothing but CONNECT is answered before connecting
+        assert_eq!(command(&mut slave, &mut bus, &[CMD_GET_STATUS]), vec![]);
+        assert_eq!(
+            slave.handle_frame(&mut bus, &rx_frame(CMD_ID, &[CMD_CONNECT, 0])),
+            Ok(Some(XcpEvent::Connected))
+        );
+        assert_eq!(bus.take()[0].data, [0xFF, 0x05, 0, 8, 8, 0, 1, 1]);
+        assert!(slave.is_connected());
+
+        assert_eq!(
+            command(&mut slave, &mut bus, &[CMD_GET_STATUS]),
+            [0xFF, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        // SHORT_UPLOAD sets the MTA, UPLOAD carries on from where it ended
+        assert_eq!(
+            command(
+                &mut slave,
+                &mut bus,
+                &[CMD_SHORT_UPLOAD, 4, 0, 0, 0x10, 0, 0, 0]
+            ),
+            [0xFF, 0x10, 0x11, 0x12, 0x13, 0, 0, 0]
+        );
+        assert_eq!(
+            command(&mut slave, &mut bus, &[CMD_UPLOAD, 2]),
+            [0xFF, 0x14, 0x15, 0, 0, 0, 0, 0]
+        );
+        let code = error(&mut slave, &mut bus, &[CMD_UPLOAD, 8]);
+        assert_eq!(code, XcpErrorCode::OutOfRange.to_u8());
+        let code = error(
+            &mut slave,
+            &mut bus,
+            &[CMD_SHORT_UPLOAD, 4, 0, 0, 0xFE, 0, 0, 0],
+        );
+        assert_eq!(code, XcpErrorCode::AccessDenied.to_u8());
+
+        ok(&mut slave, &mut bus, &[CMD_SET_MTA, 0, 0, 0, 0x20, 0, 0, 0]);
+        assert_eq!(
+            slave.handle_frame(&mut bus, &rx_frame(CMD_ID, &[CMD_DOWNLOAD, 4, 1, 2, 3, 4])),
+            Ok(Some(XcpEvent::Downloaded {
+                address: 0x20,
+                extension: 0,
+                len: 4,
+            }))
+        );
+        assert_eq!(bus.take()[0].data[0], PID_RESPONSE);
+        assert_eq!(WRITTEN.load(Ordering::Relaxed), 0x0403_0201);
+
+        ok(&mut slave, &mut bus, &[CMD_SET_MTA, 0, 0, 0, 0x80, 0, 0, 0]);
+        let code = error(&mut slave, &mut bus, &[CMD_DOWNLOAD, 1, 0]);
+        assert_eq!(code, XcpErrorCode::AccessDenied.to_u8());
+        let code = error(&mut slave, &mut bus, &[CMD_DOWNLOAD, 4, 0]);
+        assert_eq!(code, XcpErrorCode::CmdSyntax.to_u8());
+        let code = error(&mut slave, &mut bus, &[0x12]);
+        assert_eq!(code, XcpErrorCode::CmdUnknown.to_u8());
+
+        // A response waits for a free mailbox
+        bus.full = true;
+        assert_eq!(command(&mut slave, &mut bus, &[CMD_GET_STATUS]), vec![]);
+        bus.full = false;
+        slave.poll(&mut bus).unwrap();
+        assert_eq!(bus.take()[0].data, [0xFF, 0, 0, 0, 0, 0, 0, 0]);
+        slave.poll(&mut bus).unwrap();
+        assert!(bus.take().is_empty());
+
+        assert_eq!(
+            slave.handle_frame(&mut bus, &rx_frame(CMD_ID, &[CMD_DISCONNECT])),
+            Ok(Some(XcpEvent::Disconnected))
+        );
+        bus.take();
+        assert_eq!(command(&mut slave, &mut bus, &[CMD_GET_STATUS]), vec![]);
+    }
+
+    #[test]
+    fn daq() {
+        let mut bus = Bus::default();
+        let mut slave = slave(8);
+        ok(&mut slave, &mut bus, &[CMD_CONNECT, 0]);
+        configure_daq(&mut slave, &mut bus);
+
+        // The PID takes a byte, so an ODT holds 7 at most
+        ok(&mut slave, &mut bus, &[CMD_SET_DAQ_PTR, 0, 0, 0, 1, 0]);
+        let code = error(
+            &mut slave,
+            &mut bus,
+            &[CMD_WRITE_DAQ, 0xFF, 8, 0, 0x60, 0, 0, 0],
+        );
+        assert_eq!(code, XcpErrorCode::OutOfRange.to_u8());
+        let code = error(&mut slave, &mut bus, &[CMD_ALLOC_DAQ, 0, 1, 0]);
+        assert_eq!(code, XcpErrorCode::Sequence.to_u8());
+
+        assert_eq!(
+            command(&mut slave, &mut bus, &[CMD_START_STOP_DAQ_LIST, 1, 0, 0]),
+            [0xFF, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert!(slave.is_daq_running());
+        assert_eq!(
+            command(&mut slave, &mut bus, &[CMD_GET_STATUS]),
+            [0xFF, SESSION_DAQ_RUNNING, 0, 0, 0, 0, 0, 0]
+        );
+
+        slave.event(&mut bus, 0).unwrap();
+        let sent = bus.take();
+        assert!(sent.iter().all(|dto| dto.format == FrameFormat::Classic));
+        assert_eq!(
+            sent.into_iter().map(|dto| dto.data).collect::<Vec<_>>(),
+            [
+                vec![0, 0x40, 0x41, 0x42, 0x43, 0x50, 0x51, 0],
+                vec![1, 0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66],
+            ]
+        );
+
+        // The second list joins with the synchronised start
+        assert_eq!(
+            command(&mut slave, &mut bus, &[CMD_START_STOP_DAQ_LIST, 2, 1, 0]),
+            [0xFF, 2, 0, 0, 0, 0, 0, 0]
+        );
+        ok(&mut slave, &mut bus, &[CMD_START_STOP_SYNCH, 1]);
+        slave.event(&mut bus, 0).unwrap();
+        let sent = dtos(&mut bus);
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[2], [2, 0x70, 0, 0, 0, 0, 0, 0]);
+
+        slave.event(&mut bus, 1).unwrap();
+        assert!(bus.take().is_empty());
+
+        bus.full = true;
+        assert_eq!(slave.event(&mut bus, 0), Err(XcpError::DaqOverrun));
+        bus.full = false;
+
+        ok(&mut slave, &mut bus, &[CMD_START_STOP_SYNCH, 0]);
+        assert!(!slave.is_daq_running());
+        slave.event(&mut bus, 0).unwrap();
+        assert!(bus.take().is_empty());
+    }
+
+    #[test]
+    fn free_daq_clears_entries() {
+        let mut bus = Bus::default();
+        let mut slave = slave(8);
+        ok(&mut slave, &mut bus, &[CMD_CONNECT, 0]);
+        configure_daq(&mut slave, &mut bus);
+
+        // Reuses the storage of ODT 0, but only its first entry gets written
+        ok(&mut slave, &mut bus, &[CMD_FREE_DAQ]);
+        ok(&mut slave, &mut bus, &[CMD_ALLOC_DAQ, 0, 1, 0]);
+        ok(&mut slave, &mut bus, &[CMD_ALLOC_ODT, 0, 0, 0, 1]);
+        ok(&mut slave, &mut bus, &[CMD_ALLOC_ODT_ENTRY, 0, 0, 0, 0, 2]);
+        ok(&mut slave, &mut bus, &[CMD_SET_DAQ_PTR, 0, 0, 0, 0, 0]);
+        ok(
+            &mut slave,
+            &mut bus,
+            &[CMD_WRITE_DAQ, 0xFF, 7, 0, 0x10, 0, 0, 0],
+        );
+        ok(&mut slave, &mut bus, &[CMD_START_STOP_DAQ_LIST, 1, 0, 0]);
+
+        slave.event(&mut bus, 0).unwrap();
+        assert_eq!(
+            dtos(&mut bus),
+            [vec![0, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16]]
+        );
+    }
+
+    #[test]
+    fn fd_dtos() {
+        let mut bus = Bus::default();
+        let mut slave = slave(64);
+        ok(&mut slave, &mut bus, &[CMD_CONNECT, 0]);
+
+        ok(&mut slave, &mut bus, &[CMD_ALLOC_DAQ, 0, 1, 0]);
+        ok(&mut slave, &mut bus, &[CMD_ALLOC_ODT, 0, 0, 0, 1]);
+        ok(&mut slave, &mut bus, &[CMD_ALLOC_ODT_ENTRY, 0, 0, 0, 0, 3]);
+        ok(&mut slave, &mut bus, &[CMD_SET_DAQ_PTR, 0, 0, 0, 0, 0]);
+
+        for address in [0x00, 0x10, 0x20] {
+            ok(
+                &mut slave,
+                &mut bus,
+                &[CMD_WRITE_DAQ, 0xFF, 4, 0, address, 0, 0, 0],
+            );
+        }
+
+        ok(&mut slave, &mut bus, &[CMD_START_STOP_DAQ_LIST, 1, 0, 0]);
+        slave.event(&mut bus, 0).unwrap();
+
+        // 13 bytes are padded up to the 16 of the next DLC
+        let dto = bus.take().pop().unwrap();
+        assert_eq!(
+            dto.format,
+            FrameFormat::Fd {
+                bitrate_switch: true
+            }
+        );
+        assert_eq!(
+            dto.data,
+            [0, 0, 1, 2, 3, 0x10, 0x11, 0x12, 0x13, 0x20, 0x21, 0x22, 0x23, 0, 0, 0]
+        );
+    }
+}
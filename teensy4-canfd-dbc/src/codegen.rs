@@ -0,0 +1,693 @@
+//! Turns the parsed DBC into Rust source
+//!
+//! Every message becomes a struct with one field per signal, plus `ID`, `LEN`, `encode` and
+//! `decode`. Fields are the smallest integer type that fits for unscaled signals (`bool` for
+//! single bits), `f64` for scaled ones, `f32`/`f64` for float signals and an enum for signals with
+//! value descriptions. The signals behind a multiplexor go into an enum with one variant per
+//! multiplexor value, which takes the multiplexor's place in the struct.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::parse::{ByteOrder, Dbc, Message, Multiplexing, Signal, ValueType};
+use crate::Error;
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else", "enum",
+    "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+    "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true",
+    "try", "type", "unsafe", "use", "where", "while", "yield",
+];
+
+pub fn generate(dbc: &Dbc, crate_path: &str, source: &str) -> Result<String, Error> {
+    let mut out = String::new();
+
+    writeln!(
+        out,
+        "// Generated by teensy4-canfd-dbc from {}, don't edit",
+        source
+    )
+    .unwrap();
+
+    for message in dbc.messages.iter() {
+        check_message(message)?;
+        MessageWriter::new(message, crate_path).write(&mut out);
+    }
+
+    Ok(out)
+}
+
+fn check_message(message: &Message) -> Result<(), Error> {
+    let multiplexors = message
+        .signals
+        .iter()
+        .filter(|signal| signal.multiplexing == Multiplexing::Multiplexor)
+        .count();
+    let multiplexed = message
+        .signals
+        .iter()
+        .any(|signal| matches!(signal.multiplexing, Multiplexing::Multiplexed(_)));
+
+    if multiplexors > 1 {
+        return Err(Error::Unsupported(format!(
+            "message {} has more than one multiplexor",
+            message.name
+        )));
+    }
+
+    if multiplexed && multiplexors == 0 {
+        return Err(Error::Unsupported(format!(
+            "message {} has multiplexed signals but no multiplexor",
+            message.name
+        )));
+    }
+
+    for signal in message.signals.iter() {
+        let bits = match signal.byte_order {
+            ByteOrder::LittleEndian => signal.start_bit as usize + signal.len as usize,
+            // Bit 7 of the signal's first byte up to where its last bit is
+            ByteOrder::BigEndian => {
+                let msb_byte = signal.start_bit as usize / 8;
+                let bits_in_first_byte = signal.start_bit as usize % 8 + 1;
+                let remaining = (signal.len as usize).saturating_sub(bits_in_first_byte);
+                (msb_byte + 1 + remaining.div_ceil(8)) * 8
+            }
+        };
+
+        if bits > message.len * 8 {
+            return Err(Error::Layout(format!(
+                "signal {} doesn't fit in the {} bytes of message {}",
+                signal.name, message.len, message.name
+            )));
+        }
+
+        let float_len = match signal.value_type {
+            ValueType::Float32 => Some(32),
+            ValueType::Float64 => Some(64),
+            _ => None,
+        };
+
+        if let Some(len) = float_len.filter(|len| *len != signal.len) {
+            return Err(Error::Layout(format!(
+                "float signal {} isn't {} bits long",
+                signal.name, len
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+struct MessageWriter<'a> {
+    message: &'a Message,
+    krate: &'a str,
+    name: String,
+}
+
+impl<'a> MessageWriter<'a> {
+    fn new(message: &'a Message, krate: &'a str) -> Self {
+        Self {
+            message,
+            krate,
+            name: type_name(&message.name),
+        }
+    }
+
+    fn multiplexor(&self) -> Option<&'a Signal> {
+        self.message
+            .signals
+            .iter()
+            .find(|signal| signal.multiplexing == Multiplexing::Multiplexor)
+    }
+
+    fn plain_signals(&self) -> impl Iterator<Item = &'a Signal> {
+        self.message
+            .signals
+            .iter()
+            .filter(|signal| signal.multiplexing == Multiplexing::None)
+    }
+
+    // The multiplexed signals by multiplexor value
+    fn multiplexed_signals(&self) -> BTreeMap<u64, Vec<&'a Signal>> {
+        let mut map: BTreeMap<u64, Vec<&Signal>> = BTreeMap::new();
+
+        for signal in self.message.signals.iter() {
+            if let Multiplexing::Multiplexed(value) = signal.multiplexing {
+                map.entry(value).or_default().push(signal);
+            }
+        }
+
+        map
+    }
+
+    fn multiplexor_type(&self, multiplexor: &Signal) -> String {
+        format!("{}{}", self.name, type_name(&multiplexor.name))
+    }
+
+    fn write(&self, out: &mut String) {
+        let message = self.message;
+
+        writeln!(out).unwrap();
+        write_doc(out, "", message.comment.as_deref(), "");
+        writeln!(out, "#[derive(Debug, Clone, Copy, PartialEq)]").unwrap();
+        writeln!(out, "pub struct {} {{", self.name).unwrap();
+
+        for signal in message.signals.iter() {
+            match signal.multiplexing {
+                Multiplexing::None => self.write_field(out, signal, "    ", "pub "),
+                Multiplexing::Multiplexor => writeln!(
+                    out,
+                    "    pub {}: {},",
+                    field_name(&signal.name),
+                    self.multiplexor_type(signal)
+                )
+                .unwrap(),
+                Multiplexing::Multiplexed(_) => (),
+            }
+        }
+
+        writeln!(out, "}}").unwrap();
+
+        self.write_impl(out);
+
+        for signal in message.signals.iter() {
+            if !signal.values.is_empty() && signal.multiplexing != Multiplexing::Multiplexor {
+                self.write_value_enum(out, signal);
+            }
+        }
+
+        if let Some(multiplexor) = self.multiplexor() {
+            self.write_multiplexor_enum(out, multiplexor);
+        }
+    }
+
+    fn write_field(&self, out: &mut String, signal: &Signal, indent: &str, visibility: &str) {
+        let unit = if signal.unit.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", signal.unit)
+        };
+
+        write_doc(out, indent, signal.comment.as_deref(), &unit);
+        writeln!(
+            out,
+            "{}{}{}: {},",
+            indent,
+            visibility,
+            field_name(&signal.name),
+            self.field_type(signal)
+        )
+        .unwrap();
+    }
+
+    fn write_impl(&self, out: &mut String) {
+        let message = self.message;
+        let id = if message.extended {
+            format!("Extended(0x{:08X})", message.id)
+        } else {
+            format!("Standard(0x{:03X})", message.id)
+        };
+
+        writeln!(out).unwrap();
+        writeln!(out, "impl {} {{", self.name).unwrap();
+        writeln!(
+            out,
+            "    pub const ID: {}::config::Id = {}::config::Id::{};",
+            self.krate, self.krate, id
+        )
+        .unwrap();
+        writeln!(out, "    pub const LEN: usize = {};", message.len).unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "    /// The payload, goes into a `TxFDFrame` with `ID`"
+        )
+        .unwrap();
+        writeln!(out, "    pub fn encode(&self) -> [u8; {}] {{", message.len).unwrap();
+
+        if message.signals.is_empty() {
+            writeln!(out, "        #[allow(unused_mut)]").unwrap();
+        }
+
+        writeln!(out, "        let mut data = [0_u8; {}];", message.len).unwrap();
+
+        for signal in self.plain_signals() {
+            let value = format!("self.{}", field_name(&signal.name));
+            writeln!(out, "        {};", self.write_signal(signal, &value)).unwrap();
+        }
+
+        if let Some(multiplexor) = self.multiplexor() {
+            let enum_name = self.multiplexor_type(multiplexor);
+
+            writeln!(
+                out,
+                "        match self.{} {{",
+                field_name(&multiplexor.name)
+            )
+            .unwrap();
+
+            for (value, signals) in self.multiplexed_signals() {
+                let fields: Vec<String> = signals
+                    .iter()
+                    .map(|signal| field_name(&signal.name))
+                    .collect();
+
+                writeln!(
+                    out,
+                    "            {}::M{} {{ {} }} => {{",
+                    enum_name,
+                    value,
+                    fields.join(", ")
+                )
+                .unwrap();
+                writeln!(
+                    out,
+                    "                {};",
+                    self.write_raw(multiplexor, &format!("{}", value))
+                )
+                .unwrap();
+
+                for (signal, field) in signals.iter().zip(fields.iter()) {
+                    writeln!(out, "                {};", self.write_signal(signal, field)).unwrap();
+                }
+
+                writeln!(out, "            }}").unwrap();
+            }
+
+            writeln!(out, "        }}").unwrap();
+        }
+
+        writeln!(out, "        data").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "    /// None if the frame has another ID or is too short"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "    pub fn decode(frame: &{}::RxFDFrame) -> Option<Self> {{",
+            self.krate
+        )
+        .unwrap();
+        writeln!(out, "        if frame.id != Self::ID {{").unwrap();
+        writeln!(out, "            return None;").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "        Self::decode_payload(&frame.buffer[..(frame.buffer_len as usize).min(64)])"
+        )
+        .unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "    /// None if the payload is too short or has an unknown multiplexor value"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "    pub fn decode_payload(data: &[u8]) -> Option<Self> {{"
+        )
+        .unwrap();
+        writeln!(out, "        if data.len() < Self::LEN {{").unwrap();
+        writeln!(out, "            return None;").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out).unwrap();
+
+        if let Some(multiplexor) = self.multiplexor() {
+            let enum_name = self.multiplexor_type(multiplexor);
+
+            writeln!(
+                out,
+                "        let {} = match {} {{",
+                field_name(&multiplexor.name),
+                self.read_raw(multiplexor)
+            )
+            .unwrap();
+
+            for (value, signals) in self.multiplexed_signals() {
+                writeln!(out, "            {} => {}::M{} {{", value, enum_name, value).unwrap();
+
+                for signal in signals {
+                    writeln!(
+                        out,
+                        "                {}: {},",
+                        field_name(&signal.name),
+                        self.read_signal(signal)
+                    )
+                    .unwrap();
+                }
+
+                writeln!(out, "            }},").unwrap();
+            }
+
+            writeln!(out, "            _ => return None,").unwrap();
+            writeln!(out, "        }};").unwrap();
+            writeln!(out).unwrap();
+        }
+
+        writeln!(out, "        Some(Self {{").unwrap();
+
+        for signal in self.message.signals.iter() {
+            match signal.multiplexing {
+                Multiplexing::None => writeln!(
+                    out,
+                    "            {}: {},",
+                    field_name(&signal.name),
+                    self.read_signal(signal)
+                )
+                .unwrap(),
+                Multiplexing::Multiplexor => {
+                    writeln!(out, "            {},", field_name(&signal.name)).unwrap()
+                }
+                Multiplexing::Multiplexed(_) => (),
+            }
+        }
+
+        writeln!(out, "        }})").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+
+    fn write_value_enum(&self, out: &mut String, signal: &Signal) {
+        let enum_name = self.value_enum_name(signal);
+        let raw_type = integer_type(signal);
+        let variants = value_variants(signal);
+
+        writeln!(out).unwrap();
+        writeln!(out, "#[derive(Debug, Clone, Copy, PartialEq, Eq)]").unwrap();
+        writeln!(out, "pub enum {} {{", enum_name).unwrap();
+
+        for (_, variant, text) in variants.iter() {
+            writeln!(out, "    /// {}", text).unwrap();
+            writeln!(out, "    {},", variant).unwrap();
+        }
+
+        writeln!(out, "    /// A value without a description").unwrap();
+        writeln!(out, "    Other({}),", raw_type).unwrap();
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "impl {} {{", enum_name).unwrap();
+        writeln!(out, "    pub fn from_raw(raw: {}) -> Self {{", raw_type).unwrap();
+        writeln!(out, "        match raw {{").unwrap();
+
+        for (value, variant, _) in variants.iter() {
+            writeln!(out, "            {} => Self::{},", value, variant).unwrap();
+        }
+
+        writeln!(out, "            _ => Self::Other(raw),").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "    pub fn to_raw(self) -> {} {{", raw_type).unwrap();
+        writeln!(out, "        match self {{").unwrap();
+
+        for (value, variant, _) in variants.iter() {
+            writeln!(out, "            Self::{} => {},", variant, value).unwrap();
+        }
+
+        writeln!(out, "            Self::Other(raw) => raw,").unwrap();
+        writeln!(out, "        }}").unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+
+    fn write_multiplexor_enum(&self, out: &mut String, multiplexor: &Signal) {
+        writeln!(out).unwrap();
+        write_doc(out, "", multiplexor.comment.as_deref(), "");
+        writeln!(out, "#[derive(Debug, Clone, Copy, PartialEq)]").unwrap();
+        writeln!(out, "pub enum {} {{", self.multiplexor_type(multiplexor)).unwrap();
+
+        for (value, signals) in self.multiplexed_signals() {
+            writeln!(out, "    M{} {{", value).unwrap();
+
+            for signal in signals {
+                self.write_field(out, signal, "        ", "");
+            }
+
+            writeln!(out, "    }},").unwrap();
+        }
+
+        writeln!(out, "}}").unwrap();
+    }
+
+    fn value_enum_name(&self, signal: &Signal) -> String {
+        format!("{}{}", self.name, type_name(&signal.name))
+    }
+
+    fn field_type(&self, signal: &Signal) -> String {
+        match signal.value_type {
+            ValueType::Float32 if signal.is_unscaled() => "f32".to_string(),
+            ValueType::Float32 | ValueType::Float64 => "f64".to_string(),
+            _ if !signal.values.is_empty() => self.value_enum_name(signal),
+            _ if !signal.is_unscaled() => "f64".to_string(),
+            ValueType::Unsigned if signal.len == 1 => "bool".to_string(),
+            _ => integer_type(signal).to_string(),
+        }
+    }
+
+    fn read_raw(&self, signal: &Signal) -> String {
+        format!(
+            "{}::signal::read_raw(data, {}, {}, {}::signal::ByteOrder::{:?})",
+            self.krate, signal.start_bit, signal.len, self.krate, signal.byte_order
+        )
+    }
+
+    fn write_raw(&self, signal: &Signal, raw: &str) -> String {
+        format!(
+            "{}::signal::write_raw(&mut data, {}, {}, {}::signal::ByteOrder::{:?}, {})",
+            self.krate, signal.start_bit, signal.len, self.krate, signal.byte_order, raw
+        )
+    }
+
+    // An expression for a signal's field value, from `data`
+    fn read_signal(&self, signal: &Signal) -> String {
+        let krate = self.krate;
+        let raw = self.read_raw(signal);
+        let signed = signal.value_type == ValueType::Signed;
+
+        match signal.value_type {
+            ValueType::Float32 if signal.is_unscaled() => format!("f32::from_bits({} as u32)", raw),
+            ValueType::Float32 => format!(
+                "f32::from_bits({} as u32) as f64 * {:?} + {:?}",
+                raw, signal.factor, signal.offset
+            ),
+            ValueType::Float64 if signal.is_unscaled() => format!("f64::from_bits({})", raw),
+            ValueType::Float64 => format!(
+                "f64::from_bits({}) * {:?} + {:?}",
+                raw, signal.factor, signal.offset
+            ),
+            _ if !signal.values.is_empty() => {
+                format!(
+                    "{}::from_raw({})",
+                    self.value_enum_name(signal),
+                    self.read_integer(signal)
+                )
+            }
+            _ if !signal.is_unscaled() => format!(
+                "{}::signal::raw_to_physical({}, {:?}, {:?}, {}, {})",
+                krate, raw, signal.factor, signal.offset, signal.len, signed
+            ),
+            ValueType::Unsigned if signal.len == 1 => format!("{} != 0", raw),
+            _ => self.read_integer(signal),
+        }
+    }
+
+    fn read_integer(&self, signal: &Signal) -> String {
+        let raw = self.read_raw(signal);
+        let integer_type = integer_type(signal);
+
+        match integer_type {
+            "u64" => raw,
+            "i64" => format!(
+                "{}::signal::sign_extend({}, {})",
+                self.krate, raw, signal.len
+            ),
+            _ if signal.value_type == ValueType::Signed => format!(
+                "{}::signal::sign_extend({}, {}) as {}",
+                self.krate, raw, signal.len, integer_type
+            ),
+            _ => format!("{} as {}", raw, integer_type),
+        }
+    }
+
+    // A statement writing a signal's field value into `data`
+    fn write_signal(&self, signal: &Signal, value: &str) -> String {
+        let krate = self.krate;
+        let signed = signal.value_type == ValueType::Signed;
+
+        let raw = match signal.value_type {
+            ValueType::Float32 if signal.is_unscaled() => format!("{}.to_bits() as u64", value),
+            ValueType::Float32 => format!(
+                "((({} - {:?}) / {:?}) as f32).to_bits() as u64",
+                value, signal.offset, signal.factor
+            ),
+            ValueType::Float64 if signal.is_unscaled() => format!("{}.to_bits()", value),
+            ValueType::Float64 => format!(
+                "(({} - {:?}) / {:?}).to_bits()",
+                value, signal.offset, signal.factor
+            ),
+            _ if !signal.values.is_empty() => {
+                self.write_integer(signal, &format!("{}.to_raw()", value))
+            }
+            _ if !signal.is_unscaled() => format!(
+                "{}::signal::physical_to_raw({}, {:?}, {:?}, {}, {})",
+                krate, value, signal.factor, signal.offset, signal.len, signed
+            ),
+            ValueType::Unsigned if signal.len == 1 => format!("{} as u64", value),
+            _ => self.write_integer(signal, value),
+        };
+
+        self.write_raw(signal, &raw)
+    }
+
+    fn write_integer(&self, signal: &Signal, value: &str) -> String {
+        let (function, cast) = match integer_type(signal) {
+            "u64" => ("unsigned_to_raw", ""),
+            "i64" => ("signed_to_raw", ""),
+            _ if signal.value_type == ValueType::Signed => ("signed_to_raw", " as i64"),
+            _ => ("unsigned_to_raw", " as u64"),
+        };
+
+        format!(
+            "{}::signal::{}({}{}, {})",
+            self.krate, function, value, cast, signal.len
+        )
+    }
+}
+
+// The smallest integer type that holds a signal's raw value
+fn integer_type(signal: &Signal) -> &'static str {
+    let signed = signal.value_type == ValueType::Signed;
+
+    match (signal.len, signed) {
+        (0..=8, false) => "u8",
+        (9..=16, false) => "u16",
+        (17..=32, false) => "u32",
+        (_, false) => "u64",
+        (0..=8, true) => "i8",
+        (9..=16, true) => "i16",
+        (17..=32, true) => "i32",
+        (_, true) => "i64",
+    }
+}
+
+// (raw value, variant name, description) for the value descriptions that fit in the signal
+fn value_variants(signal: &Signal) -> Vec<(i64, String, String)> {
+    let signed = signal.value_type == ValueType::Signed;
+    let (min, max) = match (signal.len, signed) {
+        (64, false) => (0, i64::MAX),
+        (len, false) => (0, (1_i64 << len) - 1),
+        (64, true) => (i64::MIN, i64::MAX),
+        (len, true) => (-(1_i64 << (len - 1)), (1_i64 << (len - 1)) - 1),
+    };
+    let mut variants: Vec<(i64, String, String)> = Vec::new();
+
+    for (value, text) in signal.values.iter() {
+        if *value < min || *value > max || variants.iter().any(|(other, _, _)| other == value) {
+            continue;
+        }
+
+        let mut name = type_name(text);
+
+        // Descriptions don't have to be unique, or make a usable name
+        if name == "Other" || variants.iter().any(|(_, other, _)| *other == name) {
+            name = format!("{}{}", name, value.unsigned_abs());
+        }
+
+        variants.push((*value, name, text.clone()));
+    }
+
+    variants
+}
+
+fn write_doc(out: &mut String, indent: &str, comment: Option<&str>, suffix: &str) {
+    let text = format!("{}{}", comment.unwrap_or(""), suffix);
+    let text = text.trim();
+
+    for line in text.lines() {
+        writeln!(out, "{}/// {}", indent, line.trim()).unwrap();
+    }
+}
+
+// Splits a DBC name into words, on underscores, anything not alphanumeric & case changes
+fn words(name: &str) -> Vec<String> {
+    let chars: Vec<char> = name.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+
+    for (index, &c) in chars.iter().enumerate() {
+        if !c.is_ascii_alphanumeric() {
+            if !word.is_empty() {
+                words.push(word.clone());
+                word.clear();
+            }
+
+            continue;
+        }
+
+        let previous = if index > 0 { chars[index - 1] } else { ' ' };
+        let next = chars.get(index + 1).copied().unwrap_or(' ');
+
+        // engineSpeed, ABSActive
+        let boundary = c.is_ascii_uppercase()
+            && (previous.is_ascii_lowercase()
+                || previous.is_ascii_digit()
+                || (previous.is_ascii_uppercase() && next.is_ascii_lowercase()));
+
+        if boundary && !word.is_empty() {
+            words.push(word.clone());
+            word.clear();
+        }
+
+        word.push(c);
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+fn field_name(name: &str) -> String {
+    let words: Vec<String> = words(name).iter().map(|word| word.to_lowercase()).collect();
+    let mut name = words.join("_");
+
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+
+    // These can't be raw identifiers
+    if ["crate", "self", "super"].contains(&name.as_str()) {
+        name.push('_');
+    } else if KEYWORDS.contains(&name.as_str()) {
+        name.insert_str(0, "r#");
+    }
+
+    name
+}
+
+fn type_name(name: &str) -> String {
+    let mut name: String = words(name)
+        .iter()
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            first.to_string() + &chars.as_str().to_lowercase()
+        })
+        .collect();
+
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, 'V');
+    }
+
+    if name == "Self" {
+        name.push('_');
+    }
+
+    name
+}
@@ -0,0 +1,117 @@
+//! Generates typed message structs for `teensy4-canfd` from DBC files, meant to be run from a
+//! build script
+//!
+//! Each message becomes a struct with its signals as fields, an `encode` giving the payload for a
+//! `TxFDFrame` and a `decode` taking an `RxFDFrame`. The generated code only depends on
+//! `teensy4_canfd` (its `signal` module does the bit packing), so it builds for the Teensy.
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("catalog.rs");
+//!     teensy4_canfd_dbc::generate_file("catalog.dbc", out).unwrap();
+//!     println!("cargo:rerun-if-changed=catalog.dbc");
+//! }
+//!
+//! // main.rs
+//! mod catalog {
+//!     include!(concat!(env!("OUT_DIR"), "/catalog.rs"));
+//! }
+//!
+//! let data = catalog::EngineData { engine_speed: 2500.0, ... }.encode();
+//! can.transfer_nb(&TxFDFrame {
+//!     id: catalog::EngineData::ID,
+//!     buffer: &data,
+//!     priority: None,
+//!     format: FrameFormat::Classic, // Messages over 8 bytes need `FrameFormat::Fd`
+//! });
+//! ```
+//!
+//! Only one multiplexor per message is supported, extended multiplexing (`SG_MUL_VAL_`) isn't.
+
+mod codegen;
+pub mod parse;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+    Layout(String),      // A signal doesn't fit its message
+    Unsupported(String), // Valid DBC, but not something we generate code for
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            Error::Layout(message) => write!(f, "{}", message),
+            Error::Unsupported(message) => write!(f, "unsupported: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Generator {
+    pub crate_path: String, // How the generated code names the driver crate
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self {
+            crate_path: "teensy4_canfd".to_string(),
+        }
+    }
+}
+
+impl Generator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The Rust source for a DBC file's contents
+    pub fn generate(&self, dbc: &str) -> Result<String, Error> {
+        self.generate_named(dbc, "a DBC file")
+    }
+
+    pub fn generate_file<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        input: P,
+        output: Q,
+    ) -> Result<(), Error> {
+        let input = input.as_ref();
+        let dbc = fs::read(input)?;
+
+        // DBC files are usually Windows-1252, keep whatever isn't UTF-8 out of the way
+        let dbc = String::from_utf8_lossy(&dbc);
+        let name = input.file_name().unwrap_or_default().to_string_lossy();
+        let source = self.generate_named(&dbc, &name)?;
+
+        fs::write(output, source)?;
+
+        Ok(())
+    }
+
+    fn generate_named(&self, dbc: &str, name: &str) -> Result<String, Error> {
+        let dbc = parse::parse(dbc)?;
+        codegen::generate(&dbc, &self.crate_path, name)
+    }
+}
+
+/// Generates the code for `input` into `output` with the default settings
+pub fn generate_file<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Result<(), Error> {
+    Generator::new().generate_file(input, output)
+}
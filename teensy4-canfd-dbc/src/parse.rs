@@ -0,0 +1,711 @@
+//! A parser for the parts of a DBC file that describe the payloads
+//!
+//! Messages (`BO_`), their signals (`SG_`), value descriptions (`VAL_`), comments (`CM_`) and
+//! float signal types (`SIG_VALTYPE_`) are read, every other statement is skipped.
+
+use crate::Error;
+
+/// Marks an extended ID in `BO_`
+const EXTENDED_ID_FLAG: u32 = 0x8000_0000;
+
+/// Holds the signals no message claims, not a real message
+const INDEPENDENT_SIGNALS_MESSAGE: &str = "VECTOR__INDEPENDENT_SIG_MSG";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dbc {
+    pub messages: Vec<Message>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub id: u32,
+    pub extended: bool,
+    pub name: String,
+    pub len: usize,
+    pub comment: Option<String>,
+    pub signals: Vec<Signal>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    LittleEndian, // Intel, `@1`
+    BigEndian,    // Motorola, `@0`
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Unsigned,
+    Signed,
+    Float32,
+    Float64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Multiplexing {
+    None,
+    Multiplexor,      // `M`, selects which of the multiplexed signals are in the frame
+    Multiplexed(u64), // `m<value>`, only there when the multiplexor has this raw value
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signal {
+    pub name: String,
+    pub multiplexing: Multiplexing,
+    pub start_bit: u16,
+    pub len: u8,
+    pub byte_order: ByteOrder,
+    pub value_type: ValueType,
+    pub factor: f64,
+    pub offset: f64,
+    pub min: f64,
+    pub max: f64,
+    pub unit: String,
+    pub comment: Option<String>,
+    pub values: Vec<(i64, String)>, // Value descriptions, raw value & text
+}
+
+impl Signal {
+    /// The raw value & the physical one are the same
+    pub fn is_unscaled(&self) -> bool {
+        self.factor == 1.0 && self.offset == 0.0
+    }
+}
+
+pub fn parse(text: &str) -> Result<Dbc, Error> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+        line: 1,
+    };
+    let mut messages: Vec<Message> = Vec::new();
+
+    loop {
+        parser.skip_whitespace();
+
+        if parser.at_end() {
+            break;
+        }
+
+        let line = parser.line;
+        let keyword = parser.identifier()?;
+
+        match keyword.as_str() {
+            "BO_" => messages.push(parser.message()?),
+            "SG_" => {
+                let signal = parser.signal()?;
+
+                match messages.last_mut() {
+                    Some(message) => message.signals.push(signal),
+                    None => return Err(parser.error_at(line, "SG_ outside of a message")),
+                }
+            }
+            "VAL_" => parser.value_descriptions(&mut messages)?,
+            "CM_" => parser.comment(&mut messages)?,
+            "SIG_VALTYPE_" => parser.signal_value_type(&mut messages)?,
+            // The new symbols section is an indented list of keywords
+            "NS_" => parser.skip_indented_block(),
+            "VERSION" | "BS_" | "BU_" => parser.skip_line(),
+            _ => parser.skip_statement(),
+        }
+    }
+
+    messages.retain(|message| message.name != INDEPENDENT_SIGNALS_MESSAGE);
+
+    Ok(Dbc { messages })
+}
+
+fn find_signal<'a>(messages: &'a mut [Message], id: u32, name: &str) -> Option<&'a mut Signal> {
+    messages
+        .iter_mut()
+        .find(|message| message.raw_id() == id)?
+        .signals
+        .iter_mut()
+        .find(|signal| signal.name == name)
+}
+
+impl Message {
+    // The ID as written in the DBC, with the extended flag
+    fn raw_id(&self) -> u32 {
+        if self.extended {
+            self.id | EXTENDED_ID_FLAG
+        } else {
+            self.id
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.position >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+
+        if c == '\n' {
+            self.line += 1;
+        }
+
+        Some(c)
+    }
+
+    fn error(&self, message: &str) -> Error {
+        self.error_at(self.line, message)
+    }
+
+    fn error_at(&self, line: usize, message: &str) -> Error {
+        Error::Parse {
+            line,
+            message: message.to_string(),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), Error> {
+        self.skip_whitespace();
+
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(self.error(&format!("expected '{}'", expected))),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, Error> {
+        self.skip_whitespace();
+
+        let start = self.position;
+
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            self.next();
+        }
+
+        if self.position == start {
+            return Err(self.error("expected an identifier"));
+        }
+
+        Ok(self.chars[start..self.position].iter().collect())
+    }
+
+    fn number_text(&mut self) -> Result<String, Error> {
+        self.skip_whitespace();
+
+        let start = self.position;
+
+        while self.peek().is_some_and(|c| {
+            c.is_ascii_digit() || c == '.' || c == '-' || c == '+' || c == 'e' || c == 'E'
+        }) {
+            self.next();
+        }
+
+        if self.position == start {
+            return Err(self.error("expected a number"));
+        }
+
+        Ok(self.chars[start..self.position].iter().collect())
+    }
+
+    fn integer(&mut self) -> Result<i64, Error> {
+        let text = self.number_text()?;
+        text.parse().map_err(|_| self.error("expected an integer"))
+    }
+
+    fn unsigned(&mut self) -> Result<u32, Error> {
+        let text = self.number_text()?;
+        text.parse()
+            .map_err(|_| self.error("expected an unsigned integer"))
+    }
+
+    fn float(&mut self) -> Result<f64, Error> {
+        let text = self.number_text()?;
+        text.parse().map_err(|_| self.error("expected a number"))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.expect('"')?;
+
+        let mut string = String::new();
+
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    if let Some(c) = self.next() {
+                        string.push(c);
+                    }
+                }
+                Some(c) => string.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    // Skips to the end of the line, a string can carry on past it
+    fn skip_line(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '\n' => return,
+                '"' => {
+                    let _ = self.string();
+                }
+                _ => {
+                    self.next();
+                }
+            }
+        }
+    }
+
+    // Skips up to & including the next ';' outside of a string
+    fn skip_statement(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                ';' => {
+                    self.next();
+                    return;
+                }
+                '"' => {
+                    let _ = self.string();
+                }
+                _ => {
+                    self.next();
+                }
+            }
+        }
+    }
+
+    // Skips the rest of the line & the lines after it that are blank or indented
+    fn skip_indented_block(&mut self) {
+        self.skip_line();
+
+        while self.next() == Some('\n') {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => self.skip_line(),
+                _ => return,
+            }
+        }
+    }
+
+    // BO_ <id> <name>: <length> <transmitter>
+    fn message(&mut self) -> Result<Message, Error> {
+        let raw_id = self.unsigned()?;
+        let name = self.identifier()?;
+        self.expect(':')?;
+        let len = self.unsigned()? as usize;
+        self.skip_line();
+
+        if !matches!(len, 0..=8 | 12 | 16 | 20 | 24 | 32 | 48 | 64) {
+            return Err(self.error(&format!("{} isn't a valid CAN FD frame length", len)));
+        }
+
+        Ok(Message {
+            id: raw_id & !EXTENDED_ID_FLAG,
+            extended: raw_id & EXTENDED_ID_FLAG != 0,
+            name,
+            len,
+            comment: None,
+            signals: Vec::new(),
+        })
+    }
+
+    // SG_ <name> [M|m<value>] : <start>|<length>@<order><sign> (<factor>,<offset>)
+    //     [<min>|<max>] "<unit>" <receivers>
+    fn signal(&mut self) -> Result<Signal, Error> {
+        let name = self.identifier()?;
+
+        self.skip_whitespace();
+
+        let multiplexing = if self.peek() == Some(':') {
+            Multiplexing::None
+        } else {
+            let indicator = self.identifier()?;
+
+            if indicator == "M" {
+                Multiplexing::Multiplexor
+            } else if let Some(value) = indicator.strip_prefix('m') {
+                match value.parse() {
+                    Ok(value) => Multiplexing::Multiplexed(value),
+                    Err(_) => {
+                        // m<value>M, a multiplexed multiplexor
+                        return Err(Error::Unsupported(format!(
+                            "extended multiplexing of signal {}",
+                            name
+                        )));
+                    }
+                }
+            } else {
+                return Err(self.error("expected a multiplexer indicator"));
+            }
+        };
+
+        self.expect(':')?;
+        let start_bit = self.unsigned()?;
+        self.expect('|')?;
+        let len = self.unsigned()?;
+        self.expect('@')?;
+
+        let byte_order = match self.next() {
+            Some('0') => ByteOrder::BigEndian,
+            Some('1') => ByteOrder::LittleEndian,
+            _ => return Err(self.error("expected a byte order")),
+        };
+
+        let value_type = match self.next() {
+            Some('+') => ValueType::Unsigned,
+            Some('-') => ValueType::Signed,
+            _ => return Err(self.error("expected a value type")),
+        };
+
+        self.expect('(')?;
+        let factor = self.float()?;
+        self.expect(',')?;
+        let offset = self.float()?;
+        self.expect(')')?;
+        self.expect('[')?;
+        let min = self.float()?;
+        self.expect('|')?;
+        let max = self.float()?;
+        self.expect(']')?;
+        let unit = self.string()?;
+        self.skip_line();
+
+        if len == 0 || len > 64 || start_bit >= 512 {
+            return Err(self.error(&format!("signal {} is out of range", name)));
+        }
+
+        if factor == 0.0 {
+            return Err(self.error(&format!("signal {} has a factor of 0", name)));
+        }
+
+        Ok(Signal {
+            name,
+            multiplexing,
+            start_bit: start_bit as u16,
+            len: len as u8,
+            byte_order,
+            value_type,
+            factor,
+            offset,
+            min,
+            max,
+            unit,
+            comment: None,
+            values: Vec::new(),
+        })
+    }
+
+    // VAL_ <message id> <signal> <value> "<text>" ... ;
+    // Environment variables have value descriptions too, those get skipped.
+    fn value_descriptions(&mut self, messages: &mut [Message]) -> Result<(), Error> {
+        self.skip_whitespace();
+
+        if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.skip_statement();
+            return Ok(());
+        }
+
+        let id = self.unsigned()?;
+        let name = self.identifier()?;
+        let mut values = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+
+            if self.peek() == Some(';') {
+                self.next();
+                break;
+            }
+
+            let value = self.integer()?;
+            let text = self.string()?;
+            values.push((value, text));
+        }
+
+        if let Some(signal) = find_signal(messages, id, &name) {
+            signal.values = values;
+        }
+
+        Ok(())
+    }
+
+    // CM_ [BU_ <node> | BO_ <id> | SG_ <id> <signal> | EV_ <variable>] "<text>" ;
+    fn comment(&mut self, messages: &mut [Message]) -> Result<(), Error> {
+        self.skip_whitespace();
+
+        if self.peek() == Some('"') {
+            self.skip_statement();
+            return Ok(());
+        }
+
+        match self.identifier()?.as_str() {
+            "BO_" => {
+                let id = self.unsigned()?;
+                let text = self.string()?;
+
+                if let Some(message) = messages.iter_mut().find(|message| message.raw_id() == id) {
+                    message.comment = Some(text);
+                }
+            }
+            "SG_" => {
+                let id = self.unsigned()?;
+                let name = self.identifier()?;
+                let text = self.string()?;
+
+                if let Some(signal) = find_signal(messages, id, &name) {
+                    signal.comment = Some(text);
+                }
+            }
+            _ => (),
+        }
+
+        self.skip_statement();
+
+        Ok(())
+    }
+
+    // SIG_VALTYPE_ <message id> <signal> : <0 integer | 1 float | 2 double> ;
+    fn signal_value_type(&mut self, messages: &mut [Message]) -> Result<(), Error> {
+        let id = self.unsigned()?;
+        let name = self.identifier()?;
+        self.expect(':')?;
+
+        let value_type = match self.unsigned()? {
+            1 => Some(ValueType::Float32),
+            2 => Some(ValueType::Float64),
+            _ => None,
+        };
+
+        self.skip_statement();
+
+        if let (Some(signal), Some(value_type)) = (find_signal(messages, id, &name), value_type) {
+            signal.value_type = value_type;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DBC: &str = r#"VERSION "1.0"
+
+NS_ :
+    CM_
+    VAL_
+
+BS_:
+
+BU_: Engine Dash
+
+BO_ 291 EngineData: 8 Engine
+ SG_ EngineSpeed : 0|16@1+ (0.25,0) [0|16383.75] "rpm" Dash
+ SG_ Temperature : 16|8@1- (1,-40) [-40|87] "degC" Dash
+ SG_ Gear : 31|4@0+ (1,0) [0|15] "" Dash
+
+BO_ 2566844926 Ambient: 8 Dash
+ SG_ Mode M : 0|2@1+ (1,0) [0|3] "" Engine
+ SG_ Pressure m0 : 8|16@1+ (0.1,0) [0|6553.5] "kPa" Engine
+ SG_ Humidity m1 : 8|8@1+ (1,0) [0|100] "%" Engine
+
+BO_ 3221225472 VECTOR__INDEPENDENT_SIG_MSG: 0 Vector__XXX
+ SG_ Orphan : 0|8@1+ (1,0) [0|0] "" Vector__XXX
+
+CM_ "The whole network";
+CM_ BU_ Engine "The engine ECU";
+CM_ BO_ 291 "Sent every 10 ms";
+CM_ SG_ 291 EngineSpeed "Crankshaft
+speed";
+CM_ SG_ 2566844926 Pressure "With \"quotes\"";
+VAL_ 291 Gear 0 "Neutral" 1 "First" 15 "Invalid" ;
+VAL_ 2566844926 Mode 0 "Pressure" 1 "Humidity" ;
+VAL_ EngineRunning 0 "Off" 1 "On" ;
+VAL_TABLE_ Switch 0 "Off" 1 "On" ;
+SIG_VALTYPE_ 291 EngineSpeed : 0;
+"#;
+
+    fn signal<'a>(dbc: &'a Dbc, message: &str, name: &str) -> &'a Signal {
+        dbc.messages
+            .iter()
+            .find(|m| m.name == message)
+            .unwrap()
+            .signals
+            .iter()
+            .find(|s| s.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn messages_and_signals() {
+        let dbc = parse(DBC).unwrap();
+
+        // The independent signals message is dropped
+        assert_eq!(dbc.messages.len(), 2);
+
+        let engine = &dbc.messages[0];
+        assert_eq!(engine.id, 291);
+        assert!(!engine.extended);
+        assert_eq!(engine.len, 8);
+        assert_eq!(engine.signals.len(), 3);
+
+        assert_eq!(
+            *signal(&dbc, "EngineData", "EngineSpeed"),
+            Signal {
+                name: "EngineSpeed".to_string(),
+                multiplexing: Multiplexing::None,
+                start_bit: 0,
+                len: 16,
+                byte_order: ByteOrder::LittleEndian,
+                value_type: ValueType::Unsigned,
+                factor: 0.25,
+                offset: 0.0,
+                min: 0.0,
+                max: 16383.75,
+                unit: "rpm".to_string(),
+                comment: Some("Crankshaft\nspeed".to_string()),
+                values: vec![],
+            }
+        );
+
+        let temperature = signal(&dbc, "EngineData", "Temperature");
+        assert_eq!(temperature.value_type, ValueType::Signed);
+        assert_eq!(temperature.offset, -40.0);
+        assert_eq!(temperature.min, -40.0);
+
+        let gear = signal(&dbc, "EngineData", "Gear");
+        assert_eq!(gear.start_bit, 31);
+        assert_eq!(gear.byte_order, ByteOrder::BigEndian);
+        assert!(gear.is_unscaled());
+    }
+
+    #[test]
+    fn extended_ids() {
+        let dbc = parse(DBC).unwrap();
+        let ambient = &dbc.messages[1];
+
+        assert_eq!(ambient.id, 0x18FE_F1FE);
+        assert!(ambient.extended);
+
+        // VAL_ & CM_ refer to the message with the flag still set
+        assert_eq!(
+            signal(&dbc, "Ambient", "Mode").values,
+            [(0, "Pressure".to_string()), (1, "Humidity".to_string())]
+        );
+        assert_eq!(
+            signal(&dbc, "Ambient", "Pressure").comment.as_deref(),
+            Some("With \"quotes\"")
+        );
+    }
+
+    #[test]
+    fn multiplexing() {
+        let dbc = parse(DBC).unwrap();
+
+        assert_eq!(
+            signal(&dbc, "Ambient", "Mode").multiplexing,
+            Multiplexing::Multiplexor
+        );
+        assert_eq!(
+            signal(&dbc, "Ambient", "Pressure").multiplexing,
+            Multiplexing::Multiplexed(0)
+        );
+        assert_eq!(
+            signal(&dbc, "Ambient", "Humidity").multiplexing,
+            Multiplexing::Multiplexed(1)
+        );
+
+        let extended = "BO_ 1 Nested: 8 Node\n SG_ Sub m0M : 8|8@1+ (1,0) [0|0] \"\" Node\n";
+        assert!(matches!(parse(extended), Err(Error::Unsupported(_))));
+
+        let invalid = "BO_ 1 Nested: 8 Node\n SG_ Sub x0 : 8|8@1+ (1,0) [0|0] \"\" Node\n";
+        assert!(matches!(parse(invalid), Err(Error::Parse { line: 2, .. })));
+    }
+
+    #[test]
+    fn value_descriptions_and_comments() {
+        let dbc = parse(DBC).unwrap();
+
+        assert_eq!(
+            signal(&dbc, "EngineData", "Gear").values,
+            [
+                (0, "Neutral".to_string()),
+                (1, "First".to_string()),
+                (15, "Invalid".to_string()),
+            ]
+        );
+        assert_eq!(dbc.messages[0].comment.as_deref(), Some("Sent every 10 ms"));
+        assert_eq!(dbc.messages[1].comment, None);
+        assert_eq!(signal(&dbc, "EngineData", "Temperature").comment, None);
+
+        // Descriptions & comments for things that don't exist are ignored
+        let dangling = "BO_ 1 M: 8 Node\nVAL_ 2 S 0 \"Zero\" ;\nCM_ SG_ 1 S \"Missing\";\n";
+        assert!(parse(dangling).unwrap().messages[0].signals.is_empty());
+    }
+
+    #[test]
+    fn float_signals() {
+        let text = "BO_ 1 Floats: 16 Node
+ SG_ Single : 0|32@1- (1,0) [0|0] \"\" Node
+ SG_ Double : 64|64@1- (1,0) [0|0] \"\" Node
+SIG_VALTYPE_ 1 Single : 1;
+SIG_VALTYPE_ 1 Double : 2;
+";
+        let dbc = parse(text).unwrap();
+
+        assert_eq!(dbc.messages[0].len, 16);
+        assert_eq!(
+            signal(&dbc, "Floats", "Single").value_type,
+            ValueType::Float32
+        );
+        assert_eq!(
+            signal(&dbc, "Floats", "Double").value_type,
+            ValueType::Float64
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("BO_ 1 M: 9 Node\n", 1),
+            ("\n SG_ S : 0|8@1+ (1,0) [0|0] \"\" Node\n", 2),
+            (
+                "BO_ 1 M: 8 Node\n SG_ S : 0|0@1+ (1,0) [0|0] \"\" Node\n",
+                2,
+            ),
+            (
+                "BO_ 1 M: 8 Node\n SG_ S : 0|8@1+ (0,0) [0|0] \"\" Node\n",
+                2,
+            ),
+            (
+                "BO_ 1 M: 8 Node\n SG_ S : 0|8@2+ (1,0) [0|0] \"\" Node\n",
+                2,
+            ),
+            ("BO_ 1 M: 8 Node\n\n SG_ S : 0|8@1+ (1,0) [0|0] \"unit\n", 4),
+        ];
+
+        for (text, line) in cases.iter() {
+            match parse(text) {
+                Err(Error::Parse { line: at, .. }) => assert_eq!(at, *line, "{:?}", text),
+                other => panic!("{:?} parsed to {:?}", text, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Compares the generated code for the DBC files in `snapshots/` with the `.rs` next to them.
+//! Run with `UPDATE_SNAPSHOTS=1` to rewrite the `.rs` files after an intended change.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use teensy4_canfd_dbc::{Error, Generator};
+
+fn check(name: &str) {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots");
+    let dbc = fs::read_to_string(directory.join(format!("{}.dbc", name))).unwrap();
+    let snapshot = directory.join(format!("{}.rs", name));
+
+    let generated = Generator::new().generate(&dbc).unwrap();
+
+    if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::write(&snapshot, &generated).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&snapshot).unwrap();
+
+    for (line, (generated, expected)) in generated.lines().zip(expected.lines()).enumerate() {
+        assert_eq!(generated, expected, "{}.rs:{}", name, line + 1);
+    }
+
+    assert_eq!(
+        generated.lines().count(),
+        expected.lines().count(),
+        "{}.rs",
+        name
+    );
+}
+
+#[test]
+fn intel_motorola() {
+    check("intel_motorola");
+}
+
+#[test]
+fn signed_scaled() {
+    check("signed_scaled");
+}
+
+#[test]
+fn multiplexed() {
+    check("multiplexed");
+}
+
+#[test]
+fn fd_64_bytes() {
+    check("fd_64_bytes");
+}
+
+#[test]
+fn crate_path() {
+    let generator = Generator {
+        crate_path: "crate".to_string(),
+    };
+    let generated = generator.generate("BO_ 1 Empty: 0 Node\n").unwrap();
+
+    assert!(
+        generated.contains("pub const ID: crate::config::Id = crate::config::Id::Standard(0x001);")
+    );
+    assert!(generated.contains("pub fn decode(frame: &crate::RxFDFrame) -> Option<Self> {"));
+}
+
+#[test]
+fn rejected_layouts() {
+    let cases = [
+        // Past the end of the message, for both byte orders
+        "BO_ 1 M: 2 Node\n SG_ S : 4|13@1+ (1,0) [0|0] \"\" Node\n",
+        "BO_ 1 M: 2 Node\n SG_ S : 3|13@0+ (1,0) [0|0] \"\" Node\n",
+        // Float signals of the wrong length
+        "BO_ 1 M: 8 Node\n SG_ S : 0|16@1- (1,0) [0|0] \"\" Node\nSIG_VALTYPE_ 1 S : 1;\n",
+    ];
+
+    for dbc in cases.iter() {
+        assert!(
+            matches!(Generator::new().generate(dbc), Err(Error::Layout(_))),
+            "{}",
+            dbc
+        );
+    }
+
+    let unsupported = [
+        "BO_ 1 M: 8 Node\n SG_ A M : 0|4@1+ (1,0) [0|0] \"\" Node\n SG_ B M : 4|4@1+ (1,0) [0|0] \"\" Node\n",
+        "BO_ 1 M: 8 Node\n SG_ A m0 : 0|4@1+ (1,0) [0|0] \"\" Node\n",
+    ];
+
+    for dbc in unsupported.iter() {
+        assert!(
+            matches!(Generator::new().generate(dbc), Err(Error::Unsupported(_))),
+            "{}",
+            dbc
+        );
+    }
+}
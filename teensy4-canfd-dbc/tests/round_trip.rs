@@ -0,0 +1,169 @@
+//! Compiles the snapshots in `snapshots/` against the driver crate & checks what they encode
+//! decodes back to the same message.
+
+use teensy4_canfd::config::Id;
+use teensy4_canfd::RxFDFrame;
+
+mod intel_motorola {
+    include!("snapshots/intel_motorola.rs");
+}
+
+mod signed_scaled {
+    include!("snapshots/signed_scaled.rs");
+}
+
+mod multiplexed {
+    include!("snapshots/multiplexed.rs");
+}
+
+mod fd_64_bytes {
+    include!("snapshots/fd_64_bytes.rs");
+}
+
+fn frame(id: Id, data: &[u8]) -> RxFDFrame {
+    let mut buffer = [0; 64];
+    buffer[..data.len()].copy_from_slice(data);
+
+    RxFDFrame {
+        id,
+        buffer_len: data.len() as u32,
+        buffer,
+        timestamp: 0,
+        error_state: false,
+        fd_frame: data.len() > 8,
+        bitrate_switch: data.len() > 8,
+        overrun: false,
+        mailbox_index: 0,
+        priority: 0,
+    }
+}
+
+fn assert_close(left: f64, right: f64) {
+    assert!((left - right).abs() < 1e-9, "{} != {}", left, right);
+}
+
+#[test]
+fn intel_motorola() {
+    use intel_motorola::Layout;
+
+    let message = Layout {
+        intel_across: 0xABC,
+        motorola_across: 0xABC,
+        flag: true,
+        motorola_word: 0x1234,
+        tail: 5,
+    };
+    let data = message.encode();
+
+    // Intel fills from the low bits of the first byte up, Motorola from the start bit down
+    assert_eq!(data[..4], [0xC0, 0xAB, 0x0A, 0xBC]);
+    assert_eq!(data[4] & 1, 1);
+    assert_eq!(data[5..7], [0x12, 0x34]);
+    assert_eq!(data[7] >> 5, 5);
+
+    assert_eq!(Layout::decode(&frame(Layout::ID, &data)), Some(message));
+    assert_eq!(Layout::decode(&frame(Id::Standard(0x101), &data)), None);
+    assert_eq!(Layout::decode(&frame(Layout::ID, &data[..7])), None);
+}
+
+#[test]
+fn signed_scaled() {
+    use signed_scaled::Scaled;
+
+    let message = Scaled {
+        temperature: -60.0,
+        speed: 12.34,
+        offset: -2048,
+        current: -150.5,
+        trim: -1,
+    };
+    let data = message.encode();
+
+    // -20 raw, & 0xFF for the trim of -1
+    assert_eq!(data[0], 0xEC);
+    assert_eq!(data[7], 0xFF);
+
+    let decoded = Scaled::decode(&frame(Id::Extended(0x200), &data)).unwrap();
+    assert_close(decoded.temperature, message.temperature);
+    assert_close(decoded.speed, message.speed);
+    assert_eq!(decoded.offset, message.offset);
+    assert_close(decoded.current, message.current);
+    assert_eq!(decoded.trim, message.trim);
+
+    // The extended ID doesn't match the standard one with the same number
+    assert_eq!(Scaled::decode(&frame(Id::Standard(0x200), &data)), None);
+}
+
+#[test]
+fn multiplexed() {
+    use multiplexed::{Muxed, MuxedPage, MuxedStatus};
+
+    let pages = [
+        Muxed {
+            counter: 3,
+            page: MuxedPage::M0 {
+                voltage: 12.5,
+                status: MuxedStatus::Fault,
+            },
+        },
+        Muxed {
+            counter: 15,
+            page: MuxedPage::M1 {
+                serial: 0xDEAD_BEEF,
+            },
+        },
+    ];
+
+    for message in pages.iter() {
+        let data = message.encode();
+        assert_eq!(Muxed::decode(&frame(Muxed::ID, &data)), Some(*message));
+    }
+
+    assert_eq!(pages[1].encode()[..5], [0x1F, 0xEF, 0xBE, 0xAD, 0xDE]);
+
+    // Unknown multiplexor values & status values
+    assert_eq!(Muxed::decode_payload(&[0x20, 0, 0, 0, 0, 0, 0, 0]), None);
+    assert!(matches!(
+        Muxed::decode_payload(&[0x00, 0, 0, 0x03, 0, 0, 0, 0]),
+        Some(Muxed {
+            page: MuxedPage::M0 {
+                status: MuxedStatus::Other(3),
+                ..
+            },
+            ..
+        })
+    ));
+}
+
+#[test]
+fn fd_64_bytes() {
+    use fd_64_bytes::Large;
+
+    let message = Large {
+        timestamp: u64::MAX - 1,
+        position: i64::MIN + 1,
+        ratio: -0.25,
+        scaled: 20.5,
+        precise: core::f64::consts::PI,
+        motorola64: 0x0102_0304_0506_0708,
+        last: 0xA5,
+    };
+    let data = message.encode();
+
+    assert_eq!(data.len(), Large::LEN);
+    assert_eq!(data[..8], (u64::MAX - 1).to_le_bytes());
+    assert_eq!(data[32..40], 0x0102_0304_0506_0708_u64.to_be_bytes());
+    assert_eq!(data[63], 0xA5);
+
+    let decoded = Large::decode(&frame(Large::ID, &data)).unwrap();
+    assert_eq!(decoded.timestamp, message.timestamp);
+    assert_eq!(decoded.position, message.position);
+    assert_eq!(decoded.ratio, message.ratio);
+    assert_close(decoded.scaled, message.scaled);
+    assert_eq!(decoded.precise, message.precise);
+    assert_eq!(decoded.motorola64, message.motorola64);
+    assert_eq!(decoded.last, message.last);
+
+    // A classic sized frame doesn't hold it
+    assert_eq!(Large::decode(&frame(Large::ID, &data[..8])), None);
+}
@@ -0,0 +1,57 @@
+// Generated by teensy4-canfd-dbc from a DBC file, don't edit
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Large {
+    /// [us]
+    pub timestamp: u64,
+    pub position: i64,
+    pub ratio: f32,
+    pub scaled: f64,
+    pub precise: f64,
+    pub motorola64: u64,
+    pub last: u8,
+}
+
+impl Large {
+    pub const ID: teensy4_canfd::config::Id = teensy4_canfd::config::Id::Standard(0x600);
+    pub const LEN: usize = 64;
+
+    /// The payload, goes into a `TxFDFrame` with `ID`
+    pub fn encode(&self) -> [u8; 64] {
+        let mut data = [0_u8; 64];
+        teensy4_canfd::signal::write_raw(&mut data, 0, 64, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::unsigned_to_raw(self.timestamp, 64));
+        teensy4_canfd::signal::write_raw(&mut data, 64, 64, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::signed_to_raw(self.position, 64));
+        teensy4_canfd::signal::write_raw(&mut data, 128, 32, teensy4_canfd::signal::ByteOrder::LittleEndian, self.ratio.to_bits() as u64);
+        teensy4_canfd::signal::write_raw(&mut data, 160, 32, teensy4_canfd::signal::ByteOrder::LittleEndian, (((self.scaled - 10.0) / 0.5) as f32).to_bits() as u64);
+        teensy4_canfd::signal::write_raw(&mut data, 192, 64, teensy4_canfd::signal::ByteOrder::LittleEndian, self.precise.to_bits());
+        teensy4_canfd::signal::write_raw(&mut data, 263, 64, teensy4_canfd::signal::ByteOrder::BigEndian, teensy4_canfd::signal::unsigned_to_raw(self.motorola64, 64));
+        teensy4_canfd::signal::write_raw(&mut data, 504, 8, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::unsigned_to_raw(self.last as u64, 8));
+        data
+    }
+
+    /// None if the frame has another ID or is too short
+    pub fn decode(frame: &teensy4_canfd::RxFDFrame) -> Option<Self> {
+        if frame.id != Self::ID {
+            return None;
+        }
+
+        Self::decode_payload(&frame.buffer[..(frame.buffer_len as usize).min(64)])
+    }
+
+    /// None if the payload is too short or has an unknown multiplexor value
+    pub fn decode_payload(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::LEN {
+            return None;
+        }
+
+        Some(Self {
+            timestamp: teensy4_canfd::signal::read_raw(data, 0, 64, teensy4_canfd::signal::ByteOrder::LittleEndian),
+            position: teensy4_canfd::signal::sign_extend(teensy4_canfd::signal::read_raw(data, 64, 64, teensy4_canfd::signal::ByteOrder::LittleEndian), 64),
+            ratio: f32::from_bits(teensy4_canfd::signal::read_raw(data, 128, 32, teensy4_canfd::signal::ByteOrder::LittleEndian) as u32),
+            scaled: f32::from_bits(teensy4_canfd::signal::read_raw(data, 160, 32, teensy4_canfd::signal::ByteOrder::LittleEndian) as u32) as f64 * 0.5 + 10.0,
+            precise: f64::from_bits(teensy4_canfd::signal::read_raw(data, 192, 64, teensy4_canfd::signal::ByteOrder::LittleEndian)),
+            motorola64: teensy4_canfd::signal::read_raw(data, 263, 64, teensy4_canfd::signal::ByteOrder::BigEndian),
+            last: teensy4_canfd::signal::read_raw(data, 504, 8, teensy4_canfd::signal::ByteOrder::LittleEndian) as u8,
+        })
+    }
+}
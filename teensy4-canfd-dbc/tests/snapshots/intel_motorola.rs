@@ -0,0 +1,50 @@
+// Generated by teensy4-canfd-dbc from a DBC file, don't edit
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub intel_across: u16,
+    pub motorola_across: u16,
+    pub flag: bool,
+    pub motorola_word: u16,
+    pub tail: u8,
+}
+
+impl Layout {
+    pub const ID: teensy4_canfd::config::Id = teensy4_canfd::config::Id::Standard(0x100);
+    pub const LEN: usize = 8;
+
+    /// The payload, goes into a `TxFDFrame` with `ID`
+    pub fn encode(&self) -> [u8; 8] {
+        let mut data = [0_u8; 8];
+        teensy4_canfd::signal::write_raw(&mut data, 4, 12, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::unsigned_to_raw(self.intel_across as u64, 12));
+        teensy4_canfd::signal::write_raw(&mut data, 19, 12, teensy4_canfd::signal::ByteOrder::BigEndian, teensy4_canfd::signal::unsigned_to_raw(self.motorola_across as u64, 12));
+        teensy4_canfd::signal::write_raw(&mut data, 32, 1, teensy4_canfd::signal::ByteOrder::LittleEndian, self.flag as u64);
+        teensy4_canfd::signal::write_raw(&mut data, 47, 16, teensy4_canfd::signal::ByteOrder::BigEndian, teensy4_canfd::signal::unsigned_to_raw(self.motorola_word as u64, 16));
+        teensy4_canfd::signal::write_raw(&mut data, 61, 3, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::unsigned_to_raw(self.tail as u64, 3));
+        data
+    }
+
+    /// None if the frame has another ID or is too short
+    pub fn decode(frame: &teensy4_canfd::RxFDFrame) -> Option<Self> {
+        if frame.id != Self::ID {
+            return None;
+        }
+
+        Self::decode_payload(&frame.buffer[..(frame.buffer_len as usize).min(64)])
+    }
+
+    /// None if the payload is too short or has an unknown multiplexor value
+    pub fn decode_payload(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::LEN {
+            return None;
+        }
+
+        Some(Self {
+            intel_across: teensy4_canfd::signal::read_raw(data, 4, 12, teensy4_canfd::signal::ByteOrder::LittleEndian) as u16,
+            motorola_across: teensy4_canfd::signal::read_raw(data, 19, 12, teensy4_canfd::signal::ByteOrder::BigEndian) as u16,
+            flag: teensy4_canfd::signal::read_raw(data, 32, 1, teensy4_canfd::signal::ByteOrder::LittleEndian) != 0,
+            motorola_word: teensy4_canfd::signal::read_raw(data, 47, 16, teensy4_canfd::signal::ByteOrder::BigEndian) as u16,
+            tail: teensy4_canfd::signal::read_raw(data, 61, 3, teensy4_canfd::signal::ByteOrder::LittleEndian) as u8,
+        })
+    }
+}
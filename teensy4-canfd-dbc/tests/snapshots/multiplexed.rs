@@ -0,0 +1,107 @@
+// Generated by teensy4-canfd-dbc from a DBC file, don't edit
+
+/// Pages through voltage & identification
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Muxed {
+    pub counter: u8,
+    pub page: MuxedPage,
+}
+
+impl Muxed {
+    pub const ID: teensy4_canfd::config::Id = teensy4_canfd::config::Id::Standard(0x400);
+    pub const LEN: usize = 8;
+
+    /// The payload, goes into a `TxFDFrame` with `ID`
+    pub fn encode(&self) -> [u8; 8] {
+        let mut data = [0_u8; 8];
+        teensy4_canfd::signal::write_raw(&mut data, 0, 4, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::unsigned_to_raw(self.counter as u64, 4));
+        match self.page {
+            MuxedPage::M0 { voltage, status } => {
+                teensy4_canfd::signal::write_raw(&mut data, 4, 4, teensy4_canfd::signal::ByteOrder::LittleEndian, 0);
+                teensy4_canfd::signal::write_raw(&mut data, 8, 16, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::physical_to_raw(voltage, 0.001, 0.0, 16, false));
+                teensy4_canfd::signal::write_raw(&mut data, 24, 2, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::unsigned_to_raw(status.to_raw() as u64, 2));
+            }
+            MuxedPage::M1 { serial } => {
+                teensy4_canfd::signal::write_raw(&mut data, 4, 4, teensy4_canfd::signal::ByteOrder::LittleEndian, 1);
+                teensy4_canfd::signal::write_raw(&mut data, 8, 32, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::unsigned_to_raw(serial as u64, 32));
+            }
+        }
+        data
+    }
+
+    /// None if the frame has another ID or is too short
+    pub fn decode(frame: &teensy4_canfd::RxFDFrame) -> Option<Self> {
+        if frame.id != Self::ID {
+            return None;
+        }
+
+        Self::decode_payload(&frame.buffer[..(frame.buffer_len as usize).min(64)])
+    }
+
+    /// None if the payload is too short or has an unknown multiplexor value
+    pub fn decode_payload(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::LEN {
+            return None;
+        }
+
+        let page = match teensy4_canfd::signal::read_raw(data, 4, 4, teensy4_canfd::signal::ByteOrder::LittleEndian) {
+            0 => MuxedPage::M0 {
+                voltage: teensy4_canfd::signal::raw_to_physical(teensy4_canfd::signal::read_raw(data, 8, 16, teensy4_canfd::signal::ByteOrder::LittleEndian), 0.001, 0.0, 16, false),
+                status: MuxedStatus::from_raw(teensy4_canfd::signal::read_raw(data, 24, 2, teensy4_canfd::signal::ByteOrder::LittleEndian) as u8),
+            },
+            1 => MuxedPage::M1 {
+                serial: teensy4_canfd::signal::read_raw(data, 8, 32, teensy4_canfd::signal::ByteOrder::LittleEndian) as u32,
+            },
+            _ => return None,
+        };
+
+        Some(Self {
+            counter: teensy4_canfd::signal::read_raw(data, 0, 4, teensy4_canfd::signal::ByteOrder::LittleEndian) as u8,
+            page,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxedStatus {
+    /// Ok
+    Ok,
+    /// Warning
+    Warning,
+    /// Fault
+    Fault,
+    /// A value without a description
+    Other(u8),
+}
+
+impl MuxedStatus {
+    pub fn from_raw(raw: u8) -> Self {
+        match raw {
+            0 => Self::Ok,
+            1 => Self::Warning,
+            2 => Self::Fault,
+            _ => Self::Other(raw),
+        }
+    }
+
+    pub fn to_raw(self) -> u8 {
+        match self {
+            Self::Ok => 0,
+            Self::Warning => 1,
+            Self::Fault => 2,
+            Self::Other(raw) => raw,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MuxedPage {
+    M0 {
+        /// [V]
+        voltage: f64,
+        status: MuxedStatus,
+    },
+    M1 {
+        serial: u32,
+    },
+}
@@ -0,0 +1,53 @@
+// Generated by teensy4-canfd-dbc from a DBC file, don't edit
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scaled {
+    /// [degC]
+    pub temperature: f64,
+    /// [m/s]
+    pub speed: f64,
+    pub offset: i16,
+    /// Into the battery [A]
+    pub current: f64,
+    pub trim: i8,
+}
+
+impl Scaled {
+    pub const ID: teensy4_canfd::config::Id = teensy4_canfd::config::Id::Extended(0x00000200);
+    pub const LEN: usize = 8;
+
+    /// The payload, goes into a `TxFDFrame` with `ID`
+    pub fn encode(&self) -> [u8; 8] {
+        let mut data = [0_u8; 8];
+        teensy4_canfd::signal::write_raw(&mut data, 0, 8, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::physical_to_raw(self.temperature, 1.0, -40.0, 8, true));
+        teensy4_canfd::signal::write_raw(&mut data, 8, 16, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::physical_to_raw(self.speed, 0.01, 0.0, 16, false));
+        teensy4_canfd::signal::write_raw(&mut data, 24, 12, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::signed_to_raw(self.offset as i64, 12));
+        teensy4_canfd::signal::write_raw(&mut data, 47, 16, teensy4_canfd::signal::ByteOrder::BigEndian, teensy4_canfd::signal::physical_to_raw(self.current, 0.5, -100.0, 16, true));
+        teensy4_canfd::signal::write_raw(&mut data, 56, 8, teensy4_canfd::signal::ByteOrder::LittleEndian, teensy4_canfd::signal::signed_to_raw(self.trim as i64, 8));
+        data
+    }
+
+    /// None if the frame has another ID or is too short
+    pub fn decode(frame: &teensy4_canfd::RxFDFrame) -> Option<Self> {
+        if frame.id != Self::ID {
+            return None;
+        }
+
+        Self::decode_payload(&frame.buffer[..(frame.buffer_len as usize).min(64)])
+    }
+
+    /// None if the payload is too short or has an unknown multiplexor value
+    pub fn decode_payload(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::LEN {
+            return None;
+        }
+
+        Some(Self {
+            temperature: teensy4_canfd::signal::raw_to_physical(teensy4_canfd::signal::read_raw(data, 0, 8, teensy4_canfd::signal::ByteOrder::LittleEndian), 1.0, -40.0, 8, true),
+            speed: teensy4_canfd::signal::raw_to_physical(teensy4_canfd::signal::read_raw(data, 8, 16, teensy4_canfd::signal::ByteOrder::LittleEndian), 0.01, 0.0, 16, false),
+            offset: teensy4_canfd::signal::sign_extend(teensy4_canfd::signal::read_raw(data, 24, 12, teensy4_canfd::signal::ByteOrder::LittleEndian), 12) as i16,
+            current: teensy4_canfd::signal::raw_to_physical(teensy4_canfd::signal::read_raw(data, 47, 16, teensy4_canfd::signal::ByteOrder::BigEndian), 0.5, -100.0, 16, true),
+            trim: teensy4_canfd::signal::sign_extend(teensy4_canfd::signal::read_raw(data, 56, 8, teensy4_canfd::signal::ByteOrder::LittleEndian), 8) as i8,
+        })
+    }
+}
@@ -0,0 +1,230 @@
+//! A stand-in for `CAN3FD` that puts frames out as `candump -l` lines instead of on a bus
+
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use teensy4_canfd::can_error::RxTxError;
+use teensy4_canfd::candump::{self, MAX_LINE_LEN};
+use teensy4_canfd::pcapng::Sink;
+use teensy4_canfd::{FrameFormat, RxFDFrame, Transmitter, TxFDFrame};
+
+/// Interface names are capped like Linux does, `candump::MAX_LINE_LEN` counts on it
+pub const MAX_INTERFACE_LEN: usize = 15;
+
+pub struct CandumpBus<W> {
+    out: W,
+    interface: String,
+}
+
+impl<W: Write> CandumpBus<W> {
+    /// `interface` goes in every line, at most `MAX_INTERFACE_LEN` characters
+    pub fn new(out: W, interface: &str) -> Self {
+        Self {
+            out,
+            interface: interface.chars().take(MAX_INTERFACE_LEN).collect(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    fn write_line(&mut self, frame: &TxFDFrame, timestamp_us: u64) -> Result<(), RxTxError> {
+        let payload = frame.payload();
+        let mut rx_frame = RxFDFrame {
+            id: frame.id,
+            buffer_len: payload.len() as u32,
+            buffer: [0; 64],
+            timestamp: 0,
+            error_state: false,
+            fd_frame: frame.format.is_fd(),
+            bitrate_switch: frame.format.bitrate_switch(),
+            overrun: false,
+            mailbox_index: 0,
+            priority: 0,
+        };
+        rx_frame.buffer[..payload.len()].copy_from_slice(payload);
+
+        let mut line = [0; MAX_LINE_LEN];
+        let mut len = candump::write_frame(&mut line, timestamp_us, &self.interface, &rx_frame)
+            .map_err(|_| RxTxError::Unknown)?;
+
+        // `RxFDFrame` can't hold remote frames, so they're written as an empty one with the `R`
+        // & its DLC put on the end, like candump does
+        if let FrameFormat::Remote { dlc } = frame.format {
+            if dlc > 8 {
+                return Err(RxTxError::FrameTooBigForFormat);
+            }
+
+            len -= 1;
+            line[len] = b'R';
+            len += 1;
+
+            if dlc > 0 {
+                line[len] = b'0' + dlc;
+                len += 1;
+            }
+
+            line[len] = b'\n';
+            len += 1;
+        }
+
+        self.out
+            .write_all(&line[..len])
+            .and_then(|()| self.out.flush())
+            .map_err(|_| RxTxError::Unknown)
+    }
+}
+
+impl<W: Write> Transmitter for CandumpBus<W> {
+    fn transmit(&mut self, frame: &TxFDFrame) -> Result<(), RxTxError> {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_micros() as u64)
+            .unwrap_or(0);
+
+        self.write_line(frame, timestamp_us)
+    }
+}
+
+/// Lets the protocol layers write to anything implementing `std::io::Write`
+pub struct Stream<W>(pub W);
+
+impl<W: Write> Sink for Stream<W> {
+    type Error = io::Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write_all(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teensy4_canfd::config::Id;
+
+    fn line(frame: &TxFDFrame) -> String {
+        let mut bus = CandumpBus::new(Vec::new(), "vcan0");
+        bus.write_line(frame, 1_436_509_052_249_713).unwrap();
+
+        String::from_utf8(bus.out).unwrap()
+    }
+
+    fn frame(id: Id, buffer: &[u8], format: FrameFormat) -> TxFDFrame<'_> {
+        TxFDFrame {
+            id,
+            buffer,
+            priority: None,
+            format,
+        }
+    }
+
+    #[test]
+    fn classic_and_fd_lines() {
+        assert_eq!(
+            line(&frame(
+                Id::Standard(0x123),
+                &[0xDE, 0xAD, 0xBE, 0xEF],
+                FrameFormat::Classic
+            )),
+            "(1436509052.249713) vcan0 123#DEADBEEF\n"
+        );
+
+        let data: Vec<u8> = (0..12).collect();
+        assert_eq!(
+            line(&frame(
+                Id::Extended(0x1234_ABCD),
+                &data,
+                FrameFormat::Fd {
+                    bitrate_switch: true
+                }
+            )),
+            "(1436509052.249713) vcan0 1234ABCD##5000102030405060708090A0B\n"
+        );
+        assert_eq!(
+            line(&frame(
+                Id::Standard(0x7FF),
+                &[],
+                FrameFormat::Fd {
+                    bitrate_switch: false
+                }
+            )),
+            "(1436509052.249713) vcan0 7FF##4\n"
+        );
+    }
+
+    #[test]
+    fn lines_parse_back() {
+        let data = [0x11; 64];
+        let text = line(&frame(Id::Standard(0x42), &data, FrameFormat::default()));
+        let entry = candump::parse_line(&text).unwrap();
+
+        assert_eq!(entry.timestamp_us, 1_436_509_052_249_713);
+        assert_eq!(entry.interface, "vcan0");
+        assert_eq!(entry.frame.id, Id::Standard(0x42));
+        assert_eq!(
+            &entry.frame.buffer[..entry.frame.buffer_len as usize],
+            &data[..]
+        );
+        assert!(entry.frame.fd_frame && entry.frame.bitrate_switch);
+    }
+
+    #[test]
+    fn remote_frames() {
+        // The buffer isn't sent
+        assert_eq!(
+            line(&frame(
+                Id::Standard(0x123),
+                &[1, 2],
+                FrameFormat::Remote { dlc: 5 }
+            )),
+            "(1436509052.249713) vcan0 123#R5\n"
+        );
+        assert_eq!(
+            line(&frame(
+                Id::Extended(0x1ABC),
+                &[],
+                FrameFormat::Remote { dlc: 0 }
+            )),
+            "(1436509052.249713) vcan0 00001ABC#R\n"
+        );
+
+        let mut bus = CandumpBus::new(Vec::new(), "vcan0");
+        assert_eq!(
+            bus.write_line(
+                &frame(Id::Standard(1), &[], FrameFormat::Remote { dlc: 9 }),
+                0
+            ),
+            Err(RxTxError::FrameTooBigForFormat)
+        );
+        assert!(bus.out.is_empty());
+    }
+
+    #[test]
+    fn long_interface_names_are_cut() {
+        let bus = CandumpBus::new(Vec::new(), "a-very-long-interface-name");
+        assert_eq!(bus.interface, "a-very-long-int");
+    }
+
+    #[test]
+    fn refused_writes() {
+        struct Closed;
+
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut bus = CandumpBus::new(Closed, "can0");
+        assert_eq!(
+            bus.transmit(&frame(Id::Standard(1), &[1], FrameFormat::Classic)),
+            Err(RxTxError::Unknown)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Ties an SLCAN host to the candump side, one host at a time
+
+use std::io::{self, Write};
+use std::time::Instant;
+
+use teensy4_canfd::config::Clock;
+use teensy4_canfd::slcan::{Slcan, SlcanEvent};
+use teensy4_canfd::RxFDFrame;
+
+use crate::bus::{CandumpBus, Stream};
+
+// Only changes the timings handed back on `O`, which there's no FlexCAN to give to
+const CLOCK: Clock = Clock::Clock24Mhz;
+
+/// What `N` answers with
+const SERIAL_NUMBER: [u8; 4] = *b"GW01";
+
+pub struct Gateway<W> {
+    slcan: Slcan,
+    bus: CandumpBus<W>,
+    start: Instant,
+}
+
+impl<W: Write> Gateway<W> {
+    pub fn new(bus: CandumpBus<W>) -> Self {
+        Self {
+            slcan: Slcan::new(CLOCK, SERIAL_NUMBER),
+            bus,
+            start: Instant::now(),
+        }
+    }
+
+    /// Runs the commands in bytes from the host, replies go back through `host`. Frames the host
+    /// sends go out as candump lines.
+    pub fn handle_host<H: Write>(&mut self, bytes: &[u8], host: &mut H) -> io::Result<()> {
+        let mut host = Stream(host);
+
+        for byte in bytes.iter() {
+            match self.slcan.handle_byte(&mut self.bus, &mut host, *byte)? {
+                Some(SlcanEvent::Open { .. }) => eprintln!("SLCAN channel open"),
+                Some(SlcanEvent::Close) => eprintln!("SLCAN channel closed"),
+                None => (),
+            }
+        }
+
+        host.0.flush()
+    }
+
+    /// Passes a frame from the candump side on to the host, dropped while the channel is closed
+    pub fn handle_bus_frame<H: Write>(
+        &mut self,
+        frame: &RxFDFrame,
+        host: &mut H,
+    ) -> io::Result<()> {
+        let now_ms = self.start.elapsed().as_millis() as u64;
+
+        self.slcan
+            .write_frame(&mut Stream(&mut *host), frame, now_ms)?;
+        host.flush()
+    }
+
+    /// Forgets the host's settings & closes the channel, for when it goes away
+    pub fn disconnect(&mut self) {
+        self.slcan = Slcan::new(CLOCK, SERIAL_NUMBER);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teensy4_canfd::candump;
+
+    fn gateway() -> Gateway<Vec<u8>> {
+        Gateway::new(CandumpBus::new(Vec::new(), "vcan0"))
+    }
+
+    fn command(gateway: &mut Gateway<Vec<u8>>, bytes: &str) -> String {
+        let mut host = Vec::new();
+        gateway.handle_host(bytes.as_bytes(), &mut host).unwrap();
+
+        String::from_utf8(host).unwrap()
+    }
+
+    // The bus lines written so far, without their timestamps
+    fn bus_lines(gateway: &mut Gateway<Vec<u8>>) -> Vec<String> {
+        let out = String::from_utf8(std::mem::take(gateway.bus.get_mut())).unwrap();
+
+        out.lines()
+            .map(|line| line.split_once(") ").unwrap().1.to_string())
+            .collect()
+    }
+
+    fn bus_frame(line: &str) -> RxFDFrame {
+        candump::parse_line(line).unwrap().frame
+    }
+
+    #[test]
+    fn host_frames_go_out_as_candump_lines() {
+        let mut gateway = gateway();
+
+        // Nothing goes out before the channel's open
+        assert_eq!(command(&mut gateway, "t1230\r"), "\x07");
+        assert_eq!(command(&mut gateway, "S6\rY2\rO\r"), "\r\r\r");
+        assert!(gateway.slcan.is_open());
+
+        assert_eq!(
+            command(
+                &mut gateway,
+                "t1232AABB\rB1234ABCD9000102030405060708090A0B\rr7FF3\r"
+            ),
+            "z\rZ\rz\r"
+        );
+        assert_eq!(
+            bus_lines(&mut gateway),
+            [
+                "vcan0 123#AABB",
+                "vcan0 1234ABCD##5000102030405060708090A0B",
+                "vcan0 7FF#R3",
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_can_be_split_across_reads() {
+        let mut gateway = gateway();
+        assert_eq!(command(&mut gateway, "S6"), "");
+        assert_eq!(command(&mut gateway, "\rO\rt12"), "\r\r");
+        assert_eq!(command(&mut gateway, "31FF\r"), "z\r");
+        assert_eq!(bus_lines(&mut gateway), ["vcan0 123#FF"]);
+    }
+
+    #[test]
+    fn bus_frames_go_to_the_host_while_open() {
+        let mut gateway = gateway();
+        let mut host = Vec::new();
+
+        let frame = bus_frame("(1436509052.249713) can0 123#DEADBEEF");
+        gateway.handle_bus_frame(&frame, &mut host).unwrap();
+        assert!(host.is_empty());
+
+        command(&mut gateway, "S6\rO\r");
+        gateway.handle_bus_frame(&frame, &mut host).unwrap();
+        gateway
+            .handle_bus_frame(&bus_frame("(0.000000) can0 00000042##4AABB"), &mut host)
+            .unwrap();
+        assert_eq!(host, b"t1234DEADBEEF\rD000000422AABB\r");
+    }
+
+    #[test]
+    fn disconnecting_closes_the_channel() {
+        let mut gateway = gateway();
+        command(&mut gateway, "S6\rO\r");
+        gateway.disconnect();
+        assert!(!gateway.slcan.is_open());
+
+        // The next host has to pick a bitrate again
+        assert_eq!(command(&mut gateway, "O\r"), "\x07");
+        assert_eq!(command(&mut gateway, "t1230\r"), "\x07");
+        assert!(bus_lines(&mut gateway).is_empty());
+    }
+}
@@ -0,0 +1,277 @@
+//! Bridges an SLCAN host on TCP to a `candump -l` stream on stdin & stdout
+//!
+//! Frames the host sends come out on stdout as candump lines, lines read from stdin go to the host
+//! as received frames. There's no FlexCAN on the host, so a `CandumpBus` stands in for `CAN3FD`
+//! & the `slcan` & `candump` layers run just like they do on the Teensy. One host at a time, a new
+//! connection takes over from the last one.
+//!
+//! ```text
+//! candump -L can0 | teensy4-canfd-gateway --listen 127.0.0.1:3333 > sent.log
+//! socat pty,link=/tmp/ttyGW,raw tcp:127.0.0.1:3333
+//! ```
+
+mod bus;
+mod gateway;
+
+use std::env;
+use std::io::{self, BufRead, Read};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use teensy4_canfd::candump;
+use teensy4_canfd::RxFDFrame;
+
+use bus::{CandumpBus, MAX_INTERFACE_LEN};
+use gateway::Gateway;
+
+const USAGE: &str = "\
+Usage: teensy4-canfd-gateway [--listen ADDRESS] [--interface NAME]
+
+  --listen ADDRESS    Where SLCAN hosts connect, 127.0.0.1:3333 by default
+  --interface NAME    Interface name in the candump lines written, can0 by default";
+
+#[derive(Debug, PartialEq, Eq)]
+struct Options {
+    listen: String,
+    interface: String,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            listen: "127.0.0.1:3333".to_string(),
+            interface: "can0".to_string(),
+        };
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let slot = match arg.as_str() {
+                "--listen" => &mut options.listen,
+                "--interface" => &mut options.interface,
+                _ => return Err(format!("Unknown argument {:?}", arg)),
+            };
+
+            *slot = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", arg))?
+                .clone();
+        }
+
+        let interface = &options.interface;
+        if interface.is_empty()
+            || interface.len() > MAX_INTERFACE_LEN
+            || !interface.bytes().all(|c| c.is_ascii_graphic())
+        {
+            return Err(format!(
+                "The interface name has to be 1 to {} printable characters",
+                MAX_INTERFACE_LEN
+            ));
+        }
+
+        Ok(options)
+    }
+}
+
+enum Event {
+    Connected(usize, TcpStream),
+    Host(usize, Vec<u8>),
+    Disconnected(usize),
+    Bus(RxFDFrame),
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = run(&options) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> io::Result<()> {
+    let listener = TcpListener::bind(&options.listen)?;
+    eprintln!("Waiting for an SLCAN host on {}", listener.local_addr()?);
+
+    let (events, received) = mpsc::channel();
+    let bus_events = events.clone();
+    thread::spawn(move || read_bus(bus_events));
+    thread::spawn(move || accept_hosts(listener, events));
+
+    let stdout = io::stdout();
+    let mut gateway = Gateway::new(CandumpBus::new(stdout.lock(), &options.interface));
+    let mut host: Option<(usize, TcpStream)> = None;
+
+    for event in received {
+        let result = match (event, &mut host) {
+            (Event::Connected(id, stream), _) => {
+                if let Some((_, last)) = host.replace((id, stream)) {
+                    eprintln!("A new host took over");
+                    let _ = last.shutdown(Shutdown::Both);
+                } else {
+                    eprintln!("Host connected");
+                }
+
+                gateway.disconnect();
+                Ok(())
+            }
+            (Event::Host(id, bytes), Some((current, stream))) if id == *current => {
+                gateway.handle_host(&bytes, stream)
+            }
+            (Event::Disconnected(id), Some((current, _))) if id == *current => {
+                eprintln!("Host disconnected");
+                host = None;
+                gateway.disconnect();
+                Ok(())
+            }
+            (Event::Bus(frame), Some((_, stream))) => gateway.handle_bus_frame(&frame, stream),
+            // Left over from a host that's already gone, or nobody to pass a frame on to
+            _ => Ok(()),
+        };
+
+        if let Err(err) = result {
+            eprintln!("Dropping the host: {}", err);
+
+            if let Some((_, stream)) = host.take() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+
+            gateway.disconnect();
+        }
+    }
+
+    Ok(())
+}
+
+// Candump lines from stdin, anything that isn't one is skipped
+fn read_bus(events: Sender<Event>) {
+    let stdin = io::stdin();
+
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("Reading stdin: {}", err);
+                return;
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match candump::parse_line(&line) {
+            Ok(entry) => {
+                if events.send(Event::Bus(entry.frame)).is_err() {
+                    return;
+                }
+            }
+            Err(err) => eprintln!("Skipping {:?}: {:?}", line, err),
+        }
+    }
+}
+
+fn accept_hosts(listener: TcpListener, events: Sender<Event>) {
+    for (id, stream) in listener.incoming().enumerate() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Accepting a host: {}", err);
+                continue;
+            }
+        };
+
+        let reader = match stream.try_clone() {
+            Ok(reader) => reader,
+            Err(err) => {
+                eprintln!("Accepting a host: {}", err);
+                continue;
+            }
+        };
+
+        // Replies are a few bytes each, don't hold them back
+        let _ = stream.set_nodelay(true);
+
+        if events.send(Event::Connected(id, stream)).is_err() {
+            return;
+        }
+
+        let events = events.clone();
+        thread::spawn(move || read_host(id, reader, events));
+    }
+}
+
+fn read_host(id: usize, mut stream: TcpStream, events: Sender<Event>) {
+    let mut buffer = [0; 512];
+
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => {
+                if events
+                    .send(Event::Host(id, buffer[..len].to_vec()))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+
+    let _ = events.send(Event::Disconnected(id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Options::parse(&args)
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(
+            parse(&[]),
+            Ok(Options {
+                listen: "127.0.0.1:3333".to_string(),
+                interface: "can0".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn options() {
+        assert_eq!(
+            parse(&["--interface", "vcan1", "--listen", "0.0.0.0:20000"]),
+            Ok(Options {
+                listen: "0.0.0.0:20000".to_string(),
+                interface: "vcan1".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn bad_options() {
+        assert!(parse(&["--listen"]).is_err());
+        assert!(parse(&["--bitrate", "500000"]).is_err());
+        assert!(parse(&["--interface", ""]).is_err());
+        assert!(parse(&["--interface", "can 0"]).is_err());
+        assert!(parse(&["--interface", "a-very-long-interface-name"]).is_err());
+    }
+}